@@ -0,0 +1,274 @@
+//! API keys and the scopes they grant.
+//!
+//! Keys are random tokens handed out once at creation; only their SHA-256
+//! hash is stored. Keys carry enough entropy that a fast hash is as good
+//! as a slow one here.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Every key starts with this, so leaked keys are easy to search for.
+pub const KEY_PREFIX: &str = "carmine_";
+/// Random bytes in a key, hex-encoded after the prefix.
+const KEY_BYTES: usize = 32;
+/// How much of a key is kept in the clear to tell keys apart in listings.
+const DISPLAY_PREFIX_LEN: usize = KEY_PREFIX.len() + 8;
+
+/// Matches any cabinet name in a cabinet scope.
+pub const ANY_CABINET: &str = "*";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid scope '{0}': expected 'system:admin', 'cabinet:<name>:read' or 'cabinet:<name>:write'")]
+pub struct ParseScopeError(pub String);
+
+/// A permission held by a key or token. Written as `system:admin`,
+/// `cabinet:<name>:read` or `cabinet:<name>:write`, where `<name>` may be
+/// `*` for every cabinet, or narrowed to one shelf as
+/// `cabinet:<name>/<shelf>:read`. Write access includes read access.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Scope {
+    SystemAdmin,
+    CabinetRead(String),
+    CabinetWrite(String),
+    ShelfRead { cabinet: String, shelf: String },
+    ShelfWrite { cabinet: String, shelf: String },
+}
+
+/// What a request needs to be allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Reading a cabinet's data or change feed.
+    Read,
+    /// Writing a cabinet's data.
+    Write,
+    /// The `/system` endpoints.
+    Admin,
+}
+
+impl Scope {
+    /// Whether the scope reaches `shelf` of `cabinet`, or the whole cabinet
+    /// when `shelf` is `None`. Shelf scopes never reach a whole cabinet.
+    fn covers(&self, cabinet: &str, shelf: Option<&str>) -> bool {
+        let matches = |pattern: &str, name: &str| pattern == ANY_CABINET || pattern == name;
+        match self {
+            Scope::SystemAdmin => true,
+            Scope::CabinetRead(name) | Scope::CabinetWrite(name) => matches(name, cabinet),
+            Scope::ShelfRead { cabinet: c, shelf: s } | Scope::ShelfWrite { cabinet: c, shelf: s } => {
+                matches(c, cabinet) && shelf.is_some_and(|shelf| matches(s, shelf))
+            }
+        }
+    }
+
+    /// Whether this scope allows `access` to `shelf` of `cabinet`, or to
+    /// the whole cabinet when `shelf` is `None`. Admin access doesn't
+    /// concern a cabinet, so `cabinet` and `shelf` are ignored for it.
+    pub fn grants(&self, cabinet: &str, shelf: Option<&str>, access: Access) -> bool {
+        match (self, access) {
+            (Scope::SystemAdmin, _) => true,
+            (_, Access::Admin) => false,
+            (Scope::CabinetWrite(_) | Scope::ShelfWrite { .. }, _) => self.covers(cabinet, shelf),
+            (Scope::CabinetRead(_) | Scope::ShelfRead { .. }, Access::Read) => {
+                self.covers(cabinet, shelf)
+            }
+            (Scope::CabinetRead(_) | Scope::ShelfRead { .. }, Access::Write) => false,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::SystemAdmin => write!(f, "system:admin"),
+            Scope::CabinetRead(name) => write!(f, "cabinet:{}:read", name),
+            Scope::CabinetWrite(name) => write!(f, "cabinet:{}:write", name),
+            Scope::ShelfRead { cabinet, shelf } => write!(f, "cabinet:{}/{}:read", cabinet, shelf),
+            Scope::ShelfWrite { cabinet, shelf } => write!(f, "cabinet:{}/{}:write", cabinet, shelf),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ParseScopeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system:admin" {
+            return Ok(Scope::SystemAdmin);
+        }
+        // Shelf names may contain ':', so split from the right. Cabinet
+        // names can't contain ':' or '/', which separates the shelf.
+        let invalid = || ParseScopeError(s.to_string());
+        let (name, access) = s
+            .strip_prefix("cabinet:")
+            .and_then(|rest| rest.rsplit_once(':'))
+            .ok_or_else(invalid)?;
+        let write = match access {
+            "read" => false,
+            "write" => true,
+            _ => return Err(invalid()),
+        };
+        match name.split_once('/') {
+            None if !name.is_empty() => Ok(if write {
+                Scope::CabinetWrite(name.to_string())
+            } else {
+                Scope::CabinetRead(name.to_string())
+            }),
+            Some((cabinet, shelf)) if !cabinet.is_empty() && !shelf.is_empty() => {
+                let (cabinet, shelf) = (cabinet.to_string(), shelf.to_string());
+                Ok(if write {
+                    Scope::ShelfWrite { cabinet, shelf }
+                } else {
+                    Scope::ShelfRead { cabinet, shelf }
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = ParseScopeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Whether any of `scopes` allows `access` to `shelf` of `cabinet`, or to
+/// the whole cabinet when `shelf` is `None`.
+pub fn allows(scopes: &[Scope], cabinet: &str, shelf: Option<&str>, access: Access) -> bool {
+    scopes.iter().any(|scope| scope.grants(cabinet, shelf, access))
+}
+
+/// An API key as stored and listed; the key itself is never kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyMeta {
+    pub id: u64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    /// The start of the key, to tell keys apart without revealing them.
+    pub prefix: String,
+}
+
+/// Generates a new random key.
+pub fn generate_key() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; KEY_BYTES];
+    getrandom::fill(&mut bytes)?;
+    let mut key = String::with_capacity(KEY_PREFIX.len() + KEY_BYTES * 2);
+    key.push_str(KEY_PREFIX);
+    for b in bytes {
+        key.push_str(&format!("{:02x}", b));
+    }
+    Ok(key)
+}
+
+pub fn hash_key(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Compares two key hashes in constant time, so how long a check takes
+/// doesn't tell how much of a guess was right.
+pub fn hashes_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// The part of `key` that is safe to show in listings.
+pub fn display_prefix(key: &str) -> String {
+    key.chars().take(DISPLAY_PREFIX_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_round_trip() {
+        for text in [
+            "system:admin",
+            "cabinet:users:read",
+            "cabinet:*:write",
+            "cabinet:a:b:read",
+            "cabinet:users/profiles:write",
+        ] {
+            let scope: Scope = text.parse().unwrap();
+            assert_eq!(scope.to_string(), text);
+        }
+        assert_eq!(
+            "cabinet:a:b:read".parse::<Scope>().unwrap(),
+            Scope::CabinetRead("a:b".to_string())
+        );
+        assert_eq!(
+            "cabinet:users/profiles:read".parse::<Scope>().unwrap(),
+            Scope::ShelfRead { cabinet: "users".to_string(), shelf: "profiles".to_string() }
+        );
+        for bad in [
+            "system:read",
+            "cabinet::read",
+            "cabinet:users",
+            "cabinet:users:admin",
+            "cabinet:users/:read",
+            "cabinet:/profiles:read",
+            "",
+        ] {
+            assert!(bad.parse::<Scope>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_scope_grants() {
+        let read = Scope::CabinetRead("a".to_string());
+        let write = Scope::CabinetWrite("a".to_string());
+        let any = Scope::CabinetRead(ANY_CABINET.to_string());
+
+        assert!(read.grants("a", None, Access::Read));
+        assert!(read.grants("a", Some("s"), Access::Read));
+        assert!(!read.grants("a", None, Access::Write));
+        assert!(!read.grants("b", None, Access::Read));
+        assert!(write.grants("a", None, Access::Read));
+        assert!(write.grants("a", None, Access::Write));
+        assert!(!write.grants("a", None, Access::Admin));
+        assert!(any.grants("b", None, Access::Read));
+        assert!(Scope::SystemAdmin.grants("a", None, Access::Write));
+        assert!(Scope::SystemAdmin.grants("", None, Access::Admin));
+        assert!(!allows(&[], "a", None, Access::Read));
+    }
+
+    #[test]
+    fn test_shelf_scope_grants() {
+        let write: Scope = "cabinet:a/s:write".parse().unwrap();
+        let any_shelf: Scope = "cabinet:a/*:read".parse().unwrap();
+
+        assert!(write.grants("a", Some("s"), Access::Write));
+        assert!(write.grants("a", Some("s"), Access::Read));
+        assert!(!write.grants("a", Some("t"), Access::Read));
+        assert!(!write.grants("b", Some("s"), Access::Read));
+        assert!(!write.grants("a", None, Access::Read));
+        assert!(any_shelf.grants("a", Some("t"), Access::Read));
+        assert!(!any_shelf.grants("a", Some("t"), Access::Write));
+        assert!(!any_shelf.grants("a", None, Access::Read));
+    }
+
+    #[test]
+    fn test_generated_keys_are_distinct() {
+        let a = generate_key().unwrap();
+        let b = generate_key().unwrap();
+        assert!(a.starts_with(KEY_PREFIX));
+        assert_eq!(a.len(), KEY_PREFIX.len() + KEY_BYTES * 2);
+        assert_ne!(a, b);
+        assert!(!hashes_match(&hash_key(&a), &hash_key(&b)));
+        assert!(hashes_match(&hash_key(&a), &hash_key(&a)));
+    }
+}
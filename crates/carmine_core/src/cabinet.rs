@@ -1,4 +1,4 @@
-use redb::{Builder, Database, DatabaseError};
+use redb::{Builder, CompactionError, Database, DatabaseError};
 use std::sync::Arc;
 use std::{io, path::PathBuf};
 use thiserror::Error;
@@ -12,6 +12,12 @@ pub enum CabinetError {
     Register(#[from] io::Error),
     #[error("Failed to open cabinet: {0}")]
     Database(#[from] DatabaseError),
+    #[error("Cabinet '{0}' is still in use")]
+    InUse(String),
+    #[error("Failed to compact cabinet: {0}")]
+    Compaction(#[from] CompactionError),
+    #[error("Integrity check failed: {0}")]
+    Integrity(DatabaseError),
 }
 type Error = CabinetError;
 
@@ -47,4 +53,66 @@ impl Cabinet {
     pub fn database(&self) -> &Database {
         &self.db
     }
+
+    /// Whether this is the only handle to the cabinet, as [`compact`]
+    /// requires.
+    ///
+    /// [`compact`]: Cabinet::compact
+    pub fn is_exclusive(&self) -> bool {
+        Arc::strong_count(&self.db) == 1 && Arc::weak_count(&self.db) == 0
+    }
+
+    /// Rewrites the database file to release the space freed pages take
+    /// up, returning whether anything was reclaimed. Fails with
+    /// [`CabinetError::InUse`] while other handles to the cabinet exist.
+    pub fn compact(&mut self) -> Result<bool, Error> {
+        Ok(self.database_mut()?.compact()?)
+    }
+
+    /// Verifies the checksums and structure of the database file, repairing
+    /// what redb can. Returns `false` if something had to be repaired. Like
+    /// [`compact`](Cabinet::compact), needs the only handle.
+    pub fn check_integrity(&mut self) -> Result<bool, Error> {
+        self.database_mut()?.check_integrity().map_err(Error::Integrity)
+    }
+
+    fn database_mut(&mut self) -> Result<&mut Database, Error> {
+        Arc::get_mut(&mut self.db).ok_or_else(|| Error::InUse(self.name.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::{Key, KeyType};
+    use crate::shelf::Shelf;
+    use crate::transaction::Writable;
+    use crate::value::{Value, ValueType};
+
+    #[test]
+    fn test_compact_needs_the_only_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("c.redb");
+        let mut cabinet = Cabinet::create(1, "c".to_string(), path.clone(), 1 << 20).unwrap();
+        let shelf = Shelf::new("s".to_string(), KeyType::Int, ValueType::Byte);
+
+        let tx = cabinet.database().begin_write().unwrap();
+        for i in 0..2000 {
+            shelf.set(&tx, Key::Int(i.into()), Value::Byte(vec![0; 1024])).unwrap();
+        }
+        tx.commit().unwrap();
+        let tx = cabinet.database().begin_write().unwrap();
+        shelf.clear(&tx).unwrap();
+        tx.commit().unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        let other = cabinet.clone();
+        assert!(!cabinet.is_exclusive());
+        assert!(matches!(cabinet.compact(), Err(CabinetError::InUse(_))));
+        drop(other);
+
+        assert!(cabinet.is_exclusive());
+        cabinet.compact().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+    }
 }
@@ -0,0 +1,673 @@
+//! Per-cabinet change log.
+//!
+//! Every [`Writable`](crate::transaction::Writable) method on a shelf appends
+//! one record per applied change to the cabinet's `__changes` table, inside the
+//! caller's write transaction, so a change is visible in the log exactly when
+//! the write itself commits. Records are keyed by a sequence number that only
+//! ever grows, even after old records are pruned.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redb::{ReadableTable, TableDefinition};
+
+use crate::crypto::ValueCipher;
+use crate::key::Key;
+use crate::transaction::TransactionError;
+use crate::types::{Int, Number, RawObject};
+use crate::value::Value;
+
+/// Name of the table holding the change records.
+pub const CHANGES_TABLE: &str = "__changes";
+
+const CHANGES: TableDefinition<u64, &[u8]> = TableDefinition::new(CHANGES_TABLE);
+const CHANGES_META: TableDefinition<&str, u64> = TableDefinition::new("__changes_meta");
+
+const NEXT_SEQ_KEY: &str = "next_seq";
+const MAX_ENTRIES_KEY: &str = "max_entries";
+const MAX_AGE_KEY: &str = "max_age_secs";
+
+/// How many records a cabinet keeps when no retention has been configured.
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+
+/// Table names with this prefix belong to the cabinet itself and can't be
+/// used for shelves.
+pub const RESERVED_PREFIX: &str = "__";
+
+pub fn is_reserved_name(name: &str) -> bool {
+    name.starts_with(RESERVED_PREFIX)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Set,
+    Put,
+    Delete,
+    /// Every entry of the shelf was removed. Carries no key or value.
+    Clear,
+    /// The shelf was rewritten with new key/value types. Carries no key or
+    /// value; followers have to read the shelf again.
+    Migrate,
+}
+
+impl ChangeOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeOp::Set => "set",
+            ChangeOp::Put => "put",
+            ChangeOp::Delete => "delete",
+            ChangeOp::Clear => "clear",
+            ChangeOp::Migrate => "migrate",
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            ChangeOp::Set => 0,
+            ChangeOp::Put => 1,
+            ChangeOp::Delete => 2,
+            ChangeOp::Clear => 3,
+            ChangeOp::Migrate => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(ChangeOp::Set),
+            1 => Some(ChangeOp::Put),
+            2 => Some(ChangeOp::Delete),
+            3 => Some(ChangeOp::Clear),
+            4 => Some(ChangeOp::Migrate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch at the time of the write.
+    pub timestamp_ms: u64,
+    pub op: ChangeOp,
+    pub shelf: String,
+    pub key: Option<Key>,
+    /// The value after the change; `None` for deletes and clears.
+    pub value: Option<Value>,
+}
+
+/// Limits on how much history a cabinet keeps. Records beyond either limit
+/// are pruned as new ones are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_entries: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            max_age_secs: None,
+        }
+    }
+}
+
+/// A page of changes read with [`read_since`].
+#[derive(Debug, Clone, Default)]
+pub struct ChangePage {
+    pub changes: Vec<Change>,
+    /// Sequence number of the most recent change in the cabinet, or 0 if
+    /// nothing has been written yet.
+    pub latest: u64,
+    /// Sequence number of the oldest change still retained.
+    pub oldest: Option<u64>,
+    /// Records after the requested position were already pruned, so the
+    /// page doesn't pick up exactly where the caller left off.
+    pub truncated: bool,
+    /// Position to pass as `since` for the next page. With a shelf filter
+    /// this can be past the last returned change.
+    pub next: u64,
+}
+
+/// Appends records to the change log of the transaction's cabinet. Opened
+/// once per `Writable` call so a batch only touches the log tables once.
+pub(crate) struct ChangeWriter<'txn> {
+    changes: redb::Table<'txn, u64, &'static [u8]>,
+    meta: redb::Table<'txn, &'static str, u64>,
+    next_seq: u64,
+    timestamp_ms: u64,
+    appended: bool,
+}
+
+impl<'txn> ChangeWriter<'txn> {
+    pub(crate) fn open(tx: &'txn redb::WriteTransaction) -> Result<Self, TransactionError> {
+        let changes = tx.open_table(CHANGES)?;
+        let meta = tx.open_table(CHANGES_META)?;
+        let next_seq = meta_value(&meta, NEXT_SEQ_KEY)?.unwrap_or(1);
+        Ok(Self {
+            changes,
+            meta,
+            next_seq,
+            timestamp_ms: now_ms(),
+            appended: false,
+        })
+    }
+
+    /// Appends a record whose value, if any, is stored unencrypted.
+    pub(crate) fn append(
+        &mut self,
+        op: ChangeOp,
+        shelf: &str,
+        key: Option<&Key>,
+        value: Option<&Value>,
+    ) -> Result<(), TransactionError> {
+        let record = encode(self.timestamp_ms, op, shelf, key, value, None)?;
+        self.append_encoded(&record)
+    }
+
+    /// Appends a record built with [`encode_change`] before the write it
+    /// describes consumed its key and value.
+    pub(crate) fn append_encoded(&mut self, record: &[u8]) -> Result<(), TransactionError> {
+        self.changes.insert(self.next_seq, record)?;
+        self.next_seq += 1;
+        self.appended = true;
+        Ok(())
+    }
+
+    /// Stores the new sequence counter and prunes records that fall outside
+    /// the cabinet's retention.
+    pub(crate) fn finish(mut self) -> Result<(), TransactionError> {
+        if !self.appended {
+            return Ok(());
+        }
+        self.meta.insert(NEXT_SEQ_KEY, self.next_seq)?;
+        let retention = read_retention(&self.meta)?;
+        prune(
+            &mut self.changes,
+            &retention,
+            self.next_seq,
+            self.timestamp_ms,
+        )
+    }
+}
+
+/// Encodes a change record, sealing its value with `cipher` for shelves of
+/// encrypted cabinets.
+pub(crate) fn encode_change(
+    op: ChangeOp,
+    shelf: &str,
+    key: Option<&Key>,
+    value: Option<&Value>,
+    cipher: Option<&ValueCipher>,
+) -> Result<Vec<u8>, TransactionError> {
+    encode(now_ms(), op, shelf, key, value, cipher)
+}
+
+/// Records a single change. Convenience for callers outside the `Writable`
+/// methods that rewrite a shelf in bulk.
+pub(crate) fn record(
+    tx: &redb::WriteTransaction,
+    op: ChangeOp,
+    shelf: &str,
+    key: Option<&Key>,
+    value: Option<&Value>,
+) -> Result<(), TransactionError> {
+    let mut writer = ChangeWriter::open(tx)?;
+    writer.append(op, shelf, key, value)?;
+    writer.finish()
+}
+
+/// Reads up to `limit` changes with a sequence number greater than `since`,
+/// optionally only those for `shelf`. Encrypted cabinets need their
+/// `cipher` to open the values.
+pub fn read_since(
+    tx: &redb::ReadTransaction,
+    since: u64,
+    limit: usize,
+    shelf: Option<&str>,
+    cipher: Option<&ValueCipher>,
+) -> Result<ChangePage, TransactionError> {
+    let changes = match tx.open_table(CHANGES) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(ChangePage::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let meta = tx.open_table(CHANGES_META)?;
+    let latest = meta_value(&meta, NEXT_SEQ_KEY)?.unwrap_or(1) - 1;
+    let oldest = changes.first()?.map(|(k, _)| k.value());
+
+    let mut page = ChangePage {
+        changes: Vec::new(),
+        latest,
+        oldest,
+        truncated: since < latest && oldest.is_none_or(|o| o > since + 1),
+        next: since,
+    };
+    for entry in changes.range(since.saturating_add(1)..)? {
+        if page.changes.len() >= limit {
+            break;
+        }
+        let (seq, bytes) = entry?;
+        let change = decode(seq.value(), bytes.value(), cipher)?;
+        page.next = change.seq;
+        if shelf.is_none_or(|s| s == change.shelf) {
+            page.changes.push(change);
+        }
+    }
+    Ok(page)
+}
+
+/// Seals the values of up to `limit` records after `after` again with the
+/// cipher's current key, skipping those already sealed with it. Returns the
+/// sequence number to continue after, or `None` once the end of the log was
+/// reached, and how many records were rewritten.
+pub fn reseal(
+    tx: &redb::WriteTransaction,
+    cipher: &ValueCipher,
+    after: u64,
+    limit: usize,
+) -> Result<(Option<u64>, u64), TransactionError> {
+    let mut changes = tx.open_table(CHANGES)?;
+    let mut visited = 0;
+    let mut last = after;
+    let mut rewritten = Vec::new();
+    for entry in changes.range(after.saturating_add(1)..)? {
+        if visited >= limit {
+            break;
+        }
+        let (seq, bytes) = entry?;
+        visited += 1;
+        last = seq.value();
+        let current = sealed_value(seq.value(), bytes.value())?
+            .is_none_or(|sealed| ValueCipher::sealed_version(sealed) == Some(cipher.key_version()));
+        if current {
+            continue;
+        }
+        let change = decode(seq.value(), bytes.value(), Some(cipher))?;
+        let record = encode(
+            change.timestamp_ms,
+            change.op,
+            &change.shelf,
+            change.key.as_ref(),
+            change.value.as_ref(),
+            Some(cipher),
+        )?;
+        rewritten.push((change.seq, record));
+    }
+    let count = rewritten.len() as u64;
+    for (seq, record) in rewritten {
+        changes.insert(seq, record.as_slice())?;
+    }
+    Ok(((visited == limit).then_some(last), count))
+}
+
+/// When the most recent change was written, in milliseconds since the Unix
+/// epoch, or `None` if the log holds no records.
+pub fn last_write(tx: &redb::ReadTransaction) -> Result<Option<u64>, TransactionError> {
+    let changes = match tx.open_table(CHANGES) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    changes.last()?.map(|(_, bytes)| decode_timestamp(bytes.value())).transpose()
+}
+
+/// The cabinet's retention, or the default if none was configured.
+pub fn retention(tx: &redb::ReadTransaction) -> Result<Retention, TransactionError> {
+    match tx.open_table(CHANGES_META) {
+        Ok(meta) => read_retention(&meta),
+        Err(redb::TableError::TableDoesNotExist(_)) => Ok(Retention::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stores a new retention and prunes existing records to match it.
+pub fn set_retention(
+    tx: &redb::WriteTransaction,
+    retention: &Retention,
+) -> Result<(), TransactionError> {
+    let mut changes = tx.open_table(CHANGES)?;
+    let mut meta = tx.open_table(CHANGES_META)?;
+    // 0 is stored for "no limit" so that a missing key can keep meaning
+    // "use the default".
+    meta.insert(MAX_ENTRIES_KEY, retention.max_entries.unwrap_or(0))?;
+    meta.insert(MAX_AGE_KEY, retention.max_age_secs.unwrap_or(0))?;
+    let next_seq = meta_value(&meta, NEXT_SEQ_KEY)?.unwrap_or(1);
+    prune(&mut changes, retention, next_seq, now_ms())
+}
+
+fn read_retention(
+    meta: &impl ReadableTable<&'static str, u64>,
+) -> Result<Retention, TransactionError> {
+    let max_entries = match meta_value(meta, MAX_ENTRIES_KEY)? {
+        None => Some(DEFAULT_MAX_ENTRIES),
+        Some(0) => None,
+        Some(n) => Some(n),
+    };
+    let max_age_secs = meta_value(meta, MAX_AGE_KEY)?.filter(|&n| n > 0);
+    Ok(Retention {
+        max_entries,
+        max_age_secs,
+    })
+}
+
+fn meta_value(
+    meta: &impl ReadableTable<&'static str, u64>,
+    key: &str,
+) -> Result<Option<u64>, TransactionError> {
+    Ok(meta.get(key)?.map(|v| v.value()))
+}
+
+fn prune(
+    changes: &mut redb::Table<u64, &'static [u8]>,
+    retention: &Retention,
+    next_seq: u64,
+    now_ms: u64,
+) -> Result<(), TransactionError> {
+    if let Some(max_entries) = retention.max_entries {
+        let keep_from = next_seq.saturating_sub(max_entries);
+        changes.retain_in(..keep_from, |_, _| false)?;
+    }
+    if let Some(max_age) = retention.max_age_secs {
+        let cutoff = now_ms.saturating_sub(max_age.saturating_mul(1000));
+        loop {
+            let oldest = match changes.first()? {
+                Some((seq, bytes)) => (seq.value(), decode_timestamp(bytes.value())?),
+                None => break,
+            };
+            if oldest.1 >= cutoff {
+                break;
+            }
+            changes.remove(oldest.0)?;
+        }
+    }
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// --- Record encoding ---
+//
+// timestamp_ms: u64 LE | op: u8 | shelf: len-prefixed bytes |
+// key: tag u8 + len-prefixed bytes | value: tag u8 + len-prefixed bytes
+//
+// A tag of NONE_TAG marks a missing key or value and has no bytes after it.
+// Keys and values are stored in the same byte form their shelf tables use.
+// Values of encrypted cabinets are tagged SEALED_TAG and stored as sealed by
+// their `ValueCipher`, bound to the record's shelf and key.
+
+const NONE_TAG: u8 = 0xFF;
+const SEALED_TAG: u8 = 5;
+
+fn encode(
+    timestamp_ms: u64,
+    op: ChangeOp,
+    shelf: &str,
+    key: Option<&Key>,
+    value: Option<&Value>,
+    cipher: Option<&ValueCipher>,
+) -> Result<Vec<u8>, TransactionError> {
+    let mut out = Vec::with_capacity(32 + shelf.len());
+    out.extend_from_slice(&timestamp_ms.to_le_bytes());
+    out.push(op.tag());
+    put_bytes(&mut out, shelf.as_bytes());
+    match key {
+        Some(Key::String(s)) => put_tagged(&mut out, 0, s.as_bytes()),
+        Some(Key::Int(i)) => put_tagged(&mut out, 1, &i.to_be_bytes()),
+        Some(Key::Number(n)) => put_tagged(&mut out, 2, &n.to_jsonb()),
+        None => out.push(NONE_TAG),
+    }
+    match (key, value, cipher) {
+        (Some(key), Some(value), Some(cipher)) => {
+            put_tagged(&mut out, SEALED_TAG, &cipher.seal_value(shelf, key, value)?)
+        }
+        (None, Some(_), Some(_)) => {
+            return Err(TransactionError::CorruptRecord("change with a value but no key".to_string()));
+        }
+        (_, value, None) => encode_value(&mut out, value),
+        (_, None, Some(_)) => out.push(NONE_TAG),
+    }
+    Ok(out)
+}
+
+fn encode_value(out: &mut Vec<u8>, value: Option<&Value>) {
+    match value {
+        Some(Value::String(s)) => put_tagged(out, 0, s.as_bytes()),
+        Some(Value::Number(n)) => put_tagged(out, 1, &n.to_jsonb()),
+        Some(Value::Int(i)) => put_tagged(out, 2, &i.to_be_bytes()),
+        Some(Value::Object(o)) => put_tagged(out, 3, o),
+        Some(Value::Byte(b)) => put_tagged(out, 4, b),
+        None => out.push(NONE_TAG),
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_tagged(out: &mut Vec<u8>, tag: u8, bytes: &[u8]) {
+    out.push(tag);
+    put_bytes(out, bytes);
+}
+
+fn corrupt(seq: u64) -> TransactionError {
+    TransactionError::CorruptRecord(format!("change {}", seq))
+}
+
+fn decode_timestamp(bytes: &[u8]) -> Result<u64, TransactionError> {
+    bytes
+        .get(..8)
+        .and_then(|b| b.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or_else(|| TransactionError::CorruptRecord("change timestamp".to_string()))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    seq: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TransactionError> {
+        if self.bytes.len() < n {
+            return Err(corrupt(self.seq));
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, TransactionError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn len_prefixed(&mut self) -> Result<&'a [u8], TransactionError> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, TransactionError> {
+        String::from_utf8(self.len_prefixed()?.to_vec()).map_err(|_| corrupt(self.seq))
+    }
+
+    fn int(&mut self) -> Result<Int, TransactionError> {
+        let bytes: [u8; 8] = self
+            .len_prefixed()?
+            .try_into()
+            .map_err(|_| corrupt(self.seq))?;
+        Ok(Int(i64::from_be_bytes(bytes)))
+    }
+
+    fn number(&mut self) -> Result<Number, TransactionError> {
+        Number::from_jsonb(self.len_prefixed()?).map_err(|_| corrupt(self.seq))
+    }
+}
+
+/// The sealed value of a record, without opening it.
+fn sealed_value(seq: u64, bytes: &[u8]) -> Result<Option<&[u8]>, TransactionError> {
+    let mut r = Reader { bytes, seq };
+    r.take(9)?;
+    r.len_prefixed()?;
+    if r.u8()? != NONE_TAG {
+        r.len_prefixed()?;
+    }
+    match r.u8()? {
+        SEALED_TAG => Ok(Some(r.len_prefixed()?)),
+        _ => Ok(None),
+    }
+}
+
+fn decode(seq: u64, bytes: &[u8], cipher: Option<&ValueCipher>) -> Result<Change, TransactionError> {
+    let mut r = Reader { bytes, seq };
+    let timestamp_ms = u64::from_le_bytes(r.take(8)?.try_into().unwrap());
+    let op = ChangeOp::from_tag(r.u8()?).ok_or_else(|| corrupt(seq))?;
+    let shelf = r.string()?;
+    let key = match r.u8()? {
+        0 => Some(Key::String(r.string()?)),
+        1 => Some(Key::Int(r.int()?)),
+        2 => Some(Key::Number(r.number()?)),
+        NONE_TAG => None,
+        _ => return Err(corrupt(seq)),
+    };
+    let value = match r.u8()? {
+        0 => Some(Value::String(r.string()?)),
+        1 => Some(Value::Number(r.number()?)),
+        2 => Some(Value::Int(r.int()?)),
+        3 => Some(Value::Object(RawObject::from(r.len_prefixed()?.to_vec()))),
+        4 => Some(Value::Byte(r.len_prefixed()?.to_vec())),
+        SEALED_TAG => {
+            let sealed = r.len_prefixed()?;
+            let cipher = cipher.ok_or_else(|| {
+                TransactionError::CorruptRecord(format!("change {} is encrypted", seq))
+            })?;
+            let key = key.as_ref().ok_or_else(|| corrupt(seq))?;
+            Some(cipher.open_value(&shelf, key, sealed)?)
+        }
+        NONE_TAG => None,
+        _ => return Err(corrupt(seq)),
+    };
+    Ok(Change {
+        seq,
+        timestamp_ms,
+        op,
+        shelf,
+        key,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::KeyType;
+    use crate::shelf::Shelf;
+    use crate::test_util::temp_db;
+    use crate::transaction::Writable;
+    use crate::value::ValueType;
+    use redb::ReadableDatabase;
+
+    fn read_all(db: &redb::Database, since: u64) -> ChangePage {
+        let tx = db.begin_read().unwrap();
+        read_since(&tx, since, usize::MAX, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_writes_are_logged_in_order() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::String, ValueType::Int);
+        let tx = db.begin_write().unwrap();
+        shelf
+            .set(&tx, Key::String("a".into()), Value::Int(Int(1)))
+            .unwrap();
+        // A rejected put leaves no record behind.
+        assert!(
+            shelf
+                .put(&tx, Key::String("a".into()), Value::Int(Int(2)))
+                .is_err()
+        );
+        let results = shelf
+            .batch_put(
+                &tx,
+                &[
+                    (Key::String("b".into()), Value::Int(Int(2))),
+                    (Key::String("c".into()), Value::String("x".into())),
+                ],
+            )
+            .unwrap();
+        assert!(results[1].is_err());
+        shelf.delete(&tx, &Key::String("a".into())).unwrap();
+        shelf.delete(&tx, &Key::String("missing".into())).unwrap();
+        shelf.clear(&tx).unwrap();
+        tx.commit().unwrap();
+
+        let page = read_all(&db, 0);
+        let ops: Vec<_> = page.changes.iter().map(|c| (c.seq, c.op)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (1, ChangeOp::Set),
+                (2, ChangeOp::Put),
+                (3, ChangeOp::Delete),
+                (4, ChangeOp::Clear)
+            ]
+        );
+        assert_eq!(page.changes[1].key, Some(Key::String("b".into())));
+        assert_eq!(page.changes[1].value, Some(Value::Int(Int(2))));
+        assert_eq!(page.changes[2].value, None);
+        assert_eq!((page.latest, page.next), (4, 4));
+        assert!(!page.truncated);
+
+        assert_eq!(read_all(&db, 2).changes.len(), 2);
+    }
+
+    #[test]
+    fn test_dropped_transaction_logs_nothing() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::Int, ValueType::String);
+        let tx = db.begin_write().unwrap();
+        shelf
+            .set(&tx, Key::Int(Int(1)), Value::String("x".into()))
+            .unwrap();
+        drop(tx);
+
+        let page = read_all(&db, 0);
+        assert!(page.changes.is_empty());
+        assert_eq!(page.latest, 0);
+    }
+
+    #[test]
+    fn test_retention_prunes_oldest_records() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::Int, ValueType::Int);
+        let tx = db.begin_write().unwrap();
+        set_retention(
+            &tx,
+            &Retention {
+                max_entries: Some(3),
+                max_age_secs: None,
+            },
+        )
+        .unwrap();
+        for i in 0..5 {
+            shelf
+                .set(&tx, Key::Int(Int(i)), Value::Int(Int(i)))
+                .unwrap();
+        }
+        tx.commit().unwrap();
+
+        let page = read_all(&db, 0);
+        let seqs: Vec<_> = page.changes.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, vec![3, 4, 5]);
+        assert_eq!(page.oldest, Some(3));
+        assert!(page.truncated);
+        assert!(!read_all(&db, 2).truncated);
+
+        let tx = db.begin_read().unwrap();
+        assert_eq!(retention(&tx).unwrap().max_entries, Some(3));
+    }
+}
@@ -0,0 +1,497 @@
+//! Encryption of stored values for cabinets created with encryption on.
+//!
+//! Every encrypted cabinet gets its own keys, derived with HKDF-SHA256 from
+//! the server's master key, the cabinet id and a key version that rotation
+//! bumps. Values are sealed with a fresh random nonce and carry the version
+//! of the key that sealed them, so a cabinet stays readable while rotation
+//! is re-encrypting it:
+//!
+//! `key_version: u32 BE | nonce: 12 bytes | ciphertext + tag`
+//!
+//! The plaintext is the value with a leading type tag, in the same byte form
+//! its shelf table would use unencrypted. The shelf name and key are bound
+//! to it as associated data, so a sealed value copied to another entry
+//! fails to open.
+//!
+//! Cabinets also store a key check value derived from the master key, which
+//! tells a wrong master key apart from damaged data before anything is read.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::Engine;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::key::Key;
+use crate::types::{Int, Number, RawObject};
+use crate::value::Value;
+
+/// Length of a master key in bytes.
+pub const MASTER_KEY_LEN: usize = 32;
+
+const HKDF_SALT: &[u8] = b"carmine cabinet key";
+const KEY_CHECK_INFO: &[u8] = b"carmine key check";
+const VERSION_LEN: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("Invalid master key: {0}")]
+    MasterKey(String),
+    #[error("Value was sealed with invalid key version {0}")]
+    UnknownKeyVersion(u32),
+    #[error("Sealed value is truncated")]
+    Truncated,
+    #[error("Value failed authentication")]
+    Authentication,
+    #[error("Sealed value has an invalid payload")]
+    Payload,
+    #[error("Failed to seal value")]
+    Seal,
+    #[error("The master key is not the one the cabinet was encrypted with")]
+    WrongMasterKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Algorithm {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Aes256Gcm => "aes-256-gcm",
+            Algorithm::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    fn aead(&self) -> &'static aead::Algorithm {
+        match self {
+            Algorithm::Aes256Gcm => &aead::AES_256_GCM,
+            Algorithm::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(Algorithm::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Algorithm::ChaCha20Poly1305),
+            _ => Err(format!(
+                "unknown algorithm '{}', expected 'aes-256-gcm' or 'chacha20-poly1305'",
+                s
+            )),
+        }
+    }
+}
+
+impl From<Algorithm> for String {
+    fn from(algorithm: Algorithm) -> Self {
+        algorithm.as_str().to_string()
+    }
+}
+
+impl TryFrom<String> for Algorithm {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The secret every cabinet key is derived from.
+pub struct MasterKey {
+    prk: hkdf::Prk,
+}
+
+impl MasterKey {
+    pub fn new(bytes: &[u8; MASTER_KEY_LEN]) -> Self {
+        Self {
+            prk: hkdf::Salt::new(hkdf::HKDF_SHA256, HKDF_SALT).extract(bytes),
+        }
+    }
+
+    /// Parses the contents of a master key file: 32 raw bytes, or the same
+    /// as hex or base64 text.
+    pub fn from_file_contents(contents: &[u8]) -> Result<Self, CryptoError> {
+        if let Ok(bytes) = <&[u8; MASTER_KEY_LEN]>::try_from(contents) {
+            return Ok(Self::new(bytes));
+        }
+        let text = std::str::from_utf8(contents)
+            .map_err(|_| CryptoError::MasterKey(format!("expected {} bytes", MASTER_KEY_LEN)))?
+            .trim();
+        let decoded = decode_hex(text)
+            .or_else(|| base64::engine::general_purpose::STANDARD.decode(text).ok())
+            .ok_or_else(|| CryptoError::MasterKey("expected raw bytes, hex or base64".to_string()))?;
+        let bytes = <&[u8; MASTER_KEY_LEN]>::try_from(decoded.as_slice()).map_err(|_| {
+            CryptoError::MasterKey(format!(
+                "expected {} bytes, got {}",
+                MASTER_KEY_LEN,
+                decoded.len()
+            ))
+        })?;
+        Ok(Self::new(bytes))
+    }
+
+    /// The value stored with an encrypted cabinet to recognize this master
+    /// key by. It's an HKDF output of its own, so it reveals nothing about the
+    /// cabinet's keys.
+    pub fn key_check(&self, cabinet_id: u64) -> u64 {
+        let id = cabinet_id.to_be_bytes();
+        let info = [KEY_CHECK_INFO, id.as_slice()];
+        let mut check = [0u8; 8];
+        self.prk
+            .expand(&info, KeyCheckLen)
+            .and_then(|okm| okm.fill(&mut check))
+            .unwrap_or_else(|_| unreachable!("8 bytes is always a valid HKDF output"));
+        u64::from_be_bytes(check)
+    }
+
+    /// Fails unless `key_check` is this master key's check value for the
+    /// cabinet.
+    pub fn verify(&self, cabinet_id: u64, key_check: u64) -> Result<(), CryptoError> {
+        if self.key_check(cabinet_id) != key_check {
+            return Err(CryptoError::WrongMasterKey);
+        }
+        Ok(())
+    }
+
+    fn derive(&self, algorithm: Algorithm, cabinet_id: u64, version: u32) -> LessSafeKey {
+        let id = cabinet_id.to_be_bytes();
+        let version = version.to_be_bytes();
+        let info = [id.as_slice(), version.as_slice()];
+        let okm = self
+            .prk
+            .expand(&info, algorithm.aead())
+            .unwrap_or_else(|_| unreachable!("a key's length is always a valid HKDF output"));
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+struct KeyCheckLen;
+
+impl hkdf::KeyType for KeyCheckLen {
+    fn len(&self) -> usize {
+        8
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Seals and opens the values of one cabinet.
+pub struct ValueCipher {
+    master: Arc<MasterKey>,
+    algorithm: Algorithm,
+    cabinet_id: u64,
+    /// Keys for versions 1 up to the cabinet's current one; opening needs
+    /// the older ones until rotation has re-encrypted everything.
+    keys: Vec<LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl ValueCipher {
+    pub fn new(
+        master: Arc<MasterKey>,
+        algorithm: Algorithm,
+        cabinet_id: u64,
+        key_version: u32,
+    ) -> Self {
+        let keys = (1..=key_version.max(1))
+            .map(|version| master.derive(algorithm, cabinet_id, version))
+            .collect();
+        Self {
+            master,
+            algorithm,
+            cabinet_id,
+            keys,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The version new values are sealed with.
+    pub fn key_version(&self) -> u32 {
+        self.keys.len() as u32
+    }
+
+    /// The key version `sealed` was sealed with.
+    pub fn sealed_version(sealed: &[u8]) -> Option<u32> {
+        let version = sealed.get(..VERSION_LEN)?;
+        Some(u32::from_be_bytes(version.try_into().ok()?))
+    }
+
+    /// Seals `plaintext`, authenticating `aad` along with it.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| CryptoError::Seal)?;
+        let mut out = Vec::with_capacity(VERSION_LEN + NONCE_LEN + plaintext.len() + 16);
+        out.extend_from_slice(&self.key_version().to_be_bytes());
+        out.extend_from_slice(&nonce);
+        let mut body = plaintext.to_vec();
+        self.keys[self.keys.len() - 1]
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut body)
+            .map_err(|_| CryptoError::Seal)?;
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    /// Opens a value sealed with any of the cabinet's key versions. Versions
+    /// newer than this cipher's, written after a rotation it predates, are
+    /// derived on the spot so long-lived readers keep working. `aad` has to
+    /// be what the value was sealed with.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let version = Self::sealed_version(sealed).ok_or(CryptoError::Truncated)?;
+        if version == 0 {
+            return Err(CryptoError::UnknownKeyVersion(version));
+        }
+        let derived;
+        let key = match self.keys.get(version as usize - 1) {
+            Some(key) => key,
+            None => {
+                derived = self.master.derive(self.algorithm, self.cabinet_id, version);
+                &derived
+            }
+        };
+        let nonce = sealed
+            .get(VERSION_LEN..VERSION_LEN + NONCE_LEN)
+            .ok_or(CryptoError::Truncated)?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| CryptoError::Truncated)?;
+        let mut body = sealed[VERSION_LEN + NONCE_LEN..].to_vec();
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(aad), &mut body)
+            .map_err(|_| CryptoError::Authentication)?;
+        let len = plaintext.len();
+        body.truncate(len);
+        Ok(body)
+    }
+
+    /// Seals the value of `key` in `shelf`.
+    pub fn seal_value(&self, shelf: &str, key: &Key, value: &Value) -> Result<Vec<u8>, CryptoError> {
+        self.seal(&binding(shelf, key), &tagged_bytes(value))
+    }
+
+    pub fn open_value(&self, shelf: &str, key: &Key, sealed: &[u8]) -> Result<Value, CryptoError> {
+        from_tagged_bytes(&self.open(&binding(shelf, key), sealed)?).ok_or(CryptoError::Payload)
+    }
+
+    /// Seals the value inside `sealed` again with the current key. Returns
+    /// `None` when it already uses the current key.
+    pub fn reseal(&self, shelf: &str, key: &Key, sealed: &[u8]) -> Result<Option<Vec<u8>>, CryptoError> {
+        if Self::sealed_version(sealed) == Some(self.key_version()) {
+            return Ok(None);
+        }
+        let aad = binding(shelf, key);
+        self.seal(&aad, &self.open(&aad, sealed)?).map(Some)
+    }
+
+    /// Seals the value of `key` in `from` again for the same key in `to`, for
+    /// shelves that are renamed or copied.
+    pub fn rebind(&self, from: &str, to: &str, key: &Key, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.seal(&binding(to, key), &self.open(&binding(from, key), sealed)?)
+    }
+}
+
+impl fmt::Debug for ValueCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueCipher")
+            .field("algorithm", &self.algorithm)
+            .field("key_version", &self.key_version())
+            .finish()
+    }
+}
+
+/// The associated data a value is sealed with: the shelf name, length
+/// prefixed, then the key with a type tag. `Number` keys go in as their
+/// `f64` value, since equal keys like `2` and `2.0` find the same entry but
+/// encode differently.
+fn binding(shelf: &str, key: &Key) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + shelf.len() + 9);
+    out.extend_from_slice(&(shelf.len() as u32).to_be_bytes());
+    out.extend_from_slice(shelf.as_bytes());
+    match key {
+        Key::String(s) => {
+            out.push(0);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Key::Number(n) => {
+            // Adding zero turns -0.0 into 0.0, which compares equal to it.
+            out.push(1);
+            out.extend_from_slice(&(n.as_f64() + 0.0).to_be_bytes());
+        }
+        Key::Int(i) => {
+            out.push(2);
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+    }
+    out
+}
+
+fn tagged_bytes(value: &Value) -> Vec<u8> {
+    let (tag, bytes) = match value {
+        Value::String(s) => (0, s.as_bytes().to_vec()),
+        Value::Number(n) => (1, n.to_jsonb()),
+        Value::Int(i) => (2, i.to_be_bytes().to_vec()),
+        Value::Object(o) => (3, o.to_vec()),
+        Value::Byte(b) => (4, b.clone()),
+    };
+    let mut out = Vec::with_capacity(1 + bytes.len());
+    out.push(tag);
+    out.extend_from_slice(&bytes);
+    out
+}
+
+fn from_tagged_bytes(bytes: &[u8]) -> Option<Value> {
+    let (tag, rest) = bytes.split_first()?;
+    match tag {
+        0 => String::from_utf8(rest.to_vec()).ok().map(Value::String),
+        1 => Number::from_jsonb(rest).ok().map(Value::Number),
+        2 => Some(Value::Int(Int(i64::from_be_bytes(rest.try_into().ok()?)))),
+        3 => Some(Value::Object(RawObject::from(rest.to_vec()))),
+        4 => Some(Value::Byte(rest.to_vec())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(algorithm: Algorithm, cabinet_id: u64, version: u32) -> ValueCipher {
+        ValueCipher::new(Arc::new(MasterKey::new(&[7; MASTER_KEY_LEN])), algorithm, cabinet_id, version)
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::ChaCha20Poly1305] {
+            let cipher = cipher(algorithm, 1, 1);
+            let values = [
+                Value::String("secret".to_string()),
+                Value::Number(Number::from(jsonb::Number::Float64(1.5))),
+                Value::Int(Int(-42)),
+                Value::Object(RawObject::from(jsonb::parse_owned_jsonb(br#"{"a":1}"#).unwrap().to_vec())),
+                Value::Byte(vec![0, 1, 2]),
+            ];
+            let key = Key::String("k".to_string());
+            for value in values {
+                let sealed = cipher.seal_value("shelf", &key, &value).unwrap();
+                assert_eq!(cipher.open_value("shelf", &key, &sealed).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_keys_are_per_cabinet_and_authenticated() {
+        let sealed = cipher(Algorithm::Aes256Gcm, 1, 1).seal(b"", b"plain").unwrap();
+        assert!(!sealed.windows(5).any(|w| w == b"plain"));
+        assert!(matches!(
+            cipher(Algorithm::Aes256Gcm, 2, 1).open(b"", &sealed),
+            Err(CryptoError::Authentication)
+        ));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(cipher(Algorithm::Aes256Gcm, 1, 1).open(b"", &tampered).is_err());
+        assert!(cipher(Algorithm::Aes256Gcm, 1, 1).open(b"", &sealed[..10]).is_err());
+    }
+
+    #[test]
+    fn test_rotation_keeps_old_values_readable() {
+        let old = cipher(Algorithm::ChaCha20Poly1305, 1, 1);
+        let key = Key::Int(Int(1));
+        let sealed = old.seal_value("s", &key, &Value::Int(Int(5))).unwrap();
+
+        let new = cipher(Algorithm::ChaCha20Poly1305, 1, 2);
+        assert_eq!(new.open_value("s", &key, &sealed).unwrap(), Value::Int(Int(5)));
+        let resealed = new.reseal("s", &key, &sealed).unwrap().unwrap();
+        assert_eq!(ValueCipher::sealed_version(&resealed), Some(2));
+        assert!(new.reseal("s", &key, &resealed).unwrap().is_none());
+        assert_eq!(old.open_value("s", &key, &resealed).unwrap(), Value::Int(Int(5)));
+    }
+
+    #[test]
+    fn test_master_key_file_formats() {
+        let raw = [0xAB; MASTER_KEY_LEN];
+        let hex = "ab".repeat(MASTER_KEY_LEN);
+        let b64 = base64::engine::general_purpose::STANDARD.encode(raw);
+        let sealed = ValueCipher::new(
+            Arc::new(MasterKey::from_file_contents(&raw).unwrap()),
+            Algorithm::Aes256Gcm,
+            3,
+            1,
+        )
+        .seal(b"", b"x")
+        .unwrap();
+        for contents in [format!("{}\n", hex), b64] {
+            let master = MasterKey::from_file_contents(contents.as_bytes()).unwrap();
+            let cipher = ValueCipher::new(Arc::new(master), Algorithm::Aes256Gcm, 3, 1);
+            assert_eq!(cipher.open(b"", &sealed).unwrap(), b"x");
+        }
+        assert!(MasterKey::from_file_contents(b"too short").is_err());
+    }
+
+    #[test]
+    fn test_values_are_bound_to_shelf_and_key() {
+        let cipher = cipher(Algorithm::Aes256Gcm, 1, 1);
+        let key = Key::String("a".to_string());
+        let value = Value::String("v".to_string());
+        let sealed = cipher.seal_value("s", &key, &value).unwrap();
+        assert!(matches!(
+            cipher.open_value("s", &Key::String("b".to_string()), &sealed),
+            Err(CryptoError::Authentication)
+        ));
+        assert!(matches!(cipher.open_value("t", &key, &sealed), Err(CryptoError::Authentication)));
+
+        let moved = cipher.rebind("s", "t", &key, &sealed).unwrap();
+        assert_eq!(cipher.open_value("t", &key, &moved).unwrap(), value);
+
+        // Equal number keys open each other's values.
+        let two = Key::Number(Number::from(jsonb::Number::UInt64(2)));
+        let two_float = Key::Number(Number::from(jsonb::Number::Float64(2.0)));
+        let sealed = cipher.seal_value("s", &two, &value).unwrap();
+        assert_eq!(cipher.open_value("s", &two_float, &sealed).unwrap(), value);
+    }
+
+    #[test]
+    fn test_key_check_recognizes_master_key() {
+        let master = MasterKey::new(&[7; MASTER_KEY_LEN]);
+        let check = master.key_check(1);
+        assert!(master.verify(1, check).is_ok());
+        assert!(matches!(master.verify(2, check), Err(CryptoError::WrongMasterKey)));
+        assert!(matches!(
+            MasterKey::new(&[8; MASTER_KEY_LEN]).verify(1, check),
+            Err(CryptoError::WrongMasterKey)
+        ));
+    }
+}
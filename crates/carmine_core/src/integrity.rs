@@ -0,0 +1,542 @@
+//! Consistency checks of a cabinet: that its tables agree with the shelves'
+//! metadata, and that the values stored in them decode.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use redb::{
+    ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableError,
+    TableHandle, TypeName, WriteTransaction,
+};
+
+use crate::changelog::{self, ChangeOp, ChangeWriter};
+use crate::crypto::{CryptoError, ValueCipher};
+use crate::key::{Key, KeyType};
+use crate::shelf::Shelf;
+use crate::transaction::TransactionError;
+use crate::types::{Decoded, Int, Number, RawObject, TypesError};
+use crate::value::ValueType;
+
+/// How many invalid entries a report lists; the count covers all of them.
+pub const MAX_REPORTED_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableTypes {
+    pub key_type: KeyType,
+    pub value_type: ValueType,
+}
+
+/// A table no shelf refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanTable {
+    pub name: String,
+    pub entries: u64,
+    /// The types a shelf for it would have; `None` if they can't be told,
+    /// e.g. for an empty table of an encrypted cabinet.
+    pub types: Option<TableTypes>,
+}
+
+/// A shelf whose table was created with other key or value types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MismatchedTable {
+    pub shelf: String,
+    pub expected: TableTypes,
+    pub found: Option<TableTypes>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidEntry {
+    pub shelf: String,
+    pub key: Key,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    pub orphan_tables: Vec<OrphanTable>,
+    /// Shelves without a table; reading them fails until one is created.
+    pub missing_tables: Vec<String>,
+    pub mismatched_tables: Vec<MismatchedTable>,
+    /// The first [`MAX_REPORTED_ENTRIES`] entries whose value doesn't decode.
+    pub invalid_entries: Vec<InvalidEntry>,
+    pub invalid_entry_count: u64,
+    /// The first [`MAX_REPORTED_ENTRIES`] sealed values that fail
+    /// authentication or name a key version the cabinet doesn't have. These
+    /// point at the wrong master key rather than bad data, so repairs keep
+    /// them.
+    pub undecryptable_entries: Vec<InvalidEntry>,
+    pub undecryptable_entry_count: u64,
+    /// Sealed values read, decrypted or not.
+    pub sealed_entry_count: u64,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_tables.is_empty()
+            && self.missing_tables.is_empty()
+            && self.mismatched_tables.is_empty()
+            && self.invalid_entry_count == 0
+            && self.undecryptable_entry_count == 0
+    }
+
+    /// Whether there were sealed values and none of them decrypted.
+    pub fn nothing_decrypts(&self) -> bool {
+        self.sealed_entry_count > 0 && self.undecryptable_entry_count == self.sealed_entry_count
+    }
+}
+
+/// Why a stored value can't be read back.
+enum EntryError {
+    /// The value doesn't decode, or decrypts to something it shouldn't.
+    Invalid(String),
+    /// The value doesn't decrypt with the cabinet's keys.
+    Undecryptable(String),
+}
+
+/// Reads the bytes of a stored `V` without decoding them, so a value that
+/// doesn't decode can be reported rather than panic.
+#[derive(Debug)]
+struct Stored<V>(PhantomData<V>);
+
+impl<V: redb::Value + 'static> redb::Value for Stored<V> {
+    type SelfType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        V::fixed_width()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> TypeName {
+        V::type_name()
+    }
+}
+
+/// The redb key types shelves are stored with.
+trait ShelfKey: redb::Key + 'static {
+    fn to_key(key: Self::SelfType<'_>) -> Result<Key, TypesError>;
+}
+
+impl ShelfKey for String {
+    fn to_key(key: Self::SelfType<'_>) -> Result<Key, TypesError> {
+        Ok(Key::String(key))
+    }
+}
+
+impl ShelfKey for Number {
+    fn to_key(key: Self::SelfType<'_>) -> Result<Key, TypesError> {
+        key.decoded().map(Key::Number)
+    }
+}
+
+impl ShelfKey for i64 {
+    fn to_key(key: Self::SelfType<'_>) -> Result<Key, TypesError> {
+        Ok(Key::Int(Int(key)))
+    }
+}
+
+/// Calls `$f::<K, V>` with the key type and the stored value type of a
+/// shelf whose values can fail to decode: sealed values of an encrypted
+/// shelf, `Number` and `Object` values otherwise. Other shelves are skipped.
+macro_rules! with_decodable_types {
+    ($shelf:expr, $f:ident($($arg:expr),*)) => {
+        match ($shelf.key_type, $shelf.cipher.is_some(), $shelf.value_type) {
+            (KeyType::String, true, _) => $f::<String, &'static [u8]>($($arg),*),
+            (KeyType::Number, true, _) => $f::<Number, &'static [u8]>($($arg),*),
+            (KeyType::Int, true, _) => $f::<i64, &'static [u8]>($($arg),*),
+            (KeyType::String, false, ValueType::Number) => $f::<String, Number>($($arg),*),
+            (KeyType::Number, false, ValueType::Number) => $f::<Number, Number>($($arg),*),
+            (KeyType::Int, false, ValueType::Number) => $f::<i64, Number>($($arg),*),
+            (KeyType::String, false, ValueType::Object) => $f::<String, RawObject>($($arg),*),
+            (KeyType::Number, false, ValueType::Object) => $f::<Number, RawObject>($($arg),*),
+            (KeyType::Int, false, ValueType::Object) => $f::<i64, RawObject>($($arg),*),
+            _ => Ok(Default::default()),
+        }
+    };
+}
+
+fn check_encoding(value_type: ValueType, bytes: &[u8]) -> Result<(), String> {
+    match value_type {
+        ValueType::Number => Number::from_jsonb(bytes)
+            .map(|_| ())
+            .map_err(|e| format!("not a valid Number: {}", e)),
+        ValueType::Object => jsonb::from_slice(bytes)
+            .map(|_| ())
+            .map_err(|e| format!("not a valid Object: {}", e)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the value of `key` as stored in `shelf`'s table can be read back.
+fn check_value(shelf: &Shelf, key: &Key, stored: &[u8]) -> Result<(), EntryError> {
+    let Some(cipher) = &shelf.cipher else {
+        return check_encoding(shelf.value_type, stored).map_err(EntryError::Invalid);
+    };
+    let value = cipher.open_value(&shelf.name, key, stored).map_err(|e| match e {
+        CryptoError::Authentication | CryptoError::UnknownKeyVersion(_) => {
+            EntryError::Undecryptable(e.to_string())
+        }
+        e => EntryError::Invalid(e.to_string()),
+    })?;
+    if value.as_type() != shelf.value_type {
+        return Err(EntryError::Invalid(format!(
+            "expected a {} value, found {}",
+            shelf.value_type.as_str(),
+            value.as_type().as_str()
+        )));
+    }
+    match value {
+        crate::value::Value::Object(object) => {
+            check_encoding(ValueType::Object, &object).map_err(EntryError::Invalid)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn key_type(name: &TypeName) -> Option<KeyType> {
+    [
+        (KeyType::String, <String as redb::Value>::type_name()),
+        (KeyType::Number, <Number as redb::Value>::type_name()),
+        (KeyType::Int, <i64 as redb::Value>::type_name()),
+    ]
+    .into_iter()
+    .find(|(_, type_name)| type_name == name)
+    .map(|(key_type, _)| key_type)
+}
+
+fn value_type(name: &TypeName) -> Option<ValueType> {
+    [
+        (ValueType::String, <String as redb::Value>::type_name()),
+        (ValueType::Number, <Number as redb::Value>::type_name()),
+        (ValueType::Int, <i64 as redb::Value>::type_name()),
+        (ValueType::Object, <RawObject as redb::Value>::type_name()),
+        (ValueType::Byte, <&[u8] as redb::Value>::type_name()),
+    ]
+    .into_iter()
+    .find(|(_, type_name)| type_name == name)
+    .map(|(value_type, _)| value_type)
+}
+
+/// The types table `name` is stored with, or `None` if they aren't types a
+/// shelf uses. redb names the actual types when a table is opened with the
+/// wrong ones.
+fn stored_types(tx: &ReadTransaction, name: &str) -> Result<Option<TableTypes>, TransactionError> {
+    let probe: TableDefinition<(), ()> = TableDefinition::new(name);
+    match tx.open_table(probe) {
+        Ok(_) => Ok(None),
+        Err(TableError::TableTypeMismatch { key, value, .. }) => Ok(key_type(&key)
+            .zip(value_type(&value))
+            .map(|(key_type, value_type)| TableTypes { key_type, value_type })),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn first_value_type<K: ShelfKey>(
+    tx: &ReadTransaction,
+    name: &str,
+    cipher: &ValueCipher,
+) -> Result<Option<ValueType>, TransactionError> {
+    let table = tx.open_table(TableDefinition::<K, &[u8]>::new(name))?;
+    let Some((key, value)) = table.first()? else {
+        return Ok(None);
+    };
+    let Ok(key) = K::to_key(key.value()) else {
+        return Ok(None);
+    };
+    Ok(cipher.open_value(name, &key, value.value()).ok().map(|value| value.as_type()))
+}
+
+/// The types a shelf for table `name` has. In an encrypted cabinet every
+/// table stores bytes, so the value type is taken from the first entry.
+fn shelf_types(
+    tx: &ReadTransaction,
+    name: &str,
+    stored: Option<TableTypes>,
+    cipher: Option<&ValueCipher>,
+) -> Result<Option<TableTypes>, TransactionError> {
+    let (Some(stored), Some(cipher)) = (stored, cipher) else {
+        return Ok(stored);
+    };
+    if stored.value_type != ValueType::Byte {
+        return Ok(Some(stored));
+    }
+    let value_type = match stored.key_type {
+        KeyType::String => first_value_type::<String>(tx, name, cipher)?,
+        KeyType::Number => first_value_type::<Number>(tx, name, cipher)?,
+        KeyType::Int => first_value_type::<i64>(tx, name, cipher)?,
+    };
+    Ok(value_type.map(|value_type| TableTypes { key_type: stored.key_type, value_type }))
+}
+
+fn scan_entries<K: ShelfKey, V: redb::Value + 'static>(
+    tx: &ReadTransaction,
+    shelf: &Shelf,
+    report: &mut IntegrityReport,
+) -> Result<(), TransactionError> {
+    let table = tx.open_table(TableDefinition::<K, Stored<V>>::new(&shelf.name))?;
+    for entry in table.iter()? {
+        let (key, value) = entry?;
+        let key = K::to_key(key.value())?;
+        if shelf.cipher.is_some() {
+            report.sealed_entry_count += 1;
+        }
+        let (entries, count, error) = match check_value(shelf, &key, value.value()) {
+            Ok(()) => continue,
+            Err(EntryError::Invalid(error)) => {
+                (&mut report.invalid_entries, &mut report.invalid_entry_count, error)
+            }
+            Err(EntryError::Undecryptable(error)) => {
+                (&mut report.undecryptable_entries, &mut report.undecryptable_entry_count, error)
+            }
+        };
+        *count += 1;
+        if entries.len() < MAX_REPORTED_ENTRIES {
+            entries.push(InvalidEntry { shelf: shelf.name.clone(), key, error });
+        }
+    }
+    Ok(())
+}
+
+/// Removes the entries of `shelf` whose value doesn't decode, recording a
+/// delete for each in the change log so followers drop them too.
+fn remove_invalid<K: ShelfKey, V: redb::Value + 'static>(
+    tx: &WriteTransaction,
+    shelf: &Shelf,
+) -> Result<u64, TransactionError> {
+    let mut removed = Vec::new();
+    {
+        let mut table = tx.open_table(TableDefinition::<K, Stored<V>>::new(&shelf.name))?;
+        table.retain(|key, value| match K::to_key(key) {
+            Ok(key) => {
+                let invalid = matches!(check_value(shelf, &key, value), Err(EntryError::Invalid(_)));
+                if invalid {
+                    removed.push(key);
+                }
+                !invalid
+            }
+            // A key that doesn't decode fails the check before repair gets here.
+            Err(_) => true,
+        })?;
+    }
+    if !removed.is_empty() {
+        let mut changes = ChangeWriter::open(tx)?;
+        for key in &removed {
+            changes.append(ChangeOp::Delete, &shelf.name, Some(key), None)?;
+        }
+        changes.finish()?;
+    }
+    Ok(removed.len() as u64)
+}
+
+/// Compares the tables of a cabinet with `shelves`, its shelves with their
+/// ciphers, and reads every value that can fail to decode. `cipher` is the
+/// cabinet's, used to tell the types of tables no shelf refers to.
+pub fn check(
+    tx: &ReadTransaction,
+    shelves: &[Shelf],
+    cipher: Option<&ValueCipher>,
+) -> Result<IntegrityReport, TransactionError> {
+    let mut report = IntegrityReport::default();
+    let handles: HashMap<String, _> = tx
+        .list_tables()?
+        .map(|handle| (handle.name().to_string(), handle))
+        .collect();
+
+    let mut orphans: Vec<_> = handles
+        .iter()
+        .filter(|(name, _)| {
+            !changelog::is_reserved_name(name) && !shelves.iter().any(|shelf| &shelf.name == *name)
+        })
+        .collect();
+    orphans.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, handle) in orphans {
+        let entries = tx.open_untyped_table(handle.clone())?.len()?;
+        let types = shelf_types(tx, name, stored_types(tx, name)?, cipher)?;
+        report.orphan_tables.push(OrphanTable { name: name.clone(), entries, types });
+    }
+
+    for shelf in shelves {
+        if !handles.contains_key(&shelf.name) {
+            report.missing_tables.push(shelf.name.clone());
+            continue;
+        }
+        let storage = shelf.storage();
+        let stored = stored_types(tx, &shelf.name)?;
+        if stored != Some(TableTypes { key_type: storage.key_type, value_type: storage.value_type }) {
+            report.mismatched_tables.push(MismatchedTable {
+                shelf: shelf.name.clone(),
+                expected: TableTypes { key_type: shelf.key_type, value_type: shelf.value_type },
+                found: shelf_types(tx, &shelf.name, stored, shelf.cipher.as_deref())?,
+            });
+            continue;
+        }
+        with_decodable_types!(shelf, scan_entries(tx, shelf, &mut report))?;
+    }
+    Ok(report)
+}
+
+/// Fixes what can be fixed inside the cabinet: creates the missing tables
+/// and removes the entries whose value doesn't decode, returning how many
+/// were removed. Orphan and mismatched tables are left for the caller,
+/// since fixing them means changing the shelves' metadata. Removals are
+/// recorded in the change log as deletes. Values that
+/// don't decrypt are never removed, and if none do, nothing is repaired,
+/// since the cabinet is most likely being read with the wrong key.
+pub fn repair(
+    tx: &WriteTransaction,
+    shelves: &[Shelf],
+    report: &IntegrityReport,
+) -> Result<u64, TransactionError> {
+    if report.nothing_decrypts() {
+        return Err(TransactionError::RepairRefused(
+            "no sealed value decrypts with the cabinet's keys".to_string(),
+        ));
+    }
+    let mut removed = 0;
+    for shelf in shelves {
+        if report.missing_tables.contains(&shelf.name) {
+            shelf.create_table(tx)?;
+        } else if report.invalid_entry_count > 0
+            && !report.mismatched_tables.iter().any(|table| table.shelf == shelf.name)
+        {
+            removed += with_decodable_types!(shelf, remove_invalid(tx, shelf))?;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::crypto::{Algorithm, MasterKey};
+    use crate::test_util::temp_db;
+    use crate::transaction::{Readable, Writable};
+    use crate::value::Value;
+    use redb::ReadableDatabase;
+
+    fn number(n: i64) -> Value {
+        Value::Number(Number::from(jsonb::Number::Int64(n)))
+    }
+
+    #[test]
+    fn test_check_finds_and_repairs_discrepancies() {
+        let (_file, db) = temp_db();
+        let numbers = Shelf::new("numbers".to_string(), KeyType::String, ValueType::Number);
+        let orphan = Shelf::new("orphan".to_string(), KeyType::Int, ValueType::String);
+        let retyped = Shelf::new("retyped".to_string(), KeyType::String, ValueType::Int);
+        let missing = Shelf::new("missing".to_string(), KeyType::Int, ValueType::Object);
+
+        let tx = db.begin_write().unwrap();
+        numbers.set(&tx, Key::String("good".into()), number(1)).unwrap();
+        orphan.set(&tx, Key::Int(Int(1)), Value::String("a".into())).unwrap();
+        retyped.set(&tx, Key::String("a".into()), Value::Int(Int(1))).unwrap();
+        {
+            let raw: TableDefinition<String, Stored<Number>> = TableDefinition::new("numbers");
+            let mut table = tx.open_table(raw).unwrap();
+            table.insert("bad".to_string(), [0xff, 0xff, 0xff].as_slice()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let retyped_meta = Shelf::new("retyped".to_string(), KeyType::String, ValueType::String);
+        let shelves = [numbers.clone(), retyped_meta, missing.clone()];
+        let report = check(&db.begin_read().unwrap(), &shelves, None).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.orphan_tables,
+            vec![OrphanTable {
+                name: "orphan".to_string(),
+                entries: 1,
+                types: Some(TableTypes { key_type: KeyType::Int, value_type: ValueType::String }),
+            }]
+        );
+        assert_eq!(report.missing_tables, vec!["missing".to_string()]);
+        assert_eq!(report.mismatched_tables.len(), 1);
+        assert_eq!(
+            report.mismatched_tables[0].found,
+            Some(TableTypes { key_type: KeyType::String, value_type: ValueType::Int })
+        );
+        assert_eq!(report.invalid_entry_count, 1);
+        assert_eq!(report.invalid_entries[0].key, Key::String("bad".into()));
+
+        let tx = db.begin_write().unwrap();
+        assert_eq!(repair(&tx, &shelves, &report).unwrap(), 1);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        assert!(numbers.get(&tx, &Key::String("good".into())).unwrap().is_some());
+        assert_eq!(numbers.count(&tx).unwrap(), 1);
+        assert_eq!(missing.count(&tx).unwrap(), 0);
+        let changes = changelog::read_since(&tx, 0, 10, Some("numbers"), None).unwrap().changes;
+        let last = changes.last().unwrap();
+        assert_eq!((last.op, &last.key), (ChangeOp::Delete, &Some(Key::String("bad".into()))));
+        let report = check(&tx, &shelves, None).unwrap();
+        assert!(report.missing_tables.is_empty());
+        assert_eq!(report.invalid_entry_count, 0);
+    }
+
+    fn sealed_shelf(master_key: u8) -> Shelf {
+        let master = Arc::new(MasterKey::new(&[master_key; crate::crypto::MASTER_KEY_LEN]));
+        let cipher = Arc::new(ValueCipher::new(master, Algorithm::Aes256Gcm, 1, 1));
+        Shelf::new("secrets".to_string(), KeyType::Int, ValueType::String).with_cipher(Some(cipher))
+    }
+
+    #[test]
+    fn test_repair_keeps_values_that_dont_decrypt() {
+        let (_file, db) = temp_db();
+        let shelf = sealed_shelf(1);
+        let text = |s: &str| Value::String(s.to_string());
+
+        let tx = db.begin_write().unwrap();
+        shelf.set(&tx, Key::Int(Int(1)), text("good")).unwrap();
+        sealed_shelf(2).set(&tx, Key::Int(Int(2)), text("other key")).unwrap();
+        {
+            let raw: TableDefinition<i64, &[u8]> = TableDefinition::new("secrets");
+            let mut table = tx.open_table(raw).unwrap();
+            table.insert(3, [0u8, 0, 0].as_slice()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let shelves = [shelf.clone()];
+        let report = check(&db.begin_read().unwrap(), &shelves, shelf.cipher.as_deref()).unwrap();
+        assert_eq!(report.sealed_entry_count, 3);
+        assert_eq!(report.invalid_entry_count, 1);
+        assert_eq!(report.invalid_entries[0].key, Key::Int(Int(3)));
+        assert_eq!(report.undecryptable_entry_count, 1);
+        assert_eq!(report.undecryptable_entries[0].key, Key::Int(Int(2)));
+        assert!(!report.nothing_decrypts());
+
+        let tx = db.begin_write().unwrap();
+        assert_eq!(repair(&tx, &shelves, &report).unwrap(), 1);
+        tx.commit().unwrap();
+        assert_eq!(shelf.count(&db.begin_read().unwrap()).unwrap(), 2);
+
+        // Read with the wrong master key, nothing decrypts and nothing is
+        // repaired.
+        let wrong = [sealed_shelf(3)];
+        let report = check(&db.begin_read().unwrap(), &wrong, wrong[0].cipher.as_deref()).unwrap();
+        assert!(report.nothing_decrypts());
+        let tx = db.begin_write().unwrap();
+        assert!(matches!(repair(&tx, &wrong, &report), Err(TransactionError::RepairRefused(_))));
+    }
+}
@@ -0,0 +1,299 @@
+//! Verification of JWT bearer tokens issued by an outside identity
+//! provider.
+//!
+//! Tokens are accepted when signed with HS256, RS256 or EdDSA by one of the
+//! configured keys. Each key is tied to the one algorithm that fits it, so a
+//! token can't pick a weaker algorithm for a key than the key was meant for.
+//! The token's permissions are read from a claim holding [`Scope`]s, either
+//! as an array of strings or a space-separated string as in OAuth's `scope`.
+
+use std::collections::HashMap;
+
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use thiserror::Error;
+
+use crate::auth::Scope;
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("Invalid key: {0}")]
+    Key(String),
+    #[error("No configured key matches the token")]
+    NoMatchingKey,
+    #[error("Unsupported token algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error(transparent)]
+    Token(#[from] jsonwebtoken::errors::Error),
+}
+
+/// Checks on the token beyond its signature and expiry.
+#[derive(Debug, Clone)]
+pub struct JwtOptions {
+    /// Required `iss`, if any.
+    pub issuer: Option<String>,
+    /// Required `aud`, if any.
+    pub audience: Option<String>,
+    /// The claim holding the token's scopes.
+    pub scopes_claim: String,
+    /// Clock skew allowed when checking `exp` and `nbf`, in seconds.
+    pub leeway_secs: u64,
+}
+
+impl Default for JwtOptions {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            audience: None,
+            scopes_claim: "scope".to_string(),
+            leeway_secs: 60,
+        }
+    }
+}
+
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+pub struct JwtVerifier {
+    options: JwtOptions,
+    keys: Vec<VerifyingKey>,
+}
+
+impl JwtVerifier {
+    pub fn new(options: JwtOptions) -> Self {
+        Self { options, keys: Vec::new() }
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Adds a shared secret for HS256 tokens.
+    pub fn add_secret(&mut self, secret: &[u8]) {
+        self.keys.push(VerifyingKey {
+            kid: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+    }
+
+    /// Adds a PEM-encoded RSA (RS256) or Ed25519 (EdDSA) public key.
+    pub fn add_pem(&mut self, pem: &[u8]) -> Result<(), JwtError> {
+        let (algorithm, key) = if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+            (Algorithm::RS256, key)
+        } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+            (Algorithm::EdDSA, key)
+        } else {
+            return Err(JwtError::Key("expected an RSA or Ed25519 public key in PEM form".to_string()));
+        };
+        self.keys.push(VerifyingKey { kid: None, algorithm, key });
+        Ok(())
+    }
+
+    /// Adds the keys of a JWKS document. Keys of other types, such as
+    /// elliptic curve keys, are skipped; returns how many were added.
+    pub fn add_jwks(&mut self, json: &str) -> Result<usize, JwtError> {
+        let set: JwkSet = serde_json::from_str(json).map_err(|e| JwtError::Key(e.to_string()))?;
+        let mut added = 0;
+        for jwk in &set.keys {
+            let algorithm = match &jwk.algorithm {
+                AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+                    Algorithm::EdDSA
+                }
+                AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+                _ => continue,
+            };
+            // A key that names an algorithm is only used with that one.
+            if let Some(declared) = jwk.common.key_algorithm
+                && declared.to_string() != format!("{:?}", algorithm)
+            {
+                continue;
+            }
+            self.keys.push(VerifyingKey {
+                kid: jwk.common.key_id.clone(),
+                algorithm,
+                key: DecodingKey::from_jwk(jwk)?,
+            });
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Verifies `token` and returns the scopes it grants. Entries of the
+    /// scopes claim that aren't Carmine scopes are ignored, since identity
+    /// providers put their own scopes there too.
+    pub fn verify(&self, token: &str) -> Result<Vec<Scope>, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        if !matches!(header.alg, Algorithm::HS256 | Algorithm::RS256 | Algorithm::EdDSA) {
+            return Err(JwtError::UnsupportedAlgorithm(header.alg));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.options.leeway_secs;
+        let mut required = vec!["exp"];
+        match &self.options.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.options.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        validation.set_required_spec_claims(&required);
+
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg
+                && match (&header.kid, &key.kid) {
+                    (Some(wanted), Some(kid)) => wanted == kid,
+                    _ => true,
+                }
+        });
+        let mut last_error = None;
+        for key in candidates {
+            match jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(token, &key.key, &validation) {
+                Ok(data) => return Ok(self.scopes(&data.claims)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map(JwtError::Token).unwrap_or(JwtError::NoMatchingKey))
+    }
+
+    fn scopes(&self, claims: &HashMap<String, serde_json::Value>) -> Vec<Scope> {
+        let names: Vec<&str> = match claims.get(&self.options.scopes_claim) {
+            Some(serde_json::Value::String(text)) => text.split_whitespace().collect(),
+            Some(serde_json::Value::Array(items)) => items.iter().filter_map(|item| item.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        names.into_iter().filter_map(|name| name.parse().ok()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    fn hs256(secret: &[u8], claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret))
+            .unwrap()
+    }
+
+    fn verifier(secret: &[u8]) -> JwtVerifier {
+        let mut verifier = JwtVerifier::new(JwtOptions::default());
+        verifier.add_secret(secret);
+        verifier
+    }
+
+    #[test]
+    fn test_hs256_token_grants_its_scopes() {
+        let token = hs256(
+            b"secret",
+            json!({"exp": now() + 60, "scope": "openid cabinet:a:read cabinet:b/s:write"}),
+        );
+        let scopes = verifier(b"secret").verify(&token).unwrap();
+        assert_eq!(
+            scopes,
+            vec![
+                Scope::CabinetRead("a".to_string()),
+                Scope::ShelfWrite { cabinet: "b".to_string(), shelf: "s".to_string() },
+            ]
+        );
+
+        let mut verifier = JwtVerifier::new(JwtOptions {
+            scopes_claim: "permissions".to_string(),
+            ..JwtOptions::default()
+        });
+        verifier.add_secret(b"secret");
+        let token = hs256(b"secret", json!({"exp": now() + 60, "permissions": ["system:admin"]}));
+        assert_eq!(verifier.verify(&token).unwrap(), vec![Scope::SystemAdmin]);
+    }
+
+    #[test]
+    fn test_rejects_bad_tokens() {
+        let verifier = verifier(b"secret");
+        let wrong_secret = hs256(b"other", json!({"exp": now() + 60}));
+        assert!(verifier.verify(&wrong_secret).is_err());
+        let expired = hs256(b"secret", json!({"exp": now() - 600}));
+        assert!(verifier.verify(&expired).is_err());
+        let no_expiry = hs256(b"secret", json!({"scope": "system:admin"}));
+        assert!(verifier.verify(&no_expiry).is_err());
+        assert!(verifier.verify("not a token").is_err());
+    }
+
+    #[test]
+    fn test_checks_issuer_and_audience() {
+        let mut verifier = JwtVerifier::new(JwtOptions {
+            issuer: Some("gateway".to_string()),
+            audience: Some("carmine".to_string()),
+            ..JwtOptions::default()
+        });
+        verifier.add_secret(b"secret");
+        let good = hs256(b"secret", json!({"exp": now() + 60, "iss": "gateway", "aud": "carmine"}));
+        assert!(verifier.verify(&good).is_ok());
+        let other_issuer = hs256(b"secret", json!({"exp": now() + 60, "iss": "else", "aud": "carmine"}));
+        assert!(verifier.verify(&other_issuer).is_err());
+        let no_audience = hs256(b"secret", json!({"exp": now() + 60, "iss": "gateway"}));
+        assert!(verifier.verify(&no_audience).is_err());
+    }
+
+    #[test]
+    fn test_eddsa_token_from_jwks() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let x = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            pair.public_key().as_ref(),
+        );
+        let jwks = json!({"keys": [
+            {"kty": "EC", "crv": "P-256", "kid": "ec", "x": "AA", "y": "AA"},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "ed", "x": x},
+        ]});
+
+        let mut verifier = JwtVerifier::new(JwtOptions::default());
+        assert_eq!(verifier.add_jwks(&jwks.to_string()).unwrap(), 1);
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("ed".to_string());
+        let claims = json!({"exp": now() + 60, "scope": "cabinet:*:read"});
+        let token =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(pkcs8.as_ref())).unwrap();
+        assert_eq!(verifier.verify(&token).unwrap(), vec![Scope::CabinetRead("*".to_string())]);
+
+        header.kid = Some("other".to_string());
+        let token =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(pkcs8.as_ref())).unwrap();
+        assert!(matches!(verifier.verify(&token), Err(JwtError::NoMatchingKey)));
+    }
+
+    #[test]
+    fn test_algorithm_must_fit_the_key() {
+        // An HS256 token must not verify against a key meant for EdDSA.
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let mut verifier = JwtVerifier::new(JwtOptions::default());
+        let x = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            pair.public_key().as_ref(),
+        );
+        verifier
+            .add_jwks(&json!({"keys": [{"kty": "OKP", "crv": "Ed25519", "x": x}]}).to_string())
+            .unwrap();
+        let token = hs256(pair.public_key().as_ref(), json!({"exp": now() + 60}));
+        assert!(matches!(verifier.verify(&token), Err(JwtError::NoMatchingKey)));
+    }
+}
@@ -1,4 +1,11 @@
-use crate::{types::Int, types::Number, value::Value};
+use crate::{
+    types::Int,
+    types::Number,
+    types::ParseTypeError,
+    value::{Value, ValueError, ValueType},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
 use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, Error)]
@@ -18,13 +25,64 @@ pub enum Key {
     Int(Int),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum KeyType {
     String,
     Number,
     Int,
 }
 
+impl KeyType {
+    pub const NAMES: &'static [&'static str] = &["String", "Number", "Int"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::String => "String",
+            KeyType::Number => "Number",
+            KeyType::Int => "Int",
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Serialized by name so stored metadata and API responses read "String",
+// "Int", ... and unknown names fail with the list of allowed ones.
+impl Serialize for KeyType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "String" => Ok(KeyType::String),
+            "Number" => Ok(KeyType::Number),
+            "Int" => Ok(KeyType::Int),
+            _ => Err(ParseTypeError {
+                kind: "key type",
+                name: s.to_string(),
+                allowed: KeyType::NAMES,
+            }),
+        }
+    }
+}
+
 impl Key {
     pub fn as_type(&self) -> KeyType {
         match self {
@@ -33,6 +91,22 @@ impl Key {
             Key::Int(_) => KeyType::Int,
         }
     }
+
+    /// Converts the key to `target` using the same rules as
+    /// [`Value::convert`].
+    pub fn convert(self, target: KeyType) -> Result<Key, ValueError> {
+        Value::from(self).convert(target.into())?.try_into()
+    }
+}
+
+impl From<KeyType> for ValueType {
+    fn from(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::String => ValueType::String,
+            KeyType::Number => ValueType::Number,
+            KeyType::Int => ValueType::Int,
+        }
+    }
 }
 
 impl TryFrom<Key> for String {
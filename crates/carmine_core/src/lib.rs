@@ -1,14 +1,23 @@
+pub mod auth;
 pub mod cabinet;
+pub mod changelog;
+pub mod crypto;
 pub mod error;
+pub mod integrity;
+pub mod jwt;
 pub mod key;
 pub mod meta;
 pub mod shelf;
+pub mod stats;
 pub mod system_store;
 pub mod transaction;
 pub mod types;
 pub mod value;
 
+#[cfg(test)]
+pub(crate) mod test_util;
+
 pub use cabinet::Cabinet;
-pub use meta::{CabinetMeta, ShelfMeta};
+pub use meta::{CabinetMeta, EncryptionMeta, ShelfMeta};
 pub use shelf::Shelf;
 pub use system_store::SystemStore;
@@ -3,12 +3,43 @@ use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 
 use crate::cabinet::Cabinet;
+use crate::crypto::Algorithm;
+use crate::key::KeyType;
+use crate::shelf::Shelf;
+use crate::value::ValueType;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShelfMeta {
     pub name: String,
-    pub key_type: String,
-    pub value_type: String,
+    pub key_type: KeyType,
+    pub value_type: ValueType,
+}
+
+impl From<&ShelfMeta> for Shelf {
+    fn from(meta: &ShelfMeta) -> Self {
+        Shelf::new(meta.name.clone(), meta.key_type, meta.value_type)
+    }
+}
+
+impl From<&Shelf> for ShelfMeta {
+    fn from(shelf: &Shelf) -> Self {
+        Self {
+            name: shelf.name.clone(),
+            key_type: shelf.key_type,
+            value_type: shelf.value_type,
+        }
+    }
+}
+
+/// How an encrypted cabinet's values are sealed. Its keys are derived from
+/// the server's master key, the cabinet id and `key_version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionMeta {
+    pub algorithm: Algorithm,
+    pub key_version: u32,
+    /// [`MasterKey::key_check`](crate::crypto::MasterKey::key_check) of the
+    /// master key the cabinet was created with.
+    pub key_check: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +49,10 @@ pub struct CabinetMeta {
     pub path: PathBuf,
     #[serde(default)]
     pub shelves: Vec<ShelfMeta>,
+    /// Set for cabinets created with encryption on; a cabinet can't switch
+    /// later.
+    #[serde(default)]
+    pub encryption: Option<EncryptionMeta>,
 }
 
 impl From<&Cabinet> for CabinetMeta {
@@ -27,6 +62,7 @@ impl From<&Cabinet> for CabinetMeta {
             name: cabinet.name.clone(),
             path: cabinet.path.clone(),
             shelves: Vec::new(),
+            encryption: None,
         }
     }
 }
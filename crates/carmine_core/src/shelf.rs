@@ -1,7 +1,13 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
 use thiserror::Error;
 
-use crate::{key::KeyType, value::ValueType};
+use crate::{crypto::ValueCipher, key::KeyType, value::ValueType};
+pub mod migrate;
 pub mod read;
+pub mod sealed;
+pub mod table;
 pub mod write;
 
 #[derive(Debug, Clone)]
@@ -9,6 +15,9 @@ pub struct Shelf {
     pub name: String,
     pub key_type: KeyType,
     pub value_type: ValueType,
+    /// Set for shelves of encrypted cabinets, whose tables hold every value
+    /// sealed as bytes.
+    pub cipher: Option<Arc<ValueCipher>>,
 }
 
 #[derive(Debug, Error)]
@@ -23,6 +32,21 @@ impl Shelf {
             name,
             key_type,
             value_type,
+            cipher: None,
+        }
+    }
+
+    pub fn with_cipher(mut self, cipher: Option<Arc<ValueCipher>>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// The shelf as its table is laid out: for an encrypted shelf, a plain
+    /// shelf of sealed `Byte` values.
+    pub(crate) fn storage(&self) -> Cow<'_, Shelf> {
+        match self.cipher {
+            Some(_) => Cow::Owned(Shelf::new(self.name.clone(), self.key_type, ValueType::Byte)),
+            None => Cow::Borrowed(self),
         }
     }
 }
@@ -0,0 +1,295 @@
+use super::{Shelf, sealed};
+use crate::changelog::{self, ChangeOp, RESERVED_PREFIX};
+use crate::key::{Key, KeyType};
+use crate::transaction::TransactionError;
+use crate::value::{Value, ValueType};
+
+/// What to do with an entry whose key or value can't be converted.
+#[derive(Debug, Clone)]
+pub enum ConversionPolicy {
+    /// Any failure aborts the migration; the caller must not commit.
+    Strict,
+    /// Entries that fail to convert are dropped from the shelf.
+    SkipInvalid,
+    /// Values that fail to convert are replaced with the given value, which
+    /// must already have the target value type. Unconvertible keys are still
+    /// dropped.
+    DefaultValue(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationFailure {
+    /// The entry's key as it was before the migration.
+    pub key: Key,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub converted: u64,
+    pub defaulted: u64,
+    pub failed: Vec<MigrationFailure>,
+}
+
+impl MigrationReport {
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// How many entries are moved from the old table to the new one at a time.
+const MIGRATION_CHUNK: usize = 1024;
+
+impl Shelf {
+    /// Rewrites the shelf's table with new key/value types, converting every
+    /// entry with [`Key::convert`] and [`Value::convert`].
+    ///
+    /// The old table is moved aside and emptied into the new one a chunk at
+    /// a time, so only one chunk is held in memory. The change log gets a
+    /// single [`ChangeOp::Migrate`] record instead of one per entry.
+    ///
+    /// Returns the migrated shelf and a report. Under
+    /// [`ConversionPolicy::Strict`] a report with failures means the table is
+    /// only partially rewritten, so the transaction has to be dropped rather
+    /// than committed. Two keys that convert to the same key (e.g. `1.0` and
+    /// `1` becoming `Int`) count as a failure for the later one.
+    pub fn migrate(
+        &self,
+        tx: &redb::WriteTransaction,
+        key_type: KeyType,
+        value_type: ValueType,
+        policy: &ConversionPolicy,
+    ) -> Result<(Shelf, MigrationReport), TransactionError> {
+        if let ConversionPolicy::DefaultValue(default) = policy
+            && default.as_type() != value_type
+        {
+            return Err(TransactionError::ValueTypeMismatch {
+                expected: value_type,
+                actual: default.as_type(),
+            });
+        }
+
+        let storage = self.storage();
+        let source = Shelf::new(
+            format!("{}migrate_{}", RESERVED_PREFIX, self.name),
+            storage.key_type,
+            storage.value_type,
+        );
+        storage.rename_table(tx, &source.name)?;
+        let target =
+            Shelf::new(self.name.clone(), key_type, value_type).with_cipher(self.cipher.clone());
+        target.create_table(tx)?;
+        let mut report = MigrationReport::default();
+
+        loop {
+            let mut entries = source.take_page(tx, MIGRATION_CHUNK)?;
+            if entries.is_empty() {
+                break;
+            }
+            if let Some(cipher) = &self.cipher {
+                entries = sealed::open_entries(self, cipher, entries)?;
+            }
+            for (key, value) in entries {
+                let (new_key, new_value, defaulted) =
+                    match convert_entry(key.clone(), value, key_type, value_type, policy) {
+                        Ok(entry) => entry,
+                        Err(error) => {
+                            report.failed.push(MigrationFailure { key, error });
+                            continue;
+                        }
+                    };
+                let stored = target.stored_value(&new_key, new_value)?;
+                match target.storage().put_untracked(tx, new_key, stored) {
+                    Ok(()) => {}
+                    Err(TransactionError::KeyAlreadyExists) => {
+                        report.failed.push(MigrationFailure {
+                            key,
+                            error: "key collides with another entry after conversion".to_string(),
+                        });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+                if defaulted {
+                    report.defaulted += 1;
+                } else {
+                    report.converted += 1;
+                }
+            }
+        }
+
+        source.drop_table(tx)?;
+        changelog::record(tx, ChangeOp::Migrate, &self.name, None, None)?;
+        Ok((target, report))
+    }
+}
+
+/// Converts one entry, returning the new pair and whether the default value
+/// was used, or a description of why the entry can't be migrated.
+fn convert_entry(
+    key: Key,
+    value: Value,
+    key_type: KeyType,
+    value_type: ValueType,
+    policy: &ConversionPolicy,
+) -> Result<(Key, Value, bool), String> {
+    let key = key.convert(key_type).map_err(|e| format!("key: {}", e))?;
+    match (value.convert(value_type), policy) {
+        (Ok(v), _) => Ok((key, v, false)),
+        (Err(_), ConversionPolicy::DefaultValue(default)) => Ok((key, default.clone(), true)),
+        (Err(e), _) => Err(format!("value: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db;
+    use crate::transaction::{Readable, Writable};
+    use crate::types::Int;
+    use redb::ReadableDatabase;
+
+    fn seed(db: &redb::Database) -> Shelf {
+        let shelf = Shelf::new("test".to_string(), KeyType::String, ValueType::String);
+        let tx = db.begin_write().unwrap();
+        for (k, v) in [("a", "1"), ("b", "2.5"), ("c", "nope")] {
+            shelf
+                .set(&tx, Key::String(k.into()), Value::String(v.into()))
+                .unwrap();
+        }
+        tx.commit().unwrap();
+        shelf
+    }
+
+    #[test]
+    fn test_migrate_strict_reports_failures() {
+        let (_file, db) = temp_db();
+        let shelf = seed(&db);
+
+        let tx = db.begin_write().unwrap();
+        let (_, report) = shelf
+            .migrate(
+                &tx,
+                KeyType::String,
+                ValueType::Number,
+                &ConversionPolicy::Strict,
+            )
+            .unwrap();
+        assert_eq!(report.converted, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].key, Key::String("c".into()));
+        drop(tx);
+
+        // Dropping the transaction leaves the original table untouched.
+        let tx = db.begin_read().unwrap();
+        assert_eq!(shelf.count(&tx).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_migrate_skip_and_default() {
+        let (_file, db) = temp_db();
+        let shelf = seed(&db);
+
+        let tx = db.begin_write().unwrap();
+        let (migrated, report) = shelf
+            .migrate(
+                &tx,
+                KeyType::String,
+                ValueType::Int,
+                &ConversionPolicy::DefaultValue(Value::Int(Int(0))),
+            )
+            .unwrap();
+        assert_eq!((report.converted, report.defaulted), (1, 2));
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        assert_eq!(
+            migrated.get(&tx, &Key::String("a".into())).unwrap(),
+            Some(Value::Int(Int(1)))
+        );
+        assert_eq!(
+            migrated.get(&tx, &Key::String("b".into())).unwrap(),
+            Some(Value::Int(Int(0)))
+        );
+        drop(tx);
+
+        let tx = db.begin_write().unwrap();
+        let (migrated, report) = migrated
+            .migrate(
+                &tx,
+                KeyType::String,
+                ValueType::Number,
+                &ConversionPolicy::SkipInvalid,
+            )
+            .unwrap();
+        assert_eq!(report.converted, 3);
+        assert!(report.is_clean());
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        let Some(Value::Number(n)) = migrated.get(&tx, &Key::String("a".into())).unwrap() else {
+            panic!("expected a number");
+        };
+        assert_eq!(n.as_i64(), Some(1));
+    }
+
+    #[test]
+    fn test_migrate_key_collision() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::String, ValueType::Int);
+        let tx = db.begin_write().unwrap();
+        for k in ["1", " 1", "x"] {
+            shelf
+                .set(&tx, Key::String(k.into()), Value::Int(Int(1)))
+                .unwrap();
+        }
+
+        tx.commit().unwrap();
+
+        let tx = db.begin_write().unwrap();
+        let (migrated, report) = shelf
+            .migrate(
+                &tx,
+                KeyType::Int,
+                ValueType::Int,
+                &ConversionPolicy::SkipInvalid,
+            )
+            .unwrap();
+        assert_eq!(report.converted, 1);
+        assert_eq!(report.failed.len(), 2);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        assert_eq!(migrated.count(&tx).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_migrate_logs_one_record_across_chunks() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::Int, ValueType::Int);
+        let total = MIGRATION_CHUNK as i64 * 2 + 1;
+        let tx = db.begin_write().unwrap();
+        for i in 0..total {
+            shelf.set(&tx, Key::Int(Int(i)), Value::Int(Int(i))).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_write().unwrap();
+        let (migrated, report) = shelf
+            .migrate(&tx, KeyType::Int, ValueType::String, &ConversionPolicy::Strict)
+            .unwrap();
+        assert_eq!(report.converted, total as u64);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        assert_eq!(migrated.count(&tx).unwrap(), total as u64);
+        assert_eq!(
+            migrated.get(&tx, &Key::Int(Int(total - 1))).unwrap(),
+            Some(Value::String((total - 1).to_string()))
+        );
+        let page = changelog::read_since(&tx, total as u64, 10, None, None).unwrap();
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(page.changes[0].op, ChangeOp::Migrate);
+        assert_eq!(page.latest, total as u64 + 1);
+    }
+}
@@ -1,8 +1,10 @@
-use super::Shelf;
+use super::{Shelf, sealed};
 use crate::key::{Key, KeyType};
 use crate::transaction::{Readable, TransactionError};
+use crate::types::{Decoded, StoredNumber};
 use crate::value::{BatchItemError, Value, ValueRetVec, ValueType};
 use redb::{ReadableTable, ReadableTableMetadata, TableDefinition};
+use std::ops::Bound;
 
 macro_rules! get_typed {
     ($read_txn:expr, $shelf_name:expr, $key:expr,
@@ -16,7 +18,10 @@ macro_rules! get_typed {
         let value = table_handle
             .get(search_key)
             .map_err(TransactionError::from)?;
-        Ok(value.map(|v| $val_wrap(v.value())))
+        match value {
+            Some(v) => Ok(Some($val_wrap(v.value().decoded()?))),
+            None => Ok(None),
+        }
     }};
 }
 
@@ -35,20 +40,24 @@ macro_rules! batch_get_typed {
         for (i, key) in $keys.iter().enumerate() {
             if let Some(err) = &$errors[i] {
                 result_vec.push(Err(err.clone()));
-            } else if let Ok(search_key) = $key_conv(key.clone()) {
-                match table_handle.get(search_key) {
-                    Ok(Some(value)) => {
-                        result_vec.push(Ok(Some($val_conv(value.value()))));
-                    }
-                    Ok(None) => {
-                        result_vec.push(Ok(None));
-                    }
-                    Err(_e) => {
-                        result_vec.push(Ok(None));
+            } else {
+                match $key_conv(key.clone()) {
+                    Ok(search_key) => match table_handle.get(search_key) {
+                        Ok(Some(value)) => match value.value().decoded() {
+                            Ok(value) => result_vec.push(Ok(Some($val_conv(value)))),
+                            Err(e) => result_vec.push(Err(BatchItemError::Storage(e.to_string()))),
+                        },
+                        Ok(None) => {
+                            result_vec.push(Ok(None));
+                        }
+                        Err(e) => {
+                            result_vec.push(Err(BatchItemError::Storage(e.to_string())));
+                        }
+                    },
+                    Err(e) => {
+                        result_vec.push(Err(BatchItemError::KeyConversion(e.to_string())));
                     }
                 }
-            } else {
-                result_vec.push(Ok(None));
             }
         }
         Ok(ValueRetVec::$RetVariant(result_vec))
@@ -67,7 +76,7 @@ macro_rules! iter_typed {
         let iter = table_handle.iter().map_err(TransactionError::from)?;
         for entry in iter {
             let (key, value) = entry.map_err(TransactionError::from)?;
-            result.push(($key_wrap(key.value()), $val_wrap(value.value())));
+            result.push(($key_wrap(key.value().decoded()?), $val_wrap(value.value().decoded()?)));
         }
         Ok(result)
     }};
@@ -85,7 +94,7 @@ macro_rules! keys_typed {
         let iter = table_handle.iter().map_err(TransactionError::from)?;
         for entry in iter {
             let (key, _value) = entry.map_err(TransactionError::from)?;
-            result.push($key_wrap(key.value()));
+            result.push($key_wrap(key.value().decoded()?));
         }
         Ok(result)
     }};
@@ -103,7 +112,7 @@ macro_rules! values_typed {
         let iter = table_handle.iter().map_err(TransactionError::from)?;
         for entry in iter {
             let (_key, value) = entry.map_err(TransactionError::from)?;
-            result.push($val_wrap(value.value()));
+            result.push($val_wrap(value.value().decoded()?));
         }
         Ok(result)
     }};
@@ -147,7 +156,26 @@ macro_rules! range_typed {
         let iter = table_handle.range(range).map_err(TransactionError::from)?;
         for entry in iter {
             let (key, value) = entry.map_err(TransactionError::from)?;
-            result.push(($key_wrap(key.value()), $val_wrap(value.value())));
+            result.push(($key_wrap(key.value().decoded()?), $val_wrap(value.value().decoded()?)));
+        }
+        Ok(result)
+    }};
+}
+
+macro_rules! page_typed {
+    ($read_txn:expr, $shelf_name:expr, $start:expr, $end:expr, $limit:expr,
+     $KeyRedb:ty, $key_conv:expr, $key_wrap:expr,
+     $ValRedb:ty, $val_wrap:expr) => {{
+        let table: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        let table_handle = $read_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        let range = ($start.cloned().map($key_conv), $end.cloned().map($key_conv));
+        let mut result = Vec::new();
+        let iter = table_handle.range(range).map_err(TransactionError::from)?;
+        for entry in iter.take($limit) {
+            let (key, value) = entry.map_err(TransactionError::from)?;
+            result.push(($key_wrap(key.value().decoded()?), $val_wrap(value.value().decoded()?)));
         }
         Ok(result)
     }};
@@ -159,13 +187,18 @@ impl Readable for Shelf {
         tx: &redb::ReadTransaction,
         key: &Key,
     ) -> Result<Option<Value>, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::get(self, cipher, tx, key);
+        }
         let key_to_string = |k: Key| -> String {
             k.try_into()
                 .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a String key"))
         };
-        let key_to_number = |k: Key| -> crate::types::Number {
-            k.try_into()
-                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"))
+        let key_to_number = |k: Key| -> StoredNumber {
+            let n: crate::types::Number = k
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"));
+            n.into()
         };
         let key_to_int = |k: Key| -> i64 {
             let i: crate::types::Int = k
@@ -302,13 +335,16 @@ impl Readable for Shelf {
         tx: &redb::ReadTransaction,
         keys: &[Key],
     ) -> Result<ValueRetVec, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::get_batch(self, cipher, tx, keys);
+        }
         let errors: Vec<Option<BatchItemError>> = keys
             .iter()
             .map(|k| {
                 if k.as_type() != self.key_type {
-                    Some(BatchItemError::TypeMismatch {
-                        expected: format!("{:?}", self.key_type),
-                        actual: format!("{:?}", k.as_type()),
+                    Some(BatchItemError::KeyTypeMismatch {
+                        expected: self.key_type,
+                        actual: k.as_type(),
                     })
                 } else {
                     None
@@ -318,10 +354,10 @@ impl Readable for Shelf {
 
         let safe_key_to_string =
             |k: Key| -> std::result::Result<String, crate::key::KeyError> { k.try_into() };
-        let safe_key_to_number = |k: Key| -> std::result::Result<
-            crate::types::Number,
-            crate::key::KeyError,
-        > { k.try_into() };
+        let safe_key_to_number = |k: Key| -> std::result::Result<StoredNumber, crate::key::KeyError> {
+            let n: std::result::Result<crate::types::Number, crate::key::KeyError> = k.try_into();
+            n.map(StoredNumber::from)
+        };
         let safe_key_to_int = |k: Key| -> std::result::Result<i64, crate::key::KeyError> {
             let i: std::result::Result<crate::types::Int, crate::key::KeyError> = k.try_into();
             i.map(|v| *v)
@@ -497,13 +533,18 @@ impl Readable for Shelf {
     }
 
     fn exists(&self, tx: &redb::ReadTransaction, key: &Key) -> Result<bool, TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().exists(tx, key);
+        }
         let key_to_string = |k: Key| -> String {
             k.try_into()
                 .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a String key"))
         };
-        let key_to_number = |k: Key| -> crate::types::Number {
-            k.try_into()
-                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"))
+        let key_to_number = |k: Key| -> StoredNumber {
+            let n: crate::types::Number = k
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"));
+            n.into()
         };
         let key_to_int = |k: Key| -> i64 {
             let i: crate::types::Int = k
@@ -602,6 +643,9 @@ impl Readable for Shelf {
     }
 
     fn count(&self, tx: &redb::ReadTransaction) -> Result<u64, TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().count(tx);
+        }
         match (self.key_type, self.value_type) {
             (KeyType::String, ValueType::String) => count_typed!(tx, &self.name, String, String),
             (KeyType::String, ValueType::Number) => {
@@ -643,6 +687,9 @@ impl Readable for Shelf {
     }
 
     fn get_all(&self, tx: &redb::ReadTransaction) -> Result<Vec<(Key, Value)>, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::get_all(self, cipher, tx);
+        }
         let key_wrap_string = |s: String| Key::String(s);
         let key_wrap_number = |n: crate::types::Number| Key::Number(n);
         let key_wrap_int = |i: i64| Key::Int(crate::types::Int(i));
@@ -757,6 +804,9 @@ impl Readable for Shelf {
     }
 
     fn keys(&self, tx: &redb::ReadTransaction) -> Result<Vec<Key>, TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().keys(tx);
+        }
         let key_wrap_string = |s: String| Key::String(s);
         let key_wrap_number = |n: crate::types::Number| Key::Number(n);
         let key_wrap_int = |i: i64| Key::Int(crate::types::Int(i));
@@ -831,6 +881,9 @@ impl Readable for Shelf {
     }
 
     fn values(&self, tx: &redb::ReadTransaction) -> Result<Vec<Value>, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::values(self, cipher, tx);
+        }
         let val_wrap_string = |s: String| Value::String(s);
         let val_wrap_number = |n: crate::types::Number| Value::Number(n);
         let val_wrap_int = |i: i64| Value::Int(crate::types::Int(i));
@@ -916,6 +969,9 @@ impl Readable for Shelf {
         start: &Key,
         end: &Key,
     ) -> Result<Vec<(Key, Value)>, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::get_range(self, cipher, tx, start, end);
+        }
         if self.key_type != KeyType::String && self.key_type != KeyType::Int {
             return Err(TransactionError::RangeNotSupported);
         }
@@ -1067,4 +1123,234 @@ impl Readable for Shelf {
             KeyType::Number => Err(TransactionError::RangeNotSupported),
         }
     }
+
+    fn get_page(
+        &self,
+        tx: &redb::ReadTransaction,
+        start: Bound<&Key>,
+        end: Bound<&Key>,
+        limit: usize,
+    ) -> Result<Vec<(Key, Value)>, TransactionError> {
+        if let Some(cipher) = &self.cipher {
+            return sealed::get_page(self, cipher, tx, start, end, limit);
+        }
+        for bound in [start, end] {
+            if let Bound::Included(key) | Bound::Excluded(key) = bound
+                && key.as_type() != self.key_type
+            {
+                return Err(TransactionError::KeyTypeMismatch {
+                    expected: self.key_type,
+                    actual: key.as_type(),
+                });
+            }
+        }
+
+        let key_to_string = |k: Key| -> String {
+            k.try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a String key"))
+        };
+        let key_to_number = |k: Key| -> StoredNumber {
+            let n: crate::types::Number = k
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"));
+            n.into()
+        };
+        let key_to_int = |k: Key| -> i64 {
+            let i: crate::types::Int = k
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees an Int key"));
+            *i
+        };
+        let key_wrap_string = |s: String| Key::String(s);
+        let key_wrap_number = |n: crate::types::Number| Key::Number(n);
+        let key_wrap_int = |i: i64| Key::Int(crate::types::Int(i));
+        let val_wrap_string = |s: String| Value::String(s);
+        let val_wrap_number = |n: crate::types::Number| Value::Number(n);
+        let val_wrap_int = |i: i64| Value::Int(crate::types::Int(i));
+        let val_wrap_object = |o: crate::types::RawObject| Value::Object(o);
+        let val_wrap_byte = |b: &[u8]| Value::Byte(b.to_vec());
+
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                String,
+                key_to_string,
+                key_wrap_string,
+                String,
+                val_wrap_string
+            ),
+            (KeyType::String, ValueType::Number) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                String,
+                key_to_string,
+                key_wrap_string,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::String, ValueType::Int) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                String,
+                key_to_string,
+                key_wrap_string,
+                i64,
+                val_wrap_int
+            ),
+            (KeyType::String, ValueType::Object) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                String,
+                key_to_string,
+                key_wrap_string,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::String, ValueType::Byte) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                String,
+                key_to_string,
+                key_wrap_string,
+                &[u8],
+                val_wrap_byte
+            ),
+            (KeyType::Number, ValueType::String) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                crate::types::Number,
+                key_to_number,
+                key_wrap_number,
+                String,
+                val_wrap_string
+            ),
+            (KeyType::Number, ValueType::Number) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                crate::types::Number,
+                key_to_number,
+                key_wrap_number,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::Number, ValueType::Int) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                crate::types::Number,
+                key_to_number,
+                key_wrap_number,
+                i64,
+                val_wrap_int
+            ),
+            (KeyType::Number, ValueType::Object) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                crate::types::Number,
+                key_to_number,
+                key_wrap_number,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::Number, ValueType::Byte) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                crate::types::Number,
+                key_to_number,
+                key_wrap_number,
+                &[u8],
+                val_wrap_byte
+            ),
+            (KeyType::Int, ValueType::String) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                i64,
+                key_to_int,
+                key_wrap_int,
+                String,
+                val_wrap_string
+            ),
+            (KeyType::Int, ValueType::Number) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                i64,
+                key_to_int,
+                key_wrap_int,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::Int, ValueType::Int) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                i64,
+                key_to_int,
+                key_wrap_int,
+                i64,
+                val_wrap_int
+            ),
+            (KeyType::Int, ValueType::Object) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                i64,
+                key_to_int,
+                key_wrap_int,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::Int, ValueType::Byte) => page_typed!(
+                tx,
+                &self.name,
+                start,
+                end,
+                limit,
+                i64,
+                key_to_int,
+                key_wrap_int,
+                &[u8],
+                val_wrap_byte
+            ),
+        }
+    }
 }
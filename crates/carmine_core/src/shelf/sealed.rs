@@ -0,0 +1,463 @@
+//! Shelves of encrypted cabinets.
+//!
+//! Their tables are laid out as for a `Byte` shelf with the same key type
+//! (see [`Shelf::storage`]), each value sealed by the shelf's
+//! [`ValueCipher`]. The `Readable` and `Writable` impls route through here to
+//! seal values on the way in and open them on the way out.
+
+use std::ops::Bound;
+
+use redb::{ReadableTable, TableDefinition};
+
+use super::Shelf;
+use crate::crypto::ValueCipher;
+use crate::key::{Key, KeyType};
+use crate::transaction::{Readable, TransactionError};
+use crate::types::{Decoded, Int, StoredNumber};
+use crate::value::{BatchItemError, Value, ValueRetVec};
+
+type BatchResults = Vec<Result<(), TransactionError>>;
+
+macro_rules! reseal_typed {
+    ($write_txn:expr, $shelf_name:expr, $cipher:expr, $after:expr, $limit:expr,
+     $KeyRedb:ty, $key_unwrap:expr, $key_wrap:expr) => {{
+        let table: TableDefinition<$KeyRedb, &[u8]> = TableDefinition::new($shelf_name);
+        let mut table_handle = $write_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        let start = match $after {
+            Some(key) => Bound::Excluded($key_unwrap(key)),
+            None => Bound::Unbounded,
+        };
+        let mut visited = 0;
+        let mut last = None;
+        let mut resealed = Vec::new();
+        for entry in table_handle
+            .range((start, Bound::Unbounded))
+            .map_err(TransactionError::from)?
+        {
+            if visited >= $limit {
+                break;
+            }
+            let (key, value) = entry.map_err(TransactionError::from)?;
+            visited += 1;
+            let current = $key_wrap(key.value().decoded()?);
+            if let Some(sealed) = $cipher.reseal($shelf_name, &current, value.value())? {
+                resealed.push((key.value(), sealed));
+            }
+            last = Some(current);
+        }
+        let count = resealed.len() as u64;
+        for (key, sealed) in resealed {
+            table_handle
+                .insert(key, sealed.as_slice())
+                .map_err(TransactionError::from)?;
+        }
+        let next = if visited == $limit { last } else { None };
+        Ok((next, count))
+    }};
+}
+
+macro_rules! rebind_typed {
+    ($write_txn:expr, $shelf_name:expr, $dest_name:expr, $cipher:expr,
+     $KeyRedb:ty, $key_wrap:expr) => {{
+        let source: TableDefinition<$KeyRedb, &[u8]> = TableDefinition::new($shelf_name);
+        let dest: TableDefinition<$KeyRedb, &[u8]> = TableDefinition::new($dest_name);
+        let source_handle = $write_txn
+            .open_table(source)
+            .map_err(TransactionError::from)?;
+        let mut dest_handle = $write_txn
+            .open_table(dest)
+            .map_err(TransactionError::from)?;
+        let mut count = 0;
+        for entry in source_handle.iter().map_err(TransactionError::from)? {
+            let (key, value) = entry.map_err(TransactionError::from)?;
+            let sealed = $cipher.rebind(
+                $shelf_name,
+                $dest_name,
+                &$key_wrap(key.value().decoded()?),
+                value.value(),
+            )?;
+            dest_handle
+                .insert(key.value(), sealed.as_slice())
+                .map_err(TransactionError::from)?;
+            count += 1;
+        }
+        Ok(count)
+    }};
+}
+
+fn open(shelf: &Shelf, cipher: &ValueCipher, key: &Key, stored: Value) -> Result<Value, TransactionError> {
+    match stored {
+        Value::Byte(sealed) => Ok(cipher.open_value(&shelf.name, key, &sealed)?),
+        _ => unreachable!("Encrypted shelves store their values as bytes"),
+    }
+}
+
+/// Opens the sealed values of entries read from `shelf`'s table.
+pub(super) fn open_entries(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    entries: Vec<(Key, Value)>,
+) -> Result<Vec<(Key, Value)>, TransactionError> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            let value = open(shelf, cipher, &key, value)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+impl Shelf {
+    /// Turns `key`'s `value` into what the shelf's table stores: sealed bytes
+    /// for an encrypted shelf, the value itself otherwise.
+    pub(super) fn stored_value(&self, key: &Key, value: Value) -> Result<Value, TransactionError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(value);
+        };
+        if value.as_type() != self.value_type {
+            return Err(TransactionError::ValueTypeMismatch {
+                expected: self.value_type,
+                actual: value.as_type(),
+            });
+        }
+        Ok(Value::Byte(cipher.seal_value(&self.name, key, &value)?))
+    }
+
+    /// Runs a batch write against the shelf's table with each value turned
+    /// into its stored form. Entries that can't be sealed fail on their own,
+    /// like entries of the wrong type do for plain shelves.
+    pub(super) fn write_stored_batch(
+        &self,
+        entries: &[(Key, Value)],
+        write: impl FnOnce(&Shelf, &[(Key, Value)]) -> Result<BatchResults, TransactionError>,
+    ) -> Result<BatchResults, TransactionError> {
+        if self.cipher.is_none() {
+            return write(self, entries);
+        }
+        let mut results = Vec::with_capacity(entries.len());
+        let mut positions = Vec::new();
+        let mut stored = Vec::new();
+        for (i, (key, value)) in entries.iter().enumerate() {
+            match self.stored_value(key, value.clone()) {
+                Ok(value) => {
+                    positions.push(i);
+                    stored.push((key.clone(), value));
+                    results.push(Ok(()));
+                }
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        let applied = write(&self.storage(), &stored)?;
+        for (i, result) in positions.into_iter().zip(applied) {
+            results[i] = result;
+        }
+        Ok(results)
+    }
+
+    /// Seals up to `limit` entries after `after` again with the cipher's
+    /// current key, for key rotation. Returns the key to continue after, or
+    /// `None` once the end of the shelf was reached, and how many entries
+    /// were rewritten. Entries already sealed with the current key are left
+    /// alone.
+    pub fn reseal(
+        &self,
+        tx: &redb::WriteTransaction,
+        after: Option<&Key>,
+        limit: usize,
+    ) -> Result<(Option<Key>, u64), TransactionError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok((None, 0));
+        };
+        if let Some(key) = after
+            && key.as_type() != self.key_type
+        {
+            return Err(TransactionError::KeyTypeMismatch {
+                expected: self.key_type,
+                actual: key.as_type(),
+            });
+        }
+        let unwrap_string = |k: &Key| -> String {
+            k.clone()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a String key"))
+        };
+        let unwrap_number = |k: &Key| -> StoredNumber {
+            let n: crate::types::Number = k
+                .clone()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"));
+            n.into()
+        };
+        let unwrap_int = |k: &Key| -> i64 {
+            let i: Int = k
+                .clone()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("Validated key_type guarantees an Int key"));
+            *i
+        };
+
+        match self.key_type {
+            KeyType::String => reseal_typed!(
+                tx,
+                &self.name,
+                cipher,
+                after,
+                limit,
+                String,
+                unwrap_string,
+                Key::String
+            ),
+            KeyType::Number => reseal_typed!(
+                tx,
+                &self.name,
+                cipher,
+                after,
+                limit,
+                crate::types::Number,
+                unwrap_number,
+                Key::Number
+            ),
+            KeyType::Int => reseal_typed!(
+                tx,
+                &self.name,
+                cipher,
+                after,
+                limit,
+                i64,
+                unwrap_int,
+                |i| Key::Int(Int(i))
+            ),
+        }
+    }
+}
+
+// Reads, called by the `Readable` impl for shelves with a cipher.
+
+pub(super) fn get(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+    key: &Key,
+) -> Result<Option<Value>, TransactionError> {
+    shelf
+        .storage()
+        .get(tx, key)?
+        .map(|stored| open(shelf, cipher, key, stored))
+        .transpose()
+}
+
+pub(super) fn get_all(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+) -> Result<Vec<(Key, Value)>, TransactionError> {
+    open_entries(shelf, cipher, shelf.storage().get_all(tx)?)
+}
+
+pub(super) fn values(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+) -> Result<Vec<Value>, TransactionError> {
+    // Values are bound to their keys, so opening them needs the keys too.
+    shelf
+        .storage()
+        .get_all(tx)?
+        .into_iter()
+        .map(|(key, stored)| open(shelf, cipher, &key, stored))
+        .collect()
+}
+
+pub(super) fn get_range(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+    start: &Key,
+    end: &Key,
+) -> Result<Vec<(Key, Value)>, TransactionError> {
+    open_entries(shelf, cipher, shelf.storage().get_range(tx, start, end)?)
+}
+
+pub(super) fn get_page(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+    start: Bound<&Key>,
+    end: Bound<&Key>,
+    limit: usize,
+) -> Result<Vec<(Key, Value)>, TransactionError> {
+    open_entries(shelf, cipher, shelf.storage().get_page(tx, start, end, limit)?)
+}
+
+pub(super) fn get_batch(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::ReadTransaction,
+    keys: &[Key],
+) -> Result<ValueRetVec, TransactionError> {
+    let stored = shelf.storage().get_batch(tx, keys)?;
+    let mut values = ValueRetVec::new(shelf.value_type, keys.len());
+    for (i, key) in keys.iter().enumerate() {
+        let value = match stored.get(i) {
+            Ok(Some(stored)) => open(shelf, cipher, key, stored)
+                .map(Some)
+                .map_err(|e| BatchItemError::Storage(e.to_string())),
+            other => other,
+        };
+        values.set(i, value).map_err(|_| {
+            TransactionError::CorruptRecord(format!("value in shelf '{}'", shelf.name))
+        })?;
+    }
+    Ok(values)
+}
+
+/// Copies the shelf's entries into a new table named `dest_name`, sealing
+/// each value again for the new name. Returns the number of entries copied.
+pub(super) fn copy(
+    shelf: &Shelf,
+    cipher: &ValueCipher,
+    tx: &redb::WriteTransaction,
+    dest_name: &str,
+) -> Result<u64, TransactionError> {
+    match shelf.key_type {
+        KeyType::String => rebind_typed!(tx, &shelf.name, dest_name, cipher, String, Key::String),
+        KeyType::Number => {
+            rebind_typed!(tx, &shelf.name, dest_name, cipher, crate::types::Number, Key::Number)
+        }
+        KeyType::Int => rebind_typed!(tx, &shelf.name, dest_name, cipher, i64, |i| Key::Int(Int(i))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::changelog;
+    use crate::crypto::{Algorithm, MasterKey};
+    use crate::test_util::temp_db;
+    use crate::transaction::Writable;
+    use crate::value::ValueType;
+    use redb::{ReadableDatabase, TableHandle};
+
+    fn cipher(key_version: u32) -> Arc<ValueCipher> {
+        let master = Arc::new(MasterKey::new(&[1; crate::crypto::MASTER_KEY_LEN]));
+        Arc::new(ValueCipher::new(master, Algorithm::Aes256Gcm, 9, key_version))
+    }
+
+    fn shelf(key_version: u32) -> Shelf {
+        Shelf::new("secrets".to_string(), KeyType::Int, ValueType::String)
+            .with_cipher(Some(cipher(key_version)))
+    }
+
+    fn int(i: i64) -> Key {
+        Key::Int(Int(i))
+    }
+
+    fn text(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn test_values_are_stored_sealed() {
+        let (_file, db) = temp_db();
+        let shelf = shelf(1);
+        let tx = db.begin_write().unwrap();
+        shelf.set(&tx, int(1), text("plaintext")).unwrap();
+        let results = shelf
+            .batch_put(&tx, &[(int(2), text("two")), (int(3), Value::Byte(vec![1]))])
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(TransactionError::ValueTypeMismatch { expected: ValueType::String, .. })
+        ));
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        assert_eq!(shelf.get(&tx, &int(1)).unwrap(), Some(text("plaintext")));
+        assert_eq!(shelf.get_all(&tx).unwrap().len(), 2);
+        let batch = shelf.get_batch(&tx, &[int(2), int(3)]).unwrap();
+        assert_eq!(batch.as_type(), ValueType::String);
+        assert_eq!(batch.get(0).unwrap(), Some(text("two")));
+        assert_eq!(batch.get(1).unwrap(), None);
+
+        let Some(Value::Byte(raw)) = shelf.storage().get(&tx, &int(1)).unwrap() else {
+            panic!("expected the raw table to hold bytes");
+        };
+        assert!(!raw.windows(9).any(|w| w == b"plaintext"));
+
+        let changes = changelog::read_since(&tx, 0, 10, None, Some(&cipher(1))).unwrap();
+        assert_eq!(changes.changes[0].value, Some(text("plaintext")));
+        assert!(changelog::read_since(&tx, 0, 10, None, None).is_err());
+    }
+
+    #[test]
+    fn test_reseal_moves_everything_to_the_new_key() {
+        let (_file, db) = temp_db();
+        let old = shelf(1);
+        let tx = db.begin_write().unwrap();
+        for i in 0..5 {
+            old.set(&tx, int(i), text(&i.to_string())).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let new = shelf(2);
+        let mut after = None;
+        let mut total = 0;
+        loop {
+            let tx = db.begin_write().unwrap();
+            let (next, count) = new.reseal(&tx, after.as_ref(), 2).unwrap();
+            tx.commit().unwrap();
+            total += count;
+            match next {
+                Some(key) => after = Some(key),
+                None => break,
+            }
+        }
+        assert_eq!(total, 5);
+
+        let tx = db.begin_write().unwrap();
+        assert_eq!(changelog::reseal(&tx, &cipher(2), 0, 100).unwrap(), (None, 5));
+        assert_eq!(changelog::reseal(&tx, &cipher(2), 0, 100).unwrap(), (None, 0));
+        assert_eq!(new.reseal(&tx, None, 100).unwrap(), (None, 0));
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        for (key, value) in new.storage().get_all(&tx).unwrap() {
+            let Value::Byte(sealed) = value else { unreachable!() };
+            assert_eq!(ValueCipher::sealed_version(&sealed), Some(2));
+            // Readers that loaded the cabinet before the rotation still work.
+            assert!(old.get(&tx, &key).unwrap().is_some());
+        }
+        assert_eq!(new.get(&tx, &int(3)).unwrap(), Some(text("3")));
+        assert_eq!(
+            changelog::read_since(&tx, 0, 10, None, Some(&cipher(1))).unwrap().changes.len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_values_follow_renamed_and_copied_shelves() {
+        let (_file, db) = temp_db();
+        let shelf = shelf(1);
+        let tx = db.begin_write().unwrap();
+        shelf.set(&tx, int(1), text("one")).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_write().unwrap();
+        shelf.copy_table(&tx, "copy").unwrap();
+        shelf.rename_table(&tx, "renamed").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read().unwrap();
+        for name in ["copy", "renamed"] {
+            let moved = Shelf::new(name.to_string(), KeyType::Int, ValueType::String)
+                .with_cipher(Some(cipher(1)));
+            assert_eq!(moved.get(&tx, &int(1)).unwrap(), Some(text("one")));
+        }
+        assert!(tx.list_tables().unwrap().all(|table| table.name() != "secrets"));
+    }
+}
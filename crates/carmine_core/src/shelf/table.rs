@@ -0,0 +1,550 @@
+use super::{Shelf, sealed};
+use crate::key::{Key, KeyType};
+use crate::transaction::TransactionError;
+use crate::types::Decoded;
+use crate::value::{Value, ValueType};
+use redb::{ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
+
+macro_rules! drop_typed {
+    ($write_txn:expr, $shelf_name:expr, $KeyRedb:ty, $ValRedb:ty) => {{
+        let table: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        let table_handle = $write_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        let count = table_handle.len().map_err(TransactionError::from)?;
+        $write_txn
+            .delete_table(table_handle)
+            .map_err(TransactionError::from)?;
+        Ok(count)
+    }};
+}
+
+macro_rules! create_typed {
+    ($write_txn:expr, $shelf_name:expr, $KeyRedb:ty, $ValRedb:ty) => {{
+        let table: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        $write_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        Ok(())
+    }};
+}
+
+macro_rules! rename_typed {
+    ($write_txn:expr, $shelf_name:expr, $new_name:expr, $KeyRedb:ty, $ValRedb:ty) => {{
+        let table: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        let renamed: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($new_name);
+        let table_handle = $write_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        $write_txn
+            .rename_table(table_handle, renamed)
+            .map_err(TransactionError::from)?;
+        Ok(())
+    }};
+}
+
+macro_rules! copy_typed {
+    ($write_txn:expr, $shelf_name:expr, $dest_name:expr, $KeyRedb:ty, $ValRedb:ty) => {{
+        let source: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        let dest: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($dest_name);
+        let source_handle = $write_txn
+            .open_table(source)
+            .map_err(TransactionError::from)?;
+        let mut dest_handle = $write_txn
+            .open_table(dest)
+            .map_err(TransactionError::from)?;
+        let mut count = 0;
+        for entry in source_handle.iter().map_err(TransactionError::from)? {
+            let (key, value) = entry.map_err(TransactionError::from)?;
+            let (key, value) = (key.value(), value.value());
+            key.check()?;
+            value.check()?;
+            dest_handle
+                .insert(key, value)
+                .map_err(TransactionError::from)?;
+            count += 1;
+        }
+        Ok(count)
+    }};
+}
+
+macro_rules! take_typed {
+    ($write_txn:expr, $shelf_name:expr, $limit:expr,
+     $KeyRedb:ty, $key_wrap:expr,
+     $ValRedb:ty, $val_wrap:expr) => {{
+        let table: TableDefinition<$KeyRedb, $ValRedb> = TableDefinition::new($shelf_name);
+        let mut table_handle = $write_txn
+            .open_table(table)
+            .map_err(TransactionError::from)?;
+        let mut entries = Vec::new();
+        while entries.len() < $limit {
+            let Some((key, value)) = table_handle.pop_first().map_err(TransactionError::from)?
+            else {
+                break;
+            };
+            entries.push(($key_wrap(key.value().decoded()?), $val_wrap(value.value().decoded()?)));
+        }
+        Ok(entries)
+    }};
+}
+
+fn ensure_table_absent(tx: &redb::WriteTransaction, name: &str) -> Result<(), TransactionError> {
+    if tx.list_tables()?.any(|table| table.name() == name) {
+        return Err(TransactionError::TableAlreadyExists(name.to_string()));
+    }
+    Ok(())
+}
+
+impl Shelf {
+    /// Deletes the shelf's redb table, returning the number of entries it held.
+    ///
+    /// The table is opened with the shelf's key/value types first, so a shelf
+    /// whose metadata disagrees with the stored table fails instead of
+    /// silently dropping someone else's data.
+    pub fn drop_table(&self, tx: &redb::WriteTransaction) -> Result<u64, TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().drop_table(tx);
+        }
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => drop_typed!(tx, &self.name, String, String),
+            (KeyType::String, ValueType::Number) => {
+                drop_typed!(tx, &self.name, String, crate::types::Number)
+            }
+            (KeyType::String, ValueType::Int) => drop_typed!(tx, &self.name, String, i64),
+            (KeyType::String, ValueType::Object) => {
+                drop_typed!(tx, &self.name, String, crate::types::RawObject)
+            }
+            (KeyType::String, ValueType::Byte) => drop_typed!(tx, &self.name, String, &[u8]),
+            (KeyType::Number, ValueType::String) => {
+                drop_typed!(tx, &self.name, crate::types::Number, String)
+            }
+            (KeyType::Number, ValueType::Number) => {
+                drop_typed!(tx, &self.name, crate::types::Number, crate::types::Number)
+            }
+            (KeyType::Number, ValueType::Int) => {
+                drop_typed!(tx, &self.name, crate::types::Number, i64)
+            }
+            (KeyType::Number, ValueType::Object) => drop_typed!(
+                tx,
+                &self.name,
+                crate::types::Number,
+                crate::types::RawObject
+            ),
+            (KeyType::Number, ValueType::Byte) => {
+                drop_typed!(tx, &self.name, crate::types::Number, &[u8])
+            }
+            (KeyType::Int, ValueType::String) => drop_typed!(tx, &self.name, i64, String),
+            (KeyType::Int, ValueType::Number) => {
+                drop_typed!(tx, &self.name, i64, crate::types::Number)
+            }
+            (KeyType::Int, ValueType::Int) => drop_typed!(tx, &self.name, i64, i64),
+            (KeyType::Int, ValueType::Object) => {
+                drop_typed!(tx, &self.name, i64, crate::types::RawObject)
+            }
+            (KeyType::Int, ValueType::Byte) => drop_typed!(tx, &self.name, i64, &[u8]),
+        }
+    }
+
+    /// Creates the shelf's redb table if it doesn't exist yet, so it can be
+    /// read before anything was written to it.
+    pub fn create_table(&self, tx: &redb::WriteTransaction) -> Result<(), TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().create_table(tx);
+        }
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => create_typed!(tx, &self.name, String, String),
+            (KeyType::String, ValueType::Number) => {
+                create_typed!(tx, &self.name, String, crate::types::Number)
+            }
+            (KeyType::String, ValueType::Int) => create_typed!(tx, &self.name, String, i64),
+            (KeyType::String, ValueType::Object) => {
+                create_typed!(tx, &self.name, String, crate::types::RawObject)
+            }
+            (KeyType::String, ValueType::Byte) => create_typed!(tx, &self.name, String, &[u8]),
+            (KeyType::Number, ValueType::String) => {
+                create_typed!(tx, &self.name, crate::types::Number, String)
+            }
+            (KeyType::Number, ValueType::Number) => {
+                create_typed!(tx, &self.name, crate::types::Number, crate::types::Number)
+            }
+            (KeyType::Number, ValueType::Int) => {
+                create_typed!(tx, &self.name, crate::types::Number, i64)
+            }
+            (KeyType::Number, ValueType::Object) => create_typed!(
+                tx,
+                &self.name,
+                crate::types::Number,
+                crate::types::RawObject
+            ),
+            (KeyType::Number, ValueType::Byte) => {
+                create_typed!(tx, &self.name, crate::types::Number, &[u8])
+            }
+            (KeyType::Int, ValueType::String) => create_typed!(tx, &self.name, i64, String),
+            (KeyType::Int, ValueType::Number) => {
+                create_typed!(tx, &self.name, i64, crate::types::Number)
+            }
+            (KeyType::Int, ValueType::Int) => create_typed!(tx, &self.name, i64, i64),
+            (KeyType::Int, ValueType::Object) => {
+                create_typed!(tx, &self.name, i64, crate::types::RawObject)
+            }
+            (KeyType::Int, ValueType::Byte) => create_typed!(tx, &self.name, i64, &[u8]),
+        }
+    }
+
+    /// Renames the shelf's redb table to `new_name`. Values of an encrypted
+    /// shelf are bound to its name, so its entries are copied over and sealed
+    /// again instead.
+    ///
+    /// Fails with [`TransactionError::TableAlreadyExists`] if the cabinet
+    /// file already has a table by that name, even one no shelf refers to.
+    pub fn rename_table(
+        &self,
+        tx: &redb::WriteTransaction,
+        new_name: &str,
+    ) -> Result<(), TransactionError> {
+        ensure_table_absent(tx, new_name)?;
+
+        if let Some(cipher) = &self.cipher {
+            sealed::copy(self, cipher, tx, new_name)?;
+            return self.storage().drop_table(tx).map(|_| ());
+        }
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => {
+                rename_typed!(tx, &self.name, new_name, String, String)
+            }
+            (KeyType::String, ValueType::Number) => {
+                rename_typed!(tx, &self.name, new_name, String, crate::types::Number)
+            }
+            (KeyType::String, ValueType::Int) => {
+                rename_typed!(tx, &self.name, new_name, String, i64)
+            }
+            (KeyType::String, ValueType::Object) => {
+                rename_typed!(tx, &self.name, new_name, String, crate::types::RawObject)
+            }
+            (KeyType::String, ValueType::Byte) => {
+                rename_typed!(tx, &self.name, new_name, String, &[u8])
+            }
+            (KeyType::Number, ValueType::String) => {
+                rename_typed!(tx, &self.name, new_name, crate::types::Number, String)
+            }
+            (KeyType::Number, ValueType::Number) => rename_typed!(
+                tx,
+                &self.name,
+                new_name,
+                crate::types::Number,
+                crate::types::Number
+            ),
+            (KeyType::Number, ValueType::Int) => {
+                rename_typed!(tx, &self.name, new_name, crate::types::Number, i64)
+            }
+            (KeyType::Number, ValueType::Object) => rename_typed!(
+                tx,
+                &self.name,
+                new_name,
+                crate::types::Number,
+                crate::types::RawObject
+            ),
+            (KeyType::Number, ValueType::Byte) => {
+                rename_typed!(tx, &self.name, new_name, crate::types::Number, &[u8])
+            }
+            (KeyType::Int, ValueType::String) => {
+                rename_typed!(tx, &self.name, new_name, i64, String)
+            }
+            (KeyType::Int, ValueType::Number) => {
+                rename_typed!(tx, &self.name, new_name, i64, crate::types::Number)
+            }
+            (KeyType::Int, ValueType::Int) => rename_typed!(tx, &self.name, new_name, i64, i64),
+            (KeyType::Int, ValueType::Object) => {
+                rename_typed!(tx, &self.name, new_name, i64, crate::types::RawObject)
+            }
+            (KeyType::Int, ValueType::Byte) => rename_typed!(tx, &self.name, new_name, i64, &[u8]),
+        }
+    }
+
+    /// Copies every entry of the shelf into a new table named `dest_name`,
+    /// returning the number of entries copied.
+    pub fn copy_table(
+        &self,
+        tx: &redb::WriteTransaction,
+        dest_name: &str,
+    ) -> Result<u64, TransactionError> {
+        ensure_table_absent(tx, dest_name)?;
+
+        if let Some(cipher) = &self.cipher {
+            return sealed::copy(self, cipher, tx, dest_name);
+        }
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => {
+                copy_typed!(tx, &self.name, dest_name, String, String)
+            }
+            (KeyType::String, ValueType::Number) => {
+                copy_typed!(tx, &self.name, dest_name, String, crate::types::Number)
+            }
+            (KeyType::String, ValueType::Int) => {
+                copy_typed!(tx, &self.name, dest_name, String, i64)
+            }
+            (KeyType::String, ValueType::Object) => {
+                copy_typed!(tx, &self.name, dest_name, String, crate::types::RawObject)
+            }
+            (KeyType::String, ValueType::Byte) => {
+                copy_typed!(tx, &self.name, dest_name, String, &[u8])
+            }
+            (KeyType::Number, ValueType::String) => {
+                copy_typed!(tx, &self.name, dest_name, crate::types::Number, String)
+            }
+            (KeyType::Number, ValueType::Number) => copy_typed!(
+                tx,
+                &self.name,
+                dest_name,
+                crate::types::Number,
+                crate::types::Number
+            ),
+            (KeyType::Number, ValueType::Int) => {
+                copy_typed!(tx, &self.name, dest_name, crate::types::Number, i64)
+            }
+            (KeyType::Number, ValueType::Object) => copy_typed!(
+                tx,
+                &self.name,
+                dest_name,
+                crate::types::Number,
+                crate::types::RawObject
+            ),
+            (KeyType::Number, ValueType::Byte) => {
+                copy_typed!(tx, &self.name, dest_name, crate::types::Number, &[u8])
+            }
+            (KeyType::Int, ValueType::String) => {
+                copy_typed!(tx, &self.name, dest_name, i64, String)
+            }
+            (KeyType::Int, ValueType::Number) => {
+                copy_typed!(tx, &self.name, dest_name, i64, crate::types::Number)
+            }
+            (KeyType::Int, ValueType::Int) => copy_typed!(tx, &self.name, dest_name, i64, i64),
+            (KeyType::Int, ValueType::Object) => {
+                copy_typed!(tx, &self.name, dest_name, i64, crate::types::RawObject)
+            }
+            (KeyType::Int, ValueType::Byte) => copy_typed!(tx, &self.name, dest_name, i64, &[u8]),
+        }
+    }
+
+    /// Removes up to `limit` entries from the start of the shelf's table and
+    /// returns them, so a table can be emptied a bounded chunk at a time.
+    /// Values of an encrypted shelf come back sealed, as they are stored.
+    pub(super) fn take_page(
+        &self,
+        tx: &redb::WriteTransaction,
+        limit: usize,
+    ) -> Result<Vec<(Key, Value)>, TransactionError> {
+        if self.cipher.is_some() {
+            return self.storage().take_page(tx, limit);
+        }
+        let key_wrap_string = |s: String| Key::String(s);
+        let key_wrap_number = |n: crate::types::Number| Key::Number(n);
+        let key_wrap_int = |i: i64| Key::Int(crate::types::Int(i));
+        let val_wrap_string = |s: String| Value::String(s);
+        let val_wrap_number = |n: crate::types::Number| Value::Number(n);
+        let val_wrap_int = |i: i64| Value::Int(crate::types::Int(i));
+        let val_wrap_object = |o: crate::types::RawObject| Value::Object(o);
+        let val_wrap_byte = |b: &[u8]| Value::Byte(b.to_vec());
+
+        match (self.key_type, self.value_type) {
+            (KeyType::String, ValueType::String) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                String,
+                key_wrap_string,
+                String,
+                val_wrap_string
+            ),
+            (KeyType::String, ValueType::Number) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                String,
+                key_wrap_string,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::String, ValueType::Int) => {
+                take_typed!(tx, &self.name, limit, String, key_wrap_string, i64, val_wrap_int)
+            }
+            (KeyType::String, ValueType::Object) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                String,
+                key_wrap_string,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::String, ValueType::Byte) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                String,
+                key_wrap_string,
+                &[u8],
+                val_wrap_byte
+            ),
+            (KeyType::Number, ValueType::String) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                crate::types::Number,
+                key_wrap_number,
+                String,
+                val_wrap_string
+            ),
+            (KeyType::Number, ValueType::Number) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                crate::types::Number,
+                key_wrap_number,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::Number, ValueType::Int) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                crate::types::Number,
+                key_wrap_number,
+                i64,
+                val_wrap_int
+            ),
+            (KeyType::Number, ValueType::Object) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                crate::types::Number,
+                key_wrap_number,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::Number, ValueType::Byte) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                crate::types::Number,
+                key_wrap_number,
+                &[u8],
+                val_wrap_byte
+            ),
+            (KeyType::Int, ValueType::String) => {
+                take_typed!(tx, &self.name, limit, i64, key_wrap_int, String, val_wrap_string)
+            }
+            (KeyType::Int, ValueType::Number) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                i64,
+                key_wrap_int,
+                crate::types::Number,
+                val_wrap_number
+            ),
+            (KeyType::Int, ValueType::Int) => {
+                take_typed!(tx, &self.name, limit, i64, key_wrap_int, i64, val_wrap_int)
+            }
+            (KeyType::Int, ValueType::Object) => take_typed!(
+                tx,
+                &self.name,
+                limit,
+                i64,
+                key_wrap_int,
+                crate::types::RawObject,
+                val_wrap_object
+            ),
+            (KeyType::Int, ValueType::Byte) => {
+                take_typed!(tx, &self.name, limit, i64, key_wrap_int, &[u8], val_wrap_byte)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+    use crate::test_util::temp_db;
+    use crate::transaction::{Readable, TransactionError, Writable};
+    use crate::value::Value;
+    use redb::ReadableDatabase;
+
+    #[test]
+    fn test_drop_table_allows_recreate_with_other_types() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::String, ValueType::String);
+
+        {
+            let tx = db.begin_write().unwrap();
+            shelf
+                .set(&tx, Key::String("a".into()), Value::String("1".into()))
+                .unwrap();
+            shelf
+                .set(&tx, Key::String("b".into()), Value::String("2".into()))
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        {
+            let tx = db.begin_write().unwrap();
+            assert_eq!(shelf.drop_table(&tx).unwrap(), 2);
+            tx.commit().unwrap();
+        }
+
+        // Same name, different types: would be a redb type mismatch if the
+        // old table were still around.
+        let recreated = Shelf::new("test".to_string(), KeyType::Int, ValueType::Int);
+        {
+            let tx = db.begin_write().unwrap();
+            recreated
+                .set(
+                    &tx,
+                    Key::Int(crate::types::Int(1)),
+                    Value::Int(crate::types::Int(1)),
+                )
+                .unwrap();
+            tx.commit().unwrap();
+        }
+        let tx = db.begin_read().unwrap();
+        assert_eq!(recreated.count(&tx).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rename_and_copy_table() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("test".to_string(), KeyType::String, ValueType::Int);
+
+        {
+            let tx = db.begin_write().unwrap();
+            shelf
+                .set(
+                    &tx,
+                    Key::String("a".into()),
+                    Value::Int(crate::types::Int(1)),
+                )
+                .unwrap();
+            shelf.rename_table(&tx, "renamed").unwrap();
+            tx.commit().unwrap();
+        }
+
+        let renamed = Shelf::new("renamed".to_string(), KeyType::String, ValueType::Int);
+        {
+            let tx = db.begin_write().unwrap();
+            assert_eq!(renamed.copy_table(&tx, "copy").unwrap(), 1);
+            // Copying onto an existing table must not merge into it.
+            let err = renamed.copy_table(&tx, "copy").unwrap_err();
+            assert!(matches!(err, TransactionError::TableAlreadyExists(_)));
+            tx.commit().unwrap();
+        }
+
+        let copy = Shelf::new("copy".to_string(), KeyType::String, ValueType::Int);
+        let tx = db.begin_read().unwrap();
+        assert_eq!(
+            copy.get(&tx, &Key::String("a".into())).unwrap(),
+            Some(Value::Int(crate::types::Int(1)))
+        );
+        assert_eq!(renamed.count(&tx).unwrap(), 1);
+    }
+}
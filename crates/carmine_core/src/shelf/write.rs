@@ -1,6 +1,8 @@
 use super::Shelf;
+use crate::changelog::{ChangeOp, ChangeWriter, encode_change, record};
 use crate::key::{Key, KeyType};
 use crate::transaction::{TransactionError, Writable};
+use crate::types::StoredNumber;
 use crate::value::{Value, ValueType};
 use redb::{ReadableTable, ReadableTableMetadata, TableDefinition};
 
@@ -253,9 +255,11 @@ fn key_to_string(k: Key) -> String {
         .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a String key"))
 }
 
-fn key_to_number(k: Key) -> crate::types::Number {
-    k.try_into()
-        .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"))
+fn key_to_number(k: Key) -> StoredNumber {
+    let n: crate::types::Number = k
+        .try_into()
+        .unwrap_or_else(|_| unreachable!("Validated key_type guarantees a Number key"));
+    n.into()
 }
 
 fn key_to_int(k: Key) -> i64 {
@@ -270,9 +274,11 @@ fn val_to_string(v: Value) -> String {
         .unwrap_or_else(|_| unreachable!("Validated value_type guarantees a String value"))
 }
 
-fn val_to_number(v: Value) -> crate::types::Number {
-    v.try_into()
-        .unwrap_or_else(|_| unreachable!("Validated value_type guarantees a Number value"))
+fn val_to_number(v: Value) -> StoredNumber {
+    let n: crate::types::Number = v
+        .try_into()
+        .unwrap_or_else(|_| unreachable!("Validated value_type guarantees a Number value"));
+    n.into()
 }
 
 fn val_to_int(v: Value) -> i64 {
@@ -294,8 +300,10 @@ fn val_to_byte(v: Value) -> Vec<u8> {
 
 // --- Writable implementation ---
 
-impl Writable for Shelf {
-    fn set(
+// The raw table writes. The `Writable` impl below wraps each of these and
+// mirrors what it applied into the cabinet's change log.
+impl Shelf {
+    fn set_untracked(
         &self,
         tx: &redb::WriteTransaction,
         key: Key,
@@ -424,7 +432,7 @@ impl Writable for Shelf {
         }
     }
 
-    fn put(
+    pub(super) fn put_untracked(
         &self,
         tx: &redb::WriteTransaction,
         key: Key,
@@ -553,7 +561,11 @@ impl Writable for Shelf {
         }
     }
 
-    fn delete(&self, tx: &redb::WriteTransaction, key: &Key) -> Result<bool, TransactionError> {
+    fn delete_untracked(
+        &self,
+        tx: &redb::WriteTransaction,
+        key: &Key,
+    ) -> Result<bool, TransactionError> {
         if key.as_type() != self.key_type {
             return Err(TransactionError::KeyTypeMismatch {
                 expected: self.key_type,
@@ -646,7 +658,7 @@ impl Writable for Shelf {
         }
     }
 
-    fn batch_set(
+    fn batch_set_untracked(
         &self,
         tx: &redb::WriteTransaction,
         entries: &[(Key, Value)],
@@ -817,7 +829,7 @@ impl Writable for Shelf {
         }
     }
 
-    fn batch_put(
+    fn batch_put_untracked(
         &self,
         tx: &redb::WriteTransaction,
         entries: &[(Key, Value)],
@@ -988,7 +1000,7 @@ impl Writable for Shelf {
         }
     }
 
-    fn batch_delete(
+    fn batch_delete_untracked(
         &self,
         tx: &redb::WriteTransaction,
         keys: &[Key],
@@ -1114,7 +1126,7 @@ impl Writable for Shelf {
         }
     }
 
-    fn clear(&self, tx: &redb::WriteTransaction) -> Result<u64, TransactionError> {
+    fn clear_untracked(&self, tx: &redb::WriteTransaction) -> Result<u64, TransactionError> {
         match (self.key_type, self.value_type) {
             (KeyType::String, ValueType::String) => clear_typed!(tx, &self.name, String, String),
             (KeyType::String, ValueType::Number) => {
@@ -1156,17 +1168,130 @@ impl Writable for Shelf {
     }
 }
 
+impl Writable for Shelf {
+    fn set(
+        &self,
+        tx: &redb::WriteTransaction,
+        key: Key,
+        value: Value,
+    ) -> Result<(), TransactionError> {
+        let change = encode_change(
+            ChangeOp::Set,
+            &self.name,
+            Some(&key),
+            Some(&value),
+            self.cipher.as_deref(),
+        )?;
+        let stored = self.stored_value(&key, value)?;
+        self.storage().set_untracked(tx, key, stored)?;
+        let mut log = ChangeWriter::open(tx)?;
+        log.append_encoded(&change)?;
+        log.finish()
+    }
+
+    fn put(
+        &self,
+        tx: &redb::WriteTransaction,
+        key: Key,
+        value: Value,
+    ) -> Result<(), TransactionError> {
+        let change = encode_change(
+            ChangeOp::Put,
+            &self.name,
+            Some(&key),
+            Some(&value),
+            self.cipher.as_deref(),
+        )?;
+        let stored = self.stored_value(&key, value)?;
+        self.storage().put_untracked(tx, key, stored)?;
+        let mut log = ChangeWriter::open(tx)?;
+        log.append_encoded(&change)?;
+        log.finish()
+    }
+
+    fn delete(&self, tx: &redb::WriteTransaction, key: &Key) -> Result<bool, TransactionError> {
+        let existed = self.storage().delete_untracked(tx, key)?;
+        if existed {
+            record(tx, ChangeOp::Delete, &self.name, Some(key), None)?;
+        }
+        Ok(existed)
+    }
+
+    fn batch_set(
+        &self,
+        tx: &redb::WriteTransaction,
+        entries: &[(Key, Value)],
+    ) -> Result<Vec<Result<(), TransactionError>>, TransactionError> {
+        let results = self.write_stored_batch(entries, |table, entries| {
+            table.batch_set_untracked(tx, entries)
+        })?;
+        log_entries(tx, ChangeOp::Set, self, entries, &results)?;
+        Ok(results)
+    }
+
+    fn batch_put(
+        &self,
+        tx: &redb::WriteTransaction,
+        entries: &[(Key, Value)],
+    ) -> Result<Vec<Result<(), TransactionError>>, TransactionError> {
+        let results = self.write_stored_batch(entries, |table, entries| {
+            table.batch_put_untracked(tx, entries)
+        })?;
+        log_entries(tx, ChangeOp::Put, self, entries, &results)?;
+        Ok(results)
+    }
+
+    fn batch_delete(
+        &self,
+        tx: &redb::WriteTransaction,
+        keys: &[Key],
+    ) -> Result<Vec<bool>, TransactionError> {
+        let results = self.storage().batch_delete_untracked(tx, keys)?;
+        let mut log = ChangeWriter::open(tx)?;
+        for (key, _) in keys.iter().zip(&results).filter(|(_, existed)| **existed) {
+            log.append(ChangeOp::Delete, &self.name, Some(key), None)?;
+        }
+        log.finish()?;
+        Ok(results)
+    }
+
+    fn clear(&self, tx: &redb::WriteTransaction) -> Result<u64, TransactionError> {
+        let count = self.storage().clear_untracked(tx)?;
+        if count > 0 {
+            record(tx, ChangeOp::Clear, &self.name, None, None)?;
+        }
+        Ok(count)
+    }
+}
+
+fn log_entries(
+    tx: &redb::WriteTransaction,
+    op: ChangeOp,
+    shelf: &Shelf,
+    entries: &[(Key, Value)],
+    results: &[Result<(), TransactionError>],
+) -> Result<(), TransactionError> {
+    let mut log = ChangeWriter::open(tx)?;
+    for ((key, value), _) in entries.iter().zip(results).filter(|(_, r)| r.is_ok()) {
+        let record = encode_change(
+            op,
+            &shelf.name,
+            Some(key),
+            Some(value),
+            shelf.cipher.as_deref(),
+        )?;
+        log.append_encoded(&record)?;
+    }
+    log.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::temp_db;
     use crate::transaction::{Readable, TransactionError};
     use redb::ReadableDatabase;
-
-    fn temp_db() -> (tempfile::NamedTempFile, redb::Database) {
-        let file = tempfile::NamedTempFile::new().unwrap();
-        let db = redb::Database::create(file.path()).unwrap();
-        (file, db)
-    }
+    use std::ops::Bound;
 
     fn test_shelf() -> Shelf {
         Shelf::new("test".to_string(), KeyType::String, ValueType::String)
@@ -1368,4 +1493,123 @@ mod tests {
         assert_eq!(shelf.count(&tx).unwrap(), 1);
         assert_eq!(shelf.get(&tx, &Key::String("a".into())).unwrap(), None);
     }
+
+    #[test]
+    fn test_get_batch_reports_per_key_errors() {
+        let (_file, db) = temp_db();
+        let shelf = test_shelf();
+        {
+            let tx = db.begin_write().unwrap();
+            shelf
+                .set(&tx, Key::String("a".into()), Value::String("1".into()))
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        let keys = vec![
+            Key::String("a".into()),
+            Key::String("missing".into()),
+            Key::Int(crate::types::Int(1)),
+        ];
+        let tx = db.begin_read().unwrap();
+        let results = shelf.get_batch(&tx, &keys).unwrap();
+
+        assert_eq!(results.get(0).unwrap(), Some(Value::String("1".into())));
+        assert_eq!(results.get(1).unwrap(), None);
+        assert!(matches!(
+            results.get(2),
+            Err(crate::value::BatchItemError::KeyTypeMismatch {
+                expected: KeyType::String,
+                actual: KeyType::Int,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_number_reads_as_error() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("numbers".to_string(), KeyType::String, ValueType::Number);
+        {
+            let tx = db.begin_write().unwrap();
+            let n = crate::types::Number::from(jsonb::Number::Float64(1.5));
+            shelf.set(&tx, Key::String("a".into()), Value::Number(n)).unwrap();
+            // Bytes that were damaged on disk.
+            let table: TableDefinition<String, crate::types::Number> = TableDefinition::new("numbers");
+            tx.open_table(table)
+                .unwrap()
+                .insert("b".to_string(), StoredNumber(b"garbage".to_vec()))
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        let tx = db.begin_read().unwrap();
+        assert!(shelf.get(&tx, &Key::String("a".into())).unwrap().is_some());
+        assert!(matches!(
+            shelf.get(&tx, &Key::String("b".into())),
+            Err(TransactionError::Decode(_))
+        ));
+        assert!(matches!(shelf.get_all(&tx), Err(TransactionError::Decode(_))));
+
+        let keys = vec![Key::String("a".into()), Key::String("b".into())];
+        let results = shelf.get_batch(&tx, &keys).unwrap();
+        assert!(results.get(0).unwrap().is_some());
+        assert!(matches!(results.get(1), Err(crate::value::BatchItemError::Storage(_))));
+        assert!(matches!(results.get(2), Err(crate::value::BatchItemError::IndexOutOfRange(2))));
+    }
+
+    #[test]
+    fn test_number_keys_order_by_value() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("numbers".to_string(), KeyType::Number, ValueType::String);
+        let key = |n: jsonb::Number| Key::Number(crate::types::Number::from(n));
+        {
+            let tx = db.begin_write().unwrap();
+            for (n, value) in [
+                (jsonb::Number::UInt64(2), "two"),
+                (jsonb::Number::Float64(1.5), "one and a half"),
+                (jsonb::Number::Int64(-3), "minus three"),
+                (jsonb::Number::Decimal64(jsonb::Decimal64 { scale: 2, value: 150 }), "1.50"),
+            ] {
+                shelf.set(&tx, key(n), Value::String(value.into())).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        let tx = db.begin_read().unwrap();
+        let values: Vec<_> = shelf.get_all(&tx).unwrap().into_iter().map(|(_, v)| v).collect();
+        assert_eq!(values, vec![
+            Value::String("minus three".into()),
+            Value::String("1.50".into()),
+            Value::String("two".into()),
+        ]);
+        assert_eq!(
+            shelf.get(&tx, &key(jsonb::Number::Int64(2))).unwrap(),
+            Some(Value::String("two".into()))
+        );
+    }
+
+    #[test]
+    fn test_get_page_resumes_after_last_key() {
+        let (_file, db) = temp_db();
+        let shelf = Shelf::new("numbers".to_string(), KeyType::Number, ValueType::Int);
+        let key = |i: u64| Key::Number(crate::types::Number::from(jsonb::Number::UInt64(i)));
+        {
+            let tx = db.begin_write().unwrap();
+            for i in 0..5 {
+                shelf.set(&tx, key(i), Value::Int(crate::types::Int(i as i64))).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        let tx = db.begin_read().unwrap();
+        let keys = |page: Vec<(Key, Value)>| page.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let first = shelf.get_page(&tx, Bound::Unbounded, Bound::Unbounded, 2).unwrap();
+        assert_eq!(keys(first), vec![key(0), key(1)]);
+        let next = shelf.get_page(&tx, Bound::Excluded(&key(1)), Bound::Excluded(&key(4)), 5).unwrap();
+        assert_eq!(keys(next), vec![key(2), key(3)]);
+        assert!(matches!(
+            shelf.get_page(&tx, Bound::Included(&Key::String("a".into())), Bound::Unbounded, 1),
+            Err(TransactionError::KeyTypeMismatch { .. })
+        ));
+    }
 }
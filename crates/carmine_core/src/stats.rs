@@ -0,0 +1,157 @@
+//! Storage statistics of a cabinet, as reported by redb.
+
+use std::collections::HashMap;
+
+use redb::{ReadableDatabase, ReadableTableMetadata, TableHandle};
+
+use crate::cabinet::Cabinet;
+use crate::changelog;
+use crate::transaction::TransactionError;
+
+/// How a table's b-tree is laid out on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub tree_height: u32,
+    pub leaf_pages: u64,
+    pub branch_pages: u64,
+    /// Bytes of keys and values, excluding page overhead.
+    pub stored_bytes: u64,
+    pub metadata_bytes: u64,
+    /// Bytes in allocated pages that hold nothing.
+    pub fragmented_bytes: u64,
+}
+
+impl From<redb::TableStats> for TreeStats {
+    fn from(stats: redb::TableStats) -> Self {
+        Self {
+            tree_height: stats.tree_height(),
+            leaf_pages: stats.leaf_pages(),
+            branch_pages: stats.branch_pages(),
+            stored_bytes: stats.stored_bytes(),
+            metadata_bytes: stats.metadata_bytes(),
+            fragmented_bytes: stats.fragmented_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableStats {
+    pub name: String,
+    pub entries: u64,
+    pub tree: TreeStats,
+}
+
+/// Totals over the whole database file, including redb's own tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub allocated_pages: u64,
+    pub page_size: usize,
+    pub tree: TreeStats,
+}
+
+impl From<redb::DatabaseStats> for DatabaseStats {
+    fn from(stats: redb::DatabaseStats) -> Self {
+        Self {
+            allocated_pages: stats.allocated_pages(),
+            page_size: stats.page_size(),
+            tree: TreeStats {
+                tree_height: stats.tree_height(),
+                leaf_pages: stats.leaf_pages(),
+                branch_pages: stats.branch_pages(),
+                stored_bytes: stats.stored_bytes(),
+                metadata_bytes: stats.metadata_bytes(),
+                fragmented_bytes: stats.fragmented_bytes(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CabinetStats {
+    pub database: DatabaseStats,
+    /// In the order the shelves were asked for.
+    pub shelves: Vec<TableStats>,
+    pub change_log: TableStats,
+    /// When the most recent change was written, in milliseconds since the
+    /// Unix epoch; `None` if the change log holds no records.
+    pub last_write_ms: Option<u64>,
+}
+
+/// Reads the totals of `cabinet`'s database file.
+///
+/// redb only reports these from a write transaction, so this waits for
+/// in-flight writes and holds up new ones while it walks the database.
+pub fn database_stats(cabinet: &Cabinet) -> Result<DatabaseStats, TransactionError> {
+    let write = cabinet.database().begin_write()?;
+    let stats = DatabaseStats::from(write.stats()?);
+    write.abort()?;
+    Ok(stats)
+}
+
+/// Reads the statistics of `cabinet` and the shelves named in `shelves`.
+/// A shelf without a table yet counts as empty. Takes the write lock like
+/// [`database_stats`].
+pub fn cabinet_stats(cabinet: &Cabinet, shelves: &[&str]) -> Result<CabinetStats, TransactionError> {
+    let database = database_stats(cabinet)?;
+
+    let tx = cabinet.database().begin_read()?;
+    let handles: HashMap<String, _> = tx
+        .list_tables()?
+        .map(|handle| (handle.name().to_string(), handle))
+        .collect();
+    let table_stats = |name: &str| -> Result<TableStats, TransactionError> {
+        let (entries, tree) = match handles.get(name) {
+            Some(handle) => {
+                let table = tx.open_untyped_table(handle.clone())?;
+                (table.len()?, TreeStats::from(table.stats()?))
+            }
+            None => (0, TreeStats::default()),
+        };
+        Ok(TableStats { name: name.to_string(), entries, tree })
+    };
+
+    Ok(CabinetStats {
+        database,
+        shelves: shelves.iter().map(|name| table_stats(name)).collect::<Result<_, _>>()?,
+        change_log: table_stats(changelog::CHANGES_TABLE)?,
+        last_write_ms: changelog::last_write(&tx)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::{Key, KeyType};
+    use crate::shelf::Shelf;
+    use crate::transaction::Writable;
+    use crate::value::{Value, ValueType};
+
+    #[test]
+    fn test_cabinet_stats_count_entries_per_shelf() {
+        let dir = tempfile::tempdir().unwrap();
+        let cabinet = Cabinet::create(1, "c".to_string(), dir.path().join("c.redb"), 1 << 20).unwrap();
+        let shelf = Shelf::new("s".to_string(), KeyType::String, ValueType::String);
+
+        let empty = cabinet_stats(&cabinet, &["s"]).unwrap();
+        assert_eq!(empty.shelves[0].entries, 0);
+        assert_eq!(empty.last_write_ms, None);
+
+        let tx = cabinet.database().begin_write().unwrap();
+        for i in 0..10 {
+            shelf
+                .set(&tx, Key::String(format!("k{}", i)), Value::String("v".repeat(100)))
+                .unwrap();
+        }
+        tx.commit().unwrap();
+
+        let stats = cabinet_stats(&cabinet, &["s", "missing"]).unwrap();
+        assert_eq!(stats.shelves[0].name, "s");
+        assert_eq!(stats.shelves[0].entries, 10);
+        assert!(stats.shelves[0].tree.stored_bytes >= 1000);
+        assert!(stats.shelves[0].tree.leaf_pages >= 1);
+        assert_eq!(stats.shelves[1].entries, 0);
+        assert_eq!(stats.change_log.entries, 10);
+        assert!(stats.last_write_ms.is_some());
+        assert!(stats.database.allocated_pages > 0);
+    }
+}
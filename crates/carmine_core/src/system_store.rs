@@ -1,12 +1,31 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
 use std::path::Path;
 
 use jsonb::RawJsonb;
 use redb::{Builder, Database, ReadableDatabase, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::warn;
 
-use crate::meta::{CabinetMeta, ShelfMeta};
+use crate::auth::ApiKeyMeta;
+use crate::key::KeyType;
+use crate::meta::{CabinetMeta, EncryptionMeta, ShelfMeta};
+use crate::value::ValueType;
 
 const CABINETS: TableDefinition<u64, &[u8]> = TableDefinition::new("cabinets");
+/// Secondary index of cabinet name to id, kept in step with `CABINETS` by
+/// every write so lookups by name don't have to decode every record.
+const CABINET_NAMES: TableDefinition<&str, u64> = TableDefinition::new("cabinet_names");
+const SYSTEM_META: TableDefinition<&str, u64> = TableDefinition::new("system_meta");
+const API_KEYS: TableDefinition<u64, &[u8]> = TableDefinition::new("api_keys");
+/// Key hash to id, for looking up the key a request presents.
+const API_KEY_HASHES: TableDefinition<&[u8], u64> = TableDefinition::new("api_key_hashes");
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Version 1 stores shelf types as `KeyType`/`ValueType` names; version 0
+/// accepted any string.
+const SCHEMA_VERSION: u64 = 1;
 
 pub const DEFAULT_CACHE_SIZE: usize = 8 * 1024 * 1024;
 
@@ -24,6 +43,94 @@ pub enum SystemStoreError {
     Commit(#[from] redb::CommitError),
     #[error("Serialization error: {0}")]
     Jsonb(String),
+    #[error("Cabinet {0} not found")]
+    CabinetNotFound(u64),
+    #[error("Cabinet '{0}' already exists")]
+    CabinetAlreadyExists(String),
+    #[error("Shelf '{0}' not found")]
+    ShelfNotFound(String),
+    #[error("Shelf '{0}' already exists")]
+    ShelfAlreadyExists(String),
+    #[error(
+        "Can't upgrade shelf '{shelf}' of cabinet '{cabinet}': unknown key type '{key_type}' or value type '{value_type}'"
+    )]
+    UnknownLegacyType {
+        cabinet: String,
+        shelf: String,
+        key_type: String,
+        value_type: String,
+    },
+}
+
+fn encode_cabinet(meta: &CabinetMeta) -> Result<jsonb::OwnedJsonb, SystemStoreError> {
+    jsonb::to_owned_jsonb(meta).map_err(|e| SystemStoreError::Jsonb(e.to_string()))
+}
+
+fn decode_cabinet(bytes: &[u8]) -> Result<CabinetMeta, SystemStoreError> {
+    let raw_jsonb = RawJsonb::new(bytes);
+    jsonb::from_raw_jsonb(&raw_jsonb).map_err(|e| SystemStoreError::Jsonb(e.to_string()))
+}
+
+/// An API key record: the listed metadata plus the hash it is found by.
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    key: ApiKeyMeta,
+    hash: Vec<u8>,
+}
+
+fn decode_api_key(bytes: &[u8]) -> Result<StoredApiKey, SystemStoreError> {
+    jsonb::from_raw_jsonb(&RawJsonb::new(bytes)).map_err(|e| SystemStoreError::Jsonb(e.to_string()))
+}
+
+/// Cabinet record as written by schema version 0, with untyped shelf types.
+#[derive(Serialize, Deserialize)]
+struct LegacyCabinetMeta {
+    id: u64,
+    name: String,
+    path: std::path::PathBuf,
+    #[serde(default)]
+    shelves: Vec<LegacyShelfMeta>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyShelfMeta {
+    name: String,
+    key_type: String,
+    value_type: String,
+}
+
+fn normalize_type_name(name: &str, allowed: &[&'static str]) -> Option<&'static str> {
+    allowed
+        .iter()
+        .copied()
+        .find(|a| a.eq_ignore_ascii_case(name.trim()))
+}
+
+/// Converts a version 0 record, matching type names case-insensitively.
+/// Fails on a shelf whose types still don't parse rather than dropping it
+/// and orphaning its table, so the upgrade can be retried once it's fixed.
+fn upgrade_legacy_cabinet(legacy: LegacyCabinetMeta) -> Result<CabinetMeta, SystemStoreError> {
+    let mut shelves = Vec::with_capacity(legacy.shelves.len());
+    for shelf in legacy.shelves {
+        let key_type = normalize_type_name(&shelf.key_type, KeyType::NAMES).and_then(|t| t.parse().ok());
+        let value_type = normalize_type_name(&shelf.value_type, ValueType::NAMES).and_then(|t| t.parse().ok());
+        let (Some(key_type), Some(value_type)) = (key_type, value_type) else {
+            return Err(SystemStoreError::UnknownLegacyType {
+                cabinet: legacy.name,
+                shelf: shelf.name,
+                key_type: shelf.key_type,
+                value_type: shelf.value_type,
+            });
+        };
+        shelves.push(ShelfMeta { name: shelf.name, key_type, value_type });
+    }
+    Ok(CabinetMeta {
+        id: legacy.id,
+        name: legacy.name,
+        path: legacy.path,
+        shelves,
+        encryption: None,
+    })
 }
 
 pub struct SystemStore {
@@ -33,16 +140,113 @@ pub struct SystemStore {
 impl SystemStore {
     pub fn open(path: &Path, cache_size: usize) -> Result<Self, SystemStoreError> {
         let db = Builder::new().set_cache_size(cache_size).create(path)?;
-        Ok(Self { db })
+        let store = Self { db };
+        store.upgrade_schema()?;
+        store.rebuild_name_index()?;
+        Ok(store)
+    }
+
+    /// Rewrites cabinet records from older schema versions. Runs once; the
+    /// version is recorded in the same transaction as the rewritten records.
+    fn upgrade_schema(&self) -> Result<(), SystemStoreError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut meta = txn.open_table(SYSTEM_META)?;
+            let version = meta.get(SCHEMA_VERSION_KEY)?.map_or(0, |v| v.value());
+            if version >= SCHEMA_VERSION {
+                return Ok(());
+            }
+
+            let mut cabinets = txn.open_table(CABINETS)?;
+            let mut upgraded = Vec::new();
+            for entry in cabinets.iter()? {
+                let (id, value) = entry?;
+                let legacy: LegacyCabinetMeta =
+                    jsonb::from_raw_jsonb(&RawJsonb::new(value.value()))
+                        .map_err(|e| SystemStoreError::Jsonb(e.to_string()))?;
+                upgraded.push((id.value(), upgrade_legacy_cabinet(legacy)?));
+            }
+            for (id, cabinet) in upgraded {
+                let owned = encode_cabinet(&cabinet)?;
+                cabinets.insert(id, owned.as_ref())?;
+            }
+            meta.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Rebuilds the name index when its contents differ from the cabinet
+    /// records, e.g. for a `system.redb` written before the index existed.
+    fn rebuild_name_index(&self) -> Result<(), SystemStoreError> {
+        let txn = self.db.begin_write()?;
+        {
+            let cabinets = txn.open_table(CABINETS)?;
+            let mut names = txn.open_table(CABINET_NAMES)?;
+            let mut expected = BTreeMap::new();
+            for entry in cabinets.iter()? {
+                let (id, value) = entry?;
+                let meta = decode_cabinet(value.value())?;
+                // Older stores could hold duplicate names; the first id wins
+                // and the other cabinet is only reachable by id until renamed.
+                match expected.entry(meta.name) {
+                    Entry::Vacant(slot) => {
+                        slot.insert(id.value());
+                    }
+                    Entry::Occupied(slot) => warn!(
+                        name = %slot.key(),
+                        indexed = *slot.get(),
+                        duplicate = id.value(),
+                        "cabinet name is used by more than one cabinet"
+                    ),
+                }
+            }
+            let mut current = BTreeMap::new();
+            for entry in names.iter()? {
+                let (name, id) = entry?;
+                current.insert(name.value().to_string(), id.value());
+            }
+            if current == expected {
+                return Ok(());
+            }
+            names.retain(|_, _| false)?;
+            for (name, id) in &expected {
+                names.insert(name.as_str(), *id)?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Page cache statistics of the system store's database.
+    pub fn cache_stats(&self) -> redb::CacheStats {
+        self.db.cache_stats()
     }
 
+    /// Inserts or replaces a cabinet record. The name must not belong to a
+    /// different cabinet; the check and the write share one transaction.
     pub fn register_cabinet(&self, meta: &CabinetMeta) -> Result<(), SystemStoreError> {
-        let owned =
-            jsonb::to_owned_jsonb(&meta).map_err(|e| SystemStoreError::Jsonb(e.to_string()))?;
+        let owned = encode_cabinet(meta)?;
         let bytes: &[u8] = owned.as_ref();
         let txn = self.db.begin_write()?;
         {
             let mut table = txn.open_table(CABINETS)?;
+            let mut names = txn.open_table(CABINET_NAMES)?;
+            if let Some(id) = names.get(meta.name.as_str())?
+                && id.value() != meta.id
+            {
+                return Err(SystemStoreError::CabinetAlreadyExists(meta.name.clone()));
+            }
+            let previous = match table.get(meta.id)? {
+                Some(raw) => Some(decode_cabinet(raw.value())?),
+                None => None,
+            };
+            if let Some(previous) = previous
+                && previous.name != meta.name
+            {
+                names.remove(previous.name.as_str())?;
+            }
+            names.insert(meta.name.as_str(), meta.id)?;
             table.insert(meta.id, bytes)?;
         }
         txn.commit()?;
@@ -58,12 +262,7 @@ impl SystemStore {
         };
         let value = table.get(id)?;
         match value {
-            Some(raw) => {
-                let raw_jsonb = RawJsonb::new(raw.value());
-                let meta: CabinetMeta = jsonb::from_raw_jsonb(&raw_jsonb)
-                    .map_err(|e| SystemStoreError::Jsonb(e.to_string()))?;
-                Ok(Some(meta))
-            }
+            Some(raw) => Ok(Some(decode_cabinet(raw.value())?)),
             None => Ok(None),
         }
     }
@@ -78,10 +277,7 @@ impl SystemStore {
         let mut cabinets = Vec::new();
         for entry in table.iter()? {
             let (_, value) = entry?;
-            let raw_jsonb = RawJsonb::new(value.value());
-            let meta: CabinetMeta = jsonb::from_raw_jsonb(&raw_jsonb)
-                .map_err(|e| SystemStoreError::Jsonb(e.to_string()))?;
-            cabinets.push(meta);
+            cabinets.push(decode_cabinet(value.value())?);
         }
         Ok(cabinets)
     }
@@ -90,8 +286,20 @@ impl SystemStore {
         &self,
         name: &str,
     ) -> Result<Option<CabinetMeta>, SystemStoreError> {
-        let cabinets = self.list_cabinets()?;
-        Ok(cabinets.into_iter().find(|c| c.name == name))
+        let txn = self.db.begin_read()?;
+        let names = match txn.open_table(CABINET_NAMES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(id) = names.get(name)? else {
+            return Ok(None);
+        };
+        let table = txn.open_table(CABINETS)?;
+        match table.get(id.value())? {
+            Some(raw) => Ok(Some(decode_cabinet(raw.value())?)),
+            None => Ok(None),
+        }
     }
 
     pub fn update_cabinet(&self, meta: &CabinetMeta) -> Result<(), SystemStoreError> {
@@ -102,23 +310,31 @@ impl SystemStore {
         let txn = self.db.begin_write()?;
         let removed = {
             let mut table = txn.open_table(CABINETS)?;
-            table.remove(id)?.is_some()
+            let mut names = txn.open_table(CABINET_NAMES)?;
+            match table.remove(id)? {
+                Some(raw) => {
+                    let meta = decode_cabinet(raw.value())?;
+                    let indexed = names.get(meta.name.as_str())?.map(|v| v.value());
+                    if indexed == Some(id) {
+                        names.remove(meta.name.as_str())?;
+                    }
+                    true
+                }
+                None => false,
+            }
         };
         txn.commit()?;
         Ok(removed)
     }
 
     pub fn add_shelf(&self, cabinet_id: u64, shelf: ShelfMeta) -> Result<(), SystemStoreError> {
-        let mut cabinet = self
-            .get_cabinet(cabinet_id)?
-            .ok_or_else(|| SystemStoreError::Jsonb("Cabinet not found".to_string()))?;
-
-        if cabinet.shelves.iter().any(|s| s.name == shelf.name) {
-            return Err(SystemStoreError::Jsonb("Shelf already exists".to_string()));
-        }
-
-        cabinet.shelves.push(shelf);
-        self.update_cabinet(&cabinet)
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            if cabinet.shelves.iter().any(|s| s.name == shelf.name) {
+                return Err(SystemStoreError::ShelfAlreadyExists(shelf.name));
+            }
+            cabinet.shelves.push(shelf);
+            Ok(())
+        })
     }
 
     pub fn remove_shelf(
@@ -126,18 +342,383 @@ impl SystemStore {
         cabinet_id: u64,
         shelf_name: &str,
     ) -> Result<bool, SystemStoreError> {
-        let mut cabinet = self
-            .get_cabinet(cabinet_id)?
-            .ok_or_else(|| SystemStoreError::Jsonb("Cabinet not found".to_string()))?;
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            let initial_len = cabinet.shelves.len();
+            cabinet.shelves.retain(|s| s.name != shelf_name);
+            Ok(cabinet.shelves.len() != initial_len)
+        })
+    }
+
+    /// Renames a shelf in the cabinet's metadata. The caller is responsible
+    /// for renaming the backing table in the cabinet database.
+    pub fn rename_shelf(
+        &self,
+        cabinet_id: u64,
+        shelf_name: &str,
+        new_name: &str,
+    ) -> Result<ShelfMeta, SystemStoreError> {
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            if cabinet.shelves.iter().any(|s| s.name == new_name) {
+                return Err(SystemStoreError::ShelfAlreadyExists(new_name.to_string()));
+            }
+            let shelf = cabinet
+                .shelves
+                .iter_mut()
+                .find(|s| s.name == shelf_name)
+                .ok_or_else(|| SystemStoreError::ShelfNotFound(shelf_name.to_string()))?;
+            shelf.name = new_name.to_string();
+            Ok(shelf.clone())
+        })
+    }
 
-        let initial_len = cabinet.shelves.len();
-        cabinet.shelves.retain(|s| s.name != shelf_name);
+    /// Registers `dest_name` as a shelf with the same types as `shelf_name`.
+    /// The caller is responsible for copying the backing table.
+    pub fn copy_shelf(
+        &self,
+        cabinet_id: u64,
+        shelf_name: &str,
+        dest_name: &str,
+    ) -> Result<ShelfMeta, SystemStoreError> {
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            if cabinet.shelves.iter().any(|s| s.name == dest_name) {
+                return Err(SystemStoreError::ShelfAlreadyExists(dest_name.to_string()));
+            }
+            let source = cabinet
+                .shelves
+                .iter()
+                .find(|s| s.name == shelf_name)
+                .ok_or_else(|| SystemStoreError::ShelfNotFound(shelf_name.to_string()))?;
+            let copy = ShelfMeta {
+                name: dest_name.to_string(),
+                ..source.clone()
+            };
+            cabinet.shelves.push(copy.clone());
+            Ok(copy)
+        })
+    }
+
+    /// Replaces the metadata of the shelf named `shelf.name`, e.g. after its
+    /// table was migrated to new key/value types.
+    pub fn update_shelf(&self, cabinet_id: u64, shelf: &ShelfMeta) -> Result<(), SystemStoreError> {
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            let existing = cabinet
+                .shelves
+                .iter_mut()
+                .find(|s| s.name == shelf.name)
+                .ok_or_else(|| SystemStoreError::ShelfNotFound(shelf.name.clone()))?;
+            *existing = shelf.clone();
+            Ok(())
+        })
+    }
 
-        if cabinet.shelves.len() == initial_len {
-            return Ok(false);
+    /// Moves an encrypted cabinet to the next key version, returning its new
+    /// encryption settings, or `None` if the cabinet isn't encrypted. The
+    /// caller is responsible for re-encrypting what was sealed with older
+    /// versions.
+    pub fn rotate_cabinet_key(
+        &self,
+        cabinet_id: u64,
+    ) -> Result<Option<EncryptionMeta>, SystemStoreError> {
+        self.modify_cabinet(cabinet_id, |cabinet| {
+            if let Some(encryption) = &mut cabinet.encryption {
+                encryption.key_version += 1;
+            }
+            Ok(cabinet.encryption)
+        })
+    }
+
+    /// Renames a cabinet, rejecting names already used by another cabinet.
+    /// The check and the update happen in one write transaction.
+    pub fn rename_cabinet(
+        &self,
+        cabinet_id: u64,
+        new_name: &str,
+    ) -> Result<CabinetMeta, SystemStoreError> {
+        let txn = self.db.begin_write()?;
+        let meta = {
+            let mut table = txn.open_table(CABINETS)?;
+            let mut names = txn.open_table(CABINET_NAMES)?;
+            if let Some(id) = names.get(new_name)?
+                && id.value() != cabinet_id
+            {
+                return Err(SystemStoreError::CabinetAlreadyExists(new_name.to_string()));
+            }
+            let mut meta = match table.get(cabinet_id)? {
+                Some(raw) => decode_cabinet(raw.value())?,
+                None => return Err(SystemStoreError::CabinetNotFound(cabinet_id)),
+            };
+            names.remove(meta.name.as_str())?;
+            names.insert(new_name, cabinet_id)?;
+            meta.name = new_name.to_string();
+            let owned = encode_cabinet(&meta)?;
+            table.insert(cabinet_id, owned.as_ref())?;
+            meta
+        };
+        txn.commit()?;
+        Ok(meta)
+    }
+
+    /// Stores a new API key under the hash of its secret.
+    pub fn add_api_key(&self, key: &ApiKeyMeta, hash: &[u8]) -> Result<(), SystemStoreError> {
+        let record = StoredApiKey { key: key.clone(), hash: hash.to_vec() };
+        let owned = jsonb::to_owned_jsonb(&record).map_err(|e| SystemStoreError::Jsonb(e.to_string()))?;
+        let txn = self.db.begin_write()?;
+        {
+            txn.open_table(API_KEYS)?.insert(key.id, owned.as_ref())?;
+            txn.open_table(API_KEY_HASHES)?.insert(hash, key.id)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn list_api_keys(&self) -> Result<Vec<ApiKeyMeta>, SystemStoreError> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(API_KEYS) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut keys = Vec::new();
+        for entry in table.iter()? {
+            let (_, value) = entry?;
+            keys.push(decode_api_key(value.value())?.key);
+        }
+        Ok(keys)
+    }
+
+    /// Finds the key whose secret hashes to `hash`.
+    pub fn find_api_key(&self, hash: &[u8]) -> Result<Option<ApiKeyMeta>, SystemStoreError> {
+        let txn = self.db.begin_read()?;
+        let hashes = match txn.open_table(API_KEY_HASHES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(id) = hashes.get(hash)? else {
+            return Ok(None);
+        };
+        let table = txn.open_table(API_KEYS)?;
+        match table.get(id.value())? {
+            Some(raw) => Ok(Some(decode_api_key(raw.value())?.key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Revokes a key. Returns whether it existed.
+    pub fn remove_api_key(&self, id: u64) -> Result<bool, SystemStoreError> {
+        let txn = self.db.begin_write()?;
+        let removed = {
+            let mut table = txn.open_table(API_KEYS)?;
+            match table.remove(id)? {
+                Some(raw) => {
+                    let record = decode_api_key(raw.value())?;
+                    txn.open_table(API_KEY_HASHES)?.remove(record.hash.as_slice())?;
+                    true
+                }
+                None => false,
+            }
+        };
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    fn modify_cabinet<T>(
+        &self,
+        cabinet_id: u64,
+        f: impl FnOnce(&mut CabinetMeta) -> Result<T, SystemStoreError>,
+    ) -> Result<T, SystemStoreError> {
+        let txn = self.db.begin_write()?;
+        let result = {
+            let mut table = txn.open_table(CABINETS)?;
+            let mut meta = match table.get(cabinet_id)? {
+                Some(raw) => decode_cabinet(raw.value())?,
+                None => return Err(SystemStoreError::CabinetNotFound(cabinet_id)),
+            };
+            let result = f(&mut meta)?;
+            let owned = encode_cabinet(&meta)?;
+            table.insert(cabinet_id, owned.as_ref())?;
+            result
+        };
+        txn.commit()?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Algorithm;
+
+    fn cabinet(id: u64, name: &str) -> CabinetMeta {
+        CabinetMeta {
+            id,
+            name: name.to_string(),
+            path: format!("cabinet_{}", id).into(),
+            shelves: Vec::new(),
+            encryption: None,
+        }
+    }
+
+    #[test]
+    fn test_name_index_enforces_uniqueness() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SystemStore::open(&dir.path().join("system.redb"), DEFAULT_CACHE_SIZE).unwrap();
+
+        store.register_cabinet(&cabinet(1, "a")).unwrap();
+        let err = store.register_cabinet(&cabinet(2, "a")).unwrap_err();
+        assert!(matches!(err, SystemStoreError::CabinetAlreadyExists(_)));
+
+        store.rename_cabinet(1, "b").unwrap();
+        assert!(store.find_cabinet_by_name("a").unwrap().is_none());
+        assert_eq!(store.find_cabinet_by_name("b").unwrap().unwrap().id, 1);
+        store.register_cabinet(&cabinet(2, "a")).unwrap();
+
+        assert!(store.remove_cabinet(1).unwrap());
+        assert!(store.find_cabinet_by_name("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rotate_cabinet_key_bumps_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SystemStore::open(&dir.path().join("system.redb"), DEFAULT_CACHE_SIZE).unwrap();
+        let mut encrypted = cabinet(1, "a");
+        encrypted.encryption = Some(EncryptionMeta {
+            algorithm: Algorithm::ChaCha20Poly1305,
+            key_version: 1,
+            key_check: 7,
+        });
+        store.register_cabinet(&encrypted).unwrap();
+        store.register_cabinet(&cabinet(2, "b")).unwrap();
+
+        let rotated = store.rotate_cabinet_key(1).unwrap().unwrap();
+        assert_eq!(rotated.key_version, 2);
+        assert_eq!(store.get_cabinet(1).unwrap().unwrap().encryption, Some(rotated));
+        assert!(store.rotate_cabinet_key(2).unwrap().is_none());
+        assert!(store.get_cabinet(2).unwrap().unwrap().encryption.is_none());
+    }
+
+    /// Writes a version 0 store at `path` holding cabinet "a" with `shelves`.
+    fn write_legacy_store(path: &Path, shelves: Vec<LegacyShelfMeta>) {
+        let store = SystemStore::open(path, DEFAULT_CACHE_SIZE).unwrap();
+        let legacy = LegacyCabinetMeta {
+            id: 1,
+            name: "a".to_string(),
+            path: "cabinet_1".into(),
+            shelves,
+        };
+        let owned = jsonb::to_owned_jsonb(&legacy).unwrap();
+        let txn = store.db.begin_write().unwrap();
+        txn.open_table(CABINETS)
+            .unwrap()
+            .insert(1, owned.as_ref())
+            .unwrap();
+        txn.open_table(SYSTEM_META)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, 0)
+            .unwrap();
+        txn.commit().unwrap();
+    }
+
+    fn legacy_shelf(name: &str, key_type: &str, value_type: &str) -> LegacyShelfMeta {
+        LegacyShelfMeta {
+            name: name.to_string(),
+            key_type: key_type.to_string(),
+            value_type: value_type.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_normalizes_legacy_shelf_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("system.redb");
+        write_legacy_store(&path, vec![legacy_shelf("ok", "string", "OBJECT")]);
+
+        let store = SystemStore::open(&path, DEFAULT_CACHE_SIZE).unwrap();
+        let cabinet = store.find_cabinet_by_name("a").unwrap().unwrap();
+        assert_eq!(cabinet.shelves.len(), 1);
+        assert_eq!(cabinet.shelves[0].key_type, KeyType::String);
+        assert_eq!(cabinet.shelves[0].value_type, ValueType::Object);
+    }
+
+    #[test]
+    fn test_upgrade_fails_on_unknown_legacy_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("system.redb");
+        write_legacy_store(
+            &path,
+            vec![legacy_shelf("ok", "string", "OBJECT"), legacy_shelf("broken", "Str", "Int")],
+        );
+
+        for _ in 0..2 {
+            match SystemStore::open(&path, DEFAULT_CACHE_SIZE) {
+                Err(SystemStoreError::UnknownLegacyType { shelf, .. }) => assert_eq!(shelf, "broken"),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("upgrade should fail"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_api_keys_are_found_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SystemStore::open(&dir.path().join("system.redb"), DEFAULT_CACHE_SIZE).unwrap();
+        let key = ApiKeyMeta {
+            id: 7,
+            name: "reader".to_string(),
+            scopes: vec!["cabinet:a:read".parse().unwrap()],
+            created_at: 0,
+            prefix: "carmine_0123".to_string(),
+        };
+
+        store.add_api_key(&key, b"hash").unwrap();
+        let found = store.find_api_key(b"hash").unwrap().unwrap();
+        assert_eq!(found.id, 7);
+        assert_eq!(found.scopes, key.scopes);
+        assert!(store.find_api_key(b"other").unwrap().is_none());
+        assert_eq!(store.list_api_keys().unwrap().len(), 1);
+
+        assert!(store.remove_api_key(7).unwrap());
+        assert!(!store.remove_api_key(7).unwrap());
+        assert!(store.find_api_key(b"hash").unwrap().is_none());
+        assert!(store.list_api_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_name_index_is_rebuilt_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("system.redb");
+        {
+            let store = SystemStore::open(&path, DEFAULT_CACHE_SIZE).unwrap();
+            store.register_cabinet(&cabinet(1, "a")).unwrap();
+            // Simulate a store written before the index existed.
+            let txn = store.db.begin_write().unwrap();
+            txn.delete_table(CABINET_NAMES).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let store = SystemStore::open(&path, DEFAULT_CACHE_SIZE).unwrap();
+        assert_eq!(store.find_cabinet_by_name("a").unwrap().unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_stale_name_index_is_rebuilt_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("system.redb");
+        {
+            let store = SystemStore::open(&path, DEFAULT_CACHE_SIZE).unwrap();
+            store.register_cabinet(&cabinet(1, "a")).unwrap();
+            // Same entry count as the cabinet records, wrong contents.
+            let txn = store.db.begin_write().unwrap();
+            {
+                let mut names = txn.open_table(CABINET_NAMES).unwrap();
+                names.remove("a").unwrap();
+                names.insert("stale", 1).unwrap();
+            }
+            txn.commit().unwrap();
         }
 
-        self.update_cabinet(&cabinet)?;
-        Ok(true)
+        let store = SystemStore::open(&path, DEFAULT_CACHE_SIZE).unwrap();
+        assert_eq!(store.find_cabinet_by_name("a").unwrap().unwrap().id, 1);
+        assert!(store.find_cabinet_by_name("stale").unwrap().is_none());
     }
 }
@@ -0,0 +1,8 @@
+//! Helpers shared by the unit tests.
+
+/// A fresh database in a temporary file, deleted when the file is dropped.
+pub(crate) fn temp_db() -> (tempfile::NamedTempFile, redb::Database) {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let db = redb::Database::create(file.path()).unwrap();
+    (file, db)
+}
@@ -1,5 +1,6 @@
 use redb::ReadableDatabase;
 use thiserror::Error;
+use std::ops::Bound;
 
 use crate::{
     cabinet::Cabinet,
@@ -30,23 +31,27 @@ pub enum TransactionError {
     },
     #[error("Range queries are not supported for Number keys")]
     RangeNotSupported,
+    #[error("Table '{0}' already exists")]
+    TableAlreadyExists(String),
+    #[error("Corrupt record: {0}")]
+    CorruptRecord(String),
+    #[error("Stored data doesn't decode: {0}")]
+    Decode(#[from] crate::types::TypesError),
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] crate::crypto::CryptoError),
+    #[error("Refusing to repair: {0}")]
+    RepairRefused(String),
 }
 
 impl From<crate::error::Error> for TransactionError {
     fn from(e: crate::error::Error) -> Self {
-        TransactionError::StorageError(redb::StorageError::Io(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            e.to_string(),
-        )))
+        TransactionError::StorageError(redb::StorageError::Io(std::io::Error::other(e.to_string())))
     }
 }
 
 impl From<redb::TableError> for TransactionError {
     fn from(e: redb::TableError) -> Self {
-        TransactionError::StorageError(redb::StorageError::Io(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            e.to_string(),
-        )))
+        TransactionError::StorageError(redb::StorageError::Io(std::io::Error::other(e.to_string())))
     }
 }
 
@@ -60,6 +65,10 @@ impl<'a> TransactionOld<'a> {
         Self { cabinet, shelf }
     }
 
+    pub fn cabinet(&self) -> &Cabinet {
+        self.cabinet
+    }
+
     pub fn validate_key_type(&self, key: &crate::key::Key) -> Result<(), TransactionError> {
         let actual = key.as_type();
         if actual != self.shelf.key_type {
@@ -117,6 +126,16 @@ pub trait Readable {
         start: &Key,
         end: &Key,
     ) -> Result<Vec<(Key, Value)>, TransactionError>;
+    /// Up to `limit` entries in key order between `start` and `end`. Unlike
+    /// [`get_range`](Readable::get_range) it takes any key type, so callers
+    /// can page through a shelf by starting after the last key they got.
+    fn get_page(
+        &self,
+        tx: &redb::ReadTransaction,
+        start: Bound<&Key>,
+        end: Bound<&Key>,
+        limit: usize,
+    ) -> Result<Vec<(Key, Value)>, TransactionError>;
     fn get_batch(
         &self,
         tx: &redb::ReadTransaction,
@@ -3,6 +3,7 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::{
+    cmp::Ordering,
     convert::TryInto,
     hash::{Hash, Hasher},
     ops::{Deref, DerefMut},
@@ -16,6 +17,17 @@ pub enum TypesError {
     InvalidIntBytes,
     #[error("Number comparison failed")]
     NumberCompare,
+    #[error("NaN and infinite numbers are not supported")]
+    NotFinite,
+}
+
+/// Returned when parsing an unknown key or value type name.
+#[derive(Debug, Clone, Error)]
+#[error("Unknown {kind} '{name}', expected one of: {}", .allowed.join(", "))]
+pub struct ParseTypeError {
+    pub kind: &'static str,
+    pub name: String,
+    pub allowed: &'static [&'static str],
 }
 
 #[derive(Debug, Hash, Clone, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -94,6 +106,67 @@ impl PartialEq for Number {
 
 impl Eq for Number {}
 
+impl Number {
+    /// Wraps `n`, rejecting NaN and the infinities, which have no JSON form.
+    pub fn new(n: jsonb::Number) -> Result<Self, TypesError> {
+        match n {
+            jsonb::Number::Float64(f) if !f.is_finite() => Err(TypesError::NotFinite),
+            n => Ok(Number(n)),
+        }
+    }
+
+    /// Decodes a `Number` stored as JSONB. jsonb panics on some malformed
+    /// numbers, so the layout is checked first.
+    pub fn from_jsonb(data: &[u8]) -> Result<Self, TypesError> {
+        if !is_jsonb_number(data) {
+            return Err(TypesError::NumberDeserialize);
+        }
+        from_raw_jsonb(&RawJsonb::new(data)).map_err(|_| TypesError::NumberDeserialize)
+    }
+
+    /// Encodes the number as JSONB, the way it's stored.
+    pub fn to_jsonb(&self) -> Vec<u8> {
+        jsonb::Value::Number(self.0.clone()).to_vec()
+    }
+}
+
+/// Whether `data` is laid out as a JSONB scalar number: a scalar container
+/// header, a number entry giving the payload's length, then the payload, a
+/// tag byte followed by as many bytes as the tag calls for.
+fn is_jsonb_number(data: &[u8]) -> bool {
+    const SCALAR_CONTAINER: u32 = 0x2000_0000;
+    const CONTAINER_TYPE_MASK: u32 = 0xE000_0000;
+    const NUMBER_ENTRY: u32 = 0x2000_0000;
+    const ENTRY_TYPE_MASK: u32 = 0x7000_0000;
+    const ENTRY_LENGTH_MASK: u32 = 0x0FFF_FFFF;
+
+    let word = |at: usize| data.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let (Some(header), Some(entry)) = (word(0), word(4)) else {
+        return false;
+    };
+    let payload = &data[8..];
+    if header & CONTAINER_TYPE_MASK != SCALAR_CONTAINER
+        || entry & ENTRY_TYPE_MASK != NUMBER_ENTRY
+        || (entry & ENTRY_LENGTH_MASK) as usize != payload.len()
+    {
+        return false;
+    }
+    let Some((tag, rest)) = payload.split_first() else {
+        return false;
+    };
+    match tag {
+        // Zero, NaN and the infinities.
+        0x00 | 0x10 | 0x20 | 0x30 => rest.is_empty(),
+        // Integers.
+        0x40 | 0x50 => matches!(rest.len(), 1 | 2 | 4 | 8),
+        // Floats.
+        0x60 => rest.len() == 8,
+        // Decimals, some with a precision byte.
+        0x70 => matches!(rest.len(), 9 | 17 | 18 | 33 | 34),
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Int(pub(crate) i64);
 impl Deref for Int {
@@ -126,13 +199,76 @@ impl From<Vec<u8>> for RawObject {
     }
 }
 
+/// A `Number` as its table stores it, JSONB encoded. Reading one only copies
+/// the bytes; [`Decoded::decoded`] turns them back into the number, failing
+/// if they were damaged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredNumber(pub(crate) Vec<u8>);
+
+impl From<&Number> for StoredNumber {
+    fn from(n: &Number) -> Self {
+        StoredNumber(n.to_jsonb())
+    }
+}
+
+impl From<Number> for StoredNumber {
+    fn from(n: Number) -> Self {
+        StoredNumber::from(&n)
+    }
+}
+
+/// Turns what redb reads for a key or value type into the key or value
+/// itself, failing only for stored `Number`s that don't decode.
+pub(crate) trait Decoded: Sized {
+    type Output;
+    fn decoded(self) -> Result<Self::Output, TypesError>;
+
+    /// Fails like [`Decoded::decoded`] would, without giving up `self`.
+    fn check(&self) -> Result<(), TypesError> {
+        Ok(())
+    }
+}
+
+macro_rules! decoded_as_is {
+    ($($ty:ty),*) => {
+        $(impl Decoded for $ty {
+            type Output = $ty;
+            fn decoded(self) -> Result<Self::Output, TypesError> {
+                Ok(self)
+            }
+        })*
+    };
+}
+
+decoded_as_is!(String, i64, RawObject);
+
+impl<'a> Decoded for &'a [u8] {
+    type Output = &'a [u8];
+    fn decoded(self) -> Result<Self::Output, TypesError> {
+        Ok(self)
+    }
+}
+
+impl Decoded for StoredNumber {
+    type Output = Number;
+    fn decoded(self) -> Result<Number, TypesError> {
+        Number::from_jsonb(&self.0)
+    }
+
+    fn check(&self) -> Result<(), TypesError> {
+        Number::from_jsonb(&self.0).map(|_| ())
+    }
+}
+
+/// Tables of `Number`s read and write [`StoredNumber`]s, so a damaged one is
+/// only decoded, and fails, where it's read.
 impl redb::Value for Number {
     type SelfType<'a>
-        = Number
+        = StoredNumber
     where
         Self: 'a;
     type AsBytes<'a>
-        = Vec<u8>
+        = &'a [u8]
     where
         Self: 'a;
 
@@ -144,15 +280,14 @@ impl redb::Value for Number {
     where
         Self: 'a,
     {
-        let raw_jsonb = RawJsonb::new(data);
-        from_raw_jsonb(&raw_jsonb).expect("Failed to deserialize Number from jsonb bytes")
+        StoredNumber(data.to_vec())
     }
 
     fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
     where
         Self: 'b,
     {
-        jsonb::Value::Number(value.0.clone()).to_vec()
+        value.0.as_slice()
     }
 
     fn type_name() -> redb::TypeName {
@@ -193,11 +328,33 @@ impl redb::Value for RawObject {
     }
 }
 
+/// Keys that don't decode sort after every number, in byte order.
+fn compare_decoded<T>(
+    data1: &[u8],
+    data2: &[u8],
+    decode: impl Fn(&[u8]) -> Result<T, TypesError>,
+    cmp: impl FnOnce(&T, &T) -> Ordering,
+) -> Ordering {
+    match (decode(data1), decode(data2)) {
+        (Ok(a), Ok(b)) => cmp(&a, &b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => data1.cmp(data2),
+    }
+}
+
 impl redb::Key for Number {
-    fn compare(data1: &[u8], data2: &[u8]) -> std::cmp::Ordering {
-        let num1 = <Number as redb::Value>::from_bytes(data1);
-        let num2 = <Number as redb::Value>::from_bytes(data2);
-        num1.0.partial_cmp(&num2.0).unwrap()
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        compare_decoded(data1, data2, Number::from_jsonb, Number::cmp)
+    }
+}
+
+impl Int {
+    /// Decodes an `Int` stored as 8 big-endian bytes.
+    pub fn from_be_slice(data: &[u8]) -> Result<i64, TypesError> {
+        data.try_into()
+            .map(i64::from_be_bytes)
+            .map_err(|_| TypesError::InvalidIntBytes)
     }
 }
 
@@ -219,7 +376,12 @@ impl redb::Value for Int {
     where
         Self: 'a,
     {
-        i64::from_be_bytes(data.try_into().expect("Invalid 8-byte slice"))
+        // redb hands a fixed-width type exactly `fixed_width` bytes, so the
+        // padding is never needed; it only keeps a short slice from panicking.
+        let mut bytes = [0; 8];
+        let len = data.len().min(8);
+        bytes[..len].copy_from_slice(&data[..len]);
+        i64::from_be_bytes(bytes)
     }
 
     fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
@@ -235,9 +397,75 @@ impl redb::Value for Int {
 }
 
 impl redb::Key for Int {
-    fn compare(data1: &[u8], data2: &[u8]) -> std::cmp::Ordering {
-        i64::from_be_bytes(data1.try_into().expect("Invalid 8-byte slice")).cmp(
-            &i64::from_be_bytes(data2.try_into().expect("Invalid 8-byte slice")),
-        )
+    fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
+        compare_decoded(data1, data2, Int::from_be_slice, i64::cmp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(text: &str) -> Number {
+        let owned = jsonb::parse_owned_jsonb(text.as_bytes()).unwrap();
+        Number(from_raw_jsonb(&owned.as_raw()).unwrap())
+    }
+
+    #[test]
+    fn test_keys_compare_by_value() {
+        let compare = |a: &Number, b: &Number| <Number as redb::Key>::compare(&a.to_jsonb(), &b.to_jsonb());
+        let ordered = [
+            Number(jsonb::Number::Float64(-1e300)),
+            Number(jsonb::Number::Int64(-2)),
+            number("-0.1"),
+            number("0.1"),
+            Number(jsonb::Number::UInt64(u64::MAX)),
+            Number(jsonb::Number::Float64(1e300)),
+            Number(jsonb::Number::Float64(f64::NAN)),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(compare(a, b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+        let one = Number(jsonb::Number::Int64(1));
+        for other in [Number(jsonb::Number::UInt64(1)), Number(jsonb::Number::Float64(1.0)), number("1.00")] {
+            assert_eq!(compare(&one, &other), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_bytes_decode_as_errors() {
+        let valid = number("2.5").to_jsonb();
+        for len in 0..valid.len() {
+            assert!(Number::from_jsonb(&valid[..len]).is_err());
+        }
+        assert!(Number::from_jsonb(b"\xff\xff\xff\xff\xff").is_err());
+        // Damaged encodings may decode to another number, but never panic.
+        for text in ["0", "-7", "300000", "2.5", "1e300", "123456789012345678901234567890.5"] {
+            let encoded = number(text).to_jsonb();
+            for i in 0..encoded.len() {
+                for byte in [0x00, 0x01, 0x20, 0x40, 0x60, 0x70, 0xff] {
+                    let mut damaged = encoded.clone();
+                    damaged[i] = byte;
+                    let _ = Number::from_jsonb(&damaged);
+                }
+            }
+        }
+        assert!(Int::from_be_slice(&[1, 2, 3]).is_err());
+
+        assert!(matches!(StoredNumber(b"garbage".to_vec()).decoded(), Err(TypesError::NumberDeserialize)));
+
+        let compare = <Number as redb::Key>::compare;
+        assert_eq!(compare(&valid, b"garbage"), Ordering::Less);
+        assert_eq!(compare(b"garbage", &valid), Ordering::Greater);
+        assert_eq!(compare(b"a", b"b"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_new_rejects_non_finite() {
+        assert!(Number::new(jsonb::Number::Float64(f64::NAN)).is_err());
+        assert!(Number::new(jsonb::Number::Float64(f64::INFINITY)).is_err());
+        assert!(Number::new(jsonb::Number::Float64(1.5)).is_ok());
     }
 }
@@ -1,8 +1,10 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
 use thiserror::Error;
 
 use crate::{
-    key::Key,
-    types::{Int, Number, RawObject},
+    key::{Key, KeyType},
+    types::{Int, Number, ParseTypeError, RawObject},
 };
 
 pub type ResultString = std::result::Result<Option<String>, BatchItemError>;
@@ -17,12 +19,20 @@ pub enum ValueError {
     InvalidConversion,
     #[error("Value is not a valid key type")]
     InvalidKeyType,
+    #[error("Cannot convert {from:?} value to {to:?}")]
+    Unconvertible { from: ValueType, to: ValueType },
 }
 
 #[derive(Debug, Error, Clone)]
 pub enum BatchItemError {
     #[error("Key type mismatch: expected {expected}, got {actual}")]
-    TypeMismatch { expected: String, actual: String },
+    KeyTypeMismatch { expected: KeyType, actual: KeyType },
+    #[error("Key conversion failed: {0}")]
+    KeyConversion(String),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Index {0} is out of range")]
+    IndexOutOfRange(usize),
 }
 
 #[derive(Debug, Hash, Clone, PartialEq)]
@@ -34,7 +44,7 @@ pub enum Value {
     Byte(Vec<u8>),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ValueType {
     String,
     Number,
@@ -43,6 +53,61 @@ pub enum ValueType {
     Byte,
 }
 
+impl ValueType {
+    pub const NAMES: &'static [&'static str] = &["String", "Number", "Int", "Object", "Byte"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueType::String => "String",
+            ValueType::Number => "Number",
+            ValueType::Int => "Int",
+            ValueType::Object => "Object",
+            ValueType::Byte => "Byte",
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Serialized by name so stored metadata and API responses read "String",
+// "Int", ... and unknown names fail with the list of allowed ones.
+impl Serialize for ValueType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "String" => Ok(ValueType::String),
+            "Number" => Ok(ValueType::Number),
+            "Int" => Ok(ValueType::Int),
+            "Object" => Ok(ValueType::Object),
+            "Byte" => Ok(ValueType::Byte),
+            _ => Err(ParseTypeError {
+                kind: "value type",
+                name: s.to_string(),
+                allowed: ValueType::NAMES,
+            }),
+        }
+    }
+}
+
 impl Value {
     pub fn as_type(&self) -> ValueType {
         match self {
@@ -53,6 +118,55 @@ impl Value {
             Value::Byte(_) => ValueType::Byte,
         }
     }
+
+    /// Converts the value to `target`, keeping it as-is when it already has
+    /// that type.
+    ///
+    /// Numbers convert to `Int` only when they hold an exact integer, strings
+    /// are parsed as JSON for `Number` and `Object`, objects render as JSON
+    /// text and bytes convert to `String` only when they are valid UTF-8.
+    pub fn convert(self, target: ValueType) -> Result<Value, ValueError> {
+        let from = self.as_type();
+        let unconvertible = || ValueError::Unconvertible { from, to: target };
+        match (self, target) {
+            (value, target) if value.as_type() == target => Ok(value),
+            (Value::Int(i), ValueType::Number) => {
+                Ok(Value::Number(Number::from(jsonb::Number::Int64(*i))))
+            }
+            (Value::Int(i), ValueType::String) => Ok(Value::String(i.to_string())),
+            (Value::Number(n), ValueType::Int) => n
+                .as_i64()
+                .map(|i| Value::Int(Int::from(i)))
+                .ok_or_else(unconvertible),
+            (Value::Number(n), ValueType::String) => Ok(Value::String(n.to_string())),
+            (Value::String(s), ValueType::Int) => s
+                .trim()
+                .parse::<i64>()
+                .map(|i| Value::Int(Int::from(i)))
+                .map_err(|_| unconvertible()),
+            (Value::String(s), ValueType::Number) => jsonb::parse_owned_jsonb(s.trim().as_bytes())
+                .ok()
+                .and_then(|owned| jsonb::from_raw_jsonb::<jsonb::Number>(&owned.as_raw()).ok())
+                .and_then(|n| Number::new(n).ok())
+                .map(Value::Number)
+                .ok_or_else(unconvertible),
+            (Value::String(s), ValueType::Object) => {
+                let owned = jsonb::parse_owned_jsonb(s.as_bytes()).map_err(|_| unconvertible())?;
+                if owned.as_raw().object_keys().ok().flatten().is_none() {
+                    return Err(unconvertible());
+                }
+                Ok(Value::Object(RawObject::from(owned.to_vec())))
+            }
+            (Value::String(s), ValueType::Byte) => Ok(Value::Byte(s.into_bytes())),
+            (Value::Object(o), ValueType::String) => {
+                Ok(Value::String(jsonb::RawJsonb::new(&o).to_string()))
+            }
+            (Value::Byte(b), ValueType::String) => String::from_utf8(b)
+                .map(Value::String)
+                .map_err(|_| unconvertible()),
+            _ => Err(unconvertible()),
+        }
+    }
 }
 
 impl TryFrom<Value> for Key {
@@ -180,6 +294,9 @@ impl ValueRetVec {
             ValueType::Byte => ValueRetVec::Byte(vec![Ok(None); size]),
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     pub fn len(&self) -> usize {
         match self {
             ValueRetVec::String(s) => s.len(),
@@ -193,50 +310,35 @@ impl ValueRetVec {
     pub fn get(&self, index: usize) -> Result<Option<Value>, BatchItemError> {
         match self {
             ValueRetVec::String(s) => {
-                let res = s.get(index).ok_or_else(|| BatchItemError::TypeMismatch {
-                    expected: "String".to_string(),
-                    actual: "index out of bounds".to_string(),
-                })?;
+                let res = s.get(index).ok_or(BatchItemError::IndexOutOfRange(index))?;
                 match res {
                     Ok(opt) => Ok(opt.as_ref().map(|v| Value::String(v.clone()))),
                     Err(e) => Err(e.clone()),
                 }
             }
             ValueRetVec::Number(n) => {
-                let res = n.get(index).ok_or_else(|| BatchItemError::TypeMismatch {
-                    expected: "Number".to_string(),
-                    actual: "index out of bounds".to_string(),
-                })?;
+                let res = n.get(index).ok_or(BatchItemError::IndexOutOfRange(index))?;
                 match res {
                     Ok(opt) => Ok(opt.as_ref().map(|v| Value::Number(v.clone()))),
                     Err(e) => Err(e.clone()),
                 }
             }
             ValueRetVec::Int(i) => {
-                let res = i.get(index).ok_or_else(|| BatchItemError::TypeMismatch {
-                    expected: "Int".to_string(),
-                    actual: "index out of bounds".to_string(),
-                })?;
+                let res = i.get(index).ok_or(BatchItemError::IndexOutOfRange(index))?;
                 match res {
                     Ok(opt) => Ok(opt.as_ref().map(|v| Value::Int(*v))),
                     Err(e) => Err(e.clone()),
                 }
             }
             ValueRetVec::Object(o) => {
-                let res = o.get(index).ok_or_else(|| BatchItemError::TypeMismatch {
-                    expected: "Object".to_string(),
-                    actual: "index out of bounds".to_string(),
-                })?;
+                let res = o.get(index).ok_or(BatchItemError::IndexOutOfRange(index))?;
                 match res {
                     Ok(opt) => Ok(opt.as_ref().map(|v| Value::Object(v.clone()))),
                     Err(e) => Err(e.clone()),
                 }
             }
             ValueRetVec::Byte(b) => {
-                let res = b.get(index).ok_or_else(|| BatchItemError::TypeMismatch {
-                    expected: "Byte".to_string(),
-                    actual: "index out of bounds".to_string(),
-                })?;
+                let res = b.get(index).ok_or(BatchItemError::IndexOutOfRange(index))?;
                 match res {
                     Ok(opt) => Ok(opt.as_ref().map(|v| Value::Byte(v.clone()))),
                     Err(e) => Err(e.clone()),
@@ -330,49 +432,45 @@ impl ValueRetVec {
                     Err(ValueError::InvalidConversion)
                 }
             }
-            (v, Err(e)) => {
-                let idx = match v {
-                    ValueRetVec::String(s) => {
-                        if index < s.len() {
-                            s[index] = Err(e.clone());
-                            return Ok(());
-                        } else {
-                            return Err(ValueError::InvalidConversion);
-                        }
-                    }
-                    ValueRetVec::Number(n) => {
-                        if index < n.len() {
-                            n[index] = Err(e.clone());
-                            return Ok(());
-                        } else {
-                            return Err(ValueError::InvalidConversion);
-                        }
-                    }
-                    ValueRetVec::Int(i) => {
-                        if index < i.len() {
-                            i[index] = Err(e.clone());
-                            return Ok(());
-                        } else {
-                            return Err(ValueError::InvalidConversion);
-                        }
-                    }
-                    ValueRetVec::Object(o) => {
-                        if index < o.len() {
-                            o[index] = Err(e.clone());
-                            return Ok(());
-                        } else {
-                            return Err(ValueError::InvalidConversion);
-                        }
-                    }
-                    ValueRetVec::Byte(b) => {
-                        if index < b.len() {
-                            b[index] = Err(e.clone());
-                            return Ok(());
-                        } else {
-                            return Err(ValueError::InvalidConversion);
-                        }
-                    }
-                };
+            (ValueRetVec::String(s), Err(e)) => {
+                if index < s.len() {
+                    s[index] = Err(e);
+                    Ok(())
+                } else {
+                    Err(ValueError::InvalidConversion)
+                }
+            }
+            (ValueRetVec::Number(n), Err(e)) => {
+                if index < n.len() {
+                    n[index] = Err(e);
+                    Ok(())
+                } else {
+                    Err(ValueError::InvalidConversion)
+                }
+            }
+            (ValueRetVec::Int(i), Err(e)) => {
+                if index < i.len() {
+                    i[index] = Err(e);
+                    Ok(())
+                } else {
+                    Err(ValueError::InvalidConversion)
+                }
+            }
+            (ValueRetVec::Object(o), Err(e)) => {
+                if index < o.len() {
+                    o[index] = Err(e);
+                    Ok(())
+                } else {
+                    Err(ValueError::InvalidConversion)
+                }
+            }
+            (ValueRetVec::Byte(b), Err(e)) => {
+                if index < b.len() {
+                    b[index] = Err(e);
+                    Ok(())
+                } else {
+                    Err(ValueError::InvalidConversion)
+                }
             }
             _ => Err(ValueError::InvalidConversion),
         }
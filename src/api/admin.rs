@@ -0,0 +1,633 @@
+//! The system operations behind the `/system` endpoints, shared with the
+//! other transports. Each function does the whole operation, including
+//! invalidating cached metadata, and leaves only request parsing and
+//! response shaping to its callers.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::mapref::entry::Entry;
+
+use redb::ReadableDatabase;
+
+use crate::api::error::ApiError;
+use crate::maintenance::{self, CompactionReport, IntegrityCheck};
+use crate::encryption::{self, KeyRotation, RotationState};
+use crate::AppState;
+use carmine_core::{
+    auth::{self, ApiKeyMeta, Scope},
+    cabinet::Cabinet,
+    changelog::{self, Retention},
+    key::KeyType,
+    meta::{CabinetMeta, EncryptionMeta, ShelfMeta},
+    shelf::migrate::{ConversionPolicy, MigrationReport},
+    shelf::Shelf,
+    stats::{self, CabinetStats},
+    transaction::Writable,
+    value::ValueType,
+};
+
+/// The outcome of [`migrate_shelf`]. `shelf` is the migrated shelf if the
+/// migration was applied, or the untouched original if it wasn't.
+pub(crate) struct Migration {
+    pub applied: bool,
+    pub shelf: ShelfMeta,
+    pub report: MigrationReport,
+}
+
+/// A cabinet's encryption settings and its latest key rotation, if any.
+pub(crate) struct EncryptionStatus {
+    pub encryption: EncryptionMeta,
+    pub rotation: Option<KeyRotation>,
+}
+
+/// What [`check_cabinet`] repaired.
+pub(crate) struct Repairs {
+    pub created_tables: Vec<String>,
+    pub removed_entries: u64,
+    /// Orphan tables registered as shelves.
+    pub adopted_tables: Vec<String>,
+    /// Shelves whose types were changed to those of their table.
+    pub retyped_shelves: Vec<String>,
+}
+
+/// The outcome of [`check_cabinet`]. `repairs` is set if they were asked
+/// for.
+pub(crate) struct CabinetCheck {
+    pub check: IntegrityCheck,
+    pub repairs: Option<Repairs>,
+}
+
+/// Shelf names taken by routes under `/v1/:cabinet`, which would make a
+/// shelf of that name unreachable over HTTP.
+const RESERVED_SHELF_NAMES: &[&str] = &["changes"];
+
+/// Cabinet names taken by routes under `/v1`.
+const RESERVED_CABINET_NAMES: &[&str] = &["ws"];
+
+/// Rejects shelf names that would collide with the cabinet's own tables or
+/// with a route.
+fn check_shelf_name(name: &str) -> Result<(), ApiError> {
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("Shelf names can't be empty".to_string()));
+    }
+    // A '/' would split the shelf's name across path segments and make it
+    // unreachable over HTTP.
+    if name.contains('/') {
+        return Err(ApiError::BadRequest("Shelf names can't contain '/'".to_string()));
+    }
+    if changelog::is_reserved_name(name) {
+        return Err(ApiError::BadRequest(format!(
+            "Shelf names starting with '{}' are reserved",
+            changelog::RESERVED_PREFIX
+        )));
+    }
+    if RESERVED_SHELF_NAMES.contains(&name) {
+        return Err(ApiError::BadRequest(format!("Shelf name '{}' is reserved", name)));
+    }
+    Ok(())
+}
+
+/// Rejects cabinet names that would collide with a route or that scopes
+/// couldn't name unambiguously: scopes are written `cabinet:<name>/<shelf>:read`
+/// with `*` matching every cabinet.
+fn check_cabinet_name(name: &str) -> Result<(), ApiError> {
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("Cabinet names can't be empty".to_string()));
+    }
+    if name == auth::ANY_CABINET || name.contains(['/', ':']) {
+        return Err(ApiError::BadRequest(format!(
+            "Cabinet names can't be '{}' or contain '/' or ':'",
+            auth::ANY_CABINET
+        )));
+    }
+    if RESERVED_CABINET_NAMES.contains(&name) {
+        return Err(ApiError::BadRequest(format!("Cabinet name '{}' is reserved", name)));
+    }
+    Ok(())
+}
+
+fn find_cabinet(state: &AppState, name: &str) -> Result<CabinetMeta, ApiError> {
+    state.system_store.find_cabinet_by_name(name)?
+        .ok_or_else(|| ApiError::CabinetNotFound(name.to_string()))
+}
+
+fn find_shelf<'a>(meta: &'a CabinetMeta, name: &str) -> Result<&'a ShelfMeta, ApiError> {
+    meta.shelves.iter()
+        .find(|s| s.name == name)
+        .ok_or_else(|| ApiError::ShelfNotFound(name.to_string()))
+}
+
+/// Keeps operations that replace the cabinet's file or handle from running
+/// while it's being compacted or checked.
+fn ensure_not_in_maintenance(state: &AppState, meta: &CabinetMeta) -> Result<(), ApiError> {
+    if state.in_maintenance.contains(&meta.id) {
+        return Err(ApiError::CabinetBusy(meta.name.clone()));
+    }
+    Ok(())
+}
+
+fn open_cabinet(state: &AppState, meta: &CabinetMeta) -> Result<Cabinet, ApiError> {
+    state.get_or_open_cabinet(meta.id, meta.name.clone(), meta.path.clone())
+}
+
+/// The shelf as the data path sees it, with the cabinet's cipher if the
+/// cabinet is encrypted.
+fn open_shelf(state: &AppState, meta: &CabinetMeta, shelf_meta: &ShelfMeta) -> Result<Shelf, ApiError> {
+    Ok(Shelf::from(shelf_meta).with_cipher(encryption::cabinet_cipher(state, meta)?))
+}
+
+fn require_encryption(meta: &CabinetMeta) -> Result<EncryptionMeta, ApiError> {
+    meta.encryption
+        .ok_or_else(|| ApiError::BadRequest(format!("Cabinet '{}' is not encrypted", meta.name)))
+}
+
+/// Creates a cabinet. `encrypted` defaults to whether a master key is
+/// configured.
+pub(crate) fn create_cabinet(
+    state: &AppState,
+    name: String,
+    encrypted: Option<bool>,
+) -> Result<CabinetMeta, ApiError> {
+    check_cabinet_name(&name)?;
+    let id: u64 = small_uid::SmallUid::new().into();
+    let encryption = match (encrypted, &state.encryption) {
+        (Some(false), _) | (None, None) => None,
+        (_, Some(encryption)) => Some(EncryptionMeta {
+            algorithm: encryption.algorithm,
+            key_version: 1,
+            key_check: encryption.master_key.key_check(id),
+        }),
+        (Some(true), None) => {
+            return Err(ApiError::BadRequest(
+                "Encrypted cabinets need a master key in [encryption]".to_string(),
+            ));
+        }
+    };
+
+    let path = state.data_dir.join(format!("cabinet_{}", id));
+
+    let meta = CabinetMeta {
+        id,
+        name,
+        path: path.clone(),
+        shelves: Vec::new(),
+        encryption,
+    };
+
+    // Registering first reserves the name atomically, so two concurrent
+    // creates can't both end up with a cabinet file.
+    state.system_store.register_cabinet(&meta)?;
+
+    let cabinet = match Cabinet::create(id, meta.name.clone(), path, state.cabinets.cache_size()) {
+        Ok(cabinet) => cabinet,
+        Err(e) => {
+            let _ = state.system_store.remove_cabinet(id);
+            return Err(e.into());
+        }
+    };
+
+    state.cabinets.insert(cabinet);
+    state.invalidate_metadata(&meta.name);
+    state.check_open_limit();
+
+    Ok(meta)
+}
+
+pub(crate) fn list_cabinets(state: &AppState) -> Result<Vec<CabinetMeta>, ApiError> {
+    Ok(state.system_store.list_cabinets()?)
+}
+
+pub(crate) fn get_cabinet(state: &AppState, name: &str) -> Result<CabinetMeta, ApiError> {
+    find_cabinet(state, name)
+}
+
+pub(crate) fn delete_cabinet(state: &AppState, name: &str) -> Result<(), ApiError> {
+    let meta = find_cabinet(state, name)?;
+    ensure_not_in_maintenance(state, &meta)?;
+
+    state.cabinets.remove(meta.id);
+    state.invalidate_metadata(&meta.name);
+
+    std::fs::remove_file(&meta.path)
+        .map_err(|e| ApiError::Internal(format!("Failed to remove cabinet file: {}", e)))?;
+
+    state.system_store.remove_cabinet(meta.id)?;
+    state.invalidate_metadata(&meta.name);
+    state.metrics.forget_cabinet(&meta.name);
+    // Dropping the sender ends any change streams following this cabinet.
+    state.changes.remove(&meta.id);
+    state.key_rotations.remove(&meta.id);
+
+    Ok(())
+}
+
+pub(crate) fn rename_cabinet(
+    state: &AppState,
+    name: &str,
+    new_name: &str,
+) -> Result<CabinetMeta, ApiError> {
+    check_cabinet_name(new_name)?;
+    let meta = find_cabinet(state, name)?;
+    ensure_not_in_maintenance(state, &meta)?;
+
+    let renamed = state.system_store.rename_cabinet(meta.id, new_name)?;
+
+    state.cabinets.rename(meta.id, &renamed.name);
+    state.invalidate_metadata(&meta.name);
+    state.invalidate_metadata(&renamed.name);
+    state.metrics.forget_cabinet(&meta.name);
+
+    Ok(renamed)
+}
+
+pub(crate) fn clean_cabinet(state: &AppState, name: &str) -> Result<(), ApiError> {
+    let meta = find_cabinet(state, name)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    for shelf_meta in &meta.shelves {
+        let shelf = open_shelf(state, &meta, shelf_meta)?;
+        let _ = shelf.clear(&txn);
+    }
+
+    txn.commit()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    state.notify_changes(meta.id);
+
+    Ok(())
+}
+
+pub(crate) fn create_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    name: String,
+    key_type: KeyType,
+    value_type: ValueType,
+) -> Result<ShelfMeta, ApiError> {
+    let meta = find_cabinet(state, cabinet_name)?;
+
+    check_shelf_name(&name)?;
+    let shelf_meta = ShelfMeta {
+        name,
+        key_type,
+        value_type,
+    };
+
+    state.system_store.add_shelf(meta.id, shelf_meta.clone())?;
+    state.invalidate_metadata(&meta.name);
+
+    Ok(shelf_meta)
+}
+
+pub(crate) fn list_shelves(state: &AppState, cabinet_name: &str) -> Result<Vec<ShelfMeta>, ApiError> {
+    Ok(find_cabinet(state, cabinet_name)?.shelves)
+}
+
+/// Drops a shelf and returns how many entries it held. A shelf that still
+/// has entries is only dropped with `force`.
+pub(crate) fn delete_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    shelf_name: &str,
+    force: bool,
+) -> Result<u64, ApiError> {
+    let meta = find_cabinet(state, cabinet_name)?;
+    ensure_not_in_maintenance(state, &meta)?;
+    let shelf = open_shelf(state, &meta, find_shelf(&meta, shelf_name)?)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let deleted_entries = shelf.drop_table(&txn)?;
+
+    if deleted_entries > 0 && !force {
+        return Err(ApiError::ShelfNotEmpty { name: shelf_name.to_string(), entries: deleted_entries });
+    }
+
+    // The metadata lives in a different database, so the two updates can't
+    // share a transaction. Removing the metadata first means a failure here
+    // aborts the table drop, and a failed commit below only leaves an
+    // unreferenced table behind rather than a shelf pointing at nothing.
+    state.system_store.remove_shelf(meta.id, shelf_name)?;
+
+    let committed = txn.commit();
+    state.invalidate_metadata(&meta.name);
+    committed.map_err(|e| ApiError::Internal(e.to_string()))?;
+    state.metrics.forget_shelf(&meta.name, shelf_name);
+
+    Ok(deleted_entries)
+}
+
+pub(crate) fn rename_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    shelf_name: &str,
+    new_name: &str,
+) -> Result<ShelfMeta, ApiError> {
+    let meta = find_cabinet(state, cabinet_name)?;
+    let shelf_meta = find_shelf(&meta, shelf_name)?;
+    check_shelf_name(new_name)?;
+    if meta.shelves.iter().any(|s| s.name == new_name) {
+        return Err(ApiError::ShelfAlreadyExists(new_name.to_string()));
+    }
+    let shelf = open_shelf(state, &meta, shelf_meta)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    shelf.rename_table(&txn, new_name)?;
+
+    let renamed = state.system_store.rename_shelf(meta.id, shelf_name, new_name)?;
+
+    if let Err(e) = txn.commit() {
+        // Put the metadata back so it keeps pointing at the untouched table.
+        let _ = state.system_store.rename_shelf(meta.id, new_name, shelf_name);
+        state.invalidate_metadata(&meta.name);
+        return Err(ApiError::Internal(e.to_string()));
+    }
+    state.invalidate_metadata(&meta.name);
+    state.metrics.forget_shelf(&meta.name, shelf_name);
+
+    Ok(renamed)
+}
+
+/// Copies a shelf under a new name, returning the copy and how many entries
+/// it received.
+pub(crate) fn copy_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    shelf_name: &str,
+    new_name: &str,
+) -> Result<(ShelfMeta, u64), ApiError> {
+    let meta = find_cabinet(state, cabinet_name)?;
+    let shelf_meta = find_shelf(&meta, shelf_name)?;
+    check_shelf_name(new_name)?;
+    if meta.shelves.iter().any(|s| s.name == new_name) {
+        return Err(ApiError::ShelfAlreadyExists(new_name.to_string()));
+    }
+    let shelf = open_shelf(state, &meta, shelf_meta)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let copied_entries = shelf.copy_table(&txn, new_name)?;
+
+    let copy = state.system_store.copy_shelf(meta.id, shelf_name, new_name)?;
+
+    if let Err(e) = txn.commit() {
+        let _ = state.system_store.remove_shelf(meta.id, new_name);
+        state.invalidate_metadata(&meta.name);
+        return Err(ApiError::Internal(e.to_string()));
+    }
+    state.invalidate_metadata(&meta.name);
+
+    Ok((copy, copied_entries))
+}
+
+/// Converts a shelf to new key and value types; `None` keeps the current
+/// type. A [`ConversionPolicy::DefaultValue`] may hold a value of any type,
+/// and is converted to the target value type here.
+pub(crate) fn migrate_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    shelf_name: &str,
+    key_type: Option<KeyType>,
+    value_type: Option<ValueType>,
+    policy: ConversionPolicy,
+) -> Result<Migration, ApiError> {
+    let meta = find_cabinet(state, cabinet_name)?;
+    let shelf_meta = find_shelf(&meta, shelf_name)?;
+    let shelf = open_shelf(state, &meta, shelf_meta)?;
+
+    let key_type = key_type.unwrap_or(shelf.key_type);
+    let value_type = value_type.unwrap_or(shelf.value_type);
+    let policy = match policy {
+        ConversionPolicy::DefaultValue(default) => ConversionPolicy::DefaultValue(
+            default
+                .convert(value_type)
+                .map_err(|e| ApiError::JsonParse(format!("default: {}", e)))?,
+        ),
+        policy => policy,
+    };
+
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let (migrated, report) = shelf.migrate(&txn, key_type, value_type, &policy)?;
+
+    let migrated_meta = ShelfMeta::from(&migrated);
+
+    // A strict migration with failures leaves a half-written table behind;
+    // dropping the transaction discards it along with the drained original.
+    let applied = report.is_clean() || !matches!(policy, ConversionPolicy::Strict);
+    if !applied {
+        drop(txn);
+        return Ok(Migration { applied, shelf: shelf_meta.clone(), report });
+    }
+
+    state.system_store.update_shelf(meta.id, &migrated_meta)?;
+
+    if let Err(e) = txn.commit() {
+        let _ = state.system_store.update_shelf(meta.id, shelf_meta);
+        state.invalidate_metadata(&meta.name);
+        return Err(ApiError::Internal(e.to_string()));
+    }
+    state.invalidate_metadata(&meta.name);
+    state.notify_changes(meta.id);
+
+    Ok(Migration { applied, shelf: migrated_meta, report })
+}
+
+pub(crate) fn encryption_status(state: &AppState, name: &str) -> Result<EncryptionStatus, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    Ok(EncryptionStatus {
+        encryption: require_encryption(&meta)?,
+        rotation: state.key_rotations.get(&meta.id).map(|r| r.clone()),
+    })
+}
+
+/// Moves an encrypted cabinet to a new key. New writes use it as soon as
+/// this returns; existing values are re-encrypted in the background.
+pub(crate) fn rotate_cabinet_key(state: &Arc<AppState>, name: &str) -> Result<EncryptionStatus, ApiError> {
+    let mut meta = find_cabinet(state, name)?;
+    require_encryption(&meta)?;
+
+    // Holding the entry keeps a concurrent request from starting a second
+    // rotation of the same cabinet.
+    let entry = state.key_rotations.entry(meta.id);
+    if let Entry::Occupied(running) = &entry
+        && running.get().state == RotationState::Running
+    {
+        return Err(ApiError::BadRequest(format!(
+            "A key rotation is already running for cabinet '{}'",
+            meta.name
+        )));
+    }
+    let encryption = state.system_store.rotate_cabinet_key(meta.id)?;
+    let encryption = encryption.ok_or_else(|| ApiError::CabinetNotFound(meta.name.clone()))?;
+    let rotation = KeyRotation {
+        key_version: encryption.key_version,
+        state: RotationState::Running,
+        resealed: 0,
+        error: None,
+    };
+    entry.insert(rotation.clone());
+    state.invalidate_metadata(&meta.name);
+
+    meta.encryption = Some(encryption);
+    encryption::spawn_rotation(state.clone(), meta);
+
+    Ok(EncryptionStatus { encryption, rotation: Some(rotation) })
+}
+
+/// The outcome of [`cabinet_stats`].
+pub(crate) struct CabinetUsage {
+    pub file_size: u64,
+    pub stats: CabinetStats,
+}
+
+pub(crate) fn cabinet_stats(state: &AppState, name: &str) -> Result<CabinetUsage, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let shelves: Vec<&str> = meta.shelves.iter().map(|s| s.name.as_str()).collect();
+    let stats = stats::cabinet_stats(&cabinet, &shelves)?;
+    let file_size = std::fs::metadata(&meta.path)
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .len();
+    Ok(CabinetUsage { file_size, stats })
+}
+
+pub(crate) async fn compact_cabinet(state: &Arc<AppState>, name: &str) -> Result<CompactionReport, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    maintenance::compact(state, &meta).await
+}
+
+/// Checks the cabinet and, with `repair`, fixes what it found. The stored
+/// data is taken as the truth: orphan tables whose types can be told become
+/// shelves, and shelves take the types of their table.
+pub(crate) async fn check_cabinet(state: &Arc<AppState>, name: &str, repair: bool) -> Result<CabinetCheck, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    let cipher = encryption::cabinet_cipher(state, &meta)?;
+    let shelves = meta
+        .shelves
+        .iter()
+        .map(|s| Shelf::from(s).with_cipher(cipher.clone()))
+        .collect();
+
+    let check = maintenance::check_integrity(state, &meta, shelves, cipher, repair).await?;
+    let Some(removed_entries) = check.removed_entries else {
+        return Ok(CabinetCheck { check, repairs: None });
+    };
+
+    let report = &check.report;
+    let mut repairs = Repairs {
+        created_tables: report.missing_tables.clone(),
+        removed_entries,
+        adopted_tables: Vec::new(),
+        retyped_shelves: Vec::new(),
+    };
+    for orphan in &report.orphan_tables {
+        let Some(types) = orphan.types else { continue };
+        state.system_store.add_shelf(meta.id, ShelfMeta {
+            name: orphan.name.clone(),
+            key_type: types.key_type,
+            value_type: types.value_type,
+        })?;
+        repairs.adopted_tables.push(orphan.name.clone());
+    }
+    for table in &report.mismatched_tables {
+        let Some(types) = table.found else { continue };
+        state.system_store.update_shelf(meta.id, &ShelfMeta {
+            name: table.shelf.clone(),
+            key_type: types.key_type,
+            value_type: types.value_type,
+        })?;
+        repairs.retyped_shelves.push(table.shelf.clone());
+    }
+    state.invalidate_metadata(&meta.name);
+
+    Ok(CabinetCheck { check, repairs: Some(repairs) })
+}
+
+pub(crate) fn change_retention(state: &AppState, name: &str) -> Result<Retention, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let txn = cabinet.database().begin_read()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok(changelog::retention(&txn)?)
+}
+
+/// Stores a new retention policy and returns it as stored; a limit of zero
+/// means no limit.
+pub(crate) fn set_change_retention(
+    state: &AppState,
+    name: &str,
+    retention: Retention,
+) -> Result<Retention, ApiError> {
+    let meta = find_cabinet(state, name)?;
+    let cabinet = open_cabinet(state, &meta)?;
+
+    let retention = Retention {
+        max_entries: retention.max_entries.filter(|&n| n > 0),
+        max_age_secs: retention.max_age_secs.filter(|&n| n > 0),
+    };
+    let txn = cabinet.database().begin_write()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    changelog::set_retention(&txn, &retention)?;
+    txn.commit()
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    Ok(retention)
+}
+
+/// Parses scopes as written in requests, e.g. `cabinet:orders:read`.
+pub(crate) fn parse_scopes(scopes: &[String]) -> Result<Vec<Scope>, ApiError> {
+    scopes
+        .iter()
+        .map(|scope| scope.parse::<Scope>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+/// Creates an API key, returning its metadata and the key itself. The key
+/// is only available here; the store keeps just its hash.
+pub(crate) fn create_api_key(
+    state: &AppState,
+    name: String,
+    scopes: Vec<Scope>,
+) -> Result<(ApiKeyMeta, String), ApiError> {
+    if scopes.is_empty() {
+        return Err(ApiError::BadRequest("An API key needs at least one scope".to_string()));
+    }
+    let key = auth::generate_key().map_err(|e| ApiError::Internal(e.to_string()))?;
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let meta = ApiKeyMeta {
+        id: small_uid::SmallUid::new().into(),
+        name,
+        scopes,
+        created_at,
+        prefix: auth::display_prefix(&key),
+    };
+    state.system_store.add_api_key(&meta, &auth::hash_key(&key))?;
+    Ok((meta, key))
+}
+
+pub(crate) fn list_api_keys(state: &AppState) -> Result<Vec<ApiKeyMeta>, ApiError> {
+    Ok(state.system_store.list_api_keys()?)
+}
+
+pub(crate) fn revoke_api_key(state: &AppState, id: u64) -> Result<(), ApiError> {
+    if !state.system_store.remove_api_key(id)? {
+        return Err(ApiError::ApiKeyNotFound(id));
+    }
+    Ok(())
+}
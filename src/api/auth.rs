@@ -0,0 +1,206 @@
+//! Authentication for the HTTP API, enforced by middleware layered on each
+//! router so requests are rejected before their shelf is resolved. With
+//! `auth.mode = "api_key"` a request must present a key as
+//! `Authorization: Bearer <key>` or `X-API-Key: <key>`; with `"jwt"` it
+//! must carry a signed token as `Authorization: Bearer <token>`; with
+//! `"client_cert"` its TLS client certificate's common name must be mapped
+//! to scopes. In every mode, the scopes granted must cover what the route does.
+//!
+//! The gRPC, RESP and binary listeners take the same API keys and tokens
+//! through [`authenticate_token`] and check each operation against the
+//! permissions it returns. They don't terminate TLS, so `client_cert` mode
+//! can't be used with them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::api::error::ApiError;
+use crate::api::extractors::Path;
+use crate::config::AuthMode;
+use crate::AppState;
+use crate::tls::ClientCertificate;
+use carmine_core::auth::{self, Access, Scope};
+use carmine_core::jwt::JwtVerifier;
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// How requests are authenticated, built once from the config.
+pub struct Authenticator {
+    pub mode: AuthMode,
+    /// Hash of the configured master key, which acts as a `system:admin`
+    /// API key.
+    pub master_key_hash: Option<[u8; 32]>,
+    /// Verifies bearer tokens in `jwt` mode.
+    pub jwt: Option<JwtVerifier>,
+    /// Scopes for each client certificate common name in `client_cert`
+    /// mode.
+    pub client_scopes: HashMap<String, Vec<Scope>>,
+}
+
+/// What the caller may do. The middleware attaches it to the request so
+/// handlers that dispatch further operations, like the WebSocket, can check
+/// each one.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    /// `None` when authentication is off.
+    scopes: Option<Arc<[Scope]>>,
+}
+
+impl Permissions {
+    /// Checks `access` to `shelf` of `cabinet`, or to the whole cabinet
+    /// when `shelf` is `None`.
+    pub fn check(&self, cabinet: &str, shelf: Option<&str>, access: Access) -> Result<(), ApiError> {
+        let Some(scopes) = &self.scopes else {
+            return Ok(());
+        };
+        if auth::allows(scopes, cabinet, shelf, access) {
+            return Ok(());
+        }
+        let target = match shelf {
+            Some(shelf) => format!("shelf '{}' of cabinet '{}'", shelf, cabinet),
+            None => format!("cabinet '{}'", cabinet),
+        };
+        Err(ApiError::Forbidden(match access {
+            Access::Admin => "This endpoint needs the 'system:admin' scope".to_string(),
+            Access::Read => format!("No read access to {}", target),
+            Access::Write => format!("No write access to {}", target),
+        }))
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))
+        .map(str::trim)
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if headers.contains_key(header::AUTHORIZATION) {
+        return bearer_token(headers);
+    }
+    headers.get(API_KEY_HEADER)?.to_str().ok().map(str::trim)
+}
+
+/// Works out what the request's credentials allow, rejecting requests with
+/// missing or unknown credentials.
+pub(crate) fn authenticate(state: &AppState, request: &Request) -> Result<Permissions, ApiError> {
+    let headers = request.headers();
+    match state.auth.mode {
+        AuthMode::ApiKey => authenticate_token(state, presented_key(headers)),
+        AuthMode::None | AuthMode::Jwt => authenticate_token(state, bearer_token(headers)),
+        AuthMode::ClientCert => {
+            let name = request
+                .extensions()
+                .get::<ClientCertificate>()
+                .and_then(|client| client.common_name.as_deref())
+                .ok_or_else(|| ApiError::Unauthorized("Missing client certificate".to_string()))?;
+            let scopes = state.auth.client_scopes.get(name).ok_or_else(|| {
+                ApiError::Forbidden(format!("Client certificate '{}' has no scopes", name))
+            })?;
+            Ok(Permissions { scopes: Some(scopes.as_slice().into()) })
+        }
+    }
+}
+
+/// Works out what an API key or bearer token allows, for the listeners
+/// that take credentials outside HTTP headers. `token` is ignored while
+/// authentication is off.
+pub(crate) fn authenticate_token(state: &AppState, token: Option<&str>) -> Result<Permissions, ApiError> {
+    let auth = &state.auth;
+    match auth.mode {
+        AuthMode::None => Ok(Permissions { scopes: None }),
+        AuthMode::ApiKey => {
+            let key = token.ok_or_else(|| ApiError::Unauthorized("Missing API key".to_string()))?;
+            let hash = auth::hash_key(key);
+            if auth.master_key_hash.is_some_and(|master| auth::hashes_match(&master, &hash)) {
+                return Ok(Permissions { scopes: Some(Arc::new([Scope::SystemAdmin])) });
+            }
+            let meta = state
+                .system_store
+                .find_api_key(&hash)?
+                .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))?;
+            Ok(Permissions { scopes: Some(meta.scopes.into()) })
+        }
+        AuthMode::Jwt => {
+            let token =
+                token.ok_or_else(|| ApiError::Unauthorized("Missing bearer token".to_string()))?;
+            let verifier = auth
+                .jwt
+                .as_ref()
+                .ok_or_else(|| ApiError::Internal("JWT verifier not configured".to_string()))?;
+            let scopes = verifier
+                .verify(token)
+                .map_err(|e| ApiError::Unauthorized(format!("Invalid token: {}", e)))?;
+            Ok(Permissions { scopes: Some(scopes.into()) })
+        }
+        AuthMode::ClientCert => Err(ApiError::Unauthorized(
+            "Client certificates are only accepted on the HTTP listener".to_string(),
+        )),
+    }
+}
+
+async fn authorize(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    access: Access,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let permissions = authenticate(state, &request)?;
+    let cabinet = params.get("cabinet").map(String::as_str).unwrap_or_default();
+    permissions.check(cabinet, params.get("shelf").map(String::as_str), access)?;
+    request.extensions_mut().insert(permissions);
+    Ok(next.run(request).await)
+}
+
+/// For the `/system` endpoints.
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    authorize(&state, &HashMap::new(), Access::Admin, request, next).await
+}
+
+/// For routes that read the `:cabinet` in their path, or just its
+/// `:shelf` when there is one.
+pub async fn require_read(
+    State(state): State<Arc<AppState>>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    authorize(&state, &params, Access::Read, request, next).await
+}
+
+/// For routes that write to the `:cabinet` in their path, or just its
+/// `:shelf` when there is one.
+pub async fn require_write(
+    State(state): State<Arc<AppState>>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    authorize(&state, &params, Access::Write, request, next).await
+}
+
+/// For routes that check each operation themselves; only requires valid
+/// credentials.
+pub async fn require_credentials(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let permissions = authenticate(&state, &request)?;
+    request.extensions_mut().insert(permissions);
+    Ok(next.run(request).await)
+}
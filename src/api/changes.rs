@@ -0,0 +1,419 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+};
+use futures_util::stream::{self, Stream};
+use redb::ReadableDatabase;
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::api::error::ApiError;
+use crate::api::extractors::{cached_cabinet, resolve_shelf, Path, Query, ResolvedShelf};
+use crate::api::normal::{build_response, key_to_owned, value_to_owned};
+use crate::{AppState, CachedCabinet};
+use carmine_core::{
+    cabinet::Cabinet,
+    changelog::{self, Change, ChangeOp, ChangePage},
+    crypto::ValueCipher,
+    key::{Key, KeyType},
+    shelf::Shelf,
+    transaction::{Readable, TransactionError},
+    value::Value,
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+const DEFAULT_WATCH_TIMEOUT_SECS: u64 = 30;
+const MAX_WATCH_TIMEOUT_SECS: u64 = 300;
+
+#[derive(Deserialize)]
+pub struct ChangesParams {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+    shelf: Option<String>,
+}
+
+fn read_page(
+    cabinet: &Cabinet,
+    cipher: Option<&ValueCipher>,
+    since: u64,
+    limit: usize,
+    shelf: Option<&str>,
+) -> Result<ChangePage, ApiError> {
+    let tx = cabinet.database().begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok(changelog::read_since(&tx, since, limit, shelf, cipher)?)
+}
+
+fn to_jsonb<T: serde::Serialize>(value: &T) -> Result<jsonb::OwnedJsonb, ApiError> {
+    jsonb::to_owned_jsonb(value).map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+pub(crate) fn change_to_owned(change: &Change) -> Result<jsonb::OwnedJsonb, ApiError> {
+    let key = match &change.key {
+        Some(key) => key_to_owned(key)?,
+        None => to_jsonb(&())?,
+    };
+    let value = match &change.value {
+        Some(value) => value_to_owned(value)?,
+        None => to_jsonb(&())?,
+    };
+    let seq = to_jsonb(&change.seq)?;
+    let timestamp = to_jsonb(&change.timestamp_ms)?;
+    let op = to_jsonb(&change.op.as_str())?;
+    let shelf = to_jsonb(&change.shelf)?;
+    jsonb::OwnedJsonb::build_object([
+        ("seq", seq.as_raw()),
+        ("timestamp", timestamp.as_raw()),
+        ("op", op.as_raw()),
+        ("shelf", shelf.as_raw()),
+        ("key", key.as_raw()),
+        ("value", value.as_raw()),
+    ])
+    .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+pub async fn list_changes(
+    State(state): State<Arc<AppState>>,
+    Path(cabinet_name): Path<String>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Response, ApiError> {
+    let cached = cached_cabinet(&state, &cabinet_name)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let page = read_page(
+        &cached.cabinet,
+        cached.cipher.as_deref(),
+        params.since,
+        limit,
+        params.shelf.as_deref(),
+    )?;
+
+    let changes = page
+        .changes
+        .iter()
+        .map(change_to_owned)
+        .collect::<Result<Vec<_>, _>>()?;
+    let changes = jsonb::OwnedJsonb::build_array(changes.iter().map(|c| c.as_raw()))
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    build_response(&[
+        ("changes", changes),
+        ("next", to_jsonb(&page.next)?),
+        ("latest", to_jsonb(&page.latest)?),
+        ("oldest", to_jsonb(&page.oldest)?),
+        ("truncated", to_jsonb(&page.truncated)?),
+    ])
+}
+
+/// Follows a cabinet's change log, waiting for new changes once it has
+/// caught up. Drives both the SSE stream and WebSocket subscriptions.
+///
+/// Only takes the cabinet's handle while reading, so maintenance can run
+/// while the follower waits.
+pub(crate) struct Follower {
+    cabinet_id: u64,
+    cabinet_name: String,
+    cipher: Option<Arc<ValueCipher>>,
+    shelf: Option<String>,
+    cursor: u64,
+    pending: VecDeque<Change>,
+    wakeups: watch::Receiver<()>,
+}
+
+impl Follower {
+    pub(crate) fn new(state: &AppState, cached: &CachedCabinet, shelf: Option<String>, since: u64) -> Self {
+        Self {
+            wakeups: state.subscribe_changes(cached.cabinet.id),
+            cabinet_id: cached.cabinet.id,
+            cabinet_name: cached.cabinet.name.clone(),
+            cipher: cached.cipher.clone(),
+            shelf,
+            cursor: since,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Waits until there is a change to send, or returns `None` once the
+    /// cabinet is gone or can't be read.
+    pub(crate) async fn next_change(&mut self, state: &AppState) -> Option<Change> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Some(change);
+            }
+            // Mark the current notification as seen before reading, so a
+            // write that commits after the read still wakes us up.
+            self.wakeups.borrow_and_update();
+            let page = state.cabinet_by_id(self.cabinet_id).and_then(|cabinet| {
+                read_page(
+                    &cabinet,
+                    self.cipher.as_deref(),
+                    self.cursor,
+                    MAX_LIMIT,
+                    self.shelf.as_deref(),
+                )
+            });
+            match page {
+                Ok(page) => {
+                    self.cursor = page.next;
+                    self.pending.extend(page.changes);
+                }
+                // Maintenance wakes followers once it's done.
+                Err(ApiError::CabinetBusy(_)) => {}
+                Err(e) => {
+                    tracing::warn!("Change stream for cabinet {} stopped: {}", self.cabinet_name, e.message());
+                    return None;
+                }
+            }
+            if self.pending.is_empty() && self.wakeups.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+pub async fn stream_changes(
+    State(state): State<Arc<AppState>>,
+    Path(cabinet_name): Path<String>,
+    Query(params): Query<ChangesParams>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let cached = cached_cabinet(&state, &cabinet_name)?;
+
+    // A reconnecting EventSource sends the id of the last event it saw.
+    let since = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(params.since);
+
+    let follower = Follower::new(&state, &cached, params.shelf, since);
+
+    let events = stream::unfold((state, follower), |(state, mut follower)| async move {
+        let change = follower.next_change(&state).await?;
+        let event = match change_to_owned(&change) {
+            Ok(json) => Event::default()
+                .id(change.seq.to_string())
+                .event("change")
+                .data(json.as_raw().to_string()),
+            Err(e) => Event::default().event("error").data(e.message()),
+        };
+        Some((Ok(event), (state, follower)))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+pub struct WatchParams {
+    pub(crate) key: Option<String>,
+    pub(crate) start: Option<String>,
+    pub(crate) end: Option<String>,
+    pub(crate) since_version: Option<u64>,
+    pub(crate) timeout: Option<u64>,
+}
+
+/// What a watch is waiting on.
+pub(crate) enum WatchTarget {
+    Key(Key),
+    Range(Key, Key),
+}
+
+impl WatchTarget {
+    /// Keys arrive as query strings and are converted to the shelf's key
+    /// type.
+    fn from_params(params: &WatchParams, key_type: KeyType) -> Result<Self, ApiError> {
+        let key = |raw: &Option<String>| raw.as_ref().map(|raw| Key::String(raw.clone()));
+        Self::from_keys(key(&params.key), key(&params.start), key(&params.end), key_type)
+    }
+
+    /// Takes either a single key or both ends of a range, converting them to
+    /// `key_type`.
+    pub(crate) fn from_keys(
+        key: Option<Key>,
+        start: Option<Key>,
+        end: Option<Key>,
+        key_type: KeyType,
+    ) -> Result<Self, ApiError> {
+        let convert = |name: &str, key: Key| {
+            key.convert(key_type)
+                .map_err(|e| ApiError::BadRequest(format!("{}: {}", name, e)))
+        };
+        match (key, start, end) {
+            (Some(key), None, None) => Ok(WatchTarget::Key(convert("key", key)?)),
+            (None, Some(start), Some(end)) => {
+                if key_type == KeyType::Number {
+                    return Err(TransactionError::RangeNotSupported.into());
+                }
+                Ok(WatchTarget::Range(convert("start", start)?, convert("end", end)?))
+            }
+            _ => Err(ApiError::BadRequest(
+                "pass either 'key' or both 'start' and 'end'".to_string(),
+            )),
+        }
+    }
+
+    fn matches(&self, change: &Change) -> bool {
+        let Some(key) = &change.key else {
+            // A clear or a migration touches every key.
+            return matches!(change.op, ChangeOp::Clear | ChangeOp::Migrate);
+        };
+        match self {
+            // Number keys are matched the way the table orders them, so `2`
+            // and `2.0` are the same key.
+            WatchTarget::Key(target) => match (key, target) {
+                (Key::Number(k), Key::Number(t)) => k.cmp(t) == Ordering::Equal,
+                _ => key == target,
+            },
+            WatchTarget::Range(start, end) => match (key, start, end) {
+                (Key::String(k), Key::String(s), Key::String(e)) => s <= k && k < e,
+                (Key::Int(k), Key::Int(s), Key::Int(e)) => **s <= **k && **k < **e,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Reads the log after `cursor` until it catches up, keeping the changes
+/// that `target` cares about. Returns them with the new cursor and whether
+/// part of the history was already pruned.
+fn scan_for(
+    cabinet: &Cabinet,
+    shelf: &Shelf,
+    target: &WatchTarget,
+    mut cursor: u64,
+) -> Result<(Vec<Change>, u64, bool), ApiError> {
+    let mut matched = Vec::new();
+    let mut truncated = false;
+    loop {
+        let page = read_page(
+            cabinet,
+            shelf.cipher.as_deref(),
+            cursor,
+            MAX_LIMIT,
+            Some(&shelf.name),
+        )?;
+        truncated |= page.truncated;
+        matched.extend(page.changes.into_iter().filter(|c| target.matches(c)));
+        if page.next == cursor || page.next >= page.latest {
+            return Ok((matched, page.next.max(cursor), truncated));
+        }
+        cursor = page.next;
+    }
+}
+
+/// What a watch saw: the log position it reached, the matching changes
+/// (empty if it timed out), and whether part of the history was pruned.
+pub(crate) struct WatchOutcome {
+    pub version: u64,
+    pub changes: Vec<Change>,
+    pub truncated: bool,
+}
+
+/// Waits until a change after `since_version` (default: the latest) touches
+/// `target`, or the timeout in seconds runs out.
+///
+/// The cabinet's handle is let go while waiting, so maintenance can run in
+/// the meantime; the shelf comes back with a fresh one.
+pub(crate) async fn wait_for_changes(
+    state: &AppState,
+    resolved: ResolvedShelf,
+    target: &WatchTarget,
+    since_version: Option<u64>,
+    timeout: Option<u64>,
+) -> Result<(ResolvedShelf, WatchOutcome), ApiError> {
+    let timeout = timeout
+        .unwrap_or(DEFAULT_WATCH_TIMEOUT_SECS)
+        .min(MAX_WATCH_TIMEOUT_SECS);
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout);
+    let ResolvedShelf { cabinet, shelf } = resolved;
+    let (cabinet_id, cabinet_name) = (cabinet.id, cabinet.name.clone());
+
+    // Subscribe before the first read so a write that lands in between
+    // still wakes us.
+    let mut wakeups = state.subscribe_changes(cabinet_id);
+    wakeups.borrow_and_update();
+
+    let mut cursor = match since_version {
+        Some(version) => version,
+        None => read_page(&cabinet, None, 0, 0, None)?.latest,
+    };
+    drop(cabinet);
+    loop {
+        match state.cabinet_by_id(cabinet_id) {
+            Ok(cabinet) => {
+                let (changes, next, truncated) = scan_for(&cabinet, &shelf, target, cursor)?;
+                cursor = next;
+                if !changes.is_empty() || truncated {
+                    let outcome = WatchOutcome { version: cursor, changes, truncated };
+                    return Ok((ResolvedShelf { cabinet, shelf }, outcome));
+                }
+            }
+            // Maintenance wakes watchers once it's done.
+            Err(ApiError::CabinetBusy(_)) => {}
+            Err(e) => return Err(e),
+        }
+        match tokio::time::timeout_at(deadline, wakeups.changed()).await {
+            Ok(Ok(())) => {
+                wakeups.borrow_and_update();
+            }
+            Ok(Err(_)) => return Err(ApiError::CabinetNotFound(cabinet_name)),
+            Err(_) => {
+                let cabinet = state.cabinet_by_id(cabinet_id)?;
+                let outcome = WatchOutcome { version: cursor, changes: Vec::new(), truncated: false };
+                return Ok((ResolvedShelf { cabinet, shelf }, outcome));
+            }
+        }
+    }
+}
+
+/// The current value of a single watched key. Read fresh rather than taken
+/// from the last change, so a truncated history still reports where the key
+/// stands now.
+pub(crate) fn current_value(resolved: &ResolvedShelf, key: &Key) -> Result<Option<Value>, ApiError> {
+    Ok(resolved.shelf.get(&resolved.begin_read()?, key)?)
+}
+
+fn watch_response(
+    resolved: &ResolvedShelf,
+    target: &WatchTarget,
+    outcome: &WatchOutcome,
+) -> Result<Response, ApiError> {
+    let owned = outcome.changes.iter().map(change_to_owned).collect::<Result<Vec<_>, _>>()?;
+    let array = jsonb::OwnedJsonb::build_array(owned.iter().map(|c| c.as_raw()))
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let mut fields = vec![
+        ("version", to_jsonb(&outcome.version)?),
+        ("changes", array),
+        ("truncated", to_jsonb(&outcome.truncated)?),
+    ];
+    if let WatchTarget::Key(key) = target {
+        let value = match current_value(resolved, key)? {
+            Some(value) => value_to_owned(&value)?,
+            None => to_jsonb(&())?,
+        };
+        fields.push(("value", value));
+    }
+    build_response(&fields)
+}
+
+pub async fn watch(
+    state: State<Arc<AppState>>,
+    path: Path<(String, String)>,
+    Query(params): Query<WatchParams>,
+) -> Result<Response, ApiError> {
+    let resolved = resolve_shelf(state.clone(), path).await?;
+    state.metrics.shelf_op("http", &resolved, "watch");
+    let target = WatchTarget::from_params(&params, resolved.shelf.key_type)?;
+    let (resolved, outcome) =
+        wait_for_changes(&state, resolved, &target, params.since_version, params.timeout).await?;
+    watch_response(&resolved, &target, &outcome)
+}
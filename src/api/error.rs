@@ -1,16 +1,29 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
+use carmine_core::cabinet::CabinetError;
 use carmine_core::key::KeyType;
-use carmine_core::value::ValueType;
+use carmine_core::system_store::SystemStoreError;
+use carmine_core::transaction::TransactionError;
+use carmine_core::value::{BatchItemError, ValueType};
+use serde::Serialize;
 
 #[derive(Debug)]
 pub enum ApiError {
     CabinetNotFound(String),
+    /// A lookup by id found no cabinet, e.g. because it was deleted while
+    /// the request ran.
+    CabinetIdNotFound(u64),
     ShelfNotFound(String),
     CabinetAlreadyExists(String),
     ShelfAlreadyExists(String),
+    ShelfNotEmpty {
+        name: String,
+        entries: u64,
+    },
+    KeyAlreadyExists,
     KeyTypeMismatch {
         expected: KeyType,
         actual: KeyType,
@@ -19,48 +32,237 @@ pub enum ApiError {
         expected: ValueType,
         actual: ValueType,
     },
+    ApiKeyNotFound(u64),
+    /// The cabinet is being compacted or checked and can't be used until
+    /// it's done.
+    CabinetBusy(String),
+    /// No valid credentials were presented.
+    Unauthorized(String),
+    /// The credentials don't grant access to what was requested.
+    Forbidden(String),
     JsonParse(String),
+    /// A WebSocket connection already holds as many subscriptions as it may.
+    TooManySubscriptions(usize),
+    /// The request's JSON body, query string or path parameters don't have
+    /// the shape the endpoint expects.
+    InvalidRequest(String),
+    BadRequest(String),
     Internal(String),
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ApiError::CabinetNotFound(name) => (
-                StatusCode::NOT_FOUND,
-                format!("Cabinet '{}' not found", name),
-            ),
-            ApiError::ShelfNotFound(name) => {
-                (StatusCode::NOT_FOUND, format!("Shelf '{}' not found", name))
-            }
-            ApiError::CabinetAlreadyExists(name) => (
-                StatusCode::CONFLICT,
-                format!("Cabinet '{}' already exists", name),
-            ),
-            ApiError::ShelfAlreadyExists(name) => (
-                StatusCode::CONFLICT,
-                format!("Shelf '{}' already exists", name),
+/// Body of every error response. `error` is the human-readable message the
+/// API has always returned; `code` is stable and meant for clients to match on.
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<ErrorDetails>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ErrorDetails {
+    Cabinet { cabinet: String },
+    Shelf { shelf: String },
+    ShelfNotEmpty { shelf: String, entries: u64 },
+    KeyType { expected: KeyType, actual: KeyType },
+    ValueType { expected: ValueType, actual: ValueType },
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::CabinetNotFound(_)
+            | ApiError::CabinetIdNotFound(_)
+            | ApiError::ShelfNotFound(_)
+            | ApiError::ApiKeyNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::CabinetAlreadyExists(_)
+            | ApiError::ShelfAlreadyExists(_)
+            | ApiError::ShelfNotEmpty { .. }
+            | ApiError::KeyAlreadyExists => StatusCode::CONFLICT,
+            ApiError::KeyTypeMismatch { .. }
+            | ApiError::ValueTypeMismatch { .. }
+            | ApiError::JsonParse(_)
+            | ApiError::InvalidRequest(_)
+            | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::TooManySubscriptions(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::CabinetBusy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::CabinetNotFound(_) | ApiError::CabinetIdNotFound(_) => "cabinet_not_found",
+            ApiError::ShelfNotFound(_) => "shelf_not_found",
+            ApiError::CabinetAlreadyExists(_) => "cabinet_already_exists",
+            ApiError::ShelfAlreadyExists(_) => "shelf_already_exists",
+            ApiError::ShelfNotEmpty { .. } => "shelf_not_empty",
+            ApiError::KeyAlreadyExists => "key_already_exists",
+            ApiError::KeyTypeMismatch { .. } => "key_type_mismatch",
+            ApiError::ValueTypeMismatch { .. } => "value_type_mismatch",
+            ApiError::ApiKeyNotFound(_) => "api_key_not_found",
+            ApiError::CabinetBusy(_) => "cabinet_busy",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::JsonParse(_) => "invalid_json",
+            ApiError::TooManySubscriptions(_) => "too_many_subscriptions",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::CabinetNotFound(name) => format!("Cabinet '{}' not found", name),
+            ApiError::CabinetIdNotFound(id) => format!("No cabinet with id {}", id),
+            ApiError::ShelfNotFound(name) => format!("Shelf '{}' not found", name),
+            ApiError::CabinetAlreadyExists(name) => format!("Cabinet '{}' already exists", name),
+            ApiError::ShelfAlreadyExists(name) => format!("Shelf '{}' already exists", name),
+            ApiError::ShelfNotEmpty { name, entries } => format!(
+                "Shelf '{}' is not empty ({} entries); use force=true to delete it",
+                name, entries
             ),
-            ApiError::KeyTypeMismatch { expected, actual } => (
-                StatusCode::BAD_REQUEST,
-                format!(
-                    "Key type mismatch: expected {:?}, got {:?}",
-                    expected, actual
-                ),
+            ApiError::KeyAlreadyExists => "Key already exists".to_string(),
+            ApiError::KeyTypeMismatch { expected, actual } => format!(
+                "Key type mismatch: expected {:?}, got {:?}",
+                expected, actual
             ),
-            ApiError::ValueTypeMismatch { expected, actual } => (
-                StatusCode::BAD_REQUEST,
-                format!(
-                    "Value type mismatch: expected {:?}, got {:?}",
-                    expected, actual
-                ),
+            ApiError::ValueTypeMismatch { expected, actual } => format!(
+                "Value type mismatch: expected {:?}, got {:?}",
+                expected, actual
             ),
-            ApiError::JsonParse(e) => (StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e)),
-            ApiError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
-        };
+            ApiError::ApiKeyNotFound(id) => format!("API key {} not found", id),
+            ApiError::CabinetBusy(name) => {
+                format!("Cabinet '{}' is under maintenance; try again shortly", name)
+            }
+            ApiError::JsonParse(e) => format!("Invalid JSON: {}", e),
+            ApiError::TooManySubscriptions(limit) => {
+                format!("A connection may hold at most {} subscriptions", limit)
+            }
+            ApiError::Unauthorized(e)
+            | ApiError::Forbidden(e)
+            | ApiError::InvalidRequest(e)
+            | ApiError::BadRequest(e)
+            | ApiError::Internal(e) => e.clone(),
+        }
+    }
+
+    fn details(&self) -> Option<ErrorDetails> {
+        match self {
+            ApiError::CabinetNotFound(name)
+            | ApiError::CabinetAlreadyExists(name)
+            | ApiError::CabinetBusy(name) => {
+                Some(ErrorDetails::Cabinet { cabinet: name.clone() })
+            }
+            ApiError::ShelfNotFound(name) | ApiError::ShelfAlreadyExists(name) => {
+                Some(ErrorDetails::Shelf { shelf: name.clone() })
+            }
+            ApiError::ShelfNotEmpty { name, entries } => Some(ErrorDetails::ShelfNotEmpty {
+                shelf: name.clone(),
+                entries: *entries,
+            }),
+            ApiError::KeyTypeMismatch { expected, actual } => Some(ErrorDetails::KeyType {
+                expected: *expected,
+                actual: *actual,
+            }),
+            ApiError::ValueTypeMismatch { expected, actual } => Some(ErrorDetails::ValueType {
+                expected: *expected,
+                actual: *actual,
+            }),
+            _ => None,
+        }
+    }
+
+    fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.message(),
+            code: self.code(),
+            details: self.details(),
+        }
+    }
 
-        let body = format!(r#"{{"error":"{}"}}"#, message.replace('"', r#"\""#));
+    /// The error body as jsonb, for embedding per-item errors in a response
+    /// that otherwise succeeded.
+    pub fn to_jsonb(&self) -> Result<jsonb::OwnedJsonb, ApiError> {
+        jsonb::to_owned_jsonb(&self.body()).map_err(|e| ApiError::Internal(e.to_string()))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        match self {
+            ApiError::Unauthorized(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            ApiError::CabinetBusy(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+impl From<TransactionError> for ApiError {
+    fn from(e: TransactionError) -> Self {
+        match e {
+            TransactionError::KeyAlreadyExists => ApiError::KeyAlreadyExists,
+            TransactionError::KeyTypeMismatch { expected, actual } => {
+                ApiError::KeyTypeMismatch { expected, actual }
+            }
+            TransactionError::ValueTypeMismatch { expected, actual } => {
+                ApiError::ValueTypeMismatch { expected, actual }
+            }
+            TransactionError::RangeNotSupported | TransactionError::RepairRefused(_) => {
+                ApiError::BadRequest(e.to_string())
+            }
+            TransactionError::TableAlreadyExists(name) => ApiError::ShelfAlreadyExists(name),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<SystemStoreError> for ApiError {
+    fn from(e: SystemStoreError) -> Self {
+        match e {
+            SystemStoreError::CabinetNotFound(id) => ApiError::CabinetIdNotFound(id),
+            SystemStoreError::CabinetAlreadyExists(name) => ApiError::CabinetAlreadyExists(name),
+            SystemStoreError::ShelfNotFound(name) => ApiError::ShelfNotFound(name),
+            SystemStoreError::ShelfAlreadyExists(name) => ApiError::ShelfAlreadyExists(name),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<BatchItemError> for ApiError {
+    fn from(e: BatchItemError) -> Self {
+        match e {
+            BatchItemError::KeyTypeMismatch { expected, actual } => {
+                ApiError::KeyTypeMismatch { expected, actual }
+            }
+            BatchItemError::KeyConversion(_) => ApiError::BadRequest(e.to_string()),
+            BatchItemError::Storage(_) | BatchItemError::IndexOutOfRange(_) => {
+                ApiError::Internal(e.to_string())
+            }
+        }
+    }
+}
 
-        (status, [(axum::http::header::CONTENT_TYPE, "application/json")], body).into_response()
+impl From<CabinetError> for ApiError {
+    fn from(e: CabinetError) -> Self {
+        match e {
+            CabinetError::InUse(name) => ApiError::CabinetBusy(name),
+            e => ApiError::Internal(e.to_string()),
+        }
     }
 }
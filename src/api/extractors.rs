@@ -1,9 +1,87 @@
-use axum::extract::{Path, State};
+use axum::async_trait;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use crate::api::error::ApiError;
-use crate::AppState;
+use crate::encryption::cabinet_cipher;
+use crate::{AppState, CachedCabinet};
 use carmine_core::shelf::Shelf;
+use carmine_core::transaction::TransactionError;
+use redb::ReadableDatabase;
+
+/// `axum::Json`, but a body that can't be parsed is rejected with an
+/// [`ApiError`] like every other error. Responds the same way as
+/// `axum::Json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(request, state)
+            .await
+            .map_err(|e: JsonRejection| ApiError::InvalidRequest(e.body_text()))?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// `axum::extract::Query`, rejecting with an [`ApiError`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) = axum::extract::Query::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|e: QueryRejection| ApiError::InvalidRequest(e.body_text()))?;
+        Ok(Query(value))
+    }
+}
+
+/// `axum::extract::Path`, rejecting with an [`ApiError`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) = axum::extract::Path::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|e: PathRejection| ApiError::InvalidRequest(e.body_text()))?;
+        Ok(Path(value))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ResolvedShelf {
@@ -11,43 +89,72 @@ pub struct ResolvedShelf {
     pub shelf: Shelf,
 }
 
+impl ResolvedShelf {
+    pub(crate) fn begin_read(&self) -> Result<redb::ReadTransaction, ApiError> {
+        self.cabinet
+            .database()
+            .begin_read()
+            .map_err(|e| ApiError::from(TransactionError::from(e)))
+    }
+
+    pub(crate) fn begin_write(&self) -> Result<redb::WriteTransaction, ApiError> {
+        self.cabinet
+            .database()
+            .begin_write()
+            .map_err(|e| ApiError::from(TransactionError::from(e)))
+    }
+}
+
 pub async fn resolve_shelf(
     State(state): State<Arc<AppState>>,
     Path((cabinet_name, shelf_name)): Path<(String, String)>,
 ) -> Result<ResolvedShelf, ApiError> {
+    lookup_shelf(&state, &cabinet_name, shelf_name)
+}
+
+/// Resolves a shelf outside of an HTTP request, for the other transports.
+pub(crate) fn lookup_shelf(
+    state: &AppState,
+    cabinet_name: &str,
+    shelf_name: String,
+) -> Result<ResolvedShelf, ApiError> {
+    let cached = cached_cabinet(state, cabinet_name)?;
+
+    let shelf = cached
+        .shelves
+        .get(&shelf_name)
+        .cloned()
+        .ok_or(ApiError::ShelfNotFound(shelf_name))?;
+
+    Ok(ResolvedShelf { cabinet: cached.cabinet.clone(), shelf })
+}
+
+pub(crate) fn cached_cabinet(state: &AppState, cabinet_name: &str) -> Result<Arc<CachedCabinet>, ApiError> {
+    if let Some(cached) = state.metadata.get(cabinet_name) {
+        state.cabinets.touch(cached.cabinet.id);
+        // Handles that were busy when the limit was last enforced stay
+        // cached, so their next use retries it.
+        state.check_open_limit();
+        return Ok(cached.clone());
+    }
+
+    let generation = state.metadata_generation.load(Ordering::SeqCst);
     let cabinet_meta = state
         .system_store
-        .find_cabinet_by_name(&cabinet_name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(cabinet_name.clone()))?;
+        .find_cabinet_by_name(cabinet_name)?
+        .ok_or_else(|| ApiError::CabinetNotFound(cabinet_name.to_string()))?;
 
-    let shelf_meta = cabinet_meta
+    let cipher = cabinet_cipher(state, &cabinet_meta)?;
+    let shelves = cabinet_meta
         .shelves
         .iter()
-        .find(|s| s.name == shelf_name)
-        .ok_or_else(|| ApiError::ShelfNotFound(shelf_name.clone()))?;
-
-    let key_type = match shelf_meta.key_type.as_str() {
-        "String" => carmine_core::key::KeyType::String,
-        "Number" => carmine_core::key::KeyType::Number,
-        "Int" => carmine_core::key::KeyType::Int,
-        _ => return Err(ApiError::Internal(format!("Unknown key type: {}", shelf_meta.key_type))),
-    };
-
-    let value_type = match shelf_meta.value_type.as_str() {
-        "String" => carmine_core::value::ValueType::String,
-        "Number" => carmine_core::value::ValueType::Number,
-        "Int" => carmine_core::value::ValueType::Int,
-        "Object" => carmine_core::value::ValueType::Object,
-        "Byte" => carmine_core::value::ValueType::Byte,
-        _ => return Err(ApiError::Internal(format!("Unknown value type: {}", shelf_meta.value_type))),
-    };
-
-    let shelf = Shelf::new(shelf_name.clone(), key_type, value_type);
+        .map(|s| (s.name.clone(), Shelf::from(s).with_cipher(cipher.clone())))
+        .collect();
 
     let cabinet = state
-        .get_or_open_cabinet(cabinet_meta.id, cabinet_meta.name, cabinet_meta.path)
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
+        .get_or_open_cabinet(cabinet_meta.id, cabinet_meta.name, cabinet_meta.path)?;
 
-    Ok(ResolvedShelf { cabinet, shelf })
+    let cached = Arc::new(CachedCabinet { cabinet, shelves, cipher });
+    state.cache_metadata(cabinet_name, generation, cached.clone());
+    Ok(cached)
 }
@@ -1,42 +1,87 @@
 use std::sync::Arc;
 
 use axum::{
+    middleware::from_fn_with_state,
     routing::{delete, get, post},
     Router,
 };
 
 use crate::AppState;
 
-mod error;
-mod extractors;
-mod normal;
+pub(crate) mod admin;
+pub(crate) mod auth;
+pub(crate) mod changes;
+pub(crate) mod error;
+pub(crate) mod extractors;
+pub(crate) mod normal;
 mod system;
+mod ws;
 
-pub use error::ApiError;
-
-pub fn system_router() -> Router<Arc<AppState>> {
+pub fn system_router(state: Arc<AppState>) -> Router<Arc<AppState>> {
     Router::new()
         .route("/cabinets", post(system::create_cabinet).get(system::list_cabinets))
         .route("/cabinets/:name", get(system::get_cabinet).delete(system::delete_cabinet))
         .route("/cabinets/:name/clean", post(system::clean_cabinet))
+        .route("/cabinets/:name/rename", post(system::rename_cabinet))
         .route("/cabinets/:name/shelves", post(system::create_shelf).get(system::list_shelves))
         .route("/cabinets/:name/shelves/:shelf", delete(system::delete_shelf))
+        .route("/cabinets/:name/shelves/:shelf/rename", post(system::rename_shelf))
+        .route("/cabinets/:name/shelves/:shelf/copy", post(system::copy_shelf))
+        .route("/cabinets/:name/shelves/:shelf/migrate", post(system::migrate_shelf))
+        .route(
+            "/cabinets/:name/changes/retention",
+            get(system::get_change_retention).put(system::set_change_retention),
+        )
+        .route("/cabinets/:name/stats", get(system::get_cabinet_stats))
+        .route("/cabinets/:name/compact", post(system::compact_cabinet))
+        .route("/cabinets/:name/check", post(system::check_cabinet))
+        .route("/cabinets/:name/encryption", get(system::get_encryption))
+        .route("/cabinets/:name/encryption/rotate", post(system::rotate_cabinet_key))
+        .route("/keys", post(system::create_api_key).get(system::list_api_keys))
+        .route("/keys/:id", delete(system::revoke_api_key))
+        .route_layer(from_fn_with_state(state, auth::require_admin))
 }
 
-pub fn normal_router() -> Router<Arc<AppState>> {
+pub fn metrics_router(state: Arc<AppState>) -> Router<Arc<AppState>> {
     Router::new()
+        .route("/metrics", get(crate::metrics::export))
+        .route_layer(from_fn_with_state(state, auth::require_admin))
+}
+
+pub fn changes_router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(changes::list_changes))
+        .route("/stream", get(changes::stream_changes))
+        .route_layer(from_fn_with_state(state, auth::require_read))
+}
+
+pub fn normal_router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    let reads = Router::new()
         .route("/get", post(normal::get))
-        .route("/set", post(normal::set))
-        .route("/put", post(normal::put))
-        .route("/delete", post(normal::delete))
         .route("/all", get(normal::all))
         .route("/keys", get(normal::keys))
         .route("/values", get(normal::values))
         .route("/range", post(normal::range))
         .route("/exists", post(normal::exists))
         .route("/count", get(normal::count))
+        .route("/batch/get", post(normal::batch_get))
+        .route("/watch", get(changes::watch))
+        .route_layer(from_fn_with_state(state.clone(), auth::require_read));
+    let writes = Router::new()
+        .route("/set", post(normal::set))
+        .route("/put", post(normal::put))
+        .route("/delete", post(normal::delete))
         .route("/batch/set", post(normal::batch_set))
         .route("/batch/put", post(normal::batch_put))
         .route("/batch/delete", post(normal::batch_delete))
-        .route("/batch/get", post(normal::batch_get))
+        .route_layer(from_fn_with_state(state, auth::require_write));
+    reads.merge(writes)
+}
+
+/// Only checks credentials; each operation on the socket is checked
+/// against the key's scopes as it arrives.
+pub fn ws_router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(ws::upgrade))
+        .route_layer(from_fn_with_state(state, auth::require_credentials))
 }
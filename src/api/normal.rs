@@ -5,28 +5,25 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use std::sync::Arc;
-
-use redb::ReadableDatabase;
-
-use axum::extract::Path;
+use std::time::Instant;
 
 use crate::api::error::ApiError;
-use crate::api::extractors::resolve_shelf;
+use crate::api::extractors::{resolve_shelf, Path, ResolvedShelf};
 use crate::AppState;
 use carmine_core::{
     key::Key,
-    transaction::{Readable, Writable},
+    transaction::{Readable, TransactionError, Writable},
     types::{Int, Number, RawObject},
     value::Value,
 };
 
 // --- Parsing helpers: RawJsonb → Key/Value ---
 
-fn parse_body(body: &Bytes) -> Result<jsonb::OwnedJsonb, ApiError> {
+pub(crate) fn parse_body(body: &Bytes) -> Result<jsonb::OwnedJsonb, ApiError> {
     jsonb::parse_owned_jsonb(body.as_ref()).map_err(|e| ApiError::JsonParse(e.to_string()))
 }
 
-fn get_field(raw: &jsonb::RawJsonb, name: &str) -> Result<jsonb::OwnedJsonb, ApiError> {
+pub(crate) fn get_field(raw: &jsonb::RawJsonb, name: &str) -> Result<jsonb::OwnedJsonb, ApiError> {
     raw.get_by_name(name, false)
         .map_err(|e| ApiError::JsonParse(e.to_string()))?
         .ok_or_else(|| ApiError::JsonParse(format!("missing field '{}'", name)))
@@ -44,12 +41,12 @@ fn owned_to_key(owned: &jsonb::OwnedJsonb) -> Result<Key, ApiError> {
     }
     // Try number
     if let Ok(n) = jsonb::from_raw_jsonb::<jsonb::Number>(&raw) {
-        return Ok(Key::Number(Number::from(n)));
+        return Number::new(n).map(Key::Number).map_err(|e| ApiError::JsonParse(e.to_string()));
     }
     Err(ApiError::JsonParse("key must be a string or number".into()))
 }
 
-fn owned_to_value(owned: &jsonb::OwnedJsonb) -> Result<Value, ApiError> {
+pub(crate) fn owned_to_value(owned: &jsonb::OwnedJsonb) -> Result<Value, ApiError> {
     let raw = owned.as_raw();
     // Try string
     if let Ok(s) = jsonb::from_raw_jsonb::<String>(&raw) {
@@ -61,7 +58,7 @@ fn owned_to_value(owned: &jsonb::OwnedJsonb) -> Result<Value, ApiError> {
     }
     // Try number
     if let Ok(n) = jsonb::from_raw_jsonb::<jsonb::Number>(&raw) {
-        return Ok(Value::Number(Number::from(n)));
+        return Number::new(n).map(Value::Number).map_err(|e| ApiError::JsonParse(e.to_string()));
     }
     // Object: the raw jsonb bytes ARE the RawObject
     // Check if it's a valid object by trying to get keys
@@ -73,7 +70,7 @@ fn owned_to_value(owned: &jsonb::OwnedJsonb) -> Result<Value, ApiError> {
 
 // --- Serialization helpers: Key/Value → OwnedJsonb ---
 
-fn key_to_owned(key: &Key) -> Result<jsonb::OwnedJsonb, ApiError> {
+pub(crate) fn key_to_owned(key: &Key) -> Result<jsonb::OwnedJsonb, ApiError> {
     match key {
         Key::String(s) => jsonb::to_owned_jsonb(s),
         Key::Int(i) => jsonb::to_owned_jsonb(&**i),
@@ -82,7 +79,7 @@ fn key_to_owned(key: &Key) -> Result<jsonb::OwnedJsonb, ApiError> {
     .map_err(|e| ApiError::Internal(e.to_string()))
 }
 
-fn value_to_owned(value: &Value) -> Result<jsonb::OwnedJsonb, ApiError> {
+pub(crate) fn value_to_owned(value: &Value) -> Result<jsonb::OwnedJsonb, ApiError> {
     match value {
         Value::String(s) => jsonb::to_owned_jsonb(s)
             .map_err(|e| ApiError::Internal(e.to_string())),
@@ -102,7 +99,7 @@ fn value_to_owned(value: &Value) -> Result<jsonb::OwnedJsonb, ApiError> {
     }
 }
 
-fn build_response(fields: &[(&str, jsonb::OwnedJsonb)]) -> Result<Response, ApiError> {
+pub(crate) fn build_response(fields: &[(&str, jsonb::OwnedJsonb)]) -> Result<Response, ApiError> {
     let items: Vec<_> = fields.iter().map(|(k, v)| (*k, v.as_raw())).collect();
     let obj = jsonb::OwnedJsonb::build_object(items)
         .map_err(|e| ApiError::Internal(e.to_string()))?;
@@ -114,6 +111,17 @@ fn build_response(fields: &[(&str, jsonb::OwnedJsonb)]) -> Result<Response, ApiE
     ).into_response())
 }
 
+/// Resolves the shelf a data operation targets and counts the operation.
+async fn resolve_op(
+    state: &State<Arc<AppState>>,
+    path: Path<(String, String)>,
+    op: &str,
+) -> Result<ResolvedShelf, ApiError> {
+    let resolved = resolve_shelf(state.clone(), path).await?;
+    state.metrics.shelf_op("http", &resolved, op);
+    Ok(resolved)
+}
+
 // --- Handlers ---
 
 pub async fn set(
@@ -121,7 +129,7 @@ pub async fn set(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "set").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let key = owned_to_key(&get_field(&raw, "key")?)?;
@@ -140,10 +148,9 @@ pub async fn set(
         });
     }
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.set(&tx, key, value).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_write()?;
+    resolved.shelf.set(&tx, key, value)?;
+    commit_write(&state, &resolved, tx)?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -153,7 +160,7 @@ pub async fn put(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "put").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let key = owned_to_key(&get_field(&raw, "key")?)?;
@@ -172,10 +179,9 @@ pub async fn put(
         });
     }
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.put(&tx, key, value).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_write()?;
+    resolved.shelf.put(&tx, key, value)?;
+    commit_write(&state, &resolved, tx)?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -185,14 +191,13 @@ pub async fn get(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "get").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let key = owned_to_key(&get_field(&raw, "key")?)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let value = resolved.shelf.get(&tx, &key).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_read()?;
+    let value = resolved.shelf.get(&tx, &key)?;
 
     let val_jsonb = match value {
         Some(val) => value_to_owned(&val)?,
@@ -206,15 +211,14 @@ pub async fn delete(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "delete").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let key = owned_to_key(&get_field(&raw, "key")?)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.delete(&tx, &key).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_write()?;
+    resolved.shelf.delete(&tx, &key)?;
+    commit_write(&state, &resolved, tx)?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -223,10 +227,9 @@ pub async fn all(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let entries = resolved.shelf.get_all(&tx).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let resolved = resolve_op(&state, path, "all").await?;
+    let tx = resolved.begin_read()?;
+    let entries = resolved.shelf.get_all(&tx)?;
 
     let entry_jsonbs: Result<Vec<_>, _> = entries.iter().map(|(k, v)| {
         let ko = key_to_owned(k)?;
@@ -243,12 +246,11 @@ pub async fn keys(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let keys = resolved.shelf.keys(&tx).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let resolved = resolve_op(&state, path, "keys").await?;
+    let tx = resolved.begin_read()?;
+    let keys = resolved.shelf.keys(&tx)?;
 
-    let key_jsonbs: Result<Vec<_>, _> = keys.iter().map(|k| key_to_owned(k)).collect();
+    let key_jsonbs: Result<Vec<_>, _> = keys.iter().map(key_to_owned).collect();
     let arr = jsonb::OwnedJsonb::build_array(key_jsonbs?.iter().map(|o| o.as_raw()))
         .map_err(|e| ApiError::Internal(e.to_string()))?;
     build_response(&[("keys", arr)])
@@ -258,12 +260,11 @@ pub async fn values(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let vals = resolved.shelf.values(&tx).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let resolved = resolve_op(&state, path, "values").await?;
+    let tx = resolved.begin_read()?;
+    let vals = resolved.shelf.values(&tx)?;
 
-    let val_jsonbs: Result<Vec<_>, _> = vals.iter().map(|v| value_to_owned(v)).collect();
+    let val_jsonbs: Result<Vec<_>, _> = vals.iter().map(value_to_owned).collect();
     let arr = jsonb::OwnedJsonb::build_array(val_jsonbs?.iter().map(|o| o.as_raw()))
         .map_err(|e| ApiError::Internal(e.to_string()))?;
     build_response(&[("values", arr)])
@@ -274,15 +275,14 @@ pub async fn range(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "range").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let start = owned_to_key(&get_field(&raw, "start")?)?;
     let end = owned_to_key(&get_field(&raw, "end")?)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let entries = resolved.shelf.get_range(&tx, &start, &end).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_read()?;
+    let entries = resolved.shelf.get_range(&tx, &start, &end)?;
 
     let entry_jsonbs: Result<Vec<_>, _> = entries.iter().map(|(k, v)| {
         let ko = key_to_owned(k)?;
@@ -300,14 +300,13 @@ pub async fn exists(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "exists").await?;
     let parsed = parse_body(&body)?;
     let raw = parsed.as_raw();
     let key = owned_to_key(&get_field(&raw, "key")?)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let exists = resolved.shelf.exists(&tx, &key).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_read()?;
+    let exists = resolved.shelf.exists(&tx, &key)?;
 
     let val = jsonb::to_owned_jsonb(&exists).map_err(|e| ApiError::Internal(e.to_string()))?;
     build_response(&[("exists", val)])
@@ -317,10 +316,9 @@ pub async fn count(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let count = resolved.shelf.count(&tx).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let resolved = resolve_op(&state, path, "count").await?;
+    let tx = resolved.begin_read()?;
+    let count = resolved.shelf.count(&tx)?;
 
     let val = jsonb::to_owned_jsonb(&count).map_err(|e| ApiError::Internal(e.to_string()))?;
     build_response(&[("count", val)])
@@ -330,34 +328,30 @@ pub async fn batch_set(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
     body: Bytes,
-) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+) -> Result<Response, ApiError> {
+    let resolved = resolve_op(&state, path, "batch_set").await?;
     let parsed = parse_body(&body)?;
     let entries = parse_entries(&parsed)?;
+    let atomic = parse_atomic(&parsed)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.batch_set(&tx, &entries).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    Ok(StatusCode::NO_CONTENT)
+    let tx = resolved.begin_write()?;
+    let results = resolved.shelf.batch_set(&tx, &entries)?;
+    finish_batch_write(&state, &resolved, tx, results, atomic)
 }
 
 pub async fn batch_put(
     state: State<Arc<AppState>>,
     path: Path<(String, String)>,
     body: Bytes,
-) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+) -> Result<Response, ApiError> {
+    let resolved = resolve_op(&state, path, "batch_put").await?;
     let parsed = parse_body(&body)?;
     let entries = parse_entries(&parsed)?;
+    let atomic = parse_atomic(&parsed)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.batch_put(&tx, &entries).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    Ok(StatusCode::NO_CONTENT)
+    let tx = resolved.begin_write()?;
+    let results = resolved.shelf.batch_put(&tx, &entries)?;
+    finish_batch_write(&state, &resolved, tx, results, atomic)
 }
 
 pub async fn batch_delete(
@@ -365,14 +359,13 @@ pub async fn batch_delete(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<StatusCode, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "batch_delete").await?;
     let parsed = parse_body(&body)?;
     let keys = parse_keys_from_body(&parsed)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_write().map_err(|e| ApiError::Internal(e.to_string()))?;
-    resolved.shelf.batch_delete(&tx, &keys).map_err(|e| ApiError::Internal(e.to_string()))?;
-    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_write()?;
+    resolved.shelf.batch_delete(&tx, &keys)?;
+    commit_write(&state, &resolved, tx)?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -382,31 +375,101 @@ pub async fn batch_get(
     path: Path<(String, String)>,
     body: Bytes,
 ) -> Result<Response, ApiError> {
-    let resolved = resolve_shelf(state, path).await?;
+    let resolved = resolve_op(&state, path, "batch_get").await?;
     let parsed = parse_body(&body)?;
     let keys = parse_keys_from_body(&parsed)?;
 
-    let db = resolved.cabinet.database();
-    let tx = db.begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
-    let results = resolved.shelf.get_batch(&tx, &keys).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let tx = resolved.begin_read()?;
+    let results = resolved.shelf.get_batch(&tx, &keys)?;
 
+    let null = jsonb::to_owned_jsonb(&()).map_err(|e| ApiError::Internal(e.to_string()))?;
     let mut val_jsonbs = Vec::with_capacity(keys.len());
+    let mut err_jsonbs = Vec::with_capacity(keys.len());
     for i in 0..keys.len() {
-        let opt = results.get(i).map_err(|e| ApiError::Internal(e.to_string()))?;
-        match opt {
-            Some(val) => val_jsonbs.push(value_to_owned(&val)?),
-            None => val_jsonbs.push(
-                jsonb::to_owned_jsonb(&()).map_err(|e| ApiError::Internal(e.to_string()))?
-            ),
+        match results.get(i) {
+            Ok(Some(val)) => {
+                val_jsonbs.push(value_to_owned(&val)?);
+                err_jsonbs.push(null.clone());
+            }
+            Ok(None) => {
+                val_jsonbs.push(null.clone());
+                err_jsonbs.push(null.clone());
+            }
+            Err(e) => {
+                val_jsonbs.push(null.clone());
+                err_jsonbs.push(ApiError::from(e).to_jsonb()?);
+            }
         }
     }
-    let arr = jsonb::OwnedJsonb::build_array(val_jsonbs.iter().map(|o| o.as_raw()))
+    let values = jsonb::OwnedJsonb::build_array(val_jsonbs.iter().map(|o| o.as_raw()))
         .map_err(|e| ApiError::Internal(e.to_string()))?;
-    build_response(&[("values", arr)])
+    let errors = jsonb::OwnedJsonb::build_array(err_jsonbs.iter().map(|o| o.as_raw()))
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    build_response(&[("values", values), ("errors", errors)])
+}
+
+/// Commits a data write and wakes anyone following the cabinet's changes.
+pub(crate) fn commit_write(
+    state: &AppState,
+    resolved: &ResolvedShelf,
+    tx: redb::WriteTransaction,
+) -> Result<(), ApiError> {
+    let started = Instant::now();
+    tx.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+    state.metrics.observe_commit(&resolved.cabinet.name, started.elapsed());
+    state.notify_changes(resolved.cabinet.id);
+    Ok(())
+}
+
+/// Commits a batch write and reports each entry's outcome. In atomic mode a
+/// single failed entry drops the transaction and the response is a 422.
+fn finish_batch_write(
+    state: &AppState,
+    resolved: &ResolvedShelf,
+    tx: redb::WriteTransaction,
+    results: Vec<Result<(), TransactionError>>,
+    atomic: bool,
+) -> Result<Response, ApiError> {
+    let applied = !atomic || results.iter().all(|r| r.is_ok());
+    if applied {
+        commit_write(state, resolved, tx)?;
+    }
+
+    let ok_field = |ok: bool| jsonb::to_owned_jsonb(&ok).map_err(|e| ApiError::Internal(e.to_string()));
+    let mut items = Vec::with_capacity(results.len());
+    for result in results {
+        let item = match result {
+            Ok(()) => jsonb::OwnedJsonb::build_object([("ok", ok_field(true)?.as_raw())]),
+            // The same body as an error response, plus `ok`.
+            Err(e) => ApiError::from(e)
+                .to_jsonb()?
+                .as_raw()
+                .object_insert("ok", &ok_field(false)?.as_raw(), false),
+        }
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+        items.push(item);
+    }
+    let arr = jsonb::OwnedJsonb::build_array(items.iter().map(|o| o.as_raw()))
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    let mut response = build_response(&[("applied", ok_field(applied)?), ("results", arr)])?;
+    if !applied {
+        *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    Ok(response)
 }
 
 // --- Batch parsing helpers ---
 
+fn parse_atomic(parsed: &jsonb::OwnedJsonb) -> Result<bool, ApiError> {
+    let raw = parsed.as_raw();
+    match raw.get_by_name("atomic", false).map_err(|e| ApiError::JsonParse(e.to_string()))? {
+        Some(field) => jsonb::from_raw_jsonb::<bool>(&field.as_raw())
+            .map_err(|_| ApiError::JsonParse("field 'atomic' must be a boolean".into())),
+        None => Ok(false),
+    }
+}
+
 fn parse_entries(parsed: &jsonb::OwnedJsonb) -> Result<Vec<(Key, Value)>, ApiError> {
     let raw = parsed.as_raw();
     let entries_owned = get_field(&raw, "entries")?;
@@ -1,23 +1,187 @@
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
-    Json,
+    body::Bytes,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use std::sync::Arc;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::api::admin;
 use crate::api::error::ApiError;
+use crate::api::extractors::{Json, Path, Query};
+use crate::api::normal::{build_response, get_field, key_to_owned, owned_to_value, parse_body};
+use crate::encryption::KeyRotation;
 use crate::AppState;
 use carmine_core::{
-    meta::{CabinetMeta, ShelfMeta},
-    cabinet::Cabinet,
-    transaction::Writable,
+    auth::ApiKeyMeta,
+    meta::{EncryptionMeta, ShelfMeta},
+    integrity::{InvalidEntry, TableTypes},
+    stats::{TableStats, TreeStats},
+    key::KeyType,
+    types::ParseTypeError,
+    value::ValueType,
+    shelf::migrate::{ConversionPolicy, MigrationReport},
+    changelog::Retention,
 };
 
 #[derive(Deserialize)]
 pub struct CreateCabinetRequest {
     name: String,
+    /// Defaults to whether a master key is configured.
+    #[serde(default)]
+    encrypted: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteShelfParams {
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeleteShelfResponse {
+    deleted_entries: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct CopyShelfResponse {
+    #[serde(flatten)]
+    shelf: ShelfMeta,
+    copied_entries: u64,
+}
+
+/// Change log retention. `null` (or an omitted field) means no limit.
+#[derive(Serialize, Deserialize)]
+pub struct ChangeRetention {
+    #[serde(default)]
+    max_entries: Option<u64>,
+    #[serde(default)]
+    max_age_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct TreeStatsResponse {
+    tree_height: u32,
+    leaf_pages: u64,
+    branch_pages: u64,
+    stored_bytes: u64,
+    metadata_bytes: u64,
+    fragmented_bytes: u64,
+}
+
+impl From<&TreeStats> for TreeStatsResponse {
+    fn from(tree: &TreeStats) -> Self {
+        Self {
+            tree_height: tree.tree_height,
+            leaf_pages: tree.leaf_pages,
+            branch_pages: tree.branch_pages,
+            stored_bytes: tree.stored_bytes,
+            metadata_bytes: tree.metadata_bytes,
+            fragmented_bytes: tree.fragmented_bytes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TableStatsResponse {
+    entries: u64,
+    #[serde(flatten)]
+    tree: TreeStatsResponse,
+}
+
+#[derive(Serialize)]
+pub struct ShelfStatsResponse {
+    name: String,
+    #[serde(flatten)]
+    table: TableStatsResponse,
+}
+
+#[derive(Serialize)]
+pub struct DatabaseStatsResponse {
+    allocated_pages: u64,
+    page_size: usize,
+    #[serde(flatten)]
+    tree: TreeStatsResponse,
+}
+
+/// `last_write` is in milliseconds since the Unix epoch.
+#[derive(Serialize)]
+pub struct CabinetStatsResponse {
+    file_size: u64,
+    last_write: Option<u64>,
+    database: DatabaseStatsResponse,
+    shelves: Vec<ShelfStatsResponse>,
+    change_log: TableStatsResponse,
+}
+
+impl From<&TableStats> for TableStatsResponse {
+    fn from(table: &TableStats) -> Self {
+        Self { entries: table.entries, tree: TreeStatsResponse::from(&table.tree) }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CheckCabinetParams {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Serialize)]
+pub struct TableTypesResponse {
+    key_type: KeyType,
+    value_type: ValueType,
+}
+
+impl From<TableTypes> for TableTypesResponse {
+    fn from(types: TableTypes) -> Self {
+        Self { key_type: types.key_type, value_type: types.value_type }
+    }
+}
+
+/// The types are `null` if they can't be told.
+#[derive(Serialize)]
+pub struct OrphanTableResponse {
+    name: String,
+    entries: u64,
+    key_type: Option<KeyType>,
+    value_type: Option<ValueType>,
+}
+
+#[derive(Serialize)]
+pub struct MismatchedTableResponse {
+    shelf: String,
+    expected: TableTypesResponse,
+    found: Option<TableTypesResponse>,
+}
+
+#[derive(Serialize)]
+pub struct RepairsResponse {
+    created_tables: Vec<String>,
+    removed_entries: u64,
+    adopted_tables: Vec<String>,
+    retyped_shelves: Vec<String>,
+}
+
+/// A cabinet's encryption settings and the progress of its latest key
+/// rotation, or `null` if it was never rotated.
+#[derive(Serialize)]
+pub struct EncryptionResponse {
+    #[serde(flatten)]
+    encryption: EncryptionMeta,
+    rotation: Option<KeyRotation>,
+}
+
+impl From<admin::EncryptionStatus> for EncryptionResponse {
+    fn from(status: admin::EncryptionStatus) -> Self {
+        Self { encryption: status.encryption, rotation: status.rotation }
+    }
 }
 
 #[derive(Deserialize)]
@@ -27,123 +191,79 @@ pub struct CreateShelfRequest {
     value_type: String,
 }
 
-pub async fn create_cabinet(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<CreateCabinetRequest>,
-) -> Result<impl IntoResponse, ApiError> {
-    if state.system_store.find_cabinet_by_name(&req.name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .is_some() 
-    {
-        return Err(ApiError::CabinetAlreadyExists(req.name));
-    }
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+}
 
-    let id: u64 = small_uid::SmallUid::new().into();
-    let path = state.data_dir.join(format!("cabinet_{}", id));
-
-    let cabinet = Cabinet::create(
-        id,
-        req.name.clone(),
-        path.clone(),
-        state.cabinet_cache_size,
-    )
-    .map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    let meta = CabinetMeta {
-        id,
-        name: req.name,
-        path,
-        shelves: Vec::new(),
-    };
+/// A new key's metadata plus the key itself, which is never shown again.
+#[derive(Serialize)]
+pub struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    meta: ApiKeyMeta,
+    key: String,
+}
 
-    state.system_store.register_cabinet(&meta)
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
+fn optional_string(raw: &jsonb::RawJsonb, name: &str) -> Result<Option<String>, ApiError> {
+    match raw.get_by_name(name, false).map_err(|e| ApiError::JsonParse(e.to_string()))? {
+        Some(field) => jsonb::from_raw_jsonb::<String>(&field.as_raw())
+            .map(Some)
+            .map_err(|_| ApiError::JsonParse(format!("field '{}' must be a string", name))),
+        None => Ok(None),
+    }
+}
+
+fn parse_key_type(name: &str) -> Result<KeyType, ApiError> {
+    name.parse().map_err(|e: ParseTypeError| ApiError::BadRequest(e.to_string()))
+}
 
-    state.cabinets.insert(id, cabinet);
+fn parse_value_type(name: &str) -> Result<ValueType, ApiError> {
+    name.parse().map_err(|e: ParseTypeError| ApiError::BadRequest(e.to_string()))
+}
 
-    Ok(Json(meta))
+pub async fn create_cabinet(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateCabinetRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::create_cabinet(&state, req.name, req.encrypted)?))
 }
 
 pub async fn list_cabinets(
     State(state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let cabinets = state.system_store.list_cabinets()
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
-    Ok(Json(cabinets))
+    Ok(Json(admin::list_cabinets(&state)?))
 }
 
 pub async fn get_cabinet(
     State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let meta = state.system_store.find_cabinet_by_name(&name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(name))?;
-    Ok(Json(meta))
+    Ok(Json(admin::get_cabinet(&state, &name)?))
 }
 
 pub async fn delete_cabinet(
     State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let meta = state.system_store.find_cabinet_by_name(&name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(name.clone()))?;
-
-    state.cabinets.remove(&meta.id);
-
-    std::fs::remove_file(&meta.path)
-        .map_err(|e| ApiError::Internal(format!("Failed to remove cabinet file: {}", e)))?;
-
-    state.system_store.remove_cabinet(meta.id)
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    admin::delete_cabinet(&state, &name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    Ok(axum::http::StatusCode::NO_CONTENT)
+pub async fn rename_cabinet(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(req): Json<RenameRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::rename_cabinet(&state, &name, &req.name)?))
 }
 
 pub async fn clean_cabinet(
     State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let meta = state.system_store.find_cabinet_by_name(&name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(name))?;
-
-    let cabinet = state.get_or_open_cabinet(meta.id, meta.name.clone(), meta.path.clone())
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    let db = cabinet.database();
-
-    let txn = db.begin_write()
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    for shelf_meta in &meta.shelves {
-        let key_type = match shelf_meta.key_type.as_str() {
-            "String" => carmine_core::key::KeyType::String,
-            "Number" => carmine_core::key::KeyType::Number,
-            "Int" => carmine_core::key::KeyType::Int,
-            _ => continue,
-        };
-        let value_type = match shelf_meta.value_type.as_str() {
-            "String" => carmine_core::value::ValueType::String,
-            "Number" => carmine_core::value::ValueType::Number,
-            "Int" => carmine_core::value::ValueType::Int,
-            "Object" => carmine_core::value::ValueType::Object,
-            "Byte" => carmine_core::value::ValueType::Byte,
-            _ => continue,
-        };
-        let shelf = carmine_core::shelf::Shelf::new(
-            shelf_meta.name.clone(),
-            key_type,
-            value_type,
-        );
-        let _ = shelf.clear(&txn);
-    }
-
-    txn.commit()
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
-
-    Ok(axum::http::StatusCode::NO_CONTENT)
+    admin::clean_cabinet(&state, &name)?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn create_shelf(
@@ -151,52 +271,254 @@ pub async fn create_shelf(
     Path(cabinet_name): Path<String>,
     Json(req): Json<CreateShelfRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let mut meta = state.system_store.find_cabinet_by_name(&cabinet_name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(cabinet_name.clone()))?;
+    let key_type = parse_key_type(&req.key_type)?;
+    let value_type = parse_value_type(&req.value_type)?;
+    Ok(Json(admin::create_shelf(&state, &cabinet_name, req.name, key_type, value_type)?))
+}
 
-    if meta.shelves.iter().any(|s| s.name == req.name) {
-        return Err(ApiError::ShelfAlreadyExists(req.name));
+pub async fn list_shelves(
+    State(state): State<Arc<AppState>>,
+    Path(cabinet_name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::list_shelves(&state, &cabinet_name)?))
+}
+
+pub async fn delete_shelf(
+    State(state): State<Arc<AppState>>,
+    Path((cabinet_name, shelf_name)): Path<(String, String)>,
+    Query(params): Query<DeleteShelfParams>,
+) -> Result<Response, ApiError> {
+    let deleted_entries = admin::delete_shelf(&state, &cabinet_name, &shelf_name, params.force)?;
+    if params.force {
+        Ok(Json(DeleteShelfResponse { deleted_entries }).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
     }
+}
 
-    let shelf_meta = ShelfMeta {
-        name: req.name.clone(),
-        key_type: req.key_type,
-        value_type: req.value_type,
+pub async fn rename_shelf(
+    State(state): State<Arc<AppState>>,
+    Path((cabinet_name, shelf_name)): Path<(String, String)>,
+    Json(req): Json<RenameRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::rename_shelf(&state, &cabinet_name, &shelf_name, &req.name)?))
+}
+
+pub async fn copy_shelf(
+    State(state): State<Arc<AppState>>,
+    Path((cabinet_name, shelf_name)): Path<(String, String)>,
+    Json(req): Json<RenameRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (shelf, copied_entries) = admin::copy_shelf(&state, &cabinet_name, &shelf_name, &req.name)?;
+    Ok(Json(CopyShelfResponse { shelf, copied_entries }))
+}
+
+pub async fn migrate_shelf(
+    State(state): State<Arc<AppState>>,
+    Path((cabinet_name, shelf_name)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let parsed = parse_body(&body)?;
+    let raw = parsed.as_raw();
+
+    let key_type = optional_string(&raw, "key_type")?.as_deref().map(parse_key_type).transpose()?;
+    let value_type = optional_string(&raw, "value_type")?.as_deref().map(parse_value_type).transpose()?;
+    let policy = match optional_string(&raw, "policy")?.as_deref() {
+        None | Some("strict") => ConversionPolicy::Strict,
+        Some("skip_invalid") => ConversionPolicy::SkipInvalid,
+        Some("default_value") => {
+            ConversionPolicy::DefaultValue(owned_to_value(&get_field(&raw, "default")?)?)
+        }
+        Some(other) => return Err(ApiError::JsonParse(format!("unknown policy '{}'", other))),
     };
 
-    meta.shelves.push(shelf_meta.clone());
-    state.system_store.update_cabinet(&meta)
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let migration = admin::migrate_shelf(&state, &cabinet_name, &shelf_name, key_type, value_type, policy)?;
 
-    Ok(Json(shelf_meta))
+    let mut response = migration_response(migration.applied, &migration.shelf, &migration.report)?;
+    if !migration.applied {
+        *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    Ok(response)
 }
 
-pub async fn list_shelves(
+fn migration_response(
+    applied: bool,
+    shelf: &ShelfMeta,
+    report: &MigrationReport,
+) -> Result<Response, ApiError> {
+    let internal = |e: jsonb::Error| ApiError::Internal(e.to_string());
+
+    let failures = report.failed.iter().map(|failure| {
+        let key = key_to_owned(&failure.key)?;
+        let error = jsonb::to_owned_jsonb(&failure.error).map_err(internal)?;
+        jsonb::OwnedJsonb::build_object([("key", key.as_raw()), ("error", error.as_raw())])
+            .map_err(internal)
+    }).collect::<Result<Vec<_>, ApiError>>()?;
+    let failed = jsonb::OwnedJsonb::build_array(failures.iter().map(|o| o.as_raw()))
+        .map_err(internal)?;
+
+    build_response(&[
+        ("applied", jsonb::to_owned_jsonb(&applied).map_err(internal)?),
+        ("shelf", jsonb::to_owned_jsonb(shelf).map_err(internal)?),
+        ("converted", jsonb::to_owned_jsonb(&report.converted).map_err(internal)?),
+        ("defaulted", jsonb::to_owned_jsonb(&report.defaulted).map_err(internal)?),
+        ("failed", failed),
+    ])
+}
+
+pub async fn get_change_retention(
     State(state): State<Arc<AppState>>,
-    Path(cabinet_name): Path<String>,
+    Path(name): Path<String>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let meta = state.system_store.find_cabinet_by_name(&cabinet_name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(cabinet_name))?;
-    Ok(Json(meta.shelves))
+    let retention = admin::change_retention(&state, &name)?;
+    Ok(Json(ChangeRetention {
+        max_entries: retention.max_entries,
+        max_age_secs: retention.max_age_secs,
+    }))
 }
 
-pub async fn delete_shelf(
+pub async fn set_change_retention(
     State(state): State<Arc<AppState>>,
-    Path((cabinet_name, shelf_name)): Path<(String, String)>,
+    Path(name): Path<String>,
+    Json(req): Json<ChangeRetention>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let mut meta = state.system_store.find_cabinet_by_name(&cabinet_name)
-        .map_err(|e| ApiError::Internal(e.to_string()))?
-        .ok_or_else(|| ApiError::CabinetNotFound(cabinet_name.clone()))?;
+    let retention = admin::set_change_retention(&state, &name, Retention {
+        max_entries: req.max_entries,
+        max_age_secs: req.max_age_secs,
+    })?;
+    Ok(Json(ChangeRetention {
+        max_entries: retention.max_entries,
+        max_age_secs: retention.max_age_secs,
+    }))
+}
 
-    if !meta.shelves.iter().any(|s| s.name == shelf_name) {
-        return Err(ApiError::ShelfNotFound(shelf_name));
-    }
+pub async fn get_cabinet_stats(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let usage = admin::cabinet_stats(&state, &name)?;
+    let stats = &usage.stats;
+    Ok(Json(CabinetStatsResponse {
+        file_size: usage.file_size,
+        last_write: stats.last_write_ms,
+        database: DatabaseStatsResponse {
+            allocated_pages: stats.database.allocated_pages,
+            page_size: stats.database.page_size,
+            tree: TreeStatsResponse::from(&stats.database.tree),
+        },
+        shelves: stats
+            .shelves
+            .iter()
+            .map(|shelf| ShelfStatsResponse {
+                name: shelf.name.clone(),
+                table: TableStatsResponse::from(shelf),
+            })
+            .collect(),
+        change_log: TableStatsResponse::from(&stats.change_log),
+    }))
+}
+
+pub async fn compact_cabinet(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::compact_cabinet(&state, &name).await?))
+}
+
+pub async fn check_cabinet(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<CheckCabinetParams>,
+) -> Result<Response, ApiError> {
+    let outcome = admin::check_cabinet(&state, &name, params.repair).await?;
+    let report = &outcome.check.report;
+    let internal = |e: jsonb::Error| ApiError::Internal(e.to_string());
+
+    let orphans: Vec<_> = report.orphan_tables.iter().map(|table| OrphanTableResponse {
+        name: table.name.clone(),
+        entries: table.entries,
+        key_type: table.types.map(|types| types.key_type),
+        value_type: table.types.map(|types| types.value_type),
+    }).collect();
+    let mismatched: Vec<_> = report.mismatched_tables.iter().map(|table| MismatchedTableResponse {
+        shelf: table.shelf.clone(),
+        expected: table.expected.into(),
+        found: table.found.map(TableTypesResponse::from),
+    }).collect();
+    let entry_list = |list: &[InvalidEntry]| {
+        let entries = list.iter().map(|entry| {
+            let shelf = jsonb::to_owned_jsonb(&entry.shelf).map_err(internal)?;
+            let key = key_to_owned(&entry.key)?;
+            let error = jsonb::to_owned_jsonb(&entry.error).map_err(internal)?;
+            jsonb::OwnedJsonb::build_object([
+                ("shelf", shelf.as_raw()),
+                ("key", key.as_raw()),
+                ("error", error.as_raw()),
+            ])
+            .map_err(internal)
+        }).collect::<Result<Vec<_>, ApiError>>()?;
+        jsonb::OwnedJsonb::build_array(entries.iter().map(|o| o.as_raw())).map_err(internal)
+    };
+    let invalid_entries = entry_list(&report.invalid_entries)?;
+    let undecryptable_entries = entry_list(&report.undecryptable_entries)?;
+    let repairs = outcome.repairs.map(|repairs| RepairsResponse {
+        created_tables: repairs.created_tables,
+        removed_entries: repairs.removed_entries,
+        adopted_tables: repairs.adopted_tables,
+        retyped_shelves: repairs.retyped_shelves,
+    });
+
+    build_response(&[
+        ("clean", jsonb::to_owned_jsonb(&report.is_clean()).map_err(internal)?),
+        ("file_repaired", jsonb::to_owned_jsonb(&outcome.check.file_repaired).map_err(internal)?),
+        ("orphan_tables", jsonb::to_owned_jsonb(&orphans).map_err(internal)?),
+        ("missing_tables", jsonb::to_owned_jsonb(&report.missing_tables).map_err(internal)?),
+        ("mismatched_tables", jsonb::to_owned_jsonb(&mismatched).map_err(internal)?),
+        ("invalid_entries", invalid_entries),
+        ("invalid_entry_count", jsonb::to_owned_jsonb(&report.invalid_entry_count).map_err(internal)?),
+        ("undecryptable_entries", undecryptable_entries),
+        (
+            "undecryptable_entry_count",
+            jsonb::to_owned_jsonb(&report.undecryptable_entry_count).map_err(internal)?,
+        ),
+        ("repairs", jsonb::to_owned_jsonb(&repairs).map_err(internal)?),
+    ])
+}
+
+pub async fn get_encryption(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(EncryptionResponse::from(admin::encryption_status(&state, &name)?)))
+}
+
+pub async fn rotate_cabinet_key(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let status = admin::rotate_cabinet_key(&state, &name)?;
+    Ok((StatusCode::ACCEPTED, Json(EncryptionResponse::from(status))))
+}
 
-    meta.shelves.retain(|s| s.name != shelf_name);
-    state.system_store.update_cabinet(&meta)
-        .map_err(|e| ApiError::Internal(e.to_string()))?;
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateApiKeyRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let scopes = admin::parse_scopes(&req.scopes)?;
+    let (meta, key) = admin::create_api_key(&state, req.name, scopes)?;
+    Ok(Json(CreateApiKeyResponse { meta, key }))
+}
 
-    Ok(axum::http::StatusCode::NO_CONTENT)
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(admin::list_api_keys(&state)?))
+}
+
+pub async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, ApiError> {
+    admin::revoke_api_key(&state, id)?;
+    Ok(StatusCode::NO_CONTENT)
 }
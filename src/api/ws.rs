@@ -0,0 +1,352 @@
+//! WebSocket interface to the data API.
+//!
+//! Each text frame is one JSON request naming an operation from the HTTP
+//! data API plus the cabinet and shelf it targets. Requests run
+//! concurrently, so replies can arrive out of order and carry the request's
+//! `id`. Replies mirror the HTTP response: its status code and JSON body.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, State,
+    },
+    response::{IntoResponse, Response},
+};
+use redb::ReadableDatabase;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+
+use crate::api::auth::Permissions;
+use crate::api::changes::{self, change_to_owned, Follower, WatchParams};
+use crate::api::error::ApiError;
+use crate::api::extractors::{cached_cabinet, Path, Query};
+use crate::api::normal::{self, parse_body};
+use crate::AppState;
+use carmine_core::auth::Access;
+
+/// Replies waiting to be written; bounded so a client that stops reading
+/// eventually stalls its own requests rather than growing the queue.
+const OUTBOX_SIZE: usize = 256;
+
+/// Requests one connection may have running at once. The next frame isn't
+/// read until one finishes, so a pipelining client can't start unbounded
+/// work on the server.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Subscriptions one connection may hold at once. Each one keeps a follower
+/// task polling the cabinet's change log.
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+pub async fn upgrade(
+    State(state): State<Arc<AppState>>,
+    Extension(permissions): Extension<Permissions>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| serve(state, permissions, socket))
+}
+
+async fn serve(state: Arc<AppState>, permissions: Permissions, mut socket: WebSocket) {
+    let (outbox, mut replies) = mpsc::channel::<String>(OUTBOX_SIZE);
+    let mut tasks = JoinSet::new();
+    let mut subscriptions: HashMap<u64, AbortHandle> = HashMap::new();
+    let mut next_subscription = 1;
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+    loop {
+        tokio::select! {
+            // Replies keep draining while reads wait for a free permit.
+            incoming = socket.recv(), if in_flight.available_permits() > 0 => {
+                // Replies made here go straight to the socket: this loop is
+                // the only reader of the outbox, so waiting on a full one
+                // would hang the connection.
+                let inline = match incoming {
+                    Some(Ok(Message::Text(text))) => match Request::parse(text) {
+                        Ok(request) => match request.op.as_str() {
+                            "subscribe" if subscriptions.len() >= MAX_SUBSCRIPTIONS => Some(error_reply(
+                                request.id.as_ref(),
+                                ApiError::TooManySubscriptions(MAX_SUBSCRIPTIONS),
+                            )),
+                            "subscribe" => Some(
+                                match subscribe(&state, &permissions, &request, next_subscription, outbox.clone()) {
+                                    Ok(follower) => {
+                                        let handle = tasks.spawn(follower);
+                                        subscriptions.insert(next_subscription, handle);
+                                        let result = jsonb::to_owned_jsonb(&next_subscription)
+                                            .map_err(|e| ApiError::Internal(e.to_string()))
+                                            .and_then(|id| object(&[("subscription", id)]));
+                                        next_subscription += 1;
+                                        match result {
+                                            Ok(body) => reply(request.id.as_ref(), 200, Some(body)),
+                                            Err(e) => error_reply(request.id.as_ref(), e),
+                                        }
+                                    }
+                                    Err(e) => error_reply(request.id.as_ref(), e),
+                                },
+                            ),
+                            "unsubscribe" => Some(match unsubscribe(&request, &mut subscriptions) {
+                                Ok(()) => reply(request.id.as_ref(), 204, None),
+                                Err(e) => error_reply(request.id.as_ref(), e),
+                            }),
+                            _ => {
+                                let permit = in_flight
+                                    .clone()
+                                    .try_acquire_owned()
+                                    .expect("frames are only read while a permit is free");
+                                let state = state.clone();
+                                let permissions = permissions.clone();
+                                let outbox = outbox.clone();
+                                tasks.spawn(async move {
+                                    let reply = handle(state, &permissions, request).await;
+                                    let _ = outbox.send(reply).await;
+                                    drop(permit);
+                                });
+                                None
+                            }
+                        },
+                        Err((id, e)) => Some(error_reply(id.as_ref(), e)),
+                    },
+                    Some(Ok(Message::Binary(_))) => Some(error_reply(None, ApiError::BadRequest(
+                        "binary frames are not supported; send JSON text".to_string(),
+                    ))),
+                    Some(Ok(_)) => None,
+                    Some(Err(_)) | None => break,
+                };
+                if let Some(text) = inline
+                    && socket.send(Message::Text(text)).await.is_err()
+                {
+                    break;
+                }
+            }
+            Some(text) = replies.recv() => {
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                // Followers end on their own when the cabinet is deleted or
+                // the outbox closes; forget them so they stop counting.
+                let id = match joined {
+                    Ok((id, ())) => id,
+                    Err(e) => e.id(),
+                };
+                subscriptions.retain(|_, handle| handle.id() != id);
+            }
+        }
+    }
+    // Dropping the JoinSet aborts in-flight requests and subscriptions.
+}
+
+struct Request {
+    /// Echoed back verbatim; any JSON value.
+    id: Option<jsonb::OwnedJsonb>,
+    op: String,
+    /// The whole message, handed to the HTTP handler as its request body.
+    body: Bytes,
+    parsed: jsonb::OwnedJsonb,
+}
+
+impl Request {
+    fn parse(text: String) -> Result<Self, (Option<jsonb::OwnedJsonb>, ApiError)> {
+        let body = Bytes::from(text);
+        let parsed = parse_body(&body).map_err(|e| (None, e))?;
+        let raw = parsed.as_raw();
+        let id = raw.get_by_name("id", false).ok().flatten();
+        let op = match field_string(&raw, "op") {
+            Ok(Some(op)) => op,
+            Ok(None) => return Err((id, ApiError::JsonParse("missing field 'op'".to_string()))),
+            Err(e) => return Err((id, e)),
+        };
+        Ok(Self { id, op, body, parsed })
+    }
+
+    fn string(&self, name: &str) -> Result<Option<String>, ApiError> {
+        field_string(&self.parsed.as_raw(), name)
+    }
+
+    fn required(&self, name: &str) -> Result<String, ApiError> {
+        self.string(name)?
+            .ok_or_else(|| ApiError::JsonParse(format!("missing field '{}'", name)))
+    }
+
+    fn u64(&self, name: &str) -> Result<Option<u64>, ApiError> {
+        match self.parsed.as_raw().get_by_name(name, false).map_err(|e| ApiError::JsonParse(e.to_string()))? {
+            Some(field) => jsonb::from_raw_jsonb::<u64>(&field.as_raw())
+                .map(Some)
+                .map_err(|_| ApiError::JsonParse(format!("field '{}' must be a non-negative integer", name))),
+            None => Ok(None),
+        }
+    }
+
+    /// A key given as a JSON string or number, as the string a query
+    /// parameter would carry.
+    fn key_text(&self, name: &str) -> Result<Option<String>, ApiError> {
+        match self.parsed.as_raw().get_by_name(name, false).map_err(|e| ApiError::JsonParse(e.to_string()))? {
+            Some(field) => Ok(Some(
+                jsonb::from_raw_jsonb::<String>(&field.as_raw())
+                    .unwrap_or_else(|_| field.as_raw().to_string()),
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+fn field_string(raw: &jsonb::RawJsonb, name: &str) -> Result<Option<String>, ApiError> {
+    match raw.get_by_name(name, false).map_err(|e| ApiError::JsonParse(e.to_string()))? {
+        Some(field) => jsonb::from_raw_jsonb::<String>(&field.as_raw())
+            .map(Some)
+            .map_err(|_| ApiError::JsonParse(format!("field '{}' must be a string", name))),
+        None => Ok(None),
+    }
+}
+
+/// Operations that need write access; everything else needs read access.
+const WRITE_OPS: &[&str] = &["set", "put", "delete", "batch_set", "batch_put", "batch_delete"];
+
+/// Runs a data operation through the same handler as its HTTP route.
+async fn handle(state: Arc<AppState>, permissions: &Permissions, request: Request) -> String {
+    let path = match (request.required("cabinet"), request.required("shelf")) {
+        (Ok(cabinet), Ok(shelf)) => Path((cabinet, shelf)),
+        (Err(e), _) | (_, Err(e)) => return error_reply(request.id.as_ref(), e),
+    };
+    let access = if WRITE_OPS.contains(&request.op.as_str()) { Access::Write } else { Access::Read };
+    if let Err(e) = permissions.check(&path.0 .0, Some(&path.0 .1), access) {
+        return error_reply(request.id.as_ref(), e);
+    }
+    let state = State(state);
+    let body = request.body.clone();
+    let response = match request.op.as_str() {
+        "get" => normal::get(state, path, body).await.into_response(),
+        "set" => normal::set(state, path, body).await.into_response(),
+        "put" => normal::put(state, path, body).await.into_response(),
+        "delete" => normal::delete(state, path, body).await.into_response(),
+        "exists" => normal::exists(state, path, body).await.into_response(),
+        "range" => normal::range(state, path, body).await.into_response(),
+        "all" => normal::all(state, path).await.into_response(),
+        "keys" => normal::keys(state, path).await.into_response(),
+        "values" => normal::values(state, path).await.into_response(),
+        "count" => normal::count(state, path).await.into_response(),
+        "batch_get" => normal::batch_get(state, path, body).await.into_response(),
+        "batch_set" => normal::batch_set(state, path, body).await.into_response(),
+        "batch_put" => normal::batch_put(state, path, body).await.into_response(),
+        "batch_delete" => normal::batch_delete(state, path, body).await.into_response(),
+        "watch" => match watch_params(&request) {
+            Ok(params) => changes::watch(state, path, Query(params)).await.into_response(),
+            Err(e) => e.into_response(),
+        },
+        other => {
+            return error_reply(
+                request.id.as_ref(),
+                ApiError::BadRequest(format!("unknown op '{}'", other)),
+            )
+        }
+    };
+    response_reply(request.id.as_ref(), response).await
+}
+
+fn watch_params(request: &Request) -> Result<WatchParams, ApiError> {
+    Ok(WatchParams {
+        key: request.key_text("key")?,
+        start: request.key_text("start")?,
+        end: request.key_text("end")?,
+        since_version: request.u64("since_version")?,
+        timeout: request.u64("timeout")?,
+    })
+}
+
+/// Starts following a cabinet's changes, pushing each one to the client
+/// tagged with the subscription id.
+fn subscribe(
+    state: &Arc<AppState>,
+    permissions: &Permissions,
+    request: &Request,
+    subscription: u64,
+    outbox: mpsc::Sender<String>,
+) -> Result<impl std::future::Future<Output = ()> + Send + 'static, ApiError> {
+    let cabinet_name = request.required("cabinet")?;
+    permissions.check(&cabinet_name, None, Access::Read)?;
+    let cached = cached_cabinet(state, &cabinet_name)?;
+    let since = match request.u64("since")? {
+        Some(since) => since,
+        None => {
+            let tx = cached.cabinet.database().begin_read().map_err(|e| ApiError::Internal(e.to_string()))?;
+            carmine_core::changelog::read_since(&tx, 0, 0, None, None)?.latest
+        }
+    };
+    let mut follower = Follower::new(state, &cached, request.string("shelf")?, since);
+    let id = jsonb::to_owned_jsonb(&subscription).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let state = state.clone();
+
+    Ok(async move {
+        while let Some(change) = follower.next_change(&state).await {
+            let message = change_to_owned(&change)
+                .and_then(|change| object(&[("subscription", id.clone()), ("change", change)]));
+            let Ok(message) = message else { continue };
+            if outbox.send(message.as_raw().to_string()).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+fn unsubscribe(request: &Request, subscriptions: &mut HashMap<u64, AbortHandle>) -> Result<(), ApiError> {
+    let id = request
+        .u64("subscription")?
+        .ok_or_else(|| ApiError::JsonParse("missing field 'subscription'".to_string()))?;
+    let handle = subscriptions
+        .remove(&id)
+        .ok_or_else(|| ApiError::BadRequest(format!("no subscription {}", id)))?;
+    handle.abort();
+    Ok(())
+}
+
+fn object(fields: &[(&str, jsonb::OwnedJsonb)]) -> Result<jsonb::OwnedJsonb, ApiError> {
+    jsonb::OwnedJsonb::build_object(fields.iter().map(|(k, v)| (*k, v.as_raw())))
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+fn reply(id: Option<&jsonb::OwnedJsonb>, status: u16, body: Option<jsonb::OwnedJsonb>) -> String {
+    let build = || -> Result<jsonb::OwnedJsonb, ApiError> {
+        let null = || jsonb::to_owned_jsonb(&()).map_err(|e| ApiError::Internal(e.to_string()));
+        let id = match id {
+            Some(id) => id.clone(),
+            None => null()?,
+        };
+        let status = jsonb::to_owned_jsonb(&status).map_err(|e| ApiError::Internal(e.to_string()))?;
+        let body = match body {
+            Some(body) => body,
+            None => null()?,
+        };
+        object(&[("id", id), ("status", status), ("body", body)])
+    };
+    match build() {
+        Ok(message) => message.as_raw().to_string(),
+        Err(_) => r#"{"id":null,"status":500,"body":null}"#.to_string(),
+    }
+}
+
+fn error_reply(id: Option<&jsonb::OwnedJsonb>, error: ApiError) -> String {
+    let status = error.status().as_u16();
+    match error.to_jsonb() {
+        Ok(body) => reply(id, status, Some(body)),
+        Err(_) => reply(id, status, None),
+    }
+}
+
+async fn response_reply(id: Option<&jsonb::OwnedJsonb>, response: Response) -> String {
+    let status = response.status().as_u16();
+    let bytes = match to_bytes(response.into_body(), usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return error_reply(id, ApiError::Internal(e.to_string())),
+    };
+    if bytes.is_empty() {
+        return reply(id, status, None);
+    }
+    match jsonb::parse_owned_jsonb(&bytes) {
+        Ok(body) => reply(id, status, Some(body)),
+        Err(e) => error_reply(id, ApiError::Internal(e.to_string())),
+    }
+}
@@ -0,0 +1,376 @@
+//! Field encoding for the binary protocol. Integers are big-endian; strings
+//! and byte strings are length-prefixed.
+//!
+//! Keys and values start with a one-byte type tag. `Number`s travel as their
+//! JSONB scalar encoding so decimals survive the round trip, and `Object`
+//! values are the stored JSONB bytes, passed through untouched.
+
+use carmine_core::{
+    key::Key,
+    types::{Int, Number, RawObject},
+    value::Value,
+};
+
+use crate::api::error::ApiError;
+
+pub const TAG_NULL: u8 = 0;
+pub const TAG_STRING: u8 = 1;
+pub const TAG_NUMBER: u8 = 2;
+pub const TAG_INT: u8 = 3;
+pub const TAG_OBJECT: u8 = 4;
+pub const TAG_BYTE: u8 = 5;
+
+fn malformed(what: &str) -> ApiError {
+    ApiError::BadRequest(format!("malformed request: {}", what))
+}
+
+/// Reads fields from a request payload.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ApiError> {
+        if self.buf.len() < len {
+            return Err(malformed("unexpected end of payload"));
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ApiError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ApiError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ApiError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, ApiError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, ApiError> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// A name (cabinet or shelf) with a 16-bit length.
+    pub fn name(&mut self) -> Result<String, ApiError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed("name is not valid UTF-8"))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], ApiError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, ApiError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| malformed("string is not valid UTF-8"))
+    }
+
+    fn number(&mut self) -> Result<Number, ApiError> {
+        let n = Number::from_jsonb(self.bytes()?).map_err(|_| malformed("number is not a JSONB number"))?;
+        Number::new((*n).clone()).map_err(|_| malformed("number is NaN or infinite"))
+    }
+
+    pub fn key(&mut self) -> Result<Key, ApiError> {
+        match self.u8()? {
+            TAG_STRING => Ok(Key::String(self.string()?)),
+            TAG_NUMBER => Ok(Key::Number(self.number()?)),
+            TAG_INT => Ok(Key::Int(Int::from(self.i64()?))),
+            tag => Err(malformed(&format!("unknown key tag {}", tag))),
+        }
+    }
+
+    pub fn keys(&mut self) -> Result<Vec<Key>, ApiError> {
+        let count = self.u32()? as usize;
+        // Every key takes at least five bytes, which bounds what a bogus count
+        // can make us allocate.
+        let mut keys = Vec::with_capacity(count.min(self.buf.len() / 5));
+        for _ in 0..count {
+            keys.push(self.key()?);
+        }
+        Ok(keys)
+    }
+
+    pub fn value(&mut self) -> Result<Value, ApiError> {
+        match self.u8()? {
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_NUMBER => Ok(Value::Number(self.number()?)),
+            TAG_INT => Ok(Value::Int(Int::from(self.i64()?))),
+            TAG_OBJECT => {
+                let bytes = self.bytes()?;
+                if jsonb::RawJsonb::new(bytes).object_keys().ok().flatten().is_none() {
+                    return Err(malformed("object is not a JSONB object"));
+                }
+                Ok(Value::Object(RawObject::from(bytes.to_vec())))
+            }
+            TAG_BYTE => Ok(Value::Byte(self.bytes()?.to_vec())),
+            tag => Err(malformed(&format!("unknown value tag {}", tag))),
+        }
+    }
+
+    pub fn entries(&mut self) -> Result<Vec<(Key, Value)>, ApiError> {
+        let count = self.u32()? as usize;
+        let mut entries = Vec::with_capacity(count.min(self.buf.len() / 10));
+        for _ in 0..count {
+            entries.push((self.key()?, self.value()?));
+        }
+        Ok(entries)
+    }
+
+    /// Fails if anything is left over, which means client and server
+    /// disagree about the request layout.
+    pub fn finish(self) -> Result<(), ApiError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(malformed("trailing bytes"))
+        }
+    }
+}
+
+/// Builds a response payload.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn i64(&mut self, v: i64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, v: bool) -> &mut Self {
+        self.u8(v as u8)
+    }
+
+    /// Collection lengths, which the protocol caps at `u32::MAX`. A longer
+    /// one saturates; its payload is far past the frame limit and is never
+    /// sent.
+    pub fn len(&mut self, len: usize) -> &mut Self {
+        self.u32(u32::try_from(len).unwrap_or(u32::MAX))
+    }
+
+    pub fn name(&mut self, s: &str) -> &mut Self {
+        let bytes = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+        self.u16(bytes.len() as u16);
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) -> &mut Self {
+        self.len(b.len());
+        self.buf.extend_from_slice(b);
+        self
+    }
+
+    fn number(&mut self, n: &Number) -> Result<&mut Self, ApiError> {
+        let owned = jsonb::to_owned_jsonb(&**n).map_err(|e| ApiError::Internal(e.to_string()))?;
+        Ok(self.bytes(&owned.to_vec()))
+    }
+
+    pub fn key(&mut self, key: &Key) -> Result<&mut Self, ApiError> {
+        match key {
+            Key::String(s) => Ok(self.u8(TAG_STRING).bytes(s.as_bytes())),
+            Key::Number(n) => self.u8(TAG_NUMBER).number(n),
+            Key::Int(i) => Ok(self.u8(TAG_INT).i64(**i)),
+        }
+    }
+
+    pub fn value(&mut self, value: &Value) -> Result<&mut Self, ApiError> {
+        match value {
+            Value::String(s) => Ok(self.u8(TAG_STRING).bytes(s.as_bytes())),
+            Value::Number(n) => self.u8(TAG_NUMBER).number(n),
+            Value::Int(i) => Ok(self.u8(TAG_INT).i64(**i)),
+            Value::Object(o) => Ok(self.u8(TAG_OBJECT).bytes(o)),
+            Value::Byte(b) => Ok(self.u8(TAG_BYTE).bytes(b)),
+        }
+    }
+
+    pub fn optional_value(&mut self, value: Option<&Value>) -> Result<&mut Self, ApiError> {
+        match value {
+            Some(value) => self.value(value),
+            None => Ok(self.u8(TAG_NULL)),
+        }
+    }
+
+    /// An error as its stable code and message, the same pair the HTTP API
+    /// returns.
+    pub fn error(&mut self, error: &ApiError) -> &mut Self {
+        self.name(error.code()).bytes(error.message().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(text: &str) -> Number {
+        let owned = jsonb::parse_owned_jsonb(text.as_bytes()).unwrap();
+        Number::new(jsonb::from_raw_jsonb::<jsonb::Number>(&owned.as_raw()).unwrap()).unwrap()
+    }
+
+    fn object(text: &str) -> RawObject {
+        RawObject::from(jsonb::parse_owned_jsonb(text.as_bytes()).unwrap().to_vec())
+    }
+
+    #[test]
+    fn test_keys_round_trip() {
+        let keys = vec![
+            Key::String("alice".to_string()),
+            Key::String(String::new()),
+            Key::Number(number("42")),
+            Key::Number(number("-0.5")),
+            Key::Number(number("123456789012345678901234567890.125")),
+            Key::Int(Int::from(i64::MIN)),
+        ];
+        let mut out = Writer::new();
+        out.len(keys.len());
+        for key in &keys {
+            out.key(key).unwrap();
+        }
+        let buf = out.into_inner();
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.keys().unwrap(), keys);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let entries = vec![
+            (Key::Int(Int::from(1)), Value::String("text".to_string())),
+            (Key::Int(Int::from(2)), Value::Number(number("1e-7"))),
+            (Key::Int(Int::from(3)), Value::Int(Int::from(-7))),
+            (Key::Int(Int::from(4)), Value::Object(object(r#"{"a": [1, {"b": null}]}"#))),
+            (Key::Int(Int::from(5)), Value::Byte(vec![0, 255, 10])),
+        ];
+        let mut out = Writer::new();
+        out.len(entries.len());
+        for (key, value) in &entries {
+            out.key(key).unwrap().value(value).unwrap();
+        }
+        let buf = out.into_inner();
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.entries().unwrap(), entries);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_object_bytes_pass_through() {
+        let stored = object(r#"{"z": 1, "a": "x"}"#);
+        let mut out = Writer::new();
+        out.value(&Value::Object(stored.clone())).unwrap();
+        let buf = out.into_inner();
+        // Tag, length, then the stored JSONB unchanged.
+        assert_eq!(buf[0], TAG_OBJECT);
+        assert_eq!(&buf[5..], &stored[..]);
+        assert_eq!(Reader::new(&buf).value().unwrap(), Value::Object(stored));
+    }
+
+    #[test]
+    fn test_optional_value() {
+        let mut out = Writer::new();
+        out.optional_value(None).unwrap();
+        assert_eq!(out.into_inner(), vec![TAG_NULL]);
+    }
+
+    #[test]
+    fn test_rejects_truncated_and_trailing_bytes() {
+        let mut out = Writer::new();
+        out.name("cabinet").key(&Key::String("key".to_string())).unwrap();
+        let buf = out.into_inner();
+        for end in 0..buf.len() {
+            let mut reader = Reader::new(&buf[..end]);
+            let result = reader.name().and_then(|_| reader.key());
+            assert!(result.is_err(), "accepted {} of {} bytes", end, buf.len());
+        }
+
+        let mut with_trailing = buf.clone();
+        with_trailing.push(0);
+        let mut reader = Reader::new(&with_trailing);
+        reader.name().unwrap();
+        reader.key().unwrap();
+        assert!(reader.finish().is_err());
+    }
+
+    #[test]
+    fn test_rejects_bogus_counts_and_tags() {
+        // Claims four billion keys but holds none.
+        let buf = u32::MAX.to_be_bytes();
+        assert!(Reader::new(&buf).keys().is_err());
+        assert!(Reader::new(&buf).entries().is_err());
+        // A byte string longer than what follows.
+        assert!(Reader::new(&[0, 0, 0, 9, 1, 2]).bytes().is_err());
+
+        assert!(Reader::new(&[9]).key().is_err());
+        assert!(Reader::new(&[TAG_OBJECT, 0, 0, 0, 1, 0]).value().is_err());
+        assert!(Reader::new(&[TAG_STRING, 0, 0, 0, 1, 0xff]).key().is_err());
+        assert!(Reader::new(&[TAG_NUMBER, 0, 0, 0, 1, 0]).key().is_err());
+        assert!(Reader::new(&[TAG_OBJECT, 0, 0, 0, 0]).key().is_err());
+    }
+
+    #[test]
+    fn test_names_and_lengths() {
+        let long = "n".repeat(u16::MAX as usize + 10);
+        let mut out = Writer::new();
+        out.name(&long);
+        let buf = out.into_inner();
+        assert_eq!(Reader::new(&buf).name().unwrap().len(), u16::MAX as usize);
+
+        let mut out = Writer::new();
+        out.len(usize::MAX);
+        assert_eq!(out.into_inner(), u32::MAX.to_be_bytes());
+    }
+
+    #[test]
+    fn test_error() {
+        let mut out = Writer::new();
+        out.error(&ApiError::ShelfNotFound("users".to_string()));
+        let buf = out.into_inner();
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.name().unwrap(), "shelf_not_found");
+        assert_eq!(reader.bytes().unwrap(), b"Shelf 'users' not found");
+        reader.finish().unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+//! Length-prefixed binary protocol over TCP, for clients that want to skip
+//! JSON. Enabled by setting `server.binary_bind`.
+//!
+//! A request frame is `len: u32 | id: u32 | op: u8 | payload`, where `len`
+//! counts everything after itself. The reply is `len: u32 | id: u32 |
+//! status: u8 | payload` with the request's id. Requests on one connection
+//! run concurrently, so clients can pipeline and match replies by id.
+//!
+//! With authentication on, a client first sends an `Auth` frame carrying its
+//! API key or token; until one succeeds, every request but `Ping` fails.
+//! Each request is checked against the scopes the credentials grant.
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+use carmine_core::auth::Access;
+use carmine_core::transaction::{Readable, Writable};
+
+use crate::api::auth::{authenticate_token, Permissions};
+use crate::api::error::ApiError;
+use crate::api::extractors::lookup_shelf;
+use crate::api::normal::commit_write;
+use crate::AppState;
+
+mod codec;
+
+use codec::{Reader, Writer};
+
+/// Largest frame accepted from a client, where a longer one closes the
+/// connection, and largest reply sent back; a longer reply is replaced by
+/// an error.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Largest frame accepted before an `Auth` frame succeeds, enough for a
+/// token but not for a client that hasn't authenticated to make the server
+/// buffer much.
+const MAX_UNAUTHENTICATED_FRAME_LEN: u32 = 64 * 1024;
+
+/// Replies waiting to be written, per connection.
+const OUTBOX_SIZE: usize = 256;
+
+/// Requests one connection may have running at once. The next frame isn't
+/// read until one finishes, so a pipelining client can't buffer more than
+/// this many payloads on the server.
+const MAX_IN_FLIGHT: usize = 64;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Ping = 0x00,
+    Get = 0x01,
+    Set = 0x02,
+    Put = 0x03,
+    Delete = 0x04,
+    Exists = 0x05,
+    Range = 0x06,
+    All = 0x07,
+    Keys = 0x08,
+    Values = 0x09,
+    Count = 0x0a,
+    BatchGet = 0x0b,
+    BatchSet = 0x0c,
+    BatchPut = 0x0d,
+    BatchDelete = 0x0e,
+    Clear = 0x0f,
+    Auth = 0x10,
+}
+
+impl Op {
+    fn from_u8(op: u8) -> Option<Self> {
+        Some(match op {
+            0x00 => Op::Ping,
+            0x01 => Op::Get,
+            0x02 => Op::Set,
+            0x03 => Op::Put,
+            0x04 => Op::Delete,
+            0x05 => Op::Exists,
+            0x06 => Op::Range,
+            0x07 => Op::All,
+            0x08 => Op::Keys,
+            0x09 => Op::Values,
+            0x0a => Op::Count,
+            0x0b => Op::BatchGet,
+            0x0c => Op::BatchSet,
+            0x0d => Op::BatchPut,
+            0x0e => Op::BatchDelete,
+            0x0f => Op::Clear,
+            0x10 => Op::Auth,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Ping => "ping",
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Put => "put",
+            Op::Delete => "delete",
+            Op::Exists => "exists",
+            Op::Range => "range",
+            Op::All => "all",
+            Op::Keys => "keys",
+            Op::Values => "values",
+            Op::Count => "count",
+            Op::BatchGet => "batch_get",
+            Op::BatchSet => "batch_set",
+            Op::BatchPut => "batch_put",
+            Op::BatchDelete => "batch_delete",
+            Op::Clear => "clear",
+            Op::Auth => "auth",
+        }
+    }
+
+    fn access(self) -> Access {
+        match self {
+            Op::Set | Op::Put | Op::Delete | Op::BatchSet | Op::BatchPut | Op::BatchDelete | Op::Clear => {
+                Access::Write
+            }
+            _ => Access::Read,
+        }
+    }
+}
+
+pub async fn serve(state: Arc<AppState>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connection(state.clone(), stream));
+            }
+            Err(e) => tracing::warn!("Binary protocol accept failed: {}", e),
+        }
+    }
+}
+
+async fn connection(state: Arc<AppState>, stream: TcpStream) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (outbox, mut replies) = mpsc::channel::<Vec<u8>>(OUTBOX_SIZE);
+
+    let writing = tokio::spawn(async move {
+        while let Some(frame) = replies.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Set right away while authentication is off.
+    let mut permissions = authenticate_token(&state, None).ok();
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut requests = JoinSet::new();
+    loop {
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            break;
+        };
+        let max_len = if permissions.is_some() {
+            MAX_FRAME_LEN
+        } else {
+            MAX_UNAUTHENTICATED_FRAME_LEN
+        };
+        let (id, op, payload) = match read_frame(&mut reader, max_len).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::debug!("Closing binary protocol connection: {}", e);
+                break;
+            }
+        };
+        // Later requests depend on the outcome, so it's handled in order.
+        if op == Op::Auth as u8 {
+            let reply = match authenticate(&state, Reader::new(&payload)) {
+                Ok(granted) => {
+                    permissions = Some(granted);
+                    frame(id, STATUS_OK, &[])
+                }
+                Err(e) => error_frame(id, &e),
+            };
+            drop(permit);
+            if outbox.send(reply).await.is_err() {
+                break;
+            }
+            continue;
+        }
+        let state = state.clone();
+        let outbox = outbox.clone();
+        let permissions = permissions.clone();
+        requests.spawn(async move {
+            // Requests read and write redb, and writes wait for its single
+            // writer, so they run on the blocking pool rather than holding
+            // up the runtime's workers.
+            let reply = tokio::task::spawn_blocking(move || {
+                handle(&state, permissions.as_ref(), id, op, &payload)
+            })
+            .await
+            .unwrap_or_else(|e| error_frame(id, &ApiError::Internal(e.to_string())));
+            let _ = outbox.send(reply).await;
+            drop(permit);
+        });
+        while requests.try_join_next().is_some() {}
+    }
+
+    // The client may have only closed its write side; finish what it sent.
+    while requests.join_next().await.is_some() {}
+    drop(outbox);
+    let _ = writing.await;
+}
+
+/// Reads one request frame of at most `max_len` bytes. The payload is read
+/// as it arrives rather than allocated at the length the client claims.
+async fn read_frame(
+    reader: &mut (impl AsyncReadExt + Unpin),
+    max_len: u32,
+) -> std::io::Result<Option<(u32, u8, Vec<u8>)>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !(5..=max_len).contains(&len) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame length {} out of range", len),
+        ));
+    }
+    let id = reader.read_u32().await?;
+    let op = reader.read_u8().await?;
+    let payload_len = len as usize - 5;
+    let mut payload = Vec::new();
+    (&mut *reader).take(payload_len as u64).read_to_end(&mut payload).await?;
+    if payload.len() < payload_len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some((id, op, payload)))
+}
+
+/// Frames a reply, or an error if the payload won't fit in a frame.
+fn frame(id: u32, status: u8, payload: &[u8]) -> Vec<u8> {
+    let len = match u32::try_from(payload.len() + 5) {
+        Ok(len) if len <= MAX_FRAME_LEN => len,
+        _ => {
+            return error_frame(
+                id,
+                &ApiError::BadRequest(format!(
+                    "reply of {} bytes exceeds the {} byte frame limit; request fewer entries",
+                    payload.len(),
+                    MAX_FRAME_LEN - 5
+                )),
+            );
+        }
+    };
+    let mut out = Writer::new();
+    out.u32(len).u32(id).u8(status);
+    let mut out = out.into_inner();
+    out.extend_from_slice(payload);
+    out
+}
+
+fn error_frame(id: u32, e: &ApiError) -> Vec<u8> {
+    let mut body = Writer::new();
+    body.error(e);
+    frame(id, STATUS_ERROR, &body.into_inner())
+}
+
+/// An `Auth` payload is the API key or token as a byte string.
+fn authenticate(state: &AppState, mut req: Reader) -> Result<Permissions, ApiError> {
+    let token = req.bytes()?;
+    req.finish()?;
+    let token = std::str::from_utf8(token)
+        .map_err(|_| ApiError::BadRequest("malformed request: token is not valid UTF-8".to_string()))?;
+    authenticate_token(state, Some(token))
+}
+
+fn handle(
+    state: &AppState,
+    permissions: Option<&Permissions>,
+    id: u32,
+    op: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let result = match Op::from_u8(op) {
+        Some(op) => run(state, permissions, op, Reader::new(payload)),
+        None => Err(ApiError::BadRequest(format!("unknown op 0x{:02x}", op))),
+    };
+    match result {
+        Ok(body) => frame(id, STATUS_OK, &body),
+        Err(e) => error_frame(id, &e),
+    }
+}
+
+fn run(
+    state: &AppState,
+    permissions: Option<&Permissions>,
+    op: Op,
+    mut req: Reader,
+) -> Result<Vec<u8>, ApiError> {
+    if op == Op::Ping {
+        req.finish()?;
+        return Ok(Vec::new());
+    }
+    let cabinet = req.name()?;
+    let shelf = req.name()?;
+    permissions
+        .ok_or_else(|| ApiError::Unauthorized("Send an Auth frame first".to_string()))?
+        .check(&cabinet, Some(&shelf), op.access())?;
+    let resolved = lookup_shelf(state, &cabinet, shelf)?;
+    state.metrics.shelf_op("binary", &resolved, op.name());
+    let shelf = &resolved.shelf;
+    let mut out = Writer::new();
+
+    match op {
+        // Answered before resolving a shelf.
+        Op::Ping | Op::Auth => {}
+        Op::Get => {
+            let key = req.key()?;
+            req.finish()?;
+            let value = shelf.get(&resolved.begin_read()?, &key)?;
+            out.optional_value(value.as_ref())?;
+        }
+        Op::Set | Op::Put => {
+            let key = req.key()?;
+            let value = req.value()?;
+            req.finish()?;
+            let tx = resolved.begin_write()?;
+            if op == Op::Set {
+                shelf.set(&tx, key, value)?;
+            } else {
+                shelf.put(&tx, key, value)?;
+            }
+            commit_write(state, &resolved, tx)?;
+        }
+        Op::Delete => {
+            let key = req.key()?;
+            req.finish()?;
+            let tx = resolved.begin_write()?;
+            let existed = shelf.delete(&tx, &key)?;
+            commit_write(state, &resolved, tx)?;
+            out.bool(existed);
+        }
+        Op::Exists => {
+            let key = req.key()?;
+            req.finish()?;
+            out.bool(shelf.exists(&resolved.begin_read()?, &key)?);
+        }
+        Op::Range | Op::All => {
+            let entries = if op == Op::Range {
+                let start = req.key()?;
+                let end = req.key()?;
+                req.finish()?;
+                shelf.get_range(&resolved.begin_read()?, &start, &end)?
+            } else {
+                req.finish()?;
+                shelf.get_all(&resolved.begin_read()?)?
+            };
+            out.len(entries.len());
+            for (key, value) in &entries {
+                out.key(key)?.value(value)?;
+            }
+        }
+        Op::Keys => {
+            req.finish()?;
+            let keys = shelf.keys(&resolved.begin_read()?)?;
+            out.len(keys.len());
+            for key in &keys {
+                out.key(key)?;
+            }
+        }
+        Op::Values => {
+            req.finish()?;
+            let values = shelf.values(&resolved.begin_read()?)?;
+            out.len(values.len());
+            for value in &values {
+                out.value(value)?;
+            }
+        }
+        Op::Count => {
+            req.finish()?;
+            out.u64(shelf.count(&resolved.begin_read()?)?);
+        }
+        Op::BatchGet => {
+            let keys = req.keys()?;
+            req.finish()?;
+            let results = shelf.get_batch(&resolved.begin_read()?, &keys)?;
+            out.len(keys.len());
+            for i in 0..keys.len() {
+                match results.get(i) {
+                    Ok(value) => {
+                        out.u8(STATUS_OK).optional_value(value.as_ref())?;
+                    }
+                    Err(e) => {
+                        out.u8(STATUS_ERROR).error(&e.into());
+                    }
+                }
+            }
+        }
+        Op::BatchSet | Op::BatchPut => {
+            let atomic = req.bool()?;
+            let entries = req.entries()?;
+            req.finish()?;
+            let tx = resolved.begin_write()?;
+            let results = if op == Op::BatchSet {
+                shelf.batch_set(&tx, &entries)?
+            } else {
+                shelf.batch_put(&tx, &entries)?
+            };
+            let applied = !atomic || results.iter().all(|r| r.is_ok());
+            if applied {
+                commit_write(state, &resolved, tx)?;
+            }
+            out.bool(applied).len(results.len());
+            for result in results {
+                match result {
+                    Ok(()) => out.u8(STATUS_OK),
+                    Err(e) => out.u8(STATUS_ERROR).error(&e.into()),
+                };
+            }
+        }
+        Op::BatchDelete => {
+            let keys = req.keys()?;
+            req.finish()?;
+            let tx = resolved.begin_write()?;
+            let existed = shelf.batch_delete(&tx, &keys)?;
+            commit_write(state, &resolved, tx)?;
+            out.len(existed.len());
+            for existed in existed {
+                out.bool(existed);
+            }
+        }
+        Op::Clear => {
+            req.finish()?;
+            let tx = resolved.begin_write()?;
+            let removed = shelf.clear(&tx)?;
+            commit_write(state, &resolved, tx)?;
+            out.u64(removed);
+        }
+    }
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8], max_len: u32) -> std::io::Result<Option<(u32, u8, Vec<u8>)>> {
+        let mut reader = input;
+        read_frame(&mut reader, max_len).await
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let reply = frame(7, STATUS_OK, b"payload");
+        assert_eq!(&reply[..4], &12u32.to_be_bytes());
+        assert_eq!(read(&reply, MAX_FRAME_LEN).await.unwrap(), Some((7, STATUS_OK, b"payload".to_vec())));
+        assert_eq!(read(&frame(8, STATUS_OK, &[]), MAX_FRAME_LEN).await.unwrap(), Some((8, STATUS_OK, Vec::new())));
+        assert_eq!(read(&[], MAX_FRAME_LEN).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_limits() {
+        // Shorter than the id and op.
+        let short = [0, 0, 0, 4, 0, 0, 0, 1];
+        assert_eq!(read(&short, MAX_FRAME_LEN).await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        let request = frame(1, Op::Ping as u8, &[0; 100]);
+        assert!(read(&request, 105).await.unwrap().is_some());
+        assert_eq!(read(&request, 104).await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        // A claimed length far beyond what arrives fails at the end of input.
+        let mut claimed = (MAX_FRAME_LEN).to_be_bytes().to_vec();
+        claimed.extend_from_slice(&[0, 0, 0, 1, 0, 1, 2, 3]);
+        assert_eq!(read(&claimed, MAX_FRAME_LEN).await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_reply_becomes_an_error() {
+        let payload = vec![0; MAX_FRAME_LEN as usize - 4];
+        let reply = frame(3, STATUS_OK, &payload);
+        let (id, status, body) = read(&reply, MAX_FRAME_LEN).await.unwrap().unwrap();
+        assert_eq!((id, status), (3, STATUS_ERROR));
+        assert_eq!(Reader::new(&body).name().unwrap(), "bad_request");
+
+        let largest = frame(4, STATUS_OK, &payload[1..]);
+        assert_eq!(largest.len(), MAX_FRAME_LEN as usize + 4);
+    }
+}
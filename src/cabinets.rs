@@ -0,0 +1,431 @@
+//! The open cabinet handles, bounded by `[cache]`.
+//!
+//! Every open cabinet keeps a redb database, its file and its page cache
+//! alive, so handles are closed again once more than `max_open_cabinets`
+//! are open, least recently used first, and after sitting unused for
+//! `idle_timeout_secs`. A handle is only closed while nobody else holds a
+//! clone of it, which is checked under the map's lock so a request can't
+//! take one in between; the limit can briefly be exceeded while every
+//! handle is in use. Closing flushes the database, so requests that go over
+//! the limit leave it to a background task instead of closing handles
+//! themselves.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::Notify;
+
+use carmine_core::cabinet::Cabinet;
+
+use crate::{AppState, CachedCabinet};
+
+struct OpenCabinet {
+    cabinet: Cabinet,
+    /// Milliseconds since [`OpenCabinets::epoch`] at the last lookup.
+    last_used: AtomicU64,
+}
+
+pub struct OpenCabinets {
+    handles: DashMap<u64, OpenCabinet>,
+    epoch: Instant,
+    /// Page cache size each cabinet is opened with.
+    cache_size: usize,
+    max_open: Option<usize>,
+    idle_timeout: Option<Duration>,
+    /// Handles closed by the limit or the idle timeout.
+    closed: AtomicU64,
+    /// Cabinets whose handle was taken out of the map and is being dropped.
+    closing: Mutex<HashSet<u64>>,
+    /// Woken when a cabinet leaves `closing`.
+    closed_one: Condvar,
+    /// Woken when more than `max_open` handles are open.
+    over_limit: Notify,
+}
+
+impl OpenCabinets {
+    pub fn new(cache_size: usize, max_open: Option<usize>, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            handles: DashMap::new(),
+            epoch: Instant::now(),
+            cache_size,
+            max_open,
+            idle_timeout,
+            closed: AtomicU64::new(0),
+            closing: Mutex::new(HashSet::new()),
+            closed_one: Condvar::new(),
+            over_limit: Notify::new(),
+        }
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    pub fn get(&self, id: u64) -> Option<Cabinet> {
+        let handle = self.handles.get(&id)?;
+        handle.last_used.store(self.now(), Ordering::Relaxed);
+        Some(handle.cabinet.clone())
+    }
+
+    /// Like [`get`](Self::get), but doesn't count as a use, for background
+    /// checks that shouldn't keep the cabinet open.
+    pub fn peek(&self, id: u64) -> Option<Cabinet> {
+        self.handles.get(&id).map(|handle| handle.cabinet.clone())
+    }
+
+    /// Marks the cabinet as used, for lookups that reuse a handle they got
+    /// earlier.
+    pub fn touch(&self, id: u64) {
+        if let Some(handle) = self.handles.get(&id) {
+            handle.last_used.store(self.now(), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the open handle, or the one `open` returns given the cache
+    /// size. Opening holds the map's lock, so two requests can't both try to
+    /// open the file. A handle to it that is closing is waited for first,
+    /// without the lock.
+    pub fn get_or_open<E>(&self, id: u64, open: impl FnOnce(usize) -> Result<Cabinet, E>) -> Result<Cabinet, E> {
+        loop {
+            self.wait_until_closed(id);
+            let handle = match self.handles.entry(id) {
+                Entry::Occupied(entry) => entry.into_ref(),
+                // Closing starts under the map's lock, so one that began
+                // since the wait shows up here.
+                Entry::Vacant(_) if self.is_closing(id) => continue,
+                Entry::Vacant(entry) => {
+                    let cabinet = open(self.cache_size)?;
+                    entry.insert(OpenCabinet { cabinet, last_used: AtomicU64::new(0) })
+                }
+            };
+            handle.last_used.store(self.now(), Ordering::Relaxed);
+            return Ok(handle.cabinet.clone());
+        }
+    }
+
+    fn closing(&self) -> MutexGuard<'_, HashSet<u64>> {
+        // Nothing panics while holding it, so a poisoned set is still valid.
+        self.closing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_closing(&self, id: u64) -> bool {
+        self.closing().contains(&id)
+    }
+
+    /// Blocks while a handle to the cabinet is being dropped. Lookups run
+    /// on the runtime's workers, so a wait that can't return right away
+    /// hands the worker's other tasks to another thread first.
+    fn wait_until_closed(&self, id: u64) {
+        if !self.is_closing(id) {
+            return;
+        }
+        let wait = || {
+            let closing = self.closing();
+            drop(self.closed_one.wait_while(closing, |closing| closing.contains(&id)));
+        };
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(wait)
+            }
+            _ => wait(),
+        }
+    }
+
+    pub fn insert(&self, cabinet: Cabinet) {
+        let handle = OpenCabinet { last_used: AtomicU64::new(self.now()), cabinet };
+        self.handles.insert(handle.cabinet.id, handle);
+    }
+
+    pub fn remove(&self, id: u64) -> Option<Cabinet> {
+        self.handles.remove(&id).map(|(_, handle)| handle.cabinet)
+    }
+
+    pub fn rename(&self, id: u64, name: &str) {
+        if let Some(mut handle) = self.handles.get_mut(&id) {
+            handle.cabinet.name = name.to_string();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Clones of every open handle.
+    pub fn cabinets(&self) -> Vec<Cabinet> {
+        self.handles.iter().map(|handle| handle.cabinet.clone()).collect()
+    }
+
+    pub fn closed_total(&self) -> u64 {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Open handles ordered from least to most recently used.
+    fn by_last_use(&self) -> Vec<(u64, String, u64)> {
+        let mut handles: Vec<_> = self
+            .handles
+            .iter()
+            .map(|handle| (*handle.key(), handle.cabinet.name.clone(), handle.last_used.load(Ordering::Relaxed)))
+            .collect();
+        handles.sort_by_key(|&(_, _, last_used)| last_used);
+        handles
+    }
+
+    /// Closes the handle of `id` unless someone else holds it. The cabinet
+    /// is marked as closing before the map is unlocked, so
+    /// [`get_or_open`](Self::get_or_open) doesn't open the file again until
+    /// the database is dropped.
+    fn close_if_unused(&self, id: u64) -> bool {
+        let removed = self.handles.remove_if(&id, |_, handle| {
+            let unused = handle.cabinet.is_exclusive();
+            if unused {
+                self.closing().insert(id);
+            }
+            unused
+        });
+        let Some((_, handle)) = removed else {
+            return false;
+        };
+        drop(handle);
+        self.closing().remove(&id);
+        self.closed_one.notify_all();
+        self.closed.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl AppState {
+    /// Closes the cabinet's handle unless a request is using it. Cached
+    /// metadata holds a clone of the handle, so it's taken out first; if the
+    /// handle stays open the entry is rebuilt around it, unless it was
+    /// invalidated meanwhile.
+    fn close_cabinet(&self, id: u64, name: &str) -> bool {
+        let generation = self.metadata_generation.load(Ordering::SeqCst);
+        let kept = match self.metadata.remove_if(name, |_, cached| cached.cabinet.id == id) {
+            Some((_, cached)) => match Arc::try_unwrap(cached) {
+                Ok(CachedCabinet { cabinet, shelves, cipher }) => {
+                    drop(cabinet);
+                    Some((shelves, cipher))
+                }
+                // A request holds the metadata, so the handle is in use.
+                Err(cached) => {
+                    self.cache_metadata(name, generation, cached);
+                    return false;
+                }
+            },
+            None => None,
+        };
+        let closed = self.cabinets.close_if_unused(id);
+        if closed {
+            tracing::debug!("Closed cabinet '{}'", name);
+        } else if let Some((shelves, cipher)) = kept
+            && let Some(cabinet) = self.cabinets.peek(id)
+        {
+            self.cache_metadata(name, generation, Arc::new(CachedCabinet { cabinet, shelves, cipher }));
+        }
+        closed
+    }
+
+    /// Has the background task close handles if more than
+    /// `max_open_cabinets` are open.
+    pub fn check_open_limit(&self) {
+        if self.cabinets.max_open.is_some_and(|max_open| self.cabinets.len() > max_open) {
+            self.cabinets.over_limit.notify_one();
+        }
+    }
+
+    /// Closes least recently used handles until no more than
+    /// `max_open_cabinets` are open, skipping those in use.
+    fn enforce_open_limit(&self) {
+        let Some(max_open) = self.cabinets.max_open else {
+            return;
+        };
+        let mut excess = self.cabinets.len().saturating_sub(max_open);
+        if excess == 0 {
+            return;
+        }
+        for (id, name, _) in self.cabinets.by_last_use() {
+            if excess == 0 {
+                break;
+            }
+            if self.close_cabinet(id, &name) {
+                excess -= 1;
+            }
+        }
+    }
+
+    /// Closes the handles unused for longer than `idle_timeout_secs`.
+    fn close_idle_cabinets(&self, idle_timeout: Duration) {
+        let cutoff = self.cabinets.now().saturating_sub(idle_timeout.as_millis() as u64);
+        for (id, name, last_used) in self.cabinets.by_last_use() {
+            if last_used > cutoff {
+                break;
+            }
+            self.close_cabinet(id, &name);
+        }
+    }
+}
+
+/// Enforces `max_open_cabinets` whenever a request goes over it.
+pub(crate) fn spawn_open_limit(state: Arc<AppState>) {
+    if state.cabinets.max_open.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            state.cabinets.over_limit.notified().await;
+            let state = state.clone();
+            let result = tokio::task::spawn_blocking(move || state.enforce_open_limit()).await;
+            if let Err(e) = result {
+                tracing::warn!("Closing cabinets over the limit failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Closes idle cabinets every half `idle_timeout_secs`, and retries the open
+/// limit for handles that were in use when it was last exceeded.
+pub(crate) fn spawn_idle_close(state: Arc<AppState>) {
+    let Some(idle_timeout) = state.cabinets.idle_timeout else {
+        return;
+    };
+    tokio::spawn(async move {
+        let period = (idle_timeout / 2).max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let state = state.clone();
+            // Closing a database flushes it to disk.
+            let result = tokio::task::spawn_blocking(move || {
+                state.close_idle_cabinets(idle_timeout);
+                state.enforce_open_limit();
+            })
+            .await;
+            if let Err(e) = result {
+                tracing::warn!("Closing idle cabinets failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    use carmine_core::meta::CabinetMeta;
+
+    use crate::api::admin::create_cabinet;
+    use crate::api::extractors::cached_cabinet;
+    use crate::test_util::temp_state;
+
+    /// Creates cabinets far enough apart that their last uses differ.
+    fn create(state: &AppState, names: &[&str]) -> Vec<CabinetMeta> {
+        names
+            .iter()
+            .map(|name| {
+                std::thread::sleep(Duration::from_millis(5));
+                create_cabinet(state, name.to_string(), None).unwrap()
+            })
+            .collect()
+    }
+
+    fn is_open(state: &AppState, meta: &CabinetMeta) -> bool {
+        state.cabinets.peek(meta.id).is_some()
+    }
+
+    #[test]
+    fn test_limit_closes_least_recently_used_first() {
+        let (_dir, state) = temp_state(Some(2), None);
+        let metas = create(&state, &["a", "b", "c", "d"]);
+        std::thread::sleep(Duration::from_millis(5));
+        state.cabinets.touch(metas[0].id);
+
+        state.enforce_open_limit();
+        assert_eq!(state.cabinets.len(), 2);
+        assert!(is_open(&state, &metas[0]));
+        assert!(!is_open(&state, &metas[1]));
+        assert!(!is_open(&state, &metas[2]));
+        assert!(is_open(&state, &metas[3]));
+        assert_eq!(state.cabinets.closed_total(), 2);
+
+        // Closed cabinets open again on their next lookup.
+        assert_eq!(cached_cabinet(&state, "b").unwrap().cabinet.id, metas[1].id);
+        assert!(is_open(&state, &metas[1]));
+    }
+
+    #[test]
+    fn test_busy_handle_is_not_closed() {
+        let (_dir, state) = temp_state(Some(1), None);
+        let mut metas = create(&state, &["a", "b"]);
+        // A request holding the cached metadata, and one holding just the
+        // handle.
+        let request = cached_cabinet(&state, "a").unwrap();
+        let handle = state.cabinets.peek(metas[1].id).unwrap();
+        cached_cabinet(&state, "b").unwrap();
+        metas.extend(create(&state, &["c"]));
+
+        state.enforce_open_limit();
+        assert!(is_open(&state, &metas[0]));
+        assert!(is_open(&state, &metas[1]));
+        assert!(!is_open(&state, &metas[2]));
+        // The metadata taken out to close "b" is cached again around the
+        // handle that stayed open.
+        assert_eq!(state.metadata.get("a").unwrap().cabinet.id, metas[0].id);
+        assert_eq!(state.metadata.get("b").unwrap().cabinet.id, metas[1].id);
+
+        drop(request);
+        drop(handle);
+        state.enforce_open_limit();
+        assert_eq!(state.cabinets.len(), 1);
+    }
+
+    #[test]
+    fn test_idle_cabinets_are_closed() {
+        let idle_timeout = Duration::from_millis(50);
+        let (_dir, state) = temp_state(None, Some(idle_timeout));
+        let metas = create(&state, &["idle", "used"]);
+        std::thread::sleep(idle_timeout * 2);
+        cached_cabinet(&state, "used").unwrap();
+
+        state.close_idle_cabinets(idle_timeout);
+        assert!(!is_open(&state, &metas[0]));
+        assert!(is_open(&state, &metas[1]));
+        assert_eq!(state.cabinets.closed_total(), 1);
+    }
+
+    #[test]
+    fn test_get_or_open_waits_for_close() {
+        let (_dir, state) = temp_state(None, None);
+        let meta = create(&state, &["closing"]).remove(0);
+        state.cabinets.remove(meta.id);
+        // As if another thread were still dropping the database.
+        state.cabinets.closing().insert(meta.id);
+
+        let opened = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let lookup = scope.spawn(|| {
+                state.cabinets.get_or_open(meta.id, |cache_size| {
+                    opened.store(true, Ordering::SeqCst);
+                    Cabinet::open(meta.id, meta.name.clone(), meta.path.clone(), cache_size)
+                })
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!opened.load(Ordering::SeqCst));
+
+            state.cabinets.closing().remove(&meta.id);
+            state.cabinets.closed_one.notify_all();
+            assert_eq!(lookup.join().unwrap().unwrap().id, meta.id);
+        });
+        assert!(opened.load(Ordering::SeqCst));
+        assert!(is_open(&state, &meta));
+    }
+}
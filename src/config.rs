@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use carmine_core::auth::Scope;
+use carmine_core::crypto::{Algorithm, MasterKey};
+use carmine_core::jwt::{JwtOptions, JwtVerifier};
+
 #[derive(Parser, Debug, Clone)]
 #[command(name = "carmine", about = "A key-value store for the web")]
 pub struct CliArgs {
@@ -19,9 +24,36 @@ pub struct CliArgs {
     #[arg(short, long, env = "CARMINE_BIND", value_name = "ADDR")]
     pub bind: Option<String>,
 
+    #[arg(long, env = "CARMINE_BINARY_BIND", value_name = "ADDR")]
+    pub binary_bind: Option<String>,
+
+    #[arg(long, env = "CARMINE_GRPC_BIND", value_name = "ADDR")]
+    pub grpc_bind: Option<String>,
+
+    #[arg(long, env = "CARMINE_RESP_BIND", value_name = "ADDR")]
+    pub resp_bind: Option<String>,
+
+    #[arg(long, env = "CARMINE_RESP_CABINET", value_name = "NAME")]
+    pub resp_cabinet: Option<String>,
+
+    #[arg(long, env = "CARMINE_RESP_SHELF", value_name = "NAME")]
+    pub resp_shelf: Option<String>,
+
+    #[arg(long, env = "CARMINE_AUTH_MODE", value_name = "MODE")]
+    pub auth_mode: Option<String>,
+
+    #[arg(long, env = "CARMINE_MASTER_KEY", value_name = "KEY", hide_env_values = true)]
+    pub master_key: Option<String>,
+
+    #[arg(long, env = "CARMINE_ENCRYPTION_KEY_FILE", value_name = "FILE")]
+    pub encryption_key_file: Option<PathBuf>,
+
     #[arg(long, env = "CARMINE_CABINET_CACHE_SIZE", value_name = "SIZE")]
     pub cabinet_cache: Option<usize>,
 
+    #[arg(long, env = "CARMINE_MAX_OPEN_CABINETS", value_name = "COUNT")]
+    pub max_open_cabinets: Option<usize>,
+
     #[arg(long, env = "CARMINE_SYSTEM_CACHE_SIZE", value_name = "SIZE")]
     pub system_cache: Option<usize>,
 
@@ -36,6 +68,41 @@ pub struct CliArgs {
 #[serde(default)]
 pub struct ServerConfig {
     pub bind: String,
+    /// Address for the binary protocol listener; off when unset.
+    pub binary_bind: Option<String>,
+    /// Address for the gRPC service; off when unset.
+    pub grpc_bind: Option<String>,
+    /// Address for the Redis-compatible listener; off when unset.
+    pub resp_bind: Option<String>,
+    /// Cabinet that Redis clients work in.
+    pub resp_cabinet: Option<String>,
+    /// Shelf Redis connections start on, until they `SELECT` another.
+    pub resp_shelf: Option<String>,
+    /// Serves HTTPS on `bind` instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// PEM CA certificates that client certificates are checked against.
+    /// Clients aren't asked for a certificate when unset.
+    pub client_ca: Option<PathBuf>,
+    /// `required` or `optional`: whether a client without a certificate may
+    /// still connect when `client_ca` is set.
+    #[serde(default = "default_client_auth")]
+    pub client_auth: String,
+    /// Scopes for each client certificate common name, used in
+    /// `client_cert` auth mode.
+    #[serde(default)]
+    pub client_scopes: HashMap<String, Vec<Scope>>,
+}
+
+fn default_client_auth() -> String {
+    "required".into()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,8 +115,71 @@ pub struct StorageConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct CacheConfig {
+    /// Page cache of each open cabinet.
     pub cabinet_size: usize,
     pub system_size: usize,
+    /// Cabinets kept open at once; the least recently used are closed
+    /// beyond it. Unlimited when unset.
+    pub max_open_cabinets: Option<usize>,
+    /// Closes cabinets no request has used for this long; off when unset.
+    pub idle_timeout_secs: Option<u64>,
+    /// Page cache shared by all open cabinets, split evenly across
+    /// `max_open_cabinets` in place of `cabinet_size`.
+    pub cabinet_budget: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// `none`, `api_key`, `jwt` or `client_cert`.
+    pub mode: String,
+    /// Accepted as a `system:admin` key, so the first API keys can be
+    /// created.
+    pub master_key: Option<String>,
+    pub jwt: JwtConfig,
+}
+
+/// Keys and checks for `jwt` mode. Any mix of keys may be given; a token is
+/// accepted if one of them verifies it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    /// Shared secret for HS256 tokens.
+    pub secret: Option<String>,
+    /// PEM files holding RSA (RS256) or Ed25519 (EdDSA) public keys.
+    pub public_key_files: Vec<PathBuf>,
+    /// A JWKS document, as served by most identity providers.
+    pub jwks_file: Option<PathBuf>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// Claim holding the token's scopes.
+    pub scopes_claim: String,
+    pub leeway_secs: u64,
+}
+
+/// Encryption at rest. Cabinets created while a master key is configured
+/// are encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// File holding the 32-byte master key, raw or as hex or base64.
+    pub master_key_file: Option<PathBuf>,
+    /// Algorithm for new cabinets; existing ones keep theirs.
+    pub algorithm: Algorithm,
+}
+
+/// Scheduled compaction, off unless `interval_secs` is set. A cabinet is
+/// compacted once at least `min_fragmented_bytes` of its file are free
+/// pages, and those make up at least `min_fragmented_ratio` of the file.
+/// Only cabinets open when the check runs are looked at; closed ones aren't
+/// reopened for it and are checked once requests open them again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    /// How often to check every open cabinet.
+    pub interval_secs: Option<u64>,
+    pub min_fragmented_bytes: u64,
+    pub min_fragmented_ratio: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,6 +194,9 @@ pub struct ConfigFile {
     pub server: ServerConfig,
     pub storage: StorageConfig,
     pub cache: CacheConfig,
+    pub auth: AuthConfig,
+    pub encryption: EncryptionConfig,
+    pub compaction: CompactionConfig,
     pub logging: LoggingConfig,
 }
 
@@ -71,6 +204,12 @@ impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             bind: "0.0.0.0:3000".into(),
+            binary_bind: None,
+            grpc_bind: None,
+            resp_bind: None,
+            resp_cabinet: None,
+            resp_shelf: None,
+            tls: None,
         }
     }
 }
@@ -89,6 +228,53 @@ impl Default for CacheConfig {
         Self {
             cabinet_size: 64 * 1024 * 1024,
             system_size: 8 * 1024 * 1024,
+            max_open_cabinets: None,
+            idle_timeout_secs: None,
+            cabinet_budget: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: "none".into(),
+            master_key: None,
+            jwt: JwtConfig::default(),
+        }
+    }
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        let options = JwtOptions::default();
+        Self {
+            secret: None,
+            public_key_files: Vec::new(),
+            jwks_file: None,
+            issuer: options.issuer,
+            audience: options.audience,
+            scopes_claim: options.scopes_claim,
+            leeway_secs: options.leeway_secs,
+        }
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            master_key_file: None,
+            algorithm: Algorithm::Aes256Gcm,
+        }
+    }
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: None,
+            min_fragmented_bytes: 64 * 1024 * 1024,
+            min_fragmented_ratio: 0.5,
         }
     }
 }
@@ -105,12 +291,40 @@ impl Default for LoggingConfig {
 pub struct Config {
     pub data_dir: PathBuf,
     pub bind: String,
+    pub binary_bind: Option<String>,
+    pub grpc_bind: Option<String>,
+    pub resp_bind: Option<String>,
+    pub resp_cabinet: Option<String>,
+    pub resp_shelf: Option<String>,
+    pub tls: Option<TlsConfig>,
     pub cabinet_cache_size: usize,
     pub system_cache_size: usize,
+    pub max_open_cabinets: Option<usize>,
+    pub cabinet_idle_timeout_secs: Option<u64>,
+    pub cabinet_budget: Option<usize>,
     pub durability: Durability,
+    pub auth_mode: AuthMode,
+    pub master_key: Option<String>,
+    pub jwt: JwtConfig,
+    pub encryption_key_file: Option<PathBuf>,
+    pub encryption_algorithm: Algorithm,
+    pub compaction: CompactionConfig,
     pub log_level: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    /// Every request is allowed.
+    None,
+    /// Requests need an API key with a matching scope.
+    ApiKey,
+    /// Requests need a signed JWT whose claims carry a matching scope.
+    Jwt,
+    /// Requests need a TLS client certificate whose common name is mapped
+    /// to a matching scope.
+    ClientCert,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Durability {
     Immediate,
@@ -130,20 +344,160 @@ impl Config {
 
         let file = file.unwrap_or_default();
 
-        Ok(Self::merge(cli, file))
+        let config = Self::merge(cli, file)?;
+        config.validate()?;
+        Ok(config)
     }
 
-    fn merge(cli: CliArgs, file: ConfigFile) -> Self {
-        Self {
+    fn merge(cli: CliArgs, file: ConfigFile) -> Result<Self, ConfigError> {
+        Ok(Self {
             data_dir: cli.data_dir.unwrap_or(file.storage.data_dir),
             bind: cli.bind.unwrap_or(file.server.bind),
+            binary_bind: cli.binary_bind.or(file.server.binary_bind),
+            grpc_bind: cli.grpc_bind.or(file.server.grpc_bind),
+            resp_bind: cli.resp_bind.or(file.server.resp_bind),
+            resp_cabinet: cli.resp_cabinet.or(file.server.resp_cabinet),
+            resp_shelf: cli.resp_shelf.or(file.server.resp_shelf),
+            tls: file.server.tls,
             cabinet_cache_size: cli.cabinet_cache.unwrap_or(file.cache.cabinet_size),
             system_cache_size: cli.system_cache.unwrap_or(file.cache.system_size),
+            max_open_cabinets: cli.max_open_cabinets.or(file.cache.max_open_cabinets),
+            cabinet_idle_timeout_secs: file.cache.idle_timeout_secs,
+            cabinet_budget: file.cache.cabinet_budget,
             durability: parse_durability(&cli.durability.unwrap_or(file.storage.durability)),
+            auth_mode: parse_auth_mode(&cli.auth_mode.unwrap_or(file.auth.mode))?,
+            master_key: cli.master_key.or(file.auth.master_key),
+            jwt: file.auth.jwt,
+            encryption_key_file: cli.encryption_key_file.or(file.encryption.master_key_file),
+            encryption_algorithm: file.encryption.algorithm,
+            compaction: file.compaction,
             log_level: cli.log_level.unwrap_or(file.logging.level),
+        })
+    }
+
+    /// Rejects settings that can't work together, including listeners that
+    /// can't authenticate in the configured mode: every listener checks API
+    /// keys and tokens, but only the HTTP listener terminates TLS and so
+    /// sees client certificates.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(tls) = &self.tls
+            && !matches!(tls.client_auth.as_str(), "required" | "optional")
+        {
+            return Err(ConfigError::Invalid(format!(
+                "unknown server.tls.client_auth '{}', expected 'required' or 'optional'",
+                tls.client_auth
+            )));
+        }
+        if self.max_open_cabinets == Some(0) {
+            return Err(ConfigError::Invalid(
+                "cache.max_open_cabinets must be greater than 0".to_string(),
+            ));
+        }
+        if self.cabinet_idle_timeout_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "cache.idle_timeout_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.cabinet_budget.is_some() && self.max_open_cabinets.is_none() {
+            return Err(ConfigError::Invalid(
+                "cache.cabinet_budget needs cache.max_open_cabinets".to_string(),
+            ));
+        }
+        if self.compaction.interval_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "compaction.interval_secs must be greater than 0".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.compaction.min_fragmented_ratio) {
+            return Err(ConfigError::Invalid(
+                "compaction.min_fragmented_ratio must be between 0 and 1".to_string(),
+            ));
+        }
+        if self.auth_mode == AuthMode::ClientCert
+            && self.tls.as_ref().is_none_or(|tls| tls.client_ca.is_none())
+        {
+            return Err(ConfigError::Invalid(
+                "auth mode 'client_cert' needs server.tls.client_ca".to_string(),
+            ));
+        }
+        if self.auth_mode != AuthMode::ClientCert {
+            return Ok(());
+        }
+        // These listeners don't terminate TLS, so they never see a client
+        // certificate.
+        let without_tls = [
+            ("server.binary_bind", &self.binary_bind),
+            ("server.grpc_bind", &self.grpc_bind),
+            ("server.resp_bind", &self.resp_bind),
+        ];
+        for (name, bind) in without_tls {
+            if bind.is_some() {
+                return Err(ConfigError::Invalid(format!(
+                    "{} can't be used with auth mode 'client_cert'",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the keys configured for `jwt` mode.
+    pub fn jwt_verifier(&self) -> Result<JwtVerifier, ConfigError> {
+        let jwt = &self.jwt;
+        let mut verifier = JwtVerifier::new(JwtOptions {
+            issuer: jwt.issuer.clone(),
+            audience: jwt.audience.clone(),
+            scopes_claim: jwt.scopes_claim.clone(),
+            leeway_secs: jwt.leeway_secs,
+        });
+        if let Some(secret) = &jwt.secret {
+            verifier.add_secret(secret.as_bytes());
+        }
+        for path in &jwt.public_key_files {
+            let pem = std::fs::read(path).map_err(|e| ConfigError::ReadKey(path.clone(), e))?;
+            verifier
+                .add_pem(&pem)
+                .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e)))?;
+        }
+        if let Some(path) = &jwt.jwks_file {
+            let json = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadKey(path.clone(), e))?;
+            verifier
+                .add_jwks(&json)
+                .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e)))?;
+        }
+        if !verifier.has_keys() {
+            return Err(ConfigError::Invalid(
+                "auth mode 'jwt' needs auth.jwt.secret, public_key_files or a jwks_file with usable keys"
+                    .to_string(),
+            ));
+        }
+        Ok(verifier)
+    }
+
+    /// Loads the master key for encryption at rest, if one is configured.
+    pub fn encryption_key(&self) -> Result<Option<MasterKey>, ConfigError> {
+        let Some(path) = &self.encryption_key_file else {
+            return Ok(None);
+        };
+        let contents = std::fs::read(path).map_err(|e| ConfigError::ReadKey(path.clone(), e))?;
+        MasterKey::from_file_contents(&contents)
+            .map(Some)
+            .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The page cache each cabinet is opened with: its share of
+    /// `cabinet_budget` when one is set.
+    pub fn per_cabinet_cache_size(&self) -> usize {
+        match (self.cabinet_budget, self.max_open_cabinets) {
+            (Some(budget), Some(max_open)) => budget / max_open,
+            _ => self.cabinet_cache_size,
         }
     }
 
+    pub fn cabinet_idle_timeout(&self) -> Option<std::time::Duration> {
+        self.cabinet_idle_timeout_secs.map(std::time::Duration::from_secs)
+    }
+
     pub fn redb_durability(&self) -> redb::Durability {
         match self.durability {
             Durability::Immediate => redb::Durability::Immediate,
@@ -159,6 +513,19 @@ fn parse_durability(s: &str) -> Durability {
     }
 }
 
+fn parse_auth_mode(s: &str) -> Result<AuthMode, ConfigError> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(AuthMode::None),
+        "api_key" => Ok(AuthMode::ApiKey),
+        "jwt" => Ok(AuthMode::Jwt),
+        "client_cert" => Ok(AuthMode::ClientCert),
+        other => Err(ConfigError::Invalid(format!(
+            "unknown auth mode '{}', expected 'none', 'api_key', 'jwt' or 'client_cert'",
+            other
+        ))),
+    }
+}
+
 fn find_config_file() -> Option<PathBuf> {
     let candidates = ["carmine.toml", "config.toml", ".carmine.toml"];
     for name in candidates {
@@ -181,4 +548,8 @@ pub enum ConfigError {
     Read(PathBuf, std::io::Error),
     #[error("Failed to parse config file {0}: {1}")]
     Parse(PathBuf, String),
+    #[error("Failed to read key file {0}: {1}")]
+    ReadKey(PathBuf, std::io::Error),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
 }
@@ -0,0 +1,179 @@
+//! Encryption at rest, configured by `[encryption]`.
+//!
+//! Cabinets created while a master key is configured are encrypted, each
+//! with its own keys derived from the master key (see
+//! [`carmine_core::crypto`]). Rotating a cabinet's key bumps its key version
+//! right away, so new writes use the new key, and re-encrypts what is
+//! already stored in a background task. Values sealed with an older key stay
+//! readable in the meantime.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::api::error::ApiError;
+use crate::AppState;
+use carmine_core::cabinet::Cabinet;
+use carmine_core::changelog;
+use carmine_core::crypto::{Algorithm, MasterKey, ValueCipher};
+use carmine_core::meta::CabinetMeta;
+use carmine_core::shelf::Shelf;
+
+/// Entries re-encrypted per write transaction, so a rotation never holds a
+/// cabinet's write lock for long.
+const RESEAL_CHUNK: usize = 1000;
+
+/// How long a rotation waits before trying a chunk again while the cabinet
+/// is under maintenance.
+const MAINTENANCE_RETRY: Duration = Duration::from_secs(1);
+
+pub struct Encryption {
+    pub master_key: Arc<MasterKey>,
+    /// Used for cabinets created from now on.
+    pub algorithm: Algorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a cabinet's most recent key rotation.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyRotation {
+    pub key_version: u32,
+    pub state: RotationState,
+    /// Entries and change records re-encrypted so far.
+    pub resealed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The cipher for `meta`'s values, or `None` if the cabinet isn't encrypted.
+pub(crate) fn cabinet_cipher(
+    state: &AppState,
+    meta: &CabinetMeta,
+) -> Result<Option<Arc<ValueCipher>>, ApiError> {
+    let Some(settings) = meta.encryption else {
+        return Ok(None);
+    };
+    let encryption = state.encryption.as_ref().ok_or_else(|| {
+        ApiError::Internal(format!(
+            "Cabinet '{}' is encrypted but no master key is configured",
+            meta.name
+        ))
+    })?;
+    encryption.master_key.verify(meta.id, settings.key_check)
+        .map_err(|e| ApiError::Internal(format!("Cabinet '{}': {}", meta.name, e)))?;
+    Ok(Some(Arc::new(ValueCipher::new(
+        encryption.master_key.clone(),
+        settings.algorithm,
+        meta.id,
+        settings.key_version,
+    ))))
+}
+
+/// Fails if the master key isn't the one some encrypted cabinet was created
+/// with, so a wrong key is caught at startup rather than on first use.
+pub(crate) fn check_master_key(state: &AppState) -> Result<(), ApiError> {
+    let Some(encryption) = &state.encryption else {
+        return Ok(());
+    };
+    for meta in state.system_store.list_cabinets()? {
+        if let Some(settings) = meta.encryption {
+            encryption.master_key.verify(meta.id, settings.key_check)
+                .map_err(|e| ApiError::Internal(format!("Cabinet '{}': {}", meta.name, e)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-encrypts everything in the cabinet that isn't sealed with `meta`'s
+/// current key, recording progress in `state.key_rotations`.
+pub(crate) fn spawn_rotation(state: Arc<AppState>, meta: CabinetMeta) {
+    tokio::task::spawn_blocking(move || {
+        let result = reseal_cabinet(&state, &meta);
+        if let Some(mut rotation) = state.key_rotations.get_mut(&meta.id) {
+            match result {
+                Ok(()) => rotation.state = RotationState::Done,
+                Err(e) => {
+                    tracing::warn!("Key rotation of cabinet '{}' failed: {}", meta.name, e.message());
+                    rotation.state = RotationState::Failed;
+                    rotation.error = Some(e.message());
+                }
+            }
+        }
+    });
+}
+
+fn reseal_cabinet(state: &AppState, meta: &CabinetMeta) -> Result<(), ApiError> {
+    let cipher = cabinet_cipher(state, meta)?
+        .ok_or_else(|| ApiError::Internal(format!("Cabinet '{}' is not encrypted", meta.name)))?;
+    let progress = |count: u64| {
+        if let Some(mut rotation) = state.key_rotations.get_mut(&meta.id) {
+            rotation.resealed += count;
+        }
+    };
+
+    // A request that loaded the cabinet's metadata just before the rotation
+    // can still write with the old key after the first pass went by, so
+    // passes repeat until one finds nothing left to do.
+    loop {
+        let mut resealed = 0;
+        let shelves = state
+            .system_store
+            .get_cabinet(meta.id)?
+            .ok_or_else(|| ApiError::CabinetNotFound(meta.name.clone()))?
+            .shelves;
+        for shelf_meta in &shelves {
+            let shelf = Shelf::from(shelf_meta).with_cipher(Some(cipher.clone()));
+            let mut after = None;
+            loop {
+                let cabinet = chunk_handle(state, meta.id)?;
+                let txn = cabinet.database().begin_write()
+                    .map_err(|e| ApiError::Internal(e.to_string()))?;
+                let (next, count) = shelf.reseal(&txn, after.as_ref(), RESEAL_CHUNK)?;
+                txn.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+                progress(count);
+                resealed += count;
+                match next {
+                    Some(key) => after = Some(key),
+                    None => break,
+                }
+            }
+        }
+        let mut after = 0;
+        loop {
+            let cabinet = chunk_handle(state, meta.id)?;
+            let txn = cabinet.database().begin_write()
+                .map_err(|e| ApiError::Internal(e.to_string()))?;
+            let (next, count) = changelog::reseal(&txn, &cipher, after, RESEAL_CHUNK)?;
+            txn.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+            progress(count);
+            resealed += count;
+            match next {
+                Some(seq) => after = seq,
+                None => break,
+            }
+        }
+        if resealed == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// The cabinet's handle for one chunk. It's looked up afresh each time so
+/// that compaction and the open limit can get in between chunks; while the
+/// cabinet is under maintenance, the rotation waits.
+fn chunk_handle(state: &AppState, id: u64) -> Result<Cabinet, ApiError> {
+    loop {
+        match state.cabinet_by_id(id) {
+            Err(ApiError::CabinetBusy(_)) => std::thread::sleep(MAINTENANCE_RETRY),
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Conversions between the protobuf messages and the core types.
+//!
+//! `Number`s that aren't plain 64-bit integers or floats travel as decimal
+//! text so they survive the round trip, and `Object` values travel as JSON
+//! text.
+
+use carmine_core::{
+    auth::ApiKeyMeta,
+    changelog::{Change, Retention},
+    integrity::{IntegrityReport, InvalidEntry, TableTypes},
+    key::{Key, KeyType},
+    meta::{CabinetMeta, ShelfMeta},
+    shelf::migrate::MigrationFailure,
+    stats::{TableStats, TreeStats},
+    types::{Int, Number, RawObject},
+    value::{Value, ValueType},
+};
+
+use super::pb;
+use crate::api::admin::{CabinetCheck, CabinetUsage, EncryptionStatus};
+use crate::api::error::ApiError;
+use crate::encryption::RotationState;
+
+fn missing(field: &str) -> ApiError {
+    ApiError::BadRequest(format!("missing field '{}'", field))
+}
+
+fn number_from_pb(number: pb::Number) -> Result<Number, ApiError> {
+    let n = match number.kind.ok_or_else(|| missing("number"))? {
+        pb::number::Kind::Int64(n) => jsonb::Number::Int64(n),
+        pb::number::Kind::Uint64(n) => jsonb::Number::UInt64(n),
+        pb::number::Kind::Float64(n) => jsonb::Number::Float64(n),
+        pb::number::Kind::Decimal(text) => jsonb::parse_owned_jsonb(text.as_bytes())
+            .ok()
+            .and_then(|owned| jsonb::from_raw_jsonb::<jsonb::Number>(&owned.as_raw()).ok())
+            .ok_or_else(|| ApiError::BadRequest(format!("'{}' is not a number", text)))?,
+    };
+    Number::new(n).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+fn number_to_pb(number: &Number) -> pb::Number {
+    let kind = match &**number {
+        jsonb::Number::Int64(n) => pb::number::Kind::Int64(*n),
+        jsonb::Number::UInt64(n) => pb::number::Kind::Uint64(*n),
+        jsonb::Number::Float64(n) => pb::number::Kind::Float64(*n),
+        n => pb::number::Kind::Decimal(n.to_string()),
+    };
+    pb::Number { kind: Some(kind) }
+}
+
+pub fn key_from_pb(key: Option<pb::Key>, field: &str) -> Result<Key, ApiError> {
+    match key.and_then(|key| key.kind).ok_or_else(|| missing(field))? {
+        pb::key::Kind::String(s) => Ok(Key::String(s)),
+        pb::key::Kind::Number(n) => Ok(Key::Number(number_from_pb(n)?)),
+        pb::key::Kind::Int(i) => Ok(Key::Int(Int::from(i))),
+    }
+}
+
+pub fn keys_from_pb(keys: Vec<pb::Key>) -> Result<Vec<Key>, ApiError> {
+    keys.into_iter().map(|key| key_from_pb(Some(key), "keys")).collect()
+}
+
+pub fn key_to_pb(key: &Key) -> pb::Key {
+    let kind = match key {
+        Key::String(s) => pb::key::Kind::String(s.clone()),
+        Key::Number(n) => pb::key::Kind::Number(number_to_pb(n)),
+        Key::Int(i) => pb::key::Kind::Int(**i),
+    };
+    pb::Key { kind: Some(kind) }
+}
+
+pub fn value_from_pb(value: Option<pb::Value>, field: &str) -> Result<Value, ApiError> {
+    match value.and_then(|value| value.kind).ok_or_else(|| missing(field))? {
+        pb::value::Kind::String(s) => Ok(Value::String(s)),
+        pb::value::Kind::Number(n) => Ok(Value::Number(number_from_pb(n)?)),
+        pb::value::Kind::Int(i) => Ok(Value::Int(Int::from(i))),
+        pb::value::Kind::Object(text) => {
+            let owned = jsonb::parse_owned_jsonb(text.as_bytes())
+                .map_err(|e| ApiError::BadRequest(format!("{}: {}", field, e)))?;
+            if owned.as_raw().object_keys().ok().flatten().is_none() {
+                return Err(ApiError::BadRequest(format!("{}: not a JSON object", field)));
+            }
+            Ok(Value::Object(RawObject::from(owned.to_vec())))
+        }
+        pb::value::Kind::Byte(b) => Ok(Value::Byte(b)),
+    }
+}
+
+pub fn value_to_pb(value: &Value) -> pb::Value {
+    let kind = match value {
+        Value::String(s) => pb::value::Kind::String(s.clone()),
+        Value::Number(n) => pb::value::Kind::Number(number_to_pb(n)),
+        Value::Int(i) => pb::value::Kind::Int(**i),
+        Value::Object(o) => pb::value::Kind::Object(jsonb::RawJsonb::new(o).to_string()),
+        Value::Byte(b) => pb::value::Kind::Byte(b.clone()),
+    };
+    pb::Value { kind: Some(kind) }
+}
+
+pub fn entries_from_pb(entries: Vec<pb::Entry>) -> Result<Vec<(Key, Value)>, ApiError> {
+    entries
+        .into_iter()
+        .map(|entry| Ok((key_from_pb(entry.key, "key")?, value_from_pb(entry.value, "value")?)))
+        .collect()
+}
+
+pub fn entry_to_pb((key, value): &(Key, Value)) -> pb::Entry {
+    pb::Entry { key: Some(key_to_pb(key)), value: Some(value_to_pb(value)) }
+}
+
+pub fn error_to_pb(error: &ApiError) -> pb::Error {
+    pb::Error { code: error.code().to_string(), message: error.message() }
+}
+
+/// `None` for `KEY_TYPE_UNSPECIFIED`.
+pub fn key_type_from_pb(key_type: i32) -> Result<Option<KeyType>, ApiError> {
+    match pb::KeyType::try_from(key_type) {
+        Ok(pb::KeyType::Unspecified) => Ok(None),
+        Ok(pb::KeyType::String) => Ok(Some(KeyType::String)),
+        Ok(pb::KeyType::Number) => Ok(Some(KeyType::Number)),
+        Ok(pb::KeyType::Int) => Ok(Some(KeyType::Int)),
+        Err(_) => Err(ApiError::BadRequest(format!("unknown key type {}", key_type))),
+    }
+}
+
+/// `None` for `VALUE_TYPE_UNSPECIFIED`.
+pub fn value_type_from_pb(value_type: i32) -> Result<Option<ValueType>, ApiError> {
+    match pb::ValueType::try_from(value_type) {
+        Ok(pb::ValueType::Unspecified) => Ok(None),
+        Ok(pb::ValueType::String) => Ok(Some(ValueType::String)),
+        Ok(pb::ValueType::Number) => Ok(Some(ValueType::Number)),
+        Ok(pb::ValueType::Int) => Ok(Some(ValueType::Int)),
+        Ok(pb::ValueType::Object) => Ok(Some(ValueType::Object)),
+        Ok(pb::ValueType::Byte) => Ok(Some(ValueType::Byte)),
+        Err(_) => Err(ApiError::BadRequest(format!("unknown value type {}", value_type))),
+    }
+}
+
+fn key_type_to_pb(key_type: KeyType) -> pb::KeyType {
+    match key_type {
+        KeyType::String => pb::KeyType::String,
+        KeyType::Number => pb::KeyType::Number,
+        KeyType::Int => pb::KeyType::Int,
+    }
+}
+
+fn value_type_to_pb(value_type: ValueType) -> pb::ValueType {
+    match value_type {
+        ValueType::String => pb::ValueType::String,
+        ValueType::Number => pb::ValueType::Number,
+        ValueType::Int => pb::ValueType::Int,
+        ValueType::Object => pb::ValueType::Object,
+        ValueType::Byte => pb::ValueType::Byte,
+    }
+}
+
+pub fn shelf_to_pb(shelf: &ShelfMeta) -> pb::Shelf {
+    pb::Shelf {
+        name: shelf.name.clone(),
+        key_type: key_type_to_pb(shelf.key_type).into(),
+        value_type: value_type_to_pb(shelf.value_type).into(),
+    }
+}
+
+pub fn cabinet_to_pb(cabinet: &CabinetMeta) -> pb::Cabinet {
+    pb::Cabinet {
+        id: cabinet.id,
+        name: cabinet.name.clone(),
+        path: cabinet.path.display().to_string(),
+        shelves: cabinet.shelves.iter().map(shelf_to_pb).collect(),
+    }
+}
+
+pub fn failure_to_pb(failure: &MigrationFailure) -> pb::MigrationFailure {
+    pb::MigrationFailure { key: Some(key_to_pb(&failure.key)), error: failure.error.clone() }
+}
+
+pub fn retention_from_pb(retention: Option<pb::ChangeRetention>) -> Retention {
+    let retention = retention.unwrap_or_default();
+    Retention { max_entries: retention.max_entries, max_age_secs: retention.max_age_secs }
+}
+
+pub fn retention_to_pb(retention: &Retention) -> pb::ChangeRetention {
+    pb::ChangeRetention {
+        max_entries: retention.max_entries,
+        max_age_secs: retention.max_age_secs,
+    }
+}
+
+pub fn encryption_to_pb(status: &EncryptionStatus) -> pb::EncryptionStatus {
+    pb::EncryptionStatus {
+        algorithm: status.encryption.algorithm.as_str().to_string(),
+        key_version: status.encryption.key_version,
+        key_check: status.encryption.key_check,
+        rotation: status.rotation.as_ref().map(|rotation| pb::KeyRotation {
+            key_version: rotation.key_version,
+            state: match rotation.state {
+                RotationState::Running => pb::RotationState::Running,
+                RotationState::Done => pb::RotationState::Done,
+                RotationState::Failed => pb::RotationState::Failed,
+            }
+            .into(),
+            resealed: rotation.resealed,
+            error: rotation.error.clone(),
+        }),
+    }
+}
+
+pub fn api_key_to_pb(key: &ApiKeyMeta) -> pb::ApiKey {
+    pb::ApiKey {
+        id: key.id,
+        name: key.name.clone(),
+        scopes: key.scopes.iter().map(ToString::to_string).collect(),
+        created_at: key.created_at,
+        prefix: key.prefix.clone(),
+    }
+}
+
+fn tree_stats_to_pb(tree: &TreeStats) -> pb::TreeStats {
+    pb::TreeStats {
+        tree_height: tree.tree_height,
+        leaf_pages: tree.leaf_pages,
+        branch_pages: tree.branch_pages,
+        stored_bytes: tree.stored_bytes,
+        metadata_bytes: tree.metadata_bytes,
+        fragmented_bytes: tree.fragmented_bytes,
+    }
+}
+
+fn table_stats_to_pb(table: &TableStats, name: String) -> pb::TableStats {
+    pb::TableStats {
+        name,
+        entries: table.entries,
+        tree: Some(tree_stats_to_pb(&table.tree)),
+    }
+}
+
+pub fn cabinet_stats_to_pb(usage: &CabinetUsage) -> pb::CabinetStats {
+    let stats = &usage.stats;
+    pb::CabinetStats {
+        file_size: usage.file_size,
+        last_write: stats.last_write_ms,
+        allocated_pages: stats.database.allocated_pages,
+        page_size: stats.database.page_size as u64,
+        database: Some(tree_stats_to_pb(&stats.database.tree)),
+        shelves: stats
+            .shelves
+            .iter()
+            .map(|shelf| table_stats_to_pb(shelf, shelf.name.clone()))
+            .collect(),
+        change_log: Some(table_stats_to_pb(&stats.change_log, String::new())),
+    }
+}
+
+fn table_types_to_pb(types: TableTypes) -> pb::TableTypes {
+    pb::TableTypes {
+        key_type: key_type_to_pb(types.key_type).into(),
+        value_type: value_type_to_pb(types.value_type).into(),
+    }
+}
+
+fn invalid_entry_to_pb(entry: InvalidEntry) -> pb::InvalidEntry {
+    pb::InvalidEntry {
+        shelf: entry.shelf,
+        key: Some(key_to_pb(&entry.key)),
+        error: entry.error,
+    }
+}
+
+pub fn cabinet_check_to_pb(outcome: CabinetCheck) -> pb::CheckCabinetResponse {
+    let clean = outcome.check.report.is_clean();
+    let IntegrityReport {
+        orphan_tables,
+        missing_tables,
+        mismatched_tables,
+        invalid_entries,
+        invalid_entry_count,
+        undecryptable_entries,
+        undecryptable_entry_count,
+        sealed_entry_count: _,
+    } = outcome.check.report;
+    pb::CheckCabinetResponse {
+        clean,
+        file_repaired: outcome.check.file_repaired,
+        orphan_tables: orphan_tables
+            .into_iter()
+            .map(|table| pb::OrphanTable {
+                name: table.name,
+                entries: table.entries,
+                types: table.types.map(table_types_to_pb),
+            })
+            .collect(),
+        missing_tables,
+        mismatched_tables: mismatched_tables
+            .into_iter()
+            .map(|table| pb::MismatchedTable {
+                shelf: table.shelf,
+                expected: Some(table_types_to_pb(table.expected)),
+                found: table.found.map(table_types_to_pb),
+            })
+            .collect(),
+        invalid_entries: invalid_entries.into_iter().map(invalid_entry_to_pb).collect(),
+        invalid_entry_count,
+        undecryptable_entries: undecryptable_entries.into_iter().map(invalid_entry_to_pb).collect(),
+        undecryptable_entry_count,
+        repairs: outcome.repairs.map(|repairs| pb::CabinetRepairs {
+            created_tables: repairs.created_tables,
+            removed_entries: repairs.removed_entries,
+            adopted_tables: repairs.adopted_tables,
+            retyped_shelves: repairs.retyped_shelves,
+        }),
+    }
+}
+
+pub fn change_to_pb(change: &Change) -> pb::Change {
+    pb::Change {
+        seq: change.seq,
+        timestamp: change.timestamp_ms,
+        op: change.op.as_str().to_string(),
+        shelf: change.shelf.clone(),
+        key: change.key.as_ref().map(key_to_pb),
+        value: change.value.as_ref().map(value_to_pb),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(kind: pb::number::Kind) -> pb::Number {
+        pb::Number { kind: Some(kind) }
+    }
+
+    fn key(kind: pb::key::Kind) -> Option<pb::Key> {
+        Some(pb::Key { kind: Some(kind) })
+    }
+
+    fn value(kind: pb::value::Kind) -> Option<pb::Value> {
+        Some(pb::Value { kind: Some(kind) })
+    }
+
+    #[test]
+    fn test_keys_round_trip() {
+        let keys = [
+            pb::key::Kind::String("alice".to_string()),
+            pb::key::Kind::Int(-3),
+            pb::key::Kind::Number(number(pb::number::Kind::Int64(-42))),
+            pb::key::Kind::Number(number(pb::number::Kind::Uint64(u64::MAX))),
+            pb::key::Kind::Number(number(pb::number::Kind::Float64(0.25))),
+        ];
+        for kind in keys {
+            let key = key_from_pb(key(kind.clone()), "key").unwrap();
+            assert_eq!(key_to_pb(&key).kind, Some(kind));
+        }
+    }
+
+    fn decimal_key(text: &str) -> Result<Key, ApiError> {
+        key_from_pb(key(pb::key::Kind::Number(number(pb::number::Kind::Decimal(text.to_string())))), "key")
+    }
+
+    #[test]
+    fn test_decimal_numbers() {
+        let big = decimal_key("123456789012345678901234567890.125").unwrap();
+        let Some(pb::key::Kind::Number(pb::Number { kind: Some(kind) })) = key_to_pb(&big).kind else {
+            panic!("not a number key");
+        };
+        // Comes back as the same number, in whichever form keeps it exact.
+        assert_eq!(key_from_pb(key(pb::key::Kind::Number(number(kind))), "key").unwrap(), big);
+
+        let plain = decimal_key("-7").unwrap();
+        assert_eq!(key_to_pb(&plain).kind, Some(pb::key::Kind::Number(number(pb::number::Kind::Int64(-7)))));
+
+        for bad in ["", "abc", "\"7\"", "[1]"] {
+            assert!(matches!(decimal_key(bad), Err(ApiError::BadRequest(_))), "accepted {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let values = [
+            pb::value::Kind::String("text".to_string()),
+            pb::value::Kind::Int(i64::MAX),
+            pb::value::Kind::Number(number(pb::number::Kind::Float64(-1.5))),
+            pb::value::Kind::Byte(vec![0, 1, 255]),
+        ];
+        for kind in values {
+            let value = value_from_pb(value(kind.clone()), "value").unwrap();
+            assert_eq!(value_to_pb(&value).kind, Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_object_values() {
+        let stored = value_from_pb(value(pb::value::Kind::Object(r#"{"a": [1, 2], "b": {"c": null}}"#.to_string())), "value")
+            .unwrap();
+        let Some(pb::value::Kind::Object(text)) = value_to_pb(&stored).kind else {
+            panic!("not an object value");
+        };
+        assert_eq!(value_from_pb(value(pb::value::Kind::Object(text)), "value").unwrap(), stored);
+
+        for bad in ["", "[1, 2]", "3", "{\"a\":", "null"] {
+            let result = value_from_pb(value(pb::value::Kind::Object(bad.to_string())), "value");
+            assert!(matches!(result, Err(ApiError::BadRequest(_))), "accepted {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let message = |result: Result<Key, ApiError>| result.unwrap_err().message();
+        assert_eq!(message(key_from_pb(None, "start")), "missing field 'start'");
+        assert_eq!(message(key_from_pb(Some(pb::Key { kind: None }), "start")), "missing field 'start'");
+        assert_eq!(
+            message(key_from_pb(key(pb::key::Kind::Number(pb::Number { kind: None })), "start")),
+            "missing field 'number'"
+        );
+        assert!(value_from_pb(Some(pb::Value { kind: None }), "value").is_err());
+
+        let entries = vec![pb::Entry { key: key(pb::key::Kind::Int(1)), value: None }];
+        assert_eq!(entries_from_pb(entries).unwrap_err().message(), "missing field 'value'");
+    }
+}
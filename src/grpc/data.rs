@@ -0,0 +1,401 @@
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_util::stream::{self, Stream, StreamExt};
+use redb::ReadableDatabase;
+use tonic::{Request, Response, Status};
+
+use carmine_core::{
+    auth::Access,
+    key::{Key, KeyType},
+    transaction::{Readable, TransactionError, Writable},
+    value::Value,
+};
+
+use super::convert::{
+    change_to_pb, entries_from_pb, entry_to_pb, error_to_pb, key_from_pb, key_to_pb, keys_from_pb,
+    value_from_pb, value_to_pb,
+};
+use super::{pb, permissions, resolve};
+use crate::api::auth::Permissions;
+use crate::api::changes::{current_value, wait_for_changes, WatchTarget};
+use crate::api::error::ApiError;
+use crate::api::extractors::ResolvedShelf;
+use crate::api::normal::commit_write;
+use crate::AppState;
+
+type EntryStream = Pin<Box<dyn Stream<Item = Result<pb::Entry, Status>> + Send>>;
+
+pub struct DataService {
+    state: Arc<AppState>,
+}
+
+impl DataService {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+
+    fn batch_write(
+        &self,
+        permissions: &Permissions,
+        req: pb::BatchWriteRequest,
+        put: bool,
+    ) -> Result<pb::BatchWriteResponse, ApiError> {
+        let op = if put { "batch_put" } else { "batch_set" };
+        let resolved = resolve(&self.state, permissions, req.shelf, op, Access::Write)?;
+        let entries = entries_from_pb(req.entries)?;
+        let tx = resolved.begin_write()?;
+        let results = if put {
+            resolved.shelf.batch_put(&tx, &entries)?
+        } else {
+            resolved.shelf.batch_set(&tx, &entries)?
+        };
+        let applied = !req.atomic || results.iter().all(|r| r.is_ok());
+        if applied {
+            commit_write(&self.state, &resolved, tx)?;
+        }
+        Ok(pb::BatchWriteResponse {
+            applied,
+            results: results
+                .into_iter()
+                .map(|result| pb::BatchItemResult {
+                    error: result.err().map(|e| error_to_pb(&e.into())),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Entries read from the shelf at a time.
+const STREAM_PAGE: usize = 256;
+
+/// Streams the entries between `start` and `end` a page at a time. Each
+/// page is read once the client has taken the previous one, in its own
+/// read transaction on a handle looked up for that read, so a slow client
+/// holds neither a snapshot nor the cabinet open; entries written meanwhile
+/// past the last key sent are picked up. The first page is read here so a
+/// bad request fails before the stream starts.
+fn entry_stream(
+    state: &Arc<AppState>,
+    resolved: ResolvedShelf,
+    start: Bound<Key>,
+    end: Bound<Key>,
+) -> Result<EntryStream, ApiError> {
+    let first = resolved.shelf.get_page(&resolved.begin_read()?, start.as_ref(), end.as_ref(), STREAM_PAGE)?;
+    let ResolvedShelf { cabinet, shelf } = resolved;
+    let cabinet_id = cabinet.id;
+    drop(cabinet);
+
+    let after = next_page_start(&first);
+    let state = state.clone();
+    let rest = stream::unfold(after, move |after| {
+        let state = state.clone();
+        let shelf = shelf.clone();
+        let end = end.clone();
+        async move {
+            let after = after?;
+            let page = state.cabinet_by_id(cabinet_id).and_then(|cabinet| {
+                let tx = cabinet.database().begin_read().map_err(TransactionError::from)?;
+                Ok(shelf.get_page(&tx, Bound::Excluded(&after), end.as_ref(), STREAM_PAGE)?)
+            });
+            let next = page.as_deref().ok().and_then(next_page_start);
+            Some((page, next))
+        }
+    });
+    let pages = stream::once(async { Ok(first) }).chain(rest);
+    Ok(Box::pin(pages.flat_map(page_entries)))
+}
+
+fn page_entries(
+    page: Result<Vec<(Key, Value)>, ApiError>,
+) -> stream::Iter<std::vec::IntoIter<Result<pb::Entry, Status>>> {
+    stream::iter(match page {
+        Ok(page) => page.iter().map(entry_to_pb).map(Ok).collect(),
+        Err(e) => vec![Err(e.into())],
+    })
+}
+
+/// The key the page after `page` starts after, if there may be one.
+fn next_page_start(page: &[(Key, Value)]) -> Option<Key> {
+    page.last().filter(|_| page.len() == STREAM_PAGE).map(|(key, _)| key.clone())
+}
+
+#[tonic::async_trait]
+impl pb::data_server::Data for DataService {
+    async fn get(
+        &self,
+        request: Request<pb::GetRequest>,
+    ) -> Result<Response<pb::GetResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "get", Access::Read)?;
+        let key = key_from_pb(req.key, "key")?;
+        let value = resolved.shelf.get(&resolved.begin_read()?, &key).map_err(ApiError::from)?;
+        Ok(Response::new(pb::GetResponse { value: value.as_ref().map(value_to_pb) }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<pb::SetRequest>,
+    ) -> Result<Response<pb::SetResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "set", Access::Write)?;
+        let key = key_from_pb(req.key, "key")?;
+        let value = value_from_pb(req.value, "value")?;
+        let tx = resolved.begin_write()?;
+        resolved.shelf.set(&tx, key, value).map_err(ApiError::from)?;
+        commit_write(&self.state, &resolved, tx)?;
+        Ok(Response::new(pb::SetResponse {}))
+    }
+
+    async fn put(
+        &self,
+        request: Request<pb::PutRequest>,
+    ) -> Result<Response<pb::PutResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "put", Access::Write)?;
+        let key = key_from_pb(req.key, "key")?;
+        let value = value_from_pb(req.value, "value")?;
+        let tx = resolved.begin_write()?;
+        resolved.shelf.put(&tx, key, value).map_err(ApiError::from)?;
+        commit_write(&self.state, &resolved, tx)?;
+        Ok(Response::new(pb::PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<pb::DeleteRequest>,
+    ) -> Result<Response<pb::DeleteResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "delete", Access::Write)?;
+        let key = key_from_pb(req.key, "key")?;
+        let tx = resolved.begin_write()?;
+        let existed = resolved.shelf.delete(&tx, &key).map_err(ApiError::from)?;
+        commit_write(&self.state, &resolved, tx)?;
+        Ok(Response::new(pb::DeleteResponse { existed }))
+    }
+
+    async fn exists(
+        &self,
+        request: Request<pb::ExistsRequest>,
+    ) -> Result<Response<pb::ExistsResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "exists", Access::Read)?;
+        let key = key_from_pb(req.key, "key")?;
+        let exists = resolved.shelf.exists(&resolved.begin_read()?, &key).map_err(ApiError::from)?;
+        Ok(Response::new(pb::ExistsResponse { exists }))
+    }
+
+    async fn count(
+        &self,
+        request: Request<pb::ShelfRef>,
+    ) -> Result<Response<pb::CountResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let resolved = resolve(&self.state, &permissions, Some(request.into_inner()), "count", Access::Read)?;
+        let count = resolved.shelf.count(&resolved.begin_read()?).map_err(ApiError::from)?;
+        Ok(Response::new(pb::CountResponse { count }))
+    }
+
+    async fn keys(
+        &self,
+        request: Request<pb::ShelfRef>,
+    ) -> Result<Response<pb::KeysResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let resolved = resolve(&self.state, &permissions, Some(request.into_inner()), "keys", Access::Read)?;
+        let keys = resolved.shelf.keys(&resolved.begin_read()?).map_err(ApiError::from)?;
+        Ok(Response::new(pb::KeysResponse { keys: keys.iter().map(key_to_pb).collect() }))
+    }
+
+    async fn values(
+        &self,
+        request: Request<pb::ShelfRef>,
+    ) -> Result<Response<pb::ValuesResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let resolved = resolve(&self.state, &permissions, Some(request.into_inner()), "values", Access::Read)?;
+        let values = resolved.shelf.values(&resolved.begin_read()?).map_err(ApiError::from)?;
+        Ok(Response::new(pb::ValuesResponse { values: values.iter().map(value_to_pb).collect() }))
+    }
+
+    type RangeStream = EntryStream;
+
+    async fn range(
+        &self,
+        request: Request<pb::RangeRequest>,
+    ) -> Result<Response<Self::RangeStream>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "range", Access::Read)?;
+        let start = key_from_pb(req.start, "start")?;
+        let end = key_from_pb(req.end, "end")?;
+        if resolved.shelf.key_type == KeyType::Number {
+            return Err(ApiError::from(TransactionError::RangeNotSupported).into());
+        }
+        Ok(Response::new(entry_stream(&self.state, resolved, Bound::Included(start), Bound::Excluded(end))?))
+    }
+
+    type AllStream = EntryStream;
+
+    async fn all(
+        &self,
+        request: Request<pb::ShelfRef>,
+    ) -> Result<Response<Self::AllStream>, Status> {
+        let permissions = permissions(&request)?;
+        let resolved = resolve(&self.state, &permissions, Some(request.into_inner()), "all", Access::Read)?;
+        Ok(Response::new(entry_stream(&self.state, resolved, Bound::Unbounded, Bound::Unbounded)?))
+    }
+
+    async fn batch_get(
+        &self,
+        request: Request<pb::BatchGetRequest>,
+    ) -> Result<Response<pb::BatchGetResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "batch_get", Access::Read)?;
+        let keys = keys_from_pb(req.keys)?;
+        let values = resolved
+            .shelf
+            .get_batch(&resolved.begin_read()?, &keys)
+            .map_err(ApiError::from)?;
+        let results = (0..keys.len())
+            .map(|i| match values.get(i) {
+                Ok(value) => pb::BatchGetResult { value: value.as_ref().map(value_to_pb), error: None },
+                Err(e) => pb::BatchGetResult { value: None, error: Some(error_to_pb(&e.into())) },
+            })
+            .collect();
+        Ok(Response::new(pb::BatchGetResponse { results }))
+    }
+
+    async fn batch_set(
+        &self,
+        request: Request<pb::BatchWriteRequest>,
+    ) -> Result<Response<pb::BatchWriteResponse>, Status> {
+        let permissions = permissions(&request)?;
+        Ok(Response::new(self.batch_write(&permissions, request.into_inner(), false)?))
+    }
+
+    async fn batch_put(
+        &self,
+        request: Request<pb::BatchWriteRequest>,
+    ) -> Result<Response<pb::BatchWriteResponse>, Status> {
+        let permissions = permissions(&request)?;
+        Ok(Response::new(self.batch_write(&permissions, request.into_inner(), true)?))
+    }
+
+    async fn batch_delete(
+        &self,
+        request: Request<pb::BatchDeleteRequest>,
+    ) -> Result<Response<pb::BatchDeleteResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "batch_delete", Access::Write)?;
+        let keys = keys_from_pb(req.keys)?;
+        let tx = resolved.begin_write()?;
+        let existed = resolved.shelf.batch_delete(&tx, &keys).map_err(ApiError::from)?;
+        commit_write(&self.state, &resolved, tx)?;
+        Ok(Response::new(pb::BatchDeleteResponse { existed }))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<pb::WatchRequest>,
+    ) -> Result<Response<pb::WatchResponse>, Status> {
+        let permissions = permissions(&request)?;
+        let req = request.into_inner();
+        let resolved = resolve(&self.state, &permissions, req.shelf, "watch", Access::Read)?;
+        let key = req.key.map(|key| key_from_pb(Some(key), "key")).transpose()?;
+        let start = req.start.map(|key| key_from_pb(Some(key), "start")).transpose()?;
+        let end = req.end.map(|key| key_from_pb(Some(key), "end")).transpose()?;
+        let target = WatchTarget::from_keys(key, start, end, resolved.shelf.key_type)?;
+
+        let (resolved, outcome) =
+            wait_for_changes(&self.state, resolved, &target, req.since_version, req.timeout).await?;
+        let value = match &target {
+            WatchTarget::Key(key) => current_value(&resolved, key)?,
+            WatchTarget::Range(..) => None,
+        };
+        Ok(Response::new(pb::WatchResponse {
+            version: outcome.version,
+            changes: outcome.changes.iter().map(change_to_pb).collect(),
+            truncated: outcome.truncated,
+            value: value.as_ref().map(value_to_pb),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_shelf, temp_state};
+    use carmine_core::types::Int;
+
+    fn int(n: i64) -> Key {
+        Key::Int(Int::from(n))
+    }
+
+    fn write(resolved: &ResolvedShelf, keys: std::ops::Range<i64>) {
+        let tx = resolved.begin_write().unwrap();
+        for n in keys {
+            resolved.shelf.set(&tx, int(n), Value::String(n.to_string())).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    async fn keys(stream: EntryStream) -> Vec<Key> {
+        stream
+            .map(|entry| key_from_pb(entry.unwrap().key, "key").unwrap())
+            .collect()
+            .await
+    }
+
+    #[test]
+    fn test_next_page_start() {
+        let page: Vec<_> = (0..STREAM_PAGE as i64).map(|n| (int(n), Value::String(String::new()))).collect();
+        assert_eq!(next_page_start(&page), Some(int(STREAM_PAGE as i64 - 1)));
+        assert_eq!(next_page_start(&page[1..]), None);
+        assert_eq!(next_page_start(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_entry_stream_pages() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "stream", "entries");
+        let total = 2 * STREAM_PAGE as i64 + 10;
+        write(&resolved, 0..total);
+
+        let all = entry_stream(&state, resolved.clone(), Bound::Unbounded, Bound::Unbounded).unwrap();
+        assert_eq!(keys(all).await, (0..total).map(int).collect::<Vec<_>>());
+
+        // Ends exactly on a page boundary, then one short of and one past it.
+        for (end, expected) in [
+            (Bound::Excluded(int(2 * STREAM_PAGE as i64)), 0..2 * STREAM_PAGE as i64),
+            (Bound::Excluded(int(STREAM_PAGE as i64 - 1)), 0..STREAM_PAGE as i64 - 1),
+            (Bound::Included(int(STREAM_PAGE as i64)), 0..STREAM_PAGE as i64 + 1),
+        ] {
+            let stream = entry_stream(&state, resolved.clone(), Bound::Unbounded, end).unwrap();
+            assert_eq!(keys(stream).await, expected.map(int).collect::<Vec<_>>());
+        }
+
+        let tail = entry_stream(&state, resolved.clone(), Bound::Excluded(int(total - 3)), Bound::Unbounded).unwrap();
+        assert_eq!(keys(tail).await, vec![int(total - 2), int(total - 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_entry_stream_reads_pages_as_taken() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "stream", "entries");
+        write(&resolved, 0..STREAM_PAGE as i64);
+
+        let mut stream = entry_stream(&state, resolved.clone(), Bound::Unbounded, Bound::Unbounded).unwrap();
+        for _ in 0..STREAM_PAGE {
+            stream.next().await.unwrap().unwrap();
+        }
+        // Written after the first page was read but before the next one is.
+        write(&resolved, STREAM_PAGE as i64..STREAM_PAGE as i64 + 2);
+        assert_eq!(keys(stream).await, vec![int(STREAM_PAGE as i64), int(STREAM_PAGE as i64 + 1)]);
+    }
+}
@@ -0,0 +1,151 @@
+//! gRPC service defined in `proto/carmine.proto`. Enabled by setting
+//! `server.grpc_bind`.
+//!
+//! `System` covers the `/system` endpoints and `Data` the
+//! `/v1/:cabinet/:shelf` ones, running the same code paths. Errors map to
+//! the closest gRPC status, with the HTTP API's stable error code in the
+//! `carmine-error-code` metadata.
+//!
+//! With authentication on, calls carry an API key or token as
+//! `authorization: Bearer <token>` or `x-api-key: <key>` metadata. `System`
+//! calls need the `system:admin` scope, `Data` calls access to their shelf.
+
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Code, Request, Status};
+
+use carmine_core::auth::Access;
+
+use crate::api::auth::{authenticate_token, Permissions};
+use crate::api::error::ApiError;
+use crate::api::extractors::{lookup_shelf, ResolvedShelf};
+use crate::AppState;
+
+mod convert;
+mod data;
+mod system;
+
+pub mod pb {
+    tonic::include_proto!("carmine.v1");
+}
+
+pub const ERROR_CODE_METADATA: &str = "carmine-error-code";
+const API_KEY_METADATA: &str = "x-api-key";
+
+pub async fn serve(state: Arc<AppState>, listener: TcpListener) {
+    let incoming = match TcpIncoming::from_listener(listener, true, None) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            tracing::error!("gRPC listener failed: {}", e);
+            return;
+        }
+    };
+    let served = Server::builder()
+        .add_service(pb::system_server::SystemServer::with_interceptor(
+            system::SystemService::new(state.clone()),
+            Authenticate { state: state.clone(), access: Some(Access::Admin) },
+        ))
+        .add_service(pb::data_server::DataServer::with_interceptor(
+            data::DataService::new(state.clone()),
+            Authenticate { state, access: None },
+        ))
+        .serve_with_incoming(incoming)
+        .await;
+    if let Err(e) = served {
+        tracing::error!("gRPC server stopped: {}", e);
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match &e {
+            ApiError::CabinetNotFound(_)
+            | ApiError::CabinetIdNotFound(_)
+            | ApiError::ShelfNotFound(_)
+            | ApiError::ApiKeyNotFound(_) => Code::NotFound,
+            ApiError::Unauthorized(_) => Code::Unauthenticated,
+            ApiError::Forbidden(_) => Code::PermissionDenied,
+            ApiError::CabinetAlreadyExists(_)
+            | ApiError::ShelfAlreadyExists(_)
+            | ApiError::KeyAlreadyExists => Code::AlreadyExists,
+            ApiError::ShelfNotEmpty { .. } => Code::FailedPrecondition,
+            ApiError::TooManySubscriptions(_) => Code::ResourceExhausted,
+            ApiError::CabinetBusy(_) => Code::Unavailable,
+            ApiError::KeyTypeMismatch { .. }
+            | ApiError::ValueTypeMismatch { .. }
+            | ApiError::JsonParse(_)
+            | ApiError::InvalidRequest(_)
+            | ApiError::BadRequest(_) => Code::InvalidArgument,
+            ApiError::Internal(_) => Code::Internal,
+        };
+        let mut metadata = MetadataMap::new();
+        metadata.insert(ERROR_CODE_METADATA, MetadataValue::from_static(e.code()));
+        Status::with_metadata(code, e.message(), metadata)
+    }
+}
+
+fn presented_token<T>(request: &Request<T>) -> Option<&str> {
+    let metadata = request.metadata();
+    if let Some(value) = metadata.get("authorization") {
+        let value = value.to_str().ok()?;
+        return value
+            .strip_prefix("Bearer ")
+            .or_else(|| value.strip_prefix("bearer "))
+            .map(str::trim);
+    }
+    metadata.get(API_KEY_METADATA)?.to_str().ok().map(str::trim)
+}
+
+/// Rejects calls without valid credentials, or without `access` when it's
+/// needed for every call of the service, and attaches the caller's
+/// permissions for the calls to check.
+#[derive(Clone)]
+struct Authenticate {
+    state: Arc<AppState>,
+    access: Option<Access>,
+}
+
+impl Interceptor for Authenticate {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let permissions = authenticate_token(&self.state, presented_token(&request))?;
+        if let Some(access) = self.access {
+            permissions.check("", None, access)?;
+        }
+        request.extensions_mut().insert(permissions);
+        Ok(request)
+    }
+}
+
+/// The permissions [`Authenticate`] attached to the call.
+fn permissions<T>(request: &Request<T>) -> Result<Permissions, ApiError> {
+    request
+        .extensions()
+        .get::<Permissions>()
+        .cloned()
+        .ok_or_else(|| ApiError::Internal("Call wasn't authenticated".to_string()))
+}
+
+fn shelf_ref(shelf: Option<pb::ShelfRef>) -> Result<pb::ShelfRef, ApiError> {
+    shelf.ok_or_else(|| ApiError::BadRequest("missing field 'shelf'".to_string()))
+}
+
+/// Checks that the caller may `access` the shelf a data call targets, then
+/// resolves it and counts the call as `op`.
+fn resolve(
+    state: &AppState,
+    permissions: &Permissions,
+    shelf: Option<pb::ShelfRef>,
+    op: &str,
+    access: Access,
+) -> Result<ResolvedShelf, ApiError> {
+    let shelf = shelf_ref(shelf)?;
+    permissions.check(&shelf.cabinet, Some(&shelf.shelf), access)?;
+    let resolved = lookup_shelf(state, &shelf.cabinet, shelf.shelf)?;
+    state.metrics.shelf_op("grpc", &resolved, op);
+    Ok(resolved)
+}
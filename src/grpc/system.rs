@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+
+use carmine_core::shelf::migrate::ConversionPolicy;
+
+use super::convert::{
+    api_key_to_pb, cabinet_check_to_pb, cabinet_stats_to_pb, cabinet_to_pb, encryption_to_pb, failure_to_pb, key_type_from_pb, retention_from_pb, retention_to_pb,
+    shelf_to_pb, value_from_pb, value_type_from_pb,
+};
+use super::{pb, shelf_ref};
+use crate::api::admin;
+use crate::api::error::ApiError;
+use crate::AppState;
+
+pub struct SystemService {
+    state: Arc<AppState>,
+}
+
+impl SystemService {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+}
+
+#[tonic::async_trait]
+impl pb::system_server::System for SystemService {
+    async fn create_cabinet(
+        &self,
+        request: Request<pb::CreateCabinetRequest>,
+    ) -> Result<Response<pb::Cabinet>, Status> {
+        let req = request.into_inner();
+        let meta = admin::create_cabinet(&self.state, req.name, req.encrypted)?;
+        Ok(Response::new(cabinet_to_pb(&meta)))
+    }
+
+    async fn list_cabinets(
+        &self,
+        _request: Request<pb::ListCabinetsRequest>,
+    ) -> Result<Response<pb::ListCabinetsResponse>, Status> {
+        let cabinets = admin::list_cabinets(&self.state)?;
+        Ok(Response::new(pb::ListCabinetsResponse {
+            cabinets: cabinets.iter().map(cabinet_to_pb).collect(),
+        }))
+    }
+
+    async fn get_cabinet(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::Cabinet>, Status> {
+        let meta = admin::get_cabinet(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(cabinet_to_pb(&meta)))
+    }
+
+    async fn delete_cabinet(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::DeleteCabinetResponse>, Status> {
+        admin::delete_cabinet(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(pb::DeleteCabinetResponse {}))
+    }
+
+    async fn clean_cabinet(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::CleanCabinetResponse>, Status> {
+        admin::clean_cabinet(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(pb::CleanCabinetResponse {}))
+    }
+
+    async fn rename_cabinet(
+        &self,
+        request: Request<pb::RenameCabinetRequest>,
+    ) -> Result<Response<pb::Cabinet>, Status> {
+        let req = request.into_inner();
+        let meta = admin::rename_cabinet(&self.state, &req.name, &req.new_name)?;
+        Ok(Response::new(cabinet_to_pb(&meta)))
+    }
+
+    async fn create_shelf(
+        &self,
+        request: Request<pb::CreateShelfRequest>,
+    ) -> Result<Response<pb::Shelf>, Status> {
+        let req = request.into_inner();
+        let key_type = key_type_from_pb(req.key_type)?
+            .ok_or_else(|| ApiError::BadRequest("key_type is required".to_string()))?;
+        let value_type = value_type_from_pb(req.value_type)?
+            .ok_or_else(|| ApiError::BadRequest("value_type is required".to_string()))?;
+        let shelf = admin::create_shelf(&self.state, &req.cabinet, req.name, key_type, value_type)?;
+        Ok(Response::new(shelf_to_pb(&shelf)))
+    }
+
+    async fn list_shelves(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::ListShelvesResponse>, Status> {
+        let shelves = admin::list_shelves(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(pb::ListShelvesResponse {
+            shelves: shelves.iter().map(shelf_to_pb).collect(),
+        }))
+    }
+
+    async fn delete_shelf(
+        &self,
+        request: Request<pb::DeleteShelfRequest>,
+    ) -> Result<Response<pb::DeleteShelfResponse>, Status> {
+        let req = request.into_inner();
+        let shelf = shelf_ref(req.shelf)?;
+        let deleted_entries = admin::delete_shelf(&self.state, &shelf.cabinet, &shelf.shelf, req.force)?;
+        Ok(Response::new(pb::DeleteShelfResponse { deleted_entries }))
+    }
+
+    async fn rename_shelf(
+        &self,
+        request: Request<pb::RenameShelfRequest>,
+    ) -> Result<Response<pb::Shelf>, Status> {
+        let req = request.into_inner();
+        let shelf = shelf_ref(req.shelf)?;
+        let renamed = admin::rename_shelf(&self.state, &shelf.cabinet, &shelf.shelf, &req.new_name)?;
+        Ok(Response::new(shelf_to_pb(&renamed)))
+    }
+
+    async fn copy_shelf(
+        &self,
+        request: Request<pb::CopyShelfRequest>,
+    ) -> Result<Response<pb::CopyShelfResponse>, Status> {
+        let req = request.into_inner();
+        let shelf = shelf_ref(req.shelf)?;
+        let (copy, copied_entries) =
+            admin::copy_shelf(&self.state, &shelf.cabinet, &shelf.shelf, &req.new_name)?;
+        Ok(Response::new(pb::CopyShelfResponse { shelf: Some(shelf_to_pb(&copy)), copied_entries }))
+    }
+
+    async fn migrate_shelf(
+        &self,
+        request: Request<pb::MigrateShelfRequest>,
+    ) -> Result<Response<pb::MigrateShelfResponse>, Status> {
+        let req = request.into_inner();
+        let shelf = shelf_ref(req.shelf)?;
+        let key_type = key_type_from_pb(req.key_type)?;
+        let value_type = value_type_from_pb(req.value_type)?;
+        let policy = match pb::MigrationPolicy::try_from(req.policy) {
+            Ok(pb::MigrationPolicy::Strict) => ConversionPolicy::Strict,
+            Ok(pb::MigrationPolicy::SkipInvalid) => ConversionPolicy::SkipInvalid,
+            Ok(pb::MigrationPolicy::DefaultValue) => {
+                ConversionPolicy::DefaultValue(value_from_pb(req.default, "default")?)
+            }
+            Err(_) => {
+                return Err(ApiError::BadRequest(format!("unknown policy {}", req.policy)).into());
+            }
+        };
+
+        let migration =
+            admin::migrate_shelf(&self.state, &shelf.cabinet, &shelf.shelf, key_type, value_type, policy)?;
+        Ok(Response::new(pb::MigrateShelfResponse {
+            applied: migration.applied,
+            shelf: Some(shelf_to_pb(&migration.shelf)),
+            converted: migration.report.converted,
+            defaulted: migration.report.defaulted,
+            failed: migration.report.failed.iter().map(failure_to_pb).collect(),
+        }))
+    }
+
+    async fn get_cabinet_stats(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::CabinetStats>, Status> {
+        let usage = admin::cabinet_stats(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(cabinet_stats_to_pb(&usage)))
+    }
+
+    async fn compact_cabinet(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::CompactCabinetResponse>, Status> {
+        let report = admin::compact_cabinet(&self.state, &request.into_inner().name).await?;
+        Ok(Response::new(pb::CompactCabinetResponse {
+            compacted: report.compacted,
+            size_before: report.size_before,
+            size_after: report.size_after,
+        }))
+    }
+
+    async fn check_cabinet(
+        &self,
+        request: Request<pb::CheckCabinetRequest>,
+    ) -> Result<Response<pb::CheckCabinetResponse>, Status> {
+        let req = request.into_inner();
+        let outcome = admin::check_cabinet(&self.state, &req.name, req.repair).await?;
+        Ok(Response::new(cabinet_check_to_pb(outcome)))
+    }
+
+    async fn get_change_retention(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::ChangeRetention>, Status> {
+        let retention = admin::change_retention(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(retention_to_pb(&retention)))
+    }
+
+    async fn set_change_retention(
+        &self,
+        request: Request<pb::SetChangeRetentionRequest>,
+    ) -> Result<Response<pb::ChangeRetention>, Status> {
+        let req = request.into_inner();
+        let retention =
+            admin::set_change_retention(&self.state, &req.cabinet, retention_from_pb(req.retention))?;
+        Ok(Response::new(retention_to_pb(&retention)))
+    }
+
+    async fn get_encryption(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::EncryptionStatus>, Status> {
+        let status = admin::encryption_status(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(encryption_to_pb(&status)))
+    }
+
+    async fn rotate_cabinet_key(
+        &self,
+        request: Request<pb::CabinetRef>,
+    ) -> Result<Response<pb::EncryptionStatus>, Status> {
+        let status = admin::rotate_cabinet_key(&self.state, &request.into_inner().name)?;
+        Ok(Response::new(encryption_to_pb(&status)))
+    }
+
+    async fn create_api_key(
+        &self,
+        request: Request<pb::CreateApiKeyRequest>,
+    ) -> Result<Response<pb::CreateApiKeyResponse>, Status> {
+        let req = request.into_inner();
+        let scopes = admin::parse_scopes(&req.scopes)?;
+        let (meta, key) = admin::create_api_key(&self.state, req.name, scopes)?;
+        Ok(Response::new(pb::CreateApiKeyResponse { meta: Some(api_key_to_pb(&meta)), key }))
+    }
+
+    async fn list_api_keys(
+        &self,
+        _request: Request<pb::ListApiKeysRequest>,
+    ) -> Result<Response<pb::ListApiKeysResponse>, Status> {
+        let keys = admin::list_api_keys(&self.state)?;
+        Ok(Response::new(pb::ListApiKeysResponse { keys: keys.iter().map(api_key_to_pb).collect() }))
+    }
+
+    async fn revoke_api_key(
+        &self,
+        request: Request<pb::RevokeApiKeyRequest>,
+    ) -> Result<Response<pb::RevokeApiKeyResponse>, Status> {
+        admin::revoke_api_key(&self.state, request.into_inner().id)?;
+        Ok(Response::new(pb::RevokeApiKeyResponse {}))
+    }
+}
@@ -1,54 +1,165 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use axum::{Router, routing::get};
-use dashmap::DashMap;
+use axum::{Router, middleware::from_fn_with_state, routing::get};
+use dashmap::{DashMap, DashSet};
 use tokio::net::TcpListener;
+use tokio::sync::watch;
 
-use carmine_core::{system_store::SystemStore, cabinet::Cabinet};
+use carmine_core::{system_store::SystemStore, cabinet::Cabinet, crypto::ValueCipher, shelf::Shelf};
 
 mod api;
+mod binary;
+mod cabinets;
+mod maintenance;
 mod config;
+mod encryption;
+mod grpc;
+mod metrics;
+mod resp;
+mod tls;
 
-use config::Config;
+#[cfg(test)]
+mod test_util;
+
+use api::auth::Authenticator;
+use api::error::ApiError;
+use cabinets::OpenCabinets;
+use config::{AuthMode, Config};
+use encryption::{Encryption, KeyRotation};
+use metrics::Metrics;
+
+/// A cabinet's open handle and its shelves with parsed types, cached so the
+/// data path doesn't touch the system store.
+pub struct CachedCabinet {
+    pub cabinet: Cabinet,
+    pub shelves: HashMap<String, Shelf>,
+    /// Opens the change log's values of an encrypted cabinet.
+    pub cipher: Option<Arc<ValueCipher>>,
+}
 
 pub struct AppState {
     pub system_store: SystemStore,
     pub data_dir: PathBuf,
-    pub cabinets: DashMap<u64, Cabinet>,
-    pub cabinet_cache_size: usize,
+    pub cabinets: OpenCabinets,
     pub durability: redb::Durability,
+    /// Resolved metadata keyed by cabinet name.
+    pub metadata: DashMap<String, Arc<CachedCabinet>>,
+    /// Bumped on every invalidation so a lookup that raced with one doesn't
+    /// leave stale metadata behind.
+    pub metadata_generation: AtomicU64,
+    /// Wakes change feed followers, keyed by cabinet id. A channel only
+    /// exists once someone has subscribed.
+    pub changes: DashMap<u64, watch::Sender<()>>,
+    pub auth: Authenticator,
+    /// Set when a master key is configured for encryption at rest.
+    pub encryption: Option<Encryption>,
+    /// The latest key rotation of each cabinet, keyed by cabinet id.
+    pub key_rotations: DashMap<u64, KeyRotation>,
+    /// Ids of cabinets being compacted or checked. Their handles can't be
+    /// handed out until that finishes.
+    pub in_maintenance: DashSet<u64>,
+    pub metrics: Metrics,
 }
 
 impl AppState {
     pub fn new(
         system_store: SystemStore,
         data_dir: PathBuf,
-        cabinet_cache_size: usize,
+        cabinets: OpenCabinets,
         durability: redb::Durability,
+        auth: Authenticator,
+        encryption: Option<Encryption>,
     ) -> Self {
         Self {
             system_store,
             data_dir,
-            cabinets: DashMap::new(),
-            cabinet_cache_size,
+            cabinets,
             durability,
+            metadata: DashMap::new(),
+            metadata_generation: AtomicU64::new(0),
+            changes: DashMap::new(),
+            auth,
+            encryption,
+            key_rotations: DashMap::new(),
+            in_maintenance: DashSet::new(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Drops the cached metadata for `cabinet_name`. System endpoints call
+    /// this after changing a cabinet or its shelves.
+    pub fn invalidate_metadata(&self, cabinet_name: &str) {
+        self.metadata_generation.fetch_add(1, Ordering::SeqCst);
+        self.metadata.remove(cabinet_name);
+    }
+
+    /// Caches metadata loaded while the generation was `generation`, unless
+    /// an invalidation happened in the meantime.
+    pub fn cache_metadata(&self, cabinet_name: &str, generation: u64, cached: Arc<CachedCabinet>) {
+        self.metadata.insert(cabinet_name.to_string(), cached);
+        if self.metadata_generation.load(Ordering::SeqCst) != generation {
+            self.metadata.remove(cabinet_name);
         }
     }
 
+    /// Returns a receiver that is marked changed whenever a write to the
+    /// cabinet commits.
+    pub fn subscribe_changes(&self, cabinet_id: u64) -> watch::Receiver<()> {
+        self.changes
+            .entry(cabinet_id)
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    pub fn notify_changes(&self, cabinet_id: u64) {
+        if let Some(sender) = self.changes.get(&cabinet_id) {
+            sender.send_replace(());
+        }
+    }
+
+    /// Fails with [`ApiError::CabinetBusy`] while the cabinet is in
+    /// maintenance. The flag is checked again once the handle is taken, so
+    /// maintenance that starts in between still sees it and waits for it.
     pub fn get_or_open_cabinet(
         &self,
         id: u64,
         name: String,
         path: PathBuf,
-    ) -> Result<Cabinet, Box<dyn std::error::Error>> {
-        if let Some(cabinet) = self.cabinets.get(&id) {
-            return Ok(cabinet.clone());
+    ) -> Result<Cabinet, ApiError> {
+        if self.in_maintenance.contains(&id) {
+            return Err(ApiError::CabinetBusy(name));
+        }
+        let opened = self.cabinets.get_or_open(id, |cache_size| Cabinet::open(id, name.clone(), path, cache_size));
+        let cabinet = match opened {
+            Ok(cabinet) => cabinet,
+            // Maintenance holds the file open, so opening it fails.
+            Err(_) if self.in_maintenance.contains(&id) => return Err(ApiError::CabinetBusy(name)),
+            Err(e) => return Err(e.into()),
+        };
+        self.check_open_limit();
+        if self.in_maintenance.contains(&id) {
+            return Err(ApiError::CabinetBusy(name));
         }
-        let cabinet = Cabinet::open(id, name, path, self.cabinet_cache_size)?;
-        self.cabinets.insert(id, cabinet.clone());
         Ok(cabinet)
     }
+
+    /// The open handle of the cabinet with `id`, for tasks that outlive a
+    /// request and so can't keep one.
+    pub fn cabinet_by_id(&self, id: u64) -> Result<Cabinet, ApiError> {
+        if let Some(cabinet) = self.cabinets.get(id)
+            && !self.in_maintenance.contains(&id)
+        {
+            return Ok(cabinet);
+        }
+        let meta = self
+            .system_store
+            .get_cabinet(id)?
+            .ok_or(ApiError::CabinetIdNotFound(id))?;
+        self.get_or_open_cabinet(meta.id, meta.name, meta.path)
+    }
 }
 
 #[tokio::main]
@@ -70,20 +181,92 @@ async fn main() {
     )
     .expect("Failed to open system store");
 
+    let auth = Authenticator {
+        mode: config.auth_mode,
+        master_key_hash: config.master_key.as_deref().map(carmine_core::auth::hash_key),
+        jwt: match config.auth_mode {
+            AuthMode::Jwt => Some(config.jwt_verifier().expect("Failed to load JWT keys")),
+            _ => None,
+        },
+        client_scopes: config.tls.as_ref().map(|tls| tls.client_scopes.clone()).unwrap_or_default(),
+    };
+
+    let encryption = config
+        .encryption_key()
+        .expect("Failed to load encryption key")
+        .map(|master_key| Encryption {
+            master_key: Arc::new(master_key),
+            algorithm: config.encryption_algorithm,
+        });
+
     let state = Arc::new(AppState::new(
         system_store,
         config.data_dir.clone(),
-        config.cabinet_cache_size,
+        OpenCabinets::new(
+            config.per_cabinet_cache_size(),
+            config.max_open_cabinets,
+            config.cabinet_idle_timeout(),
+        ),
         config.redb_durability(),
+        auth,
+        encryption,
     ));
 
+    encryption::check_master_key(&state).expect("Failed to check the master key");
+
+    maintenance::spawn_compaction(state.clone(), config.compaction.clone());
+    cabinets::spawn_open_limit(state.clone());
+    cabinets::spawn_idle_close(state.clone());
+
+    if config.auth_mode == AuthMode::ApiKey
+        && config.master_key.is_none()
+        && state.system_store.list_api_keys().map(|keys| keys.is_empty()).unwrap_or(false)
+    {
+        tracing::warn!("API key auth is on but there is no master key or API key; every request will be rejected");
+    }
+
     let app = Router::new()
         .route("/health", get(|| async { "OK" }))
-        .nest("/system", api::system_router())
-        .nest("/v1/:cabinet/:shelf", api::normal_router())
-        .with_state(state);
+        .nest("/system", api::system_router(state.clone()))
+        .nest("/v1/ws", api::ws_router(state.clone()))
+        .nest("/v1/:cabinet/changes", api::changes_router(state.clone()))
+        .nest("/v1/:cabinet/:shelf", api::normal_router(state.clone()))
+        .merge(api::metrics_router(state.clone()))
+        .layer(from_fn_with_state(state.clone(), metrics::track_requests))
+        .with_state(state.clone());
+
+    if let Some(bind) = &config.binary_bind {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        tracing::info!("Binary protocol listening on {}", bind);
+        tokio::spawn(binary::serve(state.clone(), listener));
+    }
+
+    if let Some(bind) = &config.grpc_bind {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        tracing::info!("gRPC listening on {}", bind);
+        tokio::spawn(grpc::serve(state.clone(), listener));
+    }
+
+    if let Some(bind) = &config.resp_bind {
+        let cabinet = config
+            .resp_cabinet
+            .clone()
+            .expect("server.resp_cabinet must be set to enable the RESP listener");
+        let listener = TcpListener::bind(bind).await.unwrap();
+        tracing::info!("RESP listening on {}", bind);
+        tokio::spawn(resp::serve(state.clone(), listener, cabinet, config.resp_shelf.clone()));
+    }
 
     let listener = TcpListener::bind(&config.bind).await.unwrap();
-    tracing::info!("Listening on {}", config.bind);
-    axum::serve(listener, app).await.unwrap();
+    match &config.tls {
+        Some(tls_config) => {
+            let acceptor = tls::acceptor(tls_config).expect("Failed to load TLS configuration");
+            tracing::info!("Listening on {} (TLS)", config.bind);
+            tls::serve(listener, acceptor, app).await;
+        }
+        None => {
+            tracing::info!("Listening on {}", config.bind);
+            axum::serve(listener, app).await.unwrap();
+        }
+    }
 }
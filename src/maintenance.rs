@@ -0,0 +1,355 @@
+//! Maintenance of cabinet files: compaction, on request or scheduled by
+//! `[compaction]`, and integrity checks.
+//!
+//! redb only compacts or checks a database nobody else has open, so the
+//! cabinet is marked as in maintenance, which makes new requests for it fail
+//! with [`ApiError::CabinetBusy`], and taken out of `state.cabinets`. Once
+//! the requests already holding its handle finish, the work runs and the
+//! handle is put back.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use redb::ReadableDatabase;
+use serde::Serialize;
+
+use crate::api::error::ApiError;
+use crate::config::CompactionConfig;
+use crate::AppState;
+use carmine_core::cabinet::Cabinet;
+use carmine_core::crypto::ValueCipher;
+use carmine_core::integrity::{self, IntegrityReport};
+use carmine_core::meta::CabinetMeta;
+use carmine_core::shelf::Shelf;
+use carmine_core::stats;
+
+/// How long to wait for in-flight requests to let go of the cabinet.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELEASE_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CompactionReport {
+    /// Whether redb found anything to reclaim.
+    pub compacted: bool,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// The outcome of [`check_integrity`].
+pub(crate) struct IntegrityCheck {
+    /// Whether redb had to repair the file's structure.
+    pub file_repaired: bool,
+    pub report: IntegrityReport,
+    /// Set when repairs were asked for: the entries removed because their
+    /// value doesn't decode.
+    pub removed_entries: Option<u64>,
+}
+
+/// Clears the maintenance flag however maintenance ends, and wakes the
+/// change followers and watches that waited for it.
+struct MaintenanceGuard<'a> {
+    state: &'a AppState,
+    id: u64,
+}
+
+impl Drop for MaintenanceGuard<'_> {
+    fn drop(&mut self) {
+        self.state.in_maintenance.remove(&self.id);
+        self.state.notify_changes(self.id);
+    }
+}
+
+fn file_size(meta: &CabinetMeta) -> Result<u64, ApiError> {
+    Ok(std::fs::metadata(&meta.path)
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .len())
+}
+
+/// Runs `work` on the only handle of `meta`'s cabinet, on a blocking thread,
+/// holding off requests for the cabinet until it's done.
+///
+/// Maintenance runs in its own task, so a client that goes away midway
+/// doesn't clear the maintenance flag while redb still holds the file, or
+/// drop the handle instead of putting it back.
+async fn with_exclusive<T, F>(state: &Arc<AppState>, meta: &CabinetMeta, work: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Cabinet) -> Result<T, ApiError> + Send + 'static,
+{
+    let (state, meta) = (state.clone(), meta.clone());
+    tokio::spawn(async move { run_exclusive(&state, &meta, work).await })
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+}
+
+async fn run_exclusive<T, F>(state: &AppState, meta: &CabinetMeta, work: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Cabinet) -> Result<T, ApiError> + Send + 'static,
+{
+    if !state.in_maintenance.insert(meta.id) {
+        return Err(ApiError::CabinetBusy(meta.name.clone()));
+    }
+    let _guard = MaintenanceGuard { state, id: meta.id };
+    state.invalidate_metadata(&meta.name);
+
+    let mut cabinet = match state.cabinets.remove(meta.id) {
+        Some(cabinet) => cabinet,
+        // A request that is opening the cabinet right now holds the file.
+        None => Cabinet::open(meta.id, meta.name.clone(), meta.path.clone(), state.cabinets.cache_size())
+            .map_err(|_| ApiError::CabinetBusy(meta.name.clone()))?,
+    };
+
+    let deadline = tokio::time::Instant::now() + RELEASE_TIMEOUT;
+    while !cabinet.is_exclusive() {
+        if tokio::time::Instant::now() >= deadline {
+            state.cabinets.insert(cabinet);
+            return Err(ApiError::CabinetBusy(meta.name.clone()));
+        }
+        tokio::time::sleep(RELEASE_POLL).await;
+    }
+
+    let (cabinet, result) = tokio::task::spawn_blocking(move || {
+        let result = work(&mut cabinet);
+        (cabinet, result)
+    })
+    .await
+    .map_err(|e| ApiError::Internal(e.to_string()))?;
+    state.cabinets.insert(cabinet);
+    result
+}
+
+/// Compacts `meta`'s cabinet, holding off requests for it until done.
+pub(crate) async fn compact(state: &Arc<AppState>, meta: &CabinetMeta) -> Result<CompactionReport, ApiError> {
+    let size_before = file_size(meta)?;
+    let compacted = with_exclusive(state, meta, |cabinet| Ok(cabinet.compact()?)).await?;
+    Ok(CompactionReport {
+        compacted,
+        size_before,
+        size_after: file_size(meta)?,
+    })
+}
+
+/// Runs redb's integrity check on `meta`'s cabinet, then compares its tables
+/// with `shelves` and reads back the values that can fail to decode. With
+/// `repair`, also fixes what [`integrity::repair`] can.
+pub(crate) async fn check_integrity(
+    state: &Arc<AppState>,
+    meta: &CabinetMeta,
+    shelves: Vec<Shelf>,
+    cipher: Option<Arc<ValueCipher>>,
+    repair: bool,
+) -> Result<IntegrityCheck, ApiError> {
+    let (notify, id) = (state.clone(), meta.id);
+    with_exclusive(state, meta, move |cabinet| {
+        let file_repaired = !cabinet.check_integrity()?;
+        let tx = cabinet.database().begin_read()
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
+        let report = integrity::check(&tx, &shelves, cipher.as_deref())?;
+        drop(tx);
+
+        let removed_entries = if repair {
+            let txn = cabinet.database().begin_write()
+                .map_err(|e| ApiError::Internal(e.to_string()))?;
+            let removed = integrity::repair(&txn, &shelves, &report)?;
+            txn.commit().map_err(|e| ApiError::Internal(e.to_string()))?;
+            // The removals are in the change log; wake those following it.
+            if removed > 0 {
+                notify.notify_changes(id);
+            }
+            Some(removed)
+        } else {
+            None
+        };
+        Ok(IntegrityCheck { file_repaired, report, removed_entries })
+    })
+    .await
+}
+
+/// Whether `meta`'s cabinet has grown fragmented enough for `config`,
+/// counting the bytes of the file outside allocated pages, which is what
+/// compaction gives back. Only open cabinets are checked, so the schedule
+/// doesn't reopen closed ones or keep idle ones open; a closed cabinet is
+/// checked again once requests open it.
+fn needs_compaction(state: &AppState, meta: &CabinetMeta, config: &CompactionConfig) -> Result<bool, ApiError> {
+    let Some(cabinet) = state.cabinets.peek(meta.id) else {
+        return Ok(false);
+    };
+    let stats = stats::database_stats(&cabinet)?;
+    let size = file_size(meta)?;
+    let fragmented = size.saturating_sub(stats.allocated_pages * stats.page_size as u64);
+    Ok(size > 0
+        && fragmented >= config.min_fragmented_bytes
+        && fragmented as f64 / size as f64 >= config.min_fragmented_ratio)
+}
+
+/// Checks every open cabinet each `interval_secs` and compacts those past the
+/// fragmentation thresholds.
+pub(crate) fn spawn_compaction(state: Arc<AppState>, config: CompactionConfig) {
+    let Some(interval_secs) = config.interval_secs else {
+        return;
+    };
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval_secs);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let cabinets = match state.system_store.list_cabinets() {
+                Ok(cabinets) => cabinets,
+                Err(e) => {
+                    tracing::warn!("Scheduled compaction could not list cabinets: {}", e);
+                    continue;
+                }
+            };
+            for meta in cabinets {
+                let check = {
+                    let (state, meta, config) = (state.clone(), meta.clone(), config.clone());
+                    tokio::task::spawn_blocking(move || needs_compaction(&state, &meta, &config)).await
+                };
+                match check {
+                    Ok(Ok(true)) => {}
+                    Ok(Ok(false)) => continue,
+                    Ok(Err(e)) => {
+                        tracing::warn!("Could not check fragmentation of cabinet '{}': {}", meta.name, e.message());
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Could not check fragmentation of cabinet '{}': {}", meta.name, e);
+                        continue;
+                    }
+                }
+                match compact(&state, &meta).await {
+                    Ok(report) => tracing::info!(
+                        "Compacted cabinet '{}' from {} to {} bytes",
+                        meta.name,
+                        report.size_before,
+                        report.size_after
+                    ),
+                    Err(e) => tracing::warn!("Scheduled compaction of cabinet '{}' failed: {}", meta.name, e.message()),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use carmine_core::key::Key;
+    use carmine_core::transaction::Writable;
+    use carmine_core::types::Int;
+    use carmine_core::value::Value;
+    use tower::ServiceExt;
+
+    use crate::api::extractors::ResolvedShelf;
+    use crate::test_util::{temp_shelf, temp_state};
+
+    fn fill(resolved: &ResolvedShelf, entries: i64) {
+        let tx = resolved.begin_write().unwrap();
+        for n in 0..entries {
+            resolved.shelf.set(&tx, Key::Int(Int::from(n)), Value::String("x".repeat(1024))).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    /// Frees the pages `fill` used. redb reuses them only after a later
+    /// commit, so one more empty one follows.
+    fn clear(resolved: &ResolvedShelf) {
+        let tx = resolved.begin_write().unwrap();
+        resolved.shelf.clear(&tx).unwrap();
+        tx.commit().unwrap();
+        resolved.begin_write().unwrap().commit().unwrap();
+    }
+
+    fn meta(state: &AppState, name: &str) -> CabinetMeta {
+        state.system_store.find_cabinet_by_name(name).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compact_endpoint() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "compact", "entries");
+        fill(&resolved, 4096);
+        clear(&resolved);
+        drop(resolved);
+
+        let app = axum::Router::new().nest("/system", crate::api::system_router(state.clone())).with_state(state.clone());
+        let request = axum::http::Request::post("/system/cabinets/compact/compact").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["compacted"], true);
+        assert!(report["size_after"].as_u64() < report["size_before"].as_u64());
+
+        // The handle is back and no longer in maintenance.
+        assert!(state.cabinets.peek(meta(&state, "compact").id).is_some());
+        assert!(state.in_maintenance.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_waits_for_requests_to_release() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "busy", "entries");
+        let meta = meta(&state, "busy");
+
+        let compaction = tokio::spawn({
+            let (state, meta) = (state.clone(), meta.clone());
+            async move { compact(&state, &meta).await }
+        });
+        tokio::time::sleep(RELEASE_POLL * 5).await;
+        // New requests are held off while the running one finishes.
+        assert!(!compaction.is_finished());
+        assert!(matches!(
+            state.get_or_open_cabinet(meta.id, meta.name.clone(), meta.path.clone()),
+            Err(ApiError::CabinetBusy(_))
+        ));
+        drop(resolved);
+
+        compaction.await.unwrap().unwrap();
+        assert!(state.get_or_open_cabinet(meta.id, meta.name.clone(), meta.path.clone()).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_on_held_handle() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "held", "entries");
+        let meta = meta(&state, "held");
+
+        assert!(matches!(compact(&state, &meta).await, Err(ApiError::CabinetBusy(_))));
+        assert!(state.in_maintenance.is_empty());
+        assert!(state.cabinets.peek(meta.id).is_some());
+        drop(resolved);
+    }
+
+    #[test]
+    fn test_needs_compaction_thresholds() {
+        let (_dir, state) = temp_state(None, None);
+        let resolved = temp_shelf(&state, "fragmented", "entries");
+        let meta = meta(&state, "fragmented");
+        let config = |min_fragmented_bytes, min_fragmented_ratio| CompactionConfig {
+            interval_secs: Some(60),
+            min_fragmented_bytes,
+            min_fragmented_ratio,
+        };
+
+        // redb grows the file ahead of what it allocates, so a freshly
+        // filled one is already partly free.
+        fill(&resolved, 4096);
+        assert!(!needs_compaction(&state, &meta, &config(1024 * 1024, 0.75)).unwrap());
+
+        clear(&resolved);
+        assert!(needs_compaction(&state, &meta, &config(1024 * 1024, 0.75)).unwrap());
+        // Either threshold alone holds it back.
+        assert!(!needs_compaction(&state, &meta, &config(1024 * 1024 * 1024, 0.75)).unwrap());
+        assert!(!needs_compaction(&state, &meta, &config(1024 * 1024, 1.0)).unwrap());
+
+        // Closed cabinets are skipped rather than opened.
+        drop(resolved);
+        state.invalidate_metadata(&meta.name);
+        state.cabinets.remove(meta.id);
+        assert!(!needs_compaction(&state, &meta, &config(0, 0.0)).unwrap());
+        assert!(state.cabinets.peek(meta.id).is_none());
+    }
+}
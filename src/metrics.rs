@@ -0,0 +1,292 @@
+//! Prometheus metrics, served at `GET /metrics`.
+//!
+//! Request, operation and commit metrics are recorded as they happen.
+//! Storage metrics (open cabinets, file sizes, redb cache stats) are read
+//! from the cabinets when scraped.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    proto::MetricFamily, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use redb::{CacheStats, ReadableDatabase};
+
+use crate::api::error::ApiError;
+use crate::api::extractors::ResolvedShelf;
+use crate::AppState;
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    shelf_operations: IntCounterVec,
+    commit_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("carmine_http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "carmine_http_request_duration_seconds",
+                "Time to produce an HTTP response",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let shelf_operations = IntCounterVec::new(
+            Opts::new("carmine_shelf_operations_total", "Data operations on a shelf"),
+            &["cabinet", "shelf", "protocol", "op"],
+        )
+        .unwrap();
+        let commit_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "carmine_commit_duration_seconds",
+                "Time to commit a data write transaction",
+            ),
+            &["cabinet"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(shelf_operations.clone())).unwrap();
+        registry.register(Box::new(commit_duration.clone())).unwrap();
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            shelf_operations,
+            commit_duration,
+        }
+    }
+
+    /// Counts a data operation once its shelf has been resolved, so requests
+    /// for unknown cabinets or shelves don't create series.
+    pub(crate) fn shelf_op(&self, protocol: &str, resolved: &ResolvedShelf, op: &str) {
+        self.shelf_operations
+            .with_label_values(&[&resolved.cabinet.name, &resolved.shelf.name, protocol, op])
+            .inc();
+    }
+
+    /// Drops the series of a deleted or renamed cabinet, so they don't keep
+    /// being exported under its old name.
+    pub(crate) fn forget_cabinet(&self, cabinet: &str) {
+        self.forget_shelf_operations(|labels| labels["cabinet"] == cabinet);
+        let _ = self.commit_duration.remove_label_values(&[cabinet]);
+    }
+
+    /// Drops the series of a deleted or renamed shelf.
+    pub(crate) fn forget_shelf(&self, cabinet: &str, shelf: &str) {
+        self.forget_shelf_operations(|labels| labels["cabinet"] == cabinet && labels["shelf"] == shelf);
+    }
+
+    fn forget_shelf_operations(&self, matches: impl Fn(&HashMap<&str, &str>) -> bool) {
+        use prometheus::core::Collector;
+        for family in self.shelf_operations.collect() {
+            for metric in family.get_metric() {
+                let labels: HashMap<&str, &str> =
+                    metric.get_label().iter().map(|label| (label.name(), label.value())).collect();
+                if matches(&labels) {
+                    let values = ["cabinet", "shelf", "protocol", "op"].map(|name| labels[name]);
+                    let _ = self.shelf_operations.remove_label_values(&values);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn observe_commit(&self, cabinet: &str, elapsed: Duration) {
+        self.commit_duration
+            .with_label_values(&[cabinet])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// The text exposition of every metric.
+    pub(crate) fn render(&self, state: &AppState) -> Result<String, ApiError> {
+        let mut families = self.registry.gather();
+        families.extend(storage_families(state)?);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&families, &mut buffer)
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
+        String::from_utf8(buffer).map_err(|e| ApiError::Internal(e.to_string()))
+    }
+}
+
+/// Metrics for one kind of redb database, labeled by `labels`.
+struct DatabaseFamilies {
+    file_size: IntGaugeVec,
+    cache_hits: IntCounterVec,
+    cache_misses: IntCounterVec,
+    cache_evictions: IntCounterVec,
+    cache_used: IntGaugeVec,
+}
+
+impl DatabaseFamilies {
+    fn new(prefix: &str, what: &str, labels: &[&str]) -> Self {
+        let with_cache: Vec<&str> = labels.iter().copied().chain(["cache"]).collect();
+        let opts = |name: &str, help: &str| {
+            Opts::new(format!("{}_{}", prefix, name), format!("{} {}", help, what))
+        };
+        let gauge = |name, help| IntGaugeVec::new(opts(name, help), labels).unwrap();
+        let counter = |name, help, labels: &[&str]| IntCounterVec::new(opts(name, help), labels).unwrap();
+        Self {
+            file_size: gauge("file_size_bytes", "Size of the file of the"),
+            cache_hits: counter("cache_hits_total", "Page cache hits in the", &with_cache),
+            cache_misses: counter("cache_misses_total", "Page cache misses in the", &with_cache),
+            cache_evictions: counter("cache_evictions_total", "Pages evicted from the cache of the", labels),
+            cache_used: gauge("cache_used_bytes", "Bytes held in the page cache of the"),
+        }
+    }
+
+    fn record_file(&self, labels: &[&str], path: &std::path::Path) {
+        if let Ok(metadata) = std::fs::metadata(path) {
+            self.file_size.with_label_values(labels).set(metadata.len() as i64);
+        }
+    }
+
+    fn record_cache(&self, labels: &[&str], stats: &CacheStats) {
+        let with = |cache| -> Vec<&str> { labels.iter().copied().chain([cache]).collect() };
+        self.cache_hits.with_label_values(&with("read")).inc_by(stats.read_hits());
+        self.cache_hits.with_label_values(&with("write")).inc_by(stats.write_hits());
+        self.cache_misses.with_label_values(&with("read")).inc_by(stats.read_misses());
+        self.cache_misses.with_label_values(&with("write")).inc_by(stats.write_misses());
+        self.cache_evictions.with_label_values(labels).inc_by(stats.evictions());
+        self.cache_used.with_label_values(labels).set(stats.used_bytes() as i64);
+    }
+
+    fn collect(self) -> Vec<MetricFamily> {
+        use prometheus::core::Collector;
+        [
+            self.file_size.collect(),
+            self.cache_hits.collect(),
+            self.cache_misses.collect(),
+            self.cache_evictions.collect(),
+            self.cache_used.collect(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Built fresh on every scrape, since redb's cache stats are running totals
+/// kept by each open database.
+fn storage_families(state: &AppState) -> Result<Vec<MetricFamily>, ApiError> {
+    use prometheus::core::Collector;
+
+    let open = IntGauge::new("carmine_open_cabinets", "Cabinets with an open database").unwrap();
+    open.set(state.cabinets.len() as i64);
+
+    let cabinets = DatabaseFamilies::new("carmine_cabinet", "cabinet's database", &["cabinet"]);
+    for meta in state.system_store.list_cabinets()? {
+        cabinets.record_file(&[&meta.name], &meta.path);
+    }
+    for cabinet in state.cabinets.cabinets() {
+        cabinets.record_cache(&[&cabinet.name], &cabinet.database().cache_stats());
+    }
+
+    let system = DatabaseFamilies::new("carmine_system_store", "system store", &[]);
+    system.record_file(&[], &state.data_dir.join("system.redb"));
+    system.record_cache(&[], &state.system_store.cache_stats());
+
+    let closed = IntCounter::new(
+        "carmine_closed_cabinets_total",
+        "Cabinet databases closed for the open limit or idle timeout",
+    )
+    .unwrap();
+    closed.inc_by(state.cabinets.closed_total());
+
+    let mut families = open.collect();
+    families.extend(closed.collect());
+    families.extend(cabinets.collect());
+    families.extend(system.collect());
+    // The encoder rejects families without series, e.g. with no cabinets.
+    families.retain(|family| !family.get_metric().is_empty());
+    Ok(families)
+}
+
+/// Records the count and latency of every HTTP request, labeled with the
+/// route pattern it matched rather than its path.
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    state.metrics.http_requests.with_label_values(&labels).inc();
+    state
+        .metrics
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn export(State(state): State<Arc<AppState>>) -> Result<Response, ApiError> {
+    let text = state.metrics.render(&state)?;
+    let content_type = TextEncoder::new().format_type().to_string();
+    Ok(([(header::CONTENT_TYPE, content_type)], text).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use carmine_core::key::KeyType;
+    use carmine_core::value::ValueType;
+    use prometheus::core::Collector;
+
+    use crate::api::admin::create_shelf;
+    use crate::api::extractors::lookup_shelf;
+    use crate::test_util::{temp_shelf, temp_state};
+
+    fn series(vec: &impl Collector) -> usize {
+        vec.collect().iter().map(|family| family.get_metric().len()).sum()
+    }
+
+    #[test]
+    fn test_forget_removes_series() {
+        let (_dir, state) = temp_state(None, None);
+        let users = temp_shelf(&state, "app", "users");
+        create_shelf(&state, "app", "posts".to_string(), KeyType::Int, ValueType::String).unwrap();
+        let posts = lookup_shelf(&state, "app", "posts".to_string()).unwrap();
+        let other = temp_shelf(&state, "other", "users");
+
+        let metrics = Metrics::new();
+        for resolved in [&users, &posts, &other] {
+            metrics.shelf_op("http", resolved, "get");
+            metrics.shelf_op("grpc", resolved, "set");
+        }
+        metrics.observe_commit("app", Duration::from_millis(1));
+        metrics.observe_commit("other", Duration::from_millis(1));
+
+        metrics.forget_shelf("app", "users");
+        assert_eq!(series(&metrics.shelf_operations), 4);
+        metrics.forget_cabinet("app");
+        assert_eq!(series(&metrics.shelf_operations), 2);
+        assert_eq!(series(&metrics.commit_duration), 1);
+    }
+}
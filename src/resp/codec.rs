@@ -0,0 +1,280 @@
+//! Reading commands and writing replies in RESP2 and RESP3.
+
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Longest inline command or header line.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// How large a command [`read_command`] accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_args: usize,
+    pub max_bulk_len: usize,
+    /// Bytes of all arguments together.
+    pub max_command_len: usize,
+}
+
+impl Limits {
+    /// For authenticated clients. Redis uses the same 512 MB bulk limit.
+    pub const AUTHENTICATED: Limits = Limits {
+        max_args: 1024 * 1024,
+        max_bulk_len: 512 * 1024 * 1024,
+        max_command_len: 512 * 1024 * 1024,
+    };
+
+    /// Until a client authenticates it can only send `AUTH`, `HELLO` and the
+    /// like, so like Redis it gets a few small arguments.
+    pub const UNAUTHENTICATED: Limits = Limits {
+        max_args: 10,
+        max_bulk_len: 16 * 1024,
+        max_command_len: 64 * 1024,
+    };
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Protocol error: {}", what))
+}
+
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(MAX_LINE_LEN).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid(if line.len() as u64 == MAX_LINE_LEN {
+            "line too long"
+        } else {
+            "unexpected end of stream"
+        }));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+/// Reads the next command as its arguments, or `None` at end of stream.
+/// Accepts arrays of bulk strings as clients send them, and space-separated
+/// inline commands as typed into a terminal. Arguments are read as they
+/// arrive rather than allocated at the length the client claims.
+pub async fn read_command(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    limits: Limits,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader).await? else {
+            return Ok(None);
+        };
+        let Some(rest) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            if args.len() > limits.max_args {
+                return Err(invalid("too many arguments"));
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(rest, limits.max_args)?;
+        let mut args = Vec::with_capacity(count.min(1024));
+        let mut total = 0;
+        for _ in 0..count {
+            let header = read_line(reader).await?.ok_or_else(|| invalid("unexpected end of stream"))?;
+            let len = match header.strip_prefix(b"$") {
+                Some(len) => parse_len(len, limits.max_bulk_len)?,
+                None => return Err(invalid("expected bulk string")),
+            };
+            total += len;
+            if total > limits.max_command_len {
+                return Err(invalid("command too large"));
+            }
+            let mut arg = Vec::new();
+            (&mut *reader).take(len as u64 + 2).read_to_end(&mut arg).await?;
+            if arg.len() < len + 2 {
+                return Err(invalid("unexpected end of stream"));
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+/// Encodes replies for the protocol version the client negotiated.
+#[derive(Default)]
+pub struct Replies {
+    pub buf: Vec<u8>,
+    pub resp3: bool,
+}
+
+impl Replies {
+    pub fn simple(&mut self, s: &str) {
+        self.buf.push(b'+');
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    pub fn ok(&mut self) {
+        self.simple("OK");
+    }
+
+    /// An error line; `message` should start with an error prefix such as
+    /// `ERR` or `WRONGTYPE`.
+    pub fn error(&mut self, message: &str) {
+        self.buf.push(b'-');
+        // A newline would end the reply early.
+        self.buf.extend(message.bytes().map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }));
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    pub fn integer(&mut self, n: i64) {
+        self.buf.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+    }
+
+    pub fn bulk(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    pub fn null(&mut self) {
+        if self.resp3 {
+            self.buf.extend_from_slice(b"_\r\n");
+        } else {
+            self.buf.extend_from_slice(b"$-1\r\n");
+        }
+    }
+
+    pub fn array(&mut self, len: usize) {
+        self.buf.extend_from_slice(format!("*{}\r\n", len).as_bytes());
+    }
+
+    /// A map header; RESP2 clients get a flat array of twice the length.
+    pub fn map(&mut self, len: usize) {
+        if self.resp3 {
+            self.buf.extend_from_slice(format!("%{}\r\n", len).as_bytes());
+        } else {
+            self.array(len * 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8], limits: Limits) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let mut reader = input;
+        read_command(&mut reader, limits).await
+    }
+
+    fn args(list: &[&str]) -> Vec<Vec<u8>> {
+        list.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_reads_arrays_and_inline_commands() {
+        let command = read(b"*2\r\n$3\r\nGET\r\n$5\r\nal\r\ne\r\n", Limits::AUTHENTICATED).await.unwrap();
+        assert_eq!(command, Some(vec![b"GET".to_vec(), b"al\r\ne".to_vec()]));
+
+        let command = read(b"\r\n  SET  k v\r\n", Limits::AUTHENTICATED).await.unwrap();
+        assert_eq!(command, Some(args(&["SET", "k", "v"])));
+
+        assert_eq!(read(b"", Limits::AUTHENTICATED).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_malformed_frames() {
+        for input in [
+            &b"*1\r\n:3\r\n"[..],
+            b"*1\r\n$3\r\nGETX\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*x\r\n",
+            b"*2\r\n$3\r\nGET\r\n",
+            b"*1\r\n$10\r\nGET\r\n",
+            b"*1\r\n$3\r\nGET",
+        ] {
+            let err = read(input, Limits::AUTHENTICATED).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_long_lines() {
+        let mut input = vec![b'a'; MAX_LINE_LEN as usize + 1];
+        input.extend_from_slice(b"\r\n");
+        assert!(read(&input, Limits::AUTHENTICATED).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_enforces_limits() {
+        let limits = Limits { max_args: 2, max_bulk_len: 4, max_command_len: 6 };
+        assert!(read(b"*2\r\n$4\r\nabcd\r\n$2\r\nab\r\n", limits).await.unwrap().is_some());
+        // Too many arguments, as an array and inline.
+        assert!(read(b"*3\r\n", limits).await.is_err());
+        assert!(read(b"a b c\r\n", limits).await.is_err());
+        // One argument too long.
+        assert!(read(b"*1\r\n$5\r\nabcde\r\n", limits).await.is_err());
+        // Each argument fits but together they don't, which is caught before
+        // the second one is read.
+        assert!(read(b"*2\r\n$4\r\nabcd\r\n$3\r\n", limits).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_claimed_length_is_not_allocated_up_front() {
+        // A header claiming the largest bulk string, followed by a few bytes,
+        // fails at the end of input instead of allocating 512 MB first.
+        let err = read(b"*1\r\n$536870912\r\nabc", Limits::AUTHENTICATED).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_limits() {
+        let auth = read(b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n", Limits::UNAUTHENTICATED).await.unwrap();
+        assert_eq!(auth, Some(args(&["AUTH", "secret"])));
+
+        assert!(read(b"*11\r\n", Limits::UNAUTHENTICATED).await.is_err());
+        let big = format!("*1\r\n${}\r\n", Limits::UNAUTHENTICATED.max_bulk_len + 1);
+        assert!(read(big.as_bytes(), Limits::UNAUTHENTICATED).await.is_err());
+        let within = format!("*1\r\n${}\r\n", Limits::UNAUTHENTICATED.max_bulk_len);
+        // Accepted as a length; only the missing payload fails.
+        let err = read(within.as_bytes(), Limits::UNAUTHENTICATED).await.unwrap_err();
+        assert!(err.to_string().contains("unexpected end of stream"));
+    }
+
+    #[test]
+    fn test_replies() {
+        let mut out = Replies::default();
+        out.ok();
+        out.error("ERR bad\r\nline");
+        out.integer(-3);
+        out.bulk(b"hi");
+        out.null();
+        out.map(1);
+        assert_eq!(out.buf, b"+OK\r\n-ERR bad  line\r\n:-3\r\n$2\r\nhi\r\n$-1\r\n*2\r\n");
+
+        let mut out = Replies { resp3: true, ..Replies::default() };
+        out.null();
+        out.map(1);
+        assert_eq!(out.buf, b"_\r\n%1\r\n");
+    }
+}
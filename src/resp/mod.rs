@@ -0,0 +1,690 @@
+//! Redis-compatible (RESP2/RESP3) front end, so existing Redis clients can
+//! use a shelf as a persistent cache. Enabled by setting `server.resp_bind`.
+//!
+//! Each connection works on one shelf of the configured cabinet, starting
+//! with `server.resp_shelf`; `SELECT <shelf>` switches to another. Redis
+//! sends everything as strings, so keys and values are converted to the
+//! shelf's types and rejected when they don't fit.
+//!
+//! With authentication on, clients send their API key or token as the
+//! password of `AUTH` or `HELLO … AUTH` before anything else, and each
+//! command is checked against the scopes it grants.
+
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use carmine_core::{
+    auth::Access,
+    key::Key,
+    transaction::{Readable, TransactionError, Writable},
+    types::Int,
+    value::{Value, ValueType},
+};
+
+use crate::api::auth::{authenticate_token, Permissions};
+use crate::api::error::ApiError;
+use crate::api::extractors::{lookup_shelf, ResolvedShelf};
+use crate::api::normal::commit_write;
+use crate::AppState;
+
+mod codec;
+
+use codec::{Limits, Replies};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// SCAN cursors remembered per connection; the oldest are forgotten first.
+const MAX_SCAN_CURSORS: usize = 64;
+/// Buffered reply bytes that are written out even while pipelined commands
+/// are still waiting, so a long pipeline doesn't pile up its replies.
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// An error reply, starting with its Redis error prefix.
+struct Error(String);
+
+impl From<ApiError> for Error {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::KeyTypeMismatch { .. } | ApiError::ValueTypeMismatch { .. } => {
+                Error(format!("WRONGTYPE {}", e.message()))
+            }
+            ApiError::Unauthorized(_) => {
+                Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())
+            }
+            ApiError::Forbidden(_) => Error(format!("NOPERM {}", e.message())),
+            e => Error(format!("ERR {}", e.message())),
+        }
+    }
+}
+
+impl From<TransactionError> for Error {
+    fn from(e: TransactionError) -> Self {
+        ApiError::from(e).into()
+    }
+}
+
+fn syntax_error() -> Error {
+    Error("ERR syntax error".to_string())
+}
+
+fn arity_error(command: &str) -> Error {
+    Error(format!("ERR wrong number of arguments for '{}' command", command))
+}
+
+pub async fn serve(state: Arc<AppState>, listener: TcpListener, cabinet: String, shelf: Option<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connection(state.clone(), stream, cabinet.clone(), shelf.clone()));
+            }
+            Err(e) => tracing::warn!("RESP accept failed: {}", e),
+        }
+    }
+}
+
+async fn connection(state: Arc<AppState>, stream: TcpStream, cabinet: String, shelf: Option<String>) {
+    let _ = stream.set_nodelay(true);
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut session = Session {
+        cabinet,
+        shelf,
+        // Set right away while authentication is off.
+        permissions: authenticate_token(&state, None).ok(),
+        scan_cursors: HashMap::new(),
+        last_cursor: 0,
+        out: Replies::default(),
+    };
+
+    loop {
+        let limits = if session.permissions.is_some() {
+            Limits::AUTHENTICATED
+        } else {
+            Limits::UNAUTHENTICATED
+        };
+        let (args, close) = match codec::read_command(&mut reader, limits).await {
+            Ok(Some(args)) => (args, false),
+            Ok(None) => break,
+            Err(e) => {
+                session.out.error(&format!("ERR {}", e));
+                (Vec::new(), true)
+            }
+        };
+        let close = close || session.execute(&state, &args);
+        // Pipelined commands are answered in one write once the client
+        // stops sending, or once enough replies have piled up.
+        if close || reader.buffer().is_empty() || session.out.buf.len() >= FLUSH_THRESHOLD {
+            if writer.write_all(&session.out.buf).await.is_err() {
+                break;
+            }
+            session.out.buf.clear();
+        }
+        if close {
+            break;
+        }
+    }
+}
+
+struct Session {
+    cabinet: String,
+    shelf: Option<String>,
+    /// What the client may do; `None` until it authenticates.
+    permissions: Option<Permissions>,
+    /// The last key of the page each SCAN cursor handed out ends with.
+    scan_cursors: HashMap<u64, Key>,
+    last_cursor: u64,
+    out: Replies,
+}
+
+impl Session {
+    /// Runs one command, writing its reply. Returns whether to close the
+    /// connection afterwards.
+    fn execute(&mut self, state: &AppState, args: &[Vec<u8>]) -> bool {
+        let Some((name, args)) = args.split_first() else {
+            return false;
+        };
+        let command = String::from_utf8_lossy(name).to_ascii_lowercase();
+        let result = match command.as_str() {
+            "quit" => {
+                self.out.ok();
+                return true;
+            }
+            "auth" => self.auth(state, args),
+            "hello" => self.hello(state, args),
+            _ if self.permissions.is_none() => {
+                Err(Error("NOAUTH Authentication required.".to_string()))
+            }
+            "ping" => self.ping(args),
+            "echo" => self.echo(args),
+            "client" => self.client(args),
+            "command" => {
+                self.out.array(0);
+                Ok(())
+            }
+            "select" => self.select(state, args),
+            "get" => self.get(state, args),
+            "set" => self.set(state, args),
+            "del" => self.del(state, args),
+            "exists" => self.exists(state, args),
+            "mget" => self.mget(state, args),
+            "mset" => self.mset(state, args),
+            "incr" => self.incr_by(state, &command, args, Some(1)),
+            "decr" => self.incr_by(state, &command, args, Some(-1)),
+            "incrby" => self.incr_by(state, &command, args, None),
+            "decrby" => self.incr_by(state, &command, args, None),
+            "scan" => self.scan(state, args),
+            "dbsize" => self.dbsize(state, args),
+            _ => Err(Error(format!("ERR unknown command '{}'", command))),
+        };
+        if let Err(Error(message)) = result {
+            self.out.error(&message);
+        }
+        false
+    }
+
+    fn ping(&mut self, args: &[Vec<u8>]) -> Result<(), Error> {
+        match args {
+            [] => self.out.simple("PONG"),
+            [message] => self.out.bulk(message),
+            _ => return Err(arity_error("ping")),
+        }
+        Ok(())
+    }
+
+    fn echo(&mut self, args: &[Vec<u8>]) -> Result<(), Error> {
+        let [message] = args else {
+            return Err(arity_error("echo"));
+        };
+        self.out.bulk(message);
+        Ok(())
+    }
+
+    fn permissions(&self) -> Result<&Permissions, Error> {
+        self.permissions
+            .as_ref()
+            .ok_or_else(|| Error("NOAUTH Authentication required.".to_string()))
+    }
+
+    /// Authenticates with an API key or token as the password. The user
+    /// name, if given, is ignored.
+    fn authenticate(&mut self, state: &AppState, password: &[u8]) -> Result<(), Error> {
+        let token = std::str::from_utf8(password).map_err(|_| syntax_error())?;
+        self.permissions = Some(authenticate_token(state, Some(token))?);
+        Ok(())
+    }
+
+    /// `AUTH [username] password`.
+    fn auth(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let ([password] | [_, password]) = args else {
+            return Err(arity_error("auth"));
+        };
+        self.authenticate(state, password)?;
+        self.out.ok();
+        Ok(())
+    }
+
+    /// Negotiates the protocol version, authenticating first when `AUTH` is
+    /// given. `SETNAME` is accepted and ignored.
+    fn hello(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let Some((version, mut options)) = args.split_first() else {
+            self.permissions()?;
+            return self.hello_reply();
+        };
+        let resp3 = match version.as_slice() {
+            b"2" => false,
+            b"3" => true,
+            _ => return Err(Error("NOPROTO unsupported protocol version".to_string())),
+        };
+        while let Some((option, rest)) = options.split_first() {
+            options = match (option.to_ascii_lowercase().as_slice(), rest) {
+                (b"auth", [_, password, rest @ ..]) => {
+                    self.authenticate(state, password)?;
+                    rest
+                }
+                (b"setname", [_, rest @ ..]) => rest,
+                _ => return Err(syntax_error()),
+            };
+        }
+        self.permissions()?;
+        self.out.resp3 = resp3;
+        self.hello_reply()
+    }
+
+    fn hello_reply(&mut self) -> Result<(), Error> {
+        self.out.map(6);
+        self.out.bulk(b"server");
+        self.out.bulk(b"carmine");
+        self.out.bulk(b"version");
+        self.out.bulk(env!("CARGO_PKG_VERSION").as_bytes());
+        self.out.bulk(b"proto");
+        self.out.integer(if self.out.resp3 { 3 } else { 2 });
+        self.out.bulk(b"mode");
+        self.out.bulk(b"standalone");
+        self.out.bulk(b"role");
+        self.out.bulk(b"master");
+        self.out.bulk(b"modules");
+        self.out.array(0);
+        Ok(())
+    }
+
+    /// Client libraries announce themselves on connect; accept and ignore it.
+    fn client(&mut self, args: &[Vec<u8>]) -> Result<(), Error> {
+        match args.first().map(|s| s.to_ascii_lowercase()).as_deref() {
+            Some(b"setname") | Some(b"setinfo") => {
+                self.out.ok();
+                Ok(())
+            }
+            _ => Err(Error("ERR unknown subcommand for 'client'".to_string())),
+        }
+    }
+
+    fn select(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let [shelf] = args else {
+            return Err(arity_error("select"));
+        };
+        let shelf = String::from_utf8(shelf.clone()).map_err(|_| syntax_error())?;
+        self.permissions()?.check(&self.cabinet, Some(&shelf), Access::Read)?;
+        lookup_shelf(state, &self.cabinet, shelf.clone())?;
+        self.shelf = Some(shelf);
+        self.scan_cursors.clear();
+        self.out.ok();
+        Ok(())
+    }
+
+    /// Checks that the client may `access` the selected shelf, then resolves
+    /// it and counts `command` against it.
+    fn resolve(&self, state: &AppState, command: &str, access: Access) -> Result<ResolvedShelf, Error> {
+        let shelf = self
+            .shelf
+            .clone()
+            .ok_or_else(|| Error("ERR no shelf selected; use SELECT <shelf>".to_string()))?;
+        self.permissions()?.check(&self.cabinet, Some(&shelf), access)?;
+        let resolved = lookup_shelf(state, &self.cabinet, shelf)?;
+        state.metrics.shelf_op("resp", &resolved, command);
+        Ok(resolved)
+    }
+
+    fn get(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let [key] = args else {
+            return Err(arity_error("get"));
+        };
+        let resolved = self.resolve(state, "get", Access::Read)?;
+        let key = to_key(&resolved, key)?;
+        match resolved.shelf.get(&resolved.begin_read()?, &key)? {
+            Some(value) => self.value(&value),
+            None => self.out.null(),
+        }
+        Ok(())
+    }
+
+    /// `SET key value [NX | XX]`. Expiry options are rejected since shelves
+    /// don't expire entries.
+    fn set(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let [key, value, options @ ..] = args else {
+            return Err(arity_error("set"));
+        };
+        let (mut nx, mut xx) = (false, false);
+        for option in options {
+            match option.to_ascii_lowercase().as_slice() {
+                b"nx" => nx = true,
+                b"xx" => xx = true,
+                b"ex" | b"px" | b"exat" | b"pxat" | b"keepttl" => {
+                    return Err(Error("ERR expiry is not supported".to_string()));
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        if nx && xx {
+            return Err(syntax_error());
+        }
+
+        let resolved = self.resolve(state, "set", Access::Write)?;
+        let key = to_key(&resolved, key)?;
+        let value = to_value(&resolved, value)?;
+        let tx = resolved.begin_write()?;
+        // `put` refuses to overwrite, which tells us whether the key existed
+        // without a separate read. Dropping the transaction undoes it.
+        let stored = match (nx, xx) {
+            (false, false) => {
+                resolved.shelf.set(&tx, key, value)?;
+                true
+            }
+            (true, _) => match resolved.shelf.put(&tx, key, value) {
+                Ok(()) => true,
+                Err(TransactionError::KeyAlreadyExists) => false,
+                Err(e) => return Err(e.into()),
+            },
+            (_, true) => match resolved.shelf.put(&tx, key.clone(), value.clone()) {
+                Ok(()) => false,
+                Err(TransactionError::KeyAlreadyExists) => {
+                    resolved.shelf.set(&tx, key, value)?;
+                    true
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        if stored {
+            commit_write(state, &resolved, tx)?;
+            self.out.ok();
+        } else {
+            self.out.null();
+        }
+        Ok(())
+    }
+
+    fn del(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        if args.is_empty() {
+            return Err(arity_error("del"));
+        }
+        let resolved = self.resolve(state, "del", Access::Write)?;
+        let keys = args.iter().map(|k| to_key(&resolved, k)).collect::<Result<Vec<_>, _>>()?;
+        let tx = resolved.begin_write()?;
+        let deleted = resolved.shelf.batch_delete(&tx, &keys)?;
+        commit_write(state, &resolved, tx)?;
+        self.out.integer(deleted.iter().filter(|d| **d).count() as i64);
+        Ok(())
+    }
+
+    /// Counts each argument that exists, so a key given twice counts twice.
+    fn exists(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        if args.is_empty() {
+            return Err(arity_error("exists"));
+        }
+        let resolved = self.resolve(state, "exists", Access::Read)?;
+        let tx = resolved.begin_read()?;
+        let mut count = 0;
+        for key in args {
+            if resolved.shelf.exists(&tx, &to_key(&resolved, key)?)? {
+                count += 1;
+            }
+        }
+        self.out.integer(count);
+        Ok(())
+    }
+
+    fn mget(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        if args.is_empty() {
+            return Err(arity_error("mget"));
+        }
+        let resolved = self.resolve(state, "mget", Access::Read)?;
+        let keys = args.iter().map(|k| to_key(&resolved, k)).collect::<Result<Vec<_>, _>>()?;
+        let results = resolved.shelf.get_batch(&resolved.begin_read()?, &keys)?;
+        self.out.array(keys.len());
+        for i in 0..keys.len() {
+            match results.get(i) {
+                Ok(Some(value)) => self.value(&value),
+                Ok(None) => self.out.null(),
+                Err(e) => {
+                    let Error(message) = ApiError::from(e).into();
+                    self.out.error(&message);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets every pair or none of them.
+    fn mset(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(arity_error("mset"));
+        }
+        let resolved = self.resolve(state, "mset", Access::Write)?;
+        let entries = args
+            .chunks(2)
+            .map(|pair| Ok((to_key(&resolved, &pair[0])?, to_value(&resolved, &pair[1])?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let tx = resolved.begin_write()?;
+        if let Some(e) = resolved.shelf.batch_set(&tx, &entries)?.into_iter().find_map(Result::err) {
+            return Err(e.into());
+        }
+        commit_write(state, &resolved, tx)?;
+        self.out.ok();
+        Ok(())
+    }
+
+    /// `INCR`, `DECR`, `INCRBY` and `DECRBY`, on `Int` shelves only. A
+    /// missing key counts as 0.
+    fn incr_by(
+        &mut self,
+        state: &AppState,
+        command: &str,
+        args: &[Vec<u8>],
+        fixed: Option<i64>,
+    ) -> Result<(), Error> {
+        let (key, delta) = match (args, fixed) {
+            ([key], Some(delta)) => (key, delta),
+            ([key, delta], None) => {
+                let delta = std::str::from_utf8(delta)
+                    .ok()
+                    .and_then(|d| d.parse::<i64>().ok())
+                    .ok_or_else(|| Error("ERR value is not an integer or out of range".to_string()))?;
+                let delta = if command == "decrby" { delta.checked_neg() } else { Some(delta) };
+                (key, delta.ok_or_else(|| Error("ERR decrement would overflow".to_string()))?)
+            }
+            _ => return Err(arity_error(command)),
+        };
+
+        let resolved = self.resolve(state, command, Access::Write)?;
+        if resolved.shelf.value_type != ValueType::Int {
+            return Err(Error(format!(
+                "WRONGTYPE {} needs an Int shelf, '{}' holds {}",
+                command.to_ascii_uppercase(),
+                resolved.shelf.name,
+                resolved.shelf.value_type
+            )));
+        }
+        let key = to_key(&resolved, key)?;
+        let tx = resolved.begin_write()?;
+        let start = Value::Int(Int::from(delta));
+        let next = match resolved.shelf.put(&tx, key.clone(), start) {
+            Ok(()) => delta,
+            Err(TransactionError::KeyAlreadyExists) => {
+                // Nothing else can commit while we hold the write
+                // transaction, so this read sees the value we're replacing.
+                let current = match resolved.shelf.get(&resolved.begin_read()?, &key)? {
+                    Some(Value::Int(i)) => *i,
+                    _ => 0,
+                };
+                let next = current
+                    .checked_add(delta)
+                    .ok_or_else(|| Error("ERR increment or decrement would overflow".to_string()))?;
+                resolved.shelf.set(&tx, key, Value::Int(Int::from(next)))?;
+                next
+            }
+            Err(e) => return Err(e.into()),
+        };
+        commit_write(state, &resolved, tx)?;
+        self.out.integer(next);
+        Ok(())
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`. Each cursor stands for
+    /// the last key of the page it was returned with, and the next page
+    /// starts after that key, so a full scan returns every key that exists
+    /// throughout it, however the shelf changes in between.
+    fn scan(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        let Some((cursor, options)) = args.split_first() else {
+            return Err(arity_error("scan"));
+        };
+        let invalid_cursor = || Error("ERR invalid cursor".to_string());
+        let cursor = std::str::from_utf8(cursor)
+            .ok()
+            .and_then(|c| c.parse::<u64>().ok())
+            .ok_or_else(invalid_cursor)?;
+        let after = match cursor {
+            0 => None,
+            cursor => Some(self.scan_cursors.get(&cursor).cloned().ok_or_else(invalid_cursor)?),
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or_else(syntax_error)?;
+            match option.to_ascii_lowercase().as_slice() {
+                b"match" => pattern = Some(value.as_slice()),
+                b"count" => {
+                    count = std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|c| c.parse::<usize>().ok())
+                        .filter(|c| *c > 0)
+                        .ok_or_else(syntax_error)?;
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let resolved = self.resolve(state, "scan", Access::Read)?;
+        let start = match &after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        // One extra key tells whether the scan is done.
+        let mut entries = resolved
+            .shelf
+            .get_page(&resolved.begin_read()?, start, Bound::Unbounded, count.saturating_add(1))?;
+        let next = if entries.len() > count {
+            entries.truncate(count);
+            self.last_cursor += 1;
+            if self.scan_cursors.len() >= MAX_SCAN_CURSORS
+                && let Some(oldest) = self.scan_cursors.keys().min().copied()
+            {
+                self.scan_cursors.remove(&oldest);
+            }
+            let (last, _) = &entries[count - 1];
+            self.scan_cursors.insert(self.last_cursor, last.clone());
+            self.last_cursor
+        } else {
+            0
+        };
+        let page: Vec<String> = entries
+            .iter()
+            .map(|(key, _)| key_text(key))
+            .filter(|k| pattern.is_none_or(|p| glob_match(p, k.as_bytes())))
+            .collect();
+
+        self.out.array(2);
+        self.out.bulk(next.to_string().as_bytes());
+        self.out.array(page.len());
+        for key in &page {
+            self.out.bulk(key.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn dbsize(&mut self, state: &AppState, args: &[Vec<u8>]) -> Result<(), Error> {
+        if !args.is_empty() {
+            return Err(arity_error("dbsize"));
+        }
+        let resolved = self.resolve(state, "dbsize", Access::Read)?;
+        let count = resolved.shelf.count(&resolved.begin_read()?)?;
+        self.out.integer(count as i64);
+        Ok(())
+    }
+
+    /// Values go out as bulk strings: numbers as text, objects as JSON and
+    /// bytes as-is.
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::String(s) => self.out.bulk(s.as_bytes()),
+            Value::Int(i) => self.out.bulk(i.to_string().as_bytes()),
+            Value::Number(n) => self.out.bulk(n.to_string().as_bytes()),
+            Value::Object(o) => self.out.bulk(jsonb::RawJsonb::new(o).to_string().as_bytes()),
+            Value::Byte(b) => self.out.bulk(b),
+        }
+    }
+}
+
+fn to_key(resolved: &ResolvedShelf, raw: &[u8]) -> Result<Key, Error> {
+    let key_type = resolved.shelf.key_type;
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| Key::String(s.to_string()).convert(key_type).ok())
+        .ok_or_else(|| Error(format!("WRONGTYPE key is not a valid {}", key_type)))
+}
+
+fn to_value(resolved: &ResolvedShelf, raw: &[u8]) -> Result<Value, Error> {
+    let value_type = resolved.shelf.value_type;
+    if value_type == ValueType::Byte {
+        return Ok(Value::Byte(raw.to_vec()));
+    }
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| Value::String(s.to_string()).convert(value_type).ok())
+        .ok_or_else(|| Error(format!("WRONGTYPE value is not a valid {}", value_type)))
+}
+
+fn key_text(key: &Key) -> String {
+    match key {
+        Key::String(s) => s.clone(),
+        Key::Int(i) => i.to_string(),
+        Key::Number(n) => n.to_string(),
+    }
+}
+
+/// Redis glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much text it has swallowed so far.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => class_match(&pattern[p..], text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class at the start of `pattern`, returning the
+/// class's length on a match. An unclosed `[` is a literal.
+fn class_match(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    loop {
+        match pattern.get(i) {
+            None => return (c == b'[').then_some(1),
+            Some(b']') => break,
+            Some(b'\\') if i + 1 < pattern.len() => {
+                matched |= pattern[i + 1] == c;
+                i += 2;
+            }
+            Some(&lo) if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&hi| hi != b']') => {
+                let hi = pattern[i + 2];
+                matched |= (lo.min(hi)..=lo.max(hi)).contains(&c);
+                i += 3;
+            }
+            Some(&x) => {
+                matched |= x == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negate).then_some(i + 1)
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the unit tests.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use carmine_core::{key::KeyType, system_store::SystemStore, value::ValueType};
+
+use crate::api::admin;
+use crate::api::auth::Authenticator;
+use crate::api::extractors::{lookup_shelf, ResolvedShelf};
+use crate::cabinets::OpenCabinets;
+use crate::config::AuthMode;
+use crate::AppState;
+
+/// Server state over a fresh data directory, deleted when the directory is
+/// dropped.
+pub(crate) fn temp_state(
+    max_open: Option<usize>,
+    idle_timeout: Option<Duration>,
+) -> (tempfile::TempDir, Arc<AppState>) {
+    let dir = tempfile::tempdir().unwrap();
+    let system_store = SystemStore::open(&dir.path().join("system.redb"), 1024 * 1024).unwrap();
+    let auth = Authenticator {
+        mode: AuthMode::None,
+        master_key_hash: None,
+        jwt: None,
+        client_scopes: Default::default(),
+    };
+    let state = AppState::new(
+        system_store,
+        dir.path().to_path_buf(),
+        OpenCabinets::new(1024 * 1024, max_open, idle_timeout),
+        redb::Durability::Immediate,
+        auth,
+        None,
+    );
+    (dir, Arc::new(state))
+}
+
+/// Creates `cabinet` with an Int-keyed `shelf` of Strings and resolves it.
+pub(crate) fn temp_shelf(state: &AppState, cabinet: &str, shelf: &str) -> ResolvedShelf {
+    admin::create_cabinet(state, cabinet.to_string(), None).unwrap();
+    admin::create_shelf(state, cabinet, shelf.to_string(), KeyType::Int, ValueType::String).unwrap();
+    lookup_shelf(state, cabinet, shelf.to_string()).unwrap()
+}
@@ -0,0 +1,319 @@
+//! TLS termination for the HTTP listener, configured by `[server.tls]`.
+//!
+//! Connections are accepted and handshaken here, then served by hyper the
+//! same way `axum::serve` would. When clients present a certificate, its
+//! common name is attached to each request as a [`ClientCertificate`] for
+//! the `client_cert` auth mode.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ServerConnection, WebPkiClientVerifier};
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::config::TlsConfig;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to read {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("No {1} found in {0}")]
+    Missing(String, &'static str),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("Invalid client CA: {0}")]
+    ClientCa(String),
+}
+
+/// The verified certificate a client connected with.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_connection(connection: &ServerConnection) -> Option<Self> {
+        let der = connection.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        Some(Self { common_name })
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Read(path.display().to_string(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Read(path.display().to_string(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::Missing(path.display().to_string(), "certificates"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| TlsError::Read(path.display().to_string(), e))?
+        .ok_or_else(|| TlsError::Missing(path.display().to_string(), "private key"))
+}
+
+/// Builds the acceptor for `config`, loading its certificates and key.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_auth == "optional" {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(
+                verifier.build().map_err(|e| TlsError::ClientCa(e.to_string()))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config =
+        builder.with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// How long a client gets to finish the handshake before its connection is
+/// dropped, so idle sockets can't pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept that isn't about one connection, such as
+/// running out of file descriptors, so the loop doesn't spin on it. The same
+/// as `axum::serve`.
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// Serves `app` over TLS on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, acceptor: TlsAcceptor, app: Router) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_connection_error(&e) => continue,
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match handshake(&acceptor, stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let client = ClientCertificate::from_connection(stream.get_ref().1);
+            let service = service_fn(move |mut request: hyper::Request<Incoming>| {
+                if let Some(client) = &client {
+                    request.extensions_mut().insert(client.clone());
+                }
+                app.clone().oneshot(request)
+            });
+            let served = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await;
+            if let Err(e) = served {
+                tracing::debug!("Connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handshakes with a client, giving up after [`HANDSHAKE_TIMEOUT`].
+async fn handshake<IO>(acceptor: &TlsAcceptor, stream: IO) -> std::io::Result<TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")))
+}
+
+/// Accept errors that only concern the connection being accepted.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    /// A CA and the PEM certificate and key of a server and a client it
+    /// signed, the client's with common name `worker`.
+    struct Pki {
+        dir: tempfile::TempDir,
+        ca: CertificateDer<'static>,
+        client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            ca_params.distinguished_name.push(DnType::CommonName, "test ca");
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let server_key = KeyPair::generate().unwrap();
+            let server = CertificateParams::new(vec!["localhost".to_string()])
+                .unwrap()
+                .signed_by(&server_key, &ca, &ca_key)
+                .unwrap();
+
+            let client_key = KeyPair::generate().unwrap();
+            let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+            client_params.distinguished_name.push(DnType::CommonName, "worker");
+            client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+            std::fs::write(dir.path().join("server.pem"), server.pem()).unwrap();
+            std::fs::write(dir.path().join("server.key"), server_key.serialize_pem()).unwrap();
+            let client_key = PrivateKeyDer::try_from(client_key.serialize_der()).unwrap();
+            Self { dir, ca: ca.der().clone(), client: (client.der().clone(), client_key) }
+        }
+
+        fn acceptor(&self, client_auth: Option<&str>) -> TlsAcceptor {
+            acceptor(&TlsConfig {
+                cert: self.dir.path().join("server.pem"),
+                key: self.dir.path().join("server.key"),
+                client_ca: client_auth.map(|_| self.dir.path().join("ca.pem")),
+                client_auth: client_auth.unwrap_or("required").to_string(),
+                client_scopes: Default::default(),
+            })
+            .unwrap()
+        }
+
+        fn connector(&self, with_cert: bool) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = if with_cert {
+                let (cert, key) = &self.client;
+                builder.with_client_auth_cert(vec![cert.clone()], key.clone_key()).unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+
+        /// Connects in memory and returns what the server saw of the client.
+        async fn connect(&self, client_auth: Option<&str>, with_cert: bool) -> std::io::Result<Option<ClientCertificate>> {
+            let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+            let acceptor = self.acceptor(client_auth);
+            let server = async {
+                let stream = handshake(&acceptor, server_io).await?;
+                Ok(ClientCertificate::from_connection(stream.get_ref().1))
+            };
+            let client = async {
+                let name = "localhost".try_into().unwrap();
+                // Keep the client open until the server is done.
+                self.connector(with_cert).connect(name, client_io).await
+            };
+            let (server, _client) = tokio::join!(server, client);
+            server
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_common_name() {
+        let pki = Pki::new();
+        let client = pki.connect(Some("required"), true).await.unwrap().unwrap();
+        assert_eq!(client.common_name.as_deref(), Some("worker"));
+    }
+
+    #[tokio::test]
+    async fn test_required_client_auth() {
+        let pki = Pki::new();
+        assert!(pki.connect(Some("required"), false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_auth() {
+        let pki = Pki::new();
+        assert!(pki.connect(Some("optional"), false).await.unwrap().is_none());
+        let client = pki.connect(Some("optional"), true).await.unwrap().unwrap();
+        assert_eq!(client.common_name.as_deref(), Some("worker"));
+    }
+
+    #[tokio::test]
+    async fn test_no_client_ca() {
+        let pki = Pki::new();
+        assert!(pki.connect(None, false).await.unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_times_out() {
+        let pki = Pki::new();
+        let (_client_io, server_io) = tokio::io::duplex(1024);
+        let error = handshake(&pki.acceptor(None), server_io).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_connection_error(&Error::from(ErrorKind::ConnectionAborted)));
+        assert!(is_connection_error(&Error::from(ErrorKind::ConnectionReset)));
+        // Running out of file descriptors backs off instead.
+        assert!(!is_connection_error(&Error::from_raw_os_error(24)));
+        assert!(!is_connection_error(&Error::from(ErrorKind::OutOfMemory)));
+    }
+}
@@ -10,8 +10,21 @@ import type {
   ExistsResponse,
   CountResponse,
   BatchGetResponse,
+  BatchWriteOptions,
+  BatchWriteResponse,
+  ChangeRetention,
+  ChangesParams,
+  ChangesResponse,
+  DeleteShelfResponse,
+  CopyShelfResponse,
+  MigrateShelfRequest,
+  MigrationReport,
+  SocketChange,
+  SocketReply,
   KeyType,
   ValueType,
+  WatchParams,
+  WatchResponse,
 } from './types.js';
 
 export class ApiClient {
@@ -59,6 +72,29 @@ export class ApiClient {
     return { data: json as T, error: null, status };
   }
 
+  /** Posts `body` exactly as given, for payloads `JSON.stringify` can't produce. */
+  async postRaw<T = unknown>(
+    path: string,
+    body: string
+  ): Promise<{ data: T | null; error: ApiError | null; status: number; text: string }> {
+    const response = await fetch(`${this.baseUrl}${path}`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body,
+    });
+    const text = await response.text();
+    let json: unknown = null;
+    try {
+      json = text ? JSON.parse(text) : null;
+    } catch {
+      // Left as text for the caller to inspect.
+    }
+    if (!response.ok) {
+      return { data: null, error: json as ApiError | null, status: response.status, text };
+    }
+    return { data: json as T | null, error: null, status: response.status, text };
+  }
+
   async health(): Promise<string> {
     const response = await fetch(`${this.baseUrl}/health`);
     return response.text();
@@ -84,6 +120,15 @@ export class ApiClient {
     return this.request<null>('POST', `/system/cabinets/${encodeURIComponent(name)}/clean`);
   }
 
+  async renameCabinet(
+    name: string,
+    newName: string
+  ): Promise<{ data: CabinetMeta | null; error: ApiError | null; status: number }> {
+    return this.request<CabinetMeta>('POST', `/system/cabinets/${encodeURIComponent(name)}/rename`, {
+      name: newName,
+    });
+  }
+
   async createShelf(
     cabinet: string,
     name: string,
@@ -103,9 +148,93 @@ export class ApiClient {
 
   async deleteShelf(
     cabinet: string,
-    shelf: string
-  ): Promise<{ data: null; error: ApiError | null; status: number }> {
-    return this.request<null>('DELETE', `/system/cabinets/${encodeURIComponent(cabinet)}/shelves/${encodeURIComponent(shelf)}`);
+    shelf: string,
+    force = false
+  ): Promise<{ data: DeleteShelfResponse | null; error: ApiError | null; status: number }> {
+    const query = force ? '?force=true' : '';
+    return this.request<DeleteShelfResponse>(
+      'DELETE',
+      `/system/cabinets/${encodeURIComponent(cabinet)}/shelves/${encodeURIComponent(shelf)}${query}`
+    );
+  }
+
+  async renameShelf(
+    cabinet: string,
+    shelf: string,
+    newName: string
+  ): Promise<{ data: ShelfMeta | null; error: ApiError | null; status: number }> {
+    return this.request<ShelfMeta>(
+      'POST',
+      `/system/cabinets/${encodeURIComponent(cabinet)}/shelves/${encodeURIComponent(shelf)}/rename`,
+      { name: newName }
+    );
+  }
+
+  async copyShelf(
+    cabinet: string,
+    shelf: string,
+    destName: string
+  ): Promise<{ data: CopyShelfResponse | null; error: ApiError | null; status: number }> {
+    return this.request<CopyShelfResponse>(
+      'POST',
+      `/system/cabinets/${encodeURIComponent(cabinet)}/shelves/${encodeURIComponent(shelf)}/copy`,
+      { name: destName }
+    );
+  }
+
+  async migrateShelf(
+    cabinet: string,
+    shelf: string,
+    req: MigrateShelfRequest
+  ): Promise<{ data: MigrationReport | null; error: ApiError | null; status: number }> {
+    return this.request<MigrationReport>(
+      'POST',
+      `/system/cabinets/${encodeURIComponent(cabinet)}/shelves/${encodeURIComponent(shelf)}/migrate`,
+      req
+    );
+  }
+
+  async getChangeRetention(
+    cabinet: string
+  ): Promise<{ data: ChangeRetention | null; error: ApiError | null; status: number }> {
+    return this.request<ChangeRetention>('GET', `/system/cabinets/${encodeURIComponent(cabinet)}/changes/retention`);
+  }
+
+  async setChangeRetention(
+    cabinet: string,
+    retention: Partial<ChangeRetention>
+  ): Promise<{ data: ChangeRetention | null; error: ApiError | null; status: number }> {
+    return this.request<ChangeRetention>(
+      'PUT',
+      `/system/cabinets/${encodeURIComponent(cabinet)}/changes/retention`,
+      retention
+    );
+  }
+
+  async changes<K = unknown, V = unknown>(
+    cabinet: string,
+    params: ChangesParams = {}
+  ): Promise<{ data: ChangesResponse<K, V> | null; error: ApiError | null; status: number }> {
+    const query = new URLSearchParams();
+    for (const [name, value] of Object.entries(params)) {
+      if (value !== undefined) query.set(name, String(value));
+    }
+    const qs = query.toString();
+    return this.request<ChangesResponse<K, V>>(
+      'GET',
+      `/v1/${encodeURIComponent(cabinet)}/changes${qs ? `?${qs}` : ''}`
+    );
+  }
+
+  async openSocket(): Promise<DataSocket> {
+    return DataSocket.open(this.baseUrl);
+  }
+
+  /** Opens the cabinet's Server-Sent Events change stream. Abort the signal to close it. */
+  async streamChanges(cabinet: string, since = 0, signal?: AbortSignal): Promise<Response> {
+    return fetch(`${this.baseUrl}/v1/${encodeURIComponent(cabinet)}/changes/stream?since=${since}`, {
+      signal,
+    });
   }
 
   async set<K, V>(
@@ -205,20 +334,24 @@ export class ApiClient {
   async batchSet<K, V>(
     cabinet: string,
     shelf: string,
-    entries: [K, V][]
-  ): Promise<{ data: null; error: ApiError | null; status: number }> {
-    return this.request<null>('POST', `/v1/${encodeURIComponent(cabinet)}/${encodeURIComponent(shelf)}/batch/set`, {
+    entries: [K, V][],
+    options: BatchWriteOptions = {}
+  ): Promise<{ data: BatchWriteResponse | null; error: ApiError | null; status: number }> {
+    return this.request<BatchWriteResponse>('POST', `/v1/${encodeURIComponent(cabinet)}/${encodeURIComponent(shelf)}/batch/set`, {
       entries,
+      ...options,
     });
   }
 
   async batchPut<K, V>(
     cabinet: string,
     shelf: string,
-    entries: [K, V][]
-  ): Promise<{ data: null; error: ApiError | null; status: number }> {
-    return this.request<null>('POST', `/v1/${encodeURIComponent(cabinet)}/${encodeURIComponent(shelf)}/batch/put`, {
+    entries: [K, V][],
+    options: BatchWriteOptions = {}
+  ): Promise<{ data: BatchWriteResponse | null; error: ApiError | null; status: number }> {
+    return this.request<BatchWriteResponse>('POST', `/v1/${encodeURIComponent(cabinet)}/${encodeURIComponent(shelf)}/batch/put`, {
       entries,
+      ...options,
     });
   }
 
@@ -232,6 +365,21 @@ export class ApiClient {
     });
   }
 
+  async watch<K = unknown, V = unknown>(
+    cabinet: string,
+    shelf: string,
+    params: WatchParams
+  ): Promise<{ data: WatchResponse<K, V> | null; error: ApiError | null; status: number }> {
+    const query = new URLSearchParams();
+    for (const [name, value] of Object.entries(params)) {
+      if (value !== undefined) query.set(name, String(value));
+    }
+    return this.request<WatchResponse<K, V>>(
+      'GET',
+      `/v1/${encodeURIComponent(cabinet)}/${encodeURIComponent(shelf)}/watch?${query.toString()}`
+    );
+  }
+
   async batchGet<K, V>(
     cabinet: string,
     shelf: string,
@@ -243,4 +391,52 @@ export class ApiClient {
   }
 }
 
+/**
+ * A connection to the WebSocket endpoint. Requests are matched to replies by
+ * id, so several can be in flight at once.
+ */
+export class DataSocket {
+  private nextId = 1;
+  private pending = new Map<number, (reply: SocketReply) => void>();
+  readonly changes: SocketChange[] = [];
+
+  private constructor(private socket: WebSocket) {
+    socket.addEventListener('message', (event) => {
+      const message = JSON.parse(String(event.data));
+      if ('subscription' in message && 'change' in message) {
+        this.changes.push(message as SocketChange);
+        return;
+      }
+      const resolve = this.pending.get(message.id);
+      this.pending.delete(message.id);
+      resolve?.(message as SocketReply);
+    });
+  }
+
+  static open(baseUrl: string): Promise<DataSocket> {
+    const socket = new WebSocket(`${baseUrl.replace(/^http/, 'ws')}/v1/ws`);
+    return new Promise((resolve, reject) => {
+      socket.addEventListener('open', () => resolve(new DataSocket(socket)));
+      socket.addEventListener('error', reject);
+    });
+  }
+
+  request<T = unknown>(op: string, fields: Record<string, unknown> = {}): Promise<SocketReply<T>> {
+    const id = this.nextId++;
+    return new Promise((resolve) => {
+      this.pending.set(id, resolve as (reply: SocketReply) => void);
+      this.socket.send(JSON.stringify({ id, op, ...fields }));
+    });
+  }
+
+  /** Sends a frame as-is, for testing malformed input. */
+  sendRaw(data: string): void {
+    this.socket.send(data);
+  }
+
+  close(): void {
+    this.socket.close();
+  }
+}
+
 export const client = new ApiClient();
@@ -11,8 +11,35 @@ export interface CabinetMeta {
   shelves: ShelfMeta[];
 }
 
+export interface DeleteShelfResponse {
+  deleted_entries: number;
+}
+
+export interface CopyShelfResponse extends ShelfMeta {
+  copied_entries: number;
+}
+
+export type ConversionPolicy = 'strict' | 'skip_invalid' | 'default_value';
+
+export interface MigrateShelfRequest {
+  key_type?: KeyType;
+  value_type?: ValueType;
+  policy?: ConversionPolicy;
+  default?: unknown;
+}
+
+export interface MigrationReport {
+  applied: boolean;
+  shelf: ShelfMeta;
+  converted: number;
+  defaulted: number;
+  failed: { key: unknown; error: string }[];
+}
+
 export interface ApiError {
   error: string;
+  code: string;
+  details?: Record<string, unknown>;
 }
 
 export type KeyType = 'String' | 'Int' | 'Number';
@@ -44,4 +71,73 @@ export interface CountResponse {
 
 export interface BatchGetResponse<V = unknown> {
   values: (V | null)[];
+  errors: (ApiError | null)[];
+}
+
+export interface BatchWriteOptions {
+  atomic?: boolean;
+}
+
+export type BatchItemResult = { ok: true } | ({ ok: false } & ApiError);
+
+export interface BatchWriteResponse {
+  applied: boolean;
+  results: BatchItemResult[];
+}
+
+export type ChangeOp = 'set' | 'put' | 'delete' | 'clear' | 'migrate';
+
+export interface Change<K = unknown, V = unknown> {
+  seq: number;
+  timestamp: number;
+  op: ChangeOp;
+  shelf: string;
+  key: K | null;
+  value: V | null;
+}
+
+export interface ChangesParams {
+  since?: number;
+  limit?: number;
+  shelf?: string;
+}
+
+export interface ChangesResponse<K = unknown, V = unknown> {
+  changes: Change<K, V>[];
+  next: number;
+  latest: number;
+  oldest: number | null;
+  truncated: boolean;
+}
+
+export interface ChangeRetention {
+  max_entries: number | null;
+  max_age_secs: number | null;
+}
+
+export interface WatchParams {
+  key?: string | number;
+  start?: string | number;
+  end?: string | number;
+  since_version?: number;
+  timeout?: number;
+}
+
+export interface WatchResponse<K = unknown, V = unknown> {
+  version: number;
+  changes: Change<K, V>[];
+  truncated: boolean;
+  /** Current value of the watched key; only present when watching a single key. */
+  value?: V | null;
+}
+
+export interface SocketReply<T = unknown> {
+  id: unknown;
+  status: number;
+  body: T | null;
+}
+
+export interface SocketChange<K = unknown, V = unknown> {
+  subscription: number;
+  change: Change<K, V>;
 }
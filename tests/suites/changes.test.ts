@@ -0,0 +1,182 @@
+import { describe, it, expect, beforeAll, afterAll } from 'vitest';
+import { client } from '../lib/client.js';
+import type { Change } from '../lib/types.js';
+
+describe('Change feed', () => {
+  const testCabinet = `changes-test-${Date.now()}`;
+
+  beforeAll(async () => {
+    await client.createCabinet(testCabinet);
+    await client.createShelf(testCabinet, 'users', 'String', 'String');
+    await client.createShelf(testCabinet, 'counts', 'String', 'Int');
+  });
+
+  afterAll(async () => {
+    await client.deleteCabinet(testCabinet);
+  });
+
+  it('starts empty', async () => {
+    const result = await client.changes(testCabinet);
+    expect(result.error).toBeNull();
+    expect(result.data).toEqual({ changes: [], next: 0, latest: 0, oldest: null, truncated: false });
+  });
+
+  it('records writes in order', async () => {
+    await client.set(testCabinet, 'users', 'alice', 'admin');
+    await client.batchPut(testCabinet, 'counts', [
+      ['a', 1],
+      ['b', 'not an int'],
+    ]);
+    await client.delete(testCabinet, 'users', 'alice');
+
+    const result = await client.changes<string, unknown>(testCabinet);
+    expect(result.error).toBeNull();
+    const changes = result.data!.changes;
+    expect(changes.map((c) => [c.seq, c.op, c.shelf, c.key, c.value])).toEqual([
+      [1, 'set', 'users', 'alice', 'admin'],
+      [2, 'put', 'counts', 'a', 1],
+      [3, 'delete', 'users', 'alice', null],
+    ]);
+    expect(result.data!.latest).toBe(3);
+    expect(result.data!.next).toBe(3);
+  });
+
+  it('pages with since, limit and a shelf filter', async () => {
+    const page = await client.changes(testCabinet, { since: 1, limit: 1 });
+    expect(page.data!.changes.map((c) => c.seq)).toEqual([2]);
+    expect(page.data!.next).toBe(2);
+
+    const users = await client.changes(testCabinet, { shelf: 'users' });
+    expect(users.data!.changes.map((c) => c.seq)).toEqual([1, 3]);
+  });
+
+  it('does not record failed or rolled back writes', async () => {
+    const before = await client.changes(testCabinet);
+    await client.put(testCabinet, 'counts', 'a', 2);
+    await client.batchPut(
+      testCabinet,
+      'counts',
+      [
+        ['c', 3],
+        ['a', 4],
+      ],
+      { atomic: true }
+    );
+    const after = await client.changes(testCabinet);
+    expect(after.data!.latest).toBe(before.data!.latest);
+  });
+
+  it('prunes to the configured retention', async () => {
+    const defaults = await client.getChangeRetention(testCabinet);
+    expect(defaults.data).toEqual({ max_entries: 10000, max_age_secs: null });
+
+    const updated = await client.setChangeRetention(testCabinet, { max_entries: 2 });
+    expect(updated.data).toEqual({ max_entries: 2, max_age_secs: null });
+
+    const result = await client.changes(testCabinet);
+    expect(result.data!.changes.map((c) => c.seq)).toEqual([2, 3]);
+    expect(result.data!.oldest).toBe(2);
+    expect(result.data!.truncated).toBe(true);
+  });
+
+  it('streams new changes over SSE', async () => {
+    const latest = (await client.changes(testCabinet)).data!.latest;
+    const controller = new AbortController();
+    const response = await client.streamChanges(testCabinet, latest, controller.signal);
+    expect(response.headers.get('content-type')).toContain('text/event-stream');
+
+    await client.set(testCabinet, 'users', 'bob', 'user');
+
+    const reader = response.body!.pipeThrough(new TextDecoderStream()).getReader();
+    let buffered = '';
+    while (!buffered.includes('\n\n')) {
+      const { value, done } = await reader.read();
+      if (done) break;
+      buffered += value;
+    }
+    controller.abort();
+
+    const data = buffered.split('\n').find((line) => line.startsWith('data: '))!;
+    const change = JSON.parse(data.slice('data: '.length)) as Change;
+    expect(change).toMatchObject({ seq: latest + 1, op: 'set', shelf: 'users', key: 'bob', value: 'user' });
+  });
+
+  describe('Watch', () => {
+    it('returns immediately when the key changed after since_version', async () => {
+      const latest = (await client.changes(testCabinet)).data!.latest;
+      await client.set(testCabinet, 'users', 'watched', 'v1');
+
+      const result = await client.watch<string, string>(testCabinet, 'users', {
+        key: 'watched',
+        since_version: latest,
+      });
+      expect(result.error).toBeNull();
+      expect(result.data!.value).toBe('v1');
+      expect(result.data!.version).toBe(latest + 1);
+      expect(result.data!.changes.map((c) => c.key)).toEqual(['watched']);
+    });
+
+    it('blocks until the key is written', async () => {
+      const pending = client.watch<string, string>(testCabinet, 'users', { key: 'watched', timeout: 10 });
+      await new Promise((resolve) => setTimeout(resolve, 200));
+      await client.set(testCabinet, 'users', 'other', 'ignored');
+      await client.set(testCabinet, 'users', 'watched', 'v2');
+
+      const result = await pending;
+      expect(result.data!.value).toBe('v2');
+      expect(result.data!.changes).toHaveLength(1);
+    });
+
+    it('wakes on any key in a range', async () => {
+      const pending = client.watch(testCabinet, 'counts', { start: 'm', end: 'p', timeout: 10 });
+      await new Promise((resolve) => setTimeout(resolve, 200));
+      await client.set(testCabinet, 'counts', 'z', 1);
+      await client.set(testCabinet, 'counts', 'n', 2);
+
+      const result = await pending;
+      expect(result.data!.changes.map((c) => c.key)).toEqual(['n']);
+      expect(result.data!).not.toHaveProperty('value');
+    });
+
+    it('treats equal Number keys as the same key', async () => {
+      await client.createShelf(testCabinet, 'numbers', 'Number', 'String');
+      const pending = client.watch<number, string>(testCabinet, 'numbers', { key: 2, timeout: 10 });
+      await new Promise((resolve) => setTimeout(resolve, 200));
+      // JSON.stringify would turn 2.0 into 2, so the body is written by hand.
+      const written = await client.postRaw(
+        `/v1/${encodeURIComponent(testCabinet)}/numbers/set`,
+        '{"key": 2.0, "value": "two"}'
+      );
+      expect(written.status).toBe(204);
+
+      const result = await pending;
+      expect(result.data!.changes).toHaveLength(1);
+      expect(result.data!.value).toBe('two');
+    });
+
+    it('times out with no changes', async () => {
+      const result = await client.watch(testCabinet, 'users', { key: 'watched', timeout: 1 });
+      expect(result.status).toBe(200);
+      expect(result.data!.changes).toEqual([]);
+      expect(result.data!.value).toBe('v2');
+    });
+
+    it('requires a key or a range', async () => {
+      const result = await client.watch(testCabinet, 'users', { start: 'a' });
+      expect(result.status).toBe(400);
+    });
+  });
+
+  it('rejects shelf names reserved for the change log', async () => {
+    const result = await client.createShelf(testCabinet, '__changes', 'String', 'String');
+    expect(result.status).toBe(400);
+  });
+
+  it('rejects the shelf name used by the change feed route', async () => {
+    const created = await client.createShelf(testCabinet, 'changes', 'String', 'String');
+    expect(created.status).toBe(400);
+
+    const renamed = await client.renameShelf(testCabinet, 'users', 'changes');
+    expect(renamed.status).toBe(400);
+  });
+});
@@ -1,5 +1,6 @@
 import { describe, it, expect, beforeAll, afterAll } from 'vitest';
 import { client } from '../lib/client.js';
+import type { BatchWriteResponse } from '../lib/types.js';
 
 describe('Data operations', () => {
   const testCabinet = `data-test-${Date.now()}`;
@@ -65,7 +66,8 @@ describe('Data operations', () => {
     it('rejects put on existing key', async () => {
       await client.put(testCabinet, stringShelf, 'put-overwrite', 'value1');
       const result = await client.put(testCabinet, stringShelf, 'put-overwrite', 'value2');
-      expect(result.status).not.toBe(204);
+      expect(result.status).toBe(409);
+      expect(result.error).toMatchObject({ code: 'key_already_exists' });
 
       const get = await client.get<string, string>(testCabinet, stringShelf, 'put-overwrite');
       expect(get.data!.value).toBe('value1');
@@ -180,7 +182,8 @@ describe('Data operations', () => {
 
       const result = await client.batchSet(testCabinet, stringShelf, entries);
       expect(result.error).toBeNull();
-      expect(result.status).toBe(204);
+      expect(result.status).toBe(200);
+      expect(result.data).toEqual({ applied: true, results: [{ ok: true }, { ok: true }, { ok: true }] });
 
       for (const [key, value] of entries) {
         const get = await client.get<string, string>(testCabinet, stringShelf, key);
@@ -202,6 +205,21 @@ describe('Data operations', () => {
       expect(result.data!.values[0]).toBe('val1');
       expect(result.data!.values[1]).toBe('val2');
       expect(result.data!.values[2]).toBeNull();
+      expect(result.data!.errors).toEqual([null, null, null]);
+    });
+
+    it('batch get reports per-key errors separately from misses', async () => {
+      await client.set(testCabinet, stringShelf, 'batch-get-ok', 'val');
+
+      const result = await client.batchGet<unknown, string>(testCabinet, stringShelf, ['batch-get-ok', 'missing', 42]);
+      expect(result.error).toBeNull();
+      expect(result.data!.values).toEqual(['val', null, null]);
+      expect(result.data!.errors[0]).toBeNull();
+      expect(result.data!.errors[1]).toBeNull();
+      expect(result.data!.errors[2]).toMatchObject({
+        code: 'key_type_mismatch',
+        details: { expected: 'String', actual: 'Int' },
+      });
     });
 
     it('batch deletes multiple keys', async () => {
@@ -226,7 +244,57 @@ describe('Data operations', () => {
 
       const result = await client.batchPut(testCabinet, stringShelf, entries);
       expect(result.error).toBeNull();
-      expect(result.status).toBe(204);
+      expect(result.status).toBe(200);
+      expect(result.data!.applied).toBe(true);
+    });
+
+    it('batch put reports rejected entries and commits the rest', async () => {
+      await client.set(testCabinet, stringShelf, 'batch-dup', 'existing');
+
+      const result = await client.batchPut<string, unknown>(testCabinet, stringShelf, [
+        ['batch-new', 'fresh'],
+        ['batch-dup', 'again'],
+        ['batch-bad', 7],
+      ]);
+      expect(result.status).toBe(200);
+      expect(result.data!.applied).toBe(true);
+      expect(result.data!.results).toEqual([
+        { ok: true },
+        { ok: false, error: 'Key already exists', code: 'key_already_exists' },
+        {
+          ok: false,
+          error: expect.any(String),
+          code: 'value_type_mismatch',
+          details: { expected: 'String', actual: 'Int' },
+        },
+      ]);
+
+      const fresh = await client.get<string, string>(testCabinet, stringShelf, 'batch-new');
+      expect(fresh.data!.value).toBe('fresh');
+      const dup = await client.get<string, string>(testCabinet, stringShelf, 'batch-dup');
+      expect(dup.data!.value).toBe('existing');
+    });
+
+    it('atomic batch put rolls back when any entry fails', async () => {
+      await client.set(testCabinet, stringShelf, 'atomic-dup', 'existing');
+
+      const result = await client.batchPut(
+        testCabinet,
+        stringShelf,
+        [
+          ['atomic-new', 'fresh'],
+          ['atomic-dup', 'again'],
+        ],
+        { atomic: true }
+      );
+      expect(result.status).toBe(422);
+      const body = result.error as unknown as BatchWriteResponse;
+      expect(body.applied).toBe(false);
+      expect(body.results[0]).toEqual({ ok: true });
+      expect(body.results[1]).toMatchObject({ ok: false, code: 'key_already_exists' });
+
+      const get = await client.get<string, string>(testCabinet, stringShelf, 'atomic-new');
+      expect(get.data!.value).toBeNull();
     });
   });
 
@@ -252,6 +320,8 @@ describe('Data operations', () => {
       expect(result.status).toBe(400);
       expect(result.error).toMatchObject({
         error: expect.stringContaining('Key type mismatch'),
+        code: 'key_type_mismatch',
+        details: { expected: 'Int', actual: 'String' },
       });
     });
   });
@@ -279,6 +349,8 @@ describe('Data operations', () => {
       expect(result.status).toBe(400);
       expect(result.error).toMatchObject({
         error: expect.stringContaining('Value type mismatch'),
+        code: 'value_type_mismatch',
+        details: { expected: 'Int', actual: 'String' },
       });
     });
   });
@@ -1,6 +1,6 @@
 import { describe, it, expect, beforeAll, afterAll } from 'vitest';
 import { client } from '../lib/client.js';
-import type { CabinetMeta, ShelfMeta } from '../lib/types.js';
+import type { CabinetMeta, KeyType, MigrationReport, ShelfMeta, ValueType } from '../lib/types.js';
 
 describe('System API', () => {
   const testCabinet = `test-cabinet-${Date.now()}`;
@@ -39,6 +39,22 @@ describe('System API', () => {
       });
     });
 
+    it('rejects malformed JSON with a structured error', async () => {
+      const result = await client.postRaw('/system/cabinets', '{"name":');
+      expect(result.status).toBe(400);
+      expect(JSON.parse(result.text)).toEqual({
+        error: expect.any(String),
+        code: 'invalid_request',
+      });
+    });
+
+    it('rejects cabinet names scopes could not name', async () => {
+      for (const name of ['', '*', 'a/b', 'a:b']) {
+        const result = await client.createCabinet(name);
+        expect(result.status).toBe(400);
+      }
+    });
+
     it('lists cabinets', async () => {
       const result = await client.listCabinets();
       expect(result.error).toBeNull();
@@ -98,6 +114,20 @@ describe('System API', () => {
       expect(result.data!.some((s) => s.name === testShelf)).toBe(true);
     });
 
+    it('rejects unknown key and value types', async () => {
+      const badKey = await client.createShelf(testCabinet, 'typo-shelf', 'string' as KeyType, 'String');
+      expect(badKey.status).toBe(400);
+      expect(badKey.error).toMatchObject({
+        error: expect.stringContaining('expected one of: String, Number, Int'),
+      });
+
+      const badValue = await client.createShelf(testCabinet, 'typo-shelf', 'String', 'Obj' as ValueType);
+      expect(badValue.status).toBe(400);
+
+      const shelves = await client.listShelves(testCabinet);
+      expect(shelves.data!.some((s) => s.name === 'typo-shelf')).toBe(false);
+    });
+
     it('returns error when creating shelf in non-existent cabinet', async () => {
       const result = await client.createShelf('non-existent-cabinet', 'some-shelf', 'String', 'String');
       expect(result.status).toBe(404);
@@ -138,6 +168,43 @@ describe('System API', () => {
       expect(shelves.data!.some((s) => s.name === shelfToDelete)).toBe(false);
     });
 
+    it('refuses to delete a non-empty shelf without force', async () => {
+      const shelf = 'shelf-with-data';
+      await client.createShelf(testCabinet, shelf, 'String', 'String');
+      await client.set(testCabinet, shelf, 'k1', 'v1');
+      await client.set(testCabinet, shelf, 'k2', 'v2');
+
+      const result = await client.deleteShelf(testCabinet, shelf);
+      expect(result.status).toBe(409);
+      expect(result.error).toMatchObject({
+        error: expect.stringContaining('not empty'),
+      });
+
+      const count = await client.count(testCabinet, shelf);
+      expect(count.data!.count).toBe(2);
+    });
+
+    it('force-deletes a shelf and reports destroyed entries', async () => {
+      const result = await client.deleteShelf(testCabinet, 'shelf-with-data', true);
+      expect(result.error).toBeNull();
+      expect(result.status).toBe(200);
+      expect(result.data).toEqual({ deleted_entries: 2 });
+
+      const shelves = await client.listShelves(testCabinet);
+      expect(shelves.data!.some((s) => s.name === 'shelf-with-data')).toBe(false);
+    });
+
+    it('recreates a deleted shelf with different types', async () => {
+      const created = await client.createShelf(testCabinet, 'shelf-with-data', 'Int', 'Int');
+      expect(created.error).toBeNull();
+
+      const setResult = await client.set(testCabinet, 'shelf-with-data', 1, 42);
+      expect(setResult.status).toBe(204);
+
+      const count = await client.count(testCabinet, 'shelf-with-data');
+      expect(count.data!.count).toBe(1);
+    });
+
     it('returns error when deleting non-existent shelf', async () => {
       const result = await client.deleteShelf(testCabinet, 'non-existent-shelf');
       expect(result.status).toBe(404);
@@ -147,6 +214,153 @@ describe('System API', () => {
     });
   });
 
+  describe('Rename and copy', () => {
+    const renameCabinet = `test-rename-${Date.now()}`;
+    const otherCabinet = `test-rename-other-${Date.now()}`;
+
+    beforeAll(async () => {
+      await client.createCabinet(renameCabinet);
+      await client.createCabinet(otherCabinet);
+      await client.createShelf(renameCabinet, 'source', 'String', 'String');
+      await client.createShelf(renameCabinet, 'taken', 'String', 'String');
+      await client.set(renameCabinet, 'source', 'k1', 'v1');
+      await client.set(renameCabinet, 'source', 'k2', 'v2');
+    });
+
+    it('renames a shelf and keeps its data', async () => {
+      const result = await client.renameShelf(renameCabinet, 'source', 'renamed');
+      expect(result.error).toBeNull();
+      expect(result.data).toMatchObject({ name: 'renamed', key_type: 'String', value_type: 'String' });
+
+      const get = await client.get<string, string>(renameCabinet, 'renamed', 'k1');
+      expect(get.data!.value).toBe('v1');
+
+      const old = await client.count(renameCabinet, 'source');
+      expect(old.status).toBe(404);
+    });
+
+    it('rejects renaming a shelf onto an existing name', async () => {
+      const result = await client.renameShelf(renameCabinet, 'renamed', 'taken');
+      expect(result.status).toBe(409);
+    });
+
+    it('copies a shelf independently of the source', async () => {
+      const result = await client.copyShelf(renameCabinet, 'renamed', 'backup');
+      expect(result.error).toBeNull();
+      expect(result.data).toMatchObject({ name: 'backup', copied_entries: 2 });
+
+      await client.set(renameCabinet, 'renamed', 'k3', 'v3');
+      const copyCount = await client.count(renameCabinet, 'backup');
+      expect(copyCount.data!.count).toBe(2);
+    });
+
+    it('rejects copying onto an existing shelf', async () => {
+      const result = await client.copyShelf(renameCabinet, 'renamed', 'taken');
+      expect(result.status).toBe(409);
+    });
+
+    it('checks target names the way create does', async () => {
+      for (const name of ['', '__hidden', 'a/b']) {
+        const renamed = await client.renameShelf(renameCabinet, 'renamed', name);
+        expect(renamed.status).toBe(400);
+        const copied = await client.copyShelf(renameCabinet, 'renamed', name);
+        expect(copied.status).toBe(400);
+      }
+      for (const name of ['', '*', 'a/b']) {
+        const renamed = await client.renameCabinet(renameCabinet, name);
+        expect(renamed.status).toBe(400);
+      }
+    });
+
+    it('returns error when copying a non-existent shelf', async () => {
+      const result = await client.copyShelf(renameCabinet, 'missing', 'anything');
+      expect(result.status).toBe(404);
+    });
+
+    it('rejects renaming a cabinet onto an existing name', async () => {
+      const result = await client.renameCabinet(renameCabinet, otherCabinet);
+      expect(result.status).toBe(409);
+    });
+
+    it('renames a cabinet', async () => {
+      const newName = `${renameCabinet}-new`;
+      const result = await client.renameCabinet(renameCabinet, newName);
+      expect(result.error).toBeNull();
+      expect(result.data!.name).toBe(newName);
+
+      const count = await client.count(newName, 'backup');
+      expect(count.data!.count).toBe(2);
+
+      const old = await client.getCabinet(renameCabinet);
+      expect(old.status).toBe(404);
+    });
+  });
+
+  describe('Type migration', () => {
+    const migrateCabinet = `test-migrate-${Date.now()}`;
+
+    beforeAll(async () => {
+      await client.createCabinet(migrateCabinet);
+      await client.createShelf(migrateCabinet, 'counts', 'String', 'String');
+      await client.set(migrateCabinet, 'counts', 'a', '1');
+      await client.set(migrateCabinet, 'counts', 'b', 'not a number');
+    });
+
+    it('aborts a strict migration with failures', async () => {
+      const result = await client.migrateShelf(migrateCabinet, 'counts', { value_type: 'Int' });
+      expect(result.status).toBe(422);
+      const report = result.error as unknown as MigrationReport;
+      expect(report).toMatchObject({ applied: false, converted: 1 });
+      expect(report.failed).toEqual([{ key: 'b', error: expect.stringContaining('Int') }]);
+
+      const shelves = await client.listShelves(migrateCabinet);
+      expect(shelves.data!.find((s) => s.name === 'counts')!.value_type).toBe('String');
+      const value = await client.get(migrateCabinet, 'counts', 'a');
+      expect(value.data!.value).toBe('1');
+    });
+
+    it('fills unconvertible values with a default', async () => {
+      const result = await client.migrateShelf(migrateCabinet, 'counts', {
+        value_type: 'Int',
+        policy: 'default_value',
+        default: 0,
+      });
+      expect(result.error).toBeNull();
+      expect(result.data).toMatchObject({
+        applied: true,
+        converted: 1,
+        defaulted: 1,
+        failed: [],
+        shelf: { name: 'counts', key_type: 'String', value_type: 'Int' },
+      });
+
+      const all = await client.all<string, number>(migrateCabinet, 'counts');
+      expect(all.data!.entries).toEqual([
+        ['a', 1],
+        ['b', 0],
+      ]);
+    });
+
+    it('drops entries that fail under skip_invalid', async () => {
+      const result = await client.migrateShelf(migrateCabinet, 'counts', {
+        key_type: 'Int',
+        policy: 'skip_invalid',
+      });
+      expect(result.data).toMatchObject({ applied: true, converted: 0 });
+      expect(result.data!.failed).toHaveLength(2);
+
+      const count = await client.count(migrateCabinet, 'counts');
+      expect(count.data!.count).toBe(0);
+    });
+
+    it('rejects unknown types', async () => {
+      const result = await client.migrateShelf(migrateCabinet, 'counts', {
+        value_type: 'Bogus' as ValueType,
+      });
+      expect(result.status).toBe(400);
+    });
+  });
+
   describe('Cabinet cleanup', () => {
     const cleanTestCabinet = `clean-test-${Date.now()}`;
     const cleanTestShelf = 'clean-shelf';
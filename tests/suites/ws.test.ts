@@ -0,0 +1,111 @@
+import { describe, it, expect, beforeAll, afterAll } from 'vitest';
+import { client, DataSocket } from '../lib/client.js';
+
+const waitFor = async (check: () => boolean, timeoutMs = 2000) => {
+  const deadline = Date.now() + timeoutMs;
+  while (!check()) {
+    if (Date.now() > deadline) throw new Error('timed out');
+    await new Promise((resolve) => setTimeout(resolve, 20));
+  }
+};
+
+describe('WebSocket', () => {
+  const testCabinet = `ws-test-${Date.now()}`;
+  const target = { cabinet: testCabinet, shelf: 'users' };
+  let socket: DataSocket;
+
+  beforeAll(async () => {
+    await client.createCabinet(testCabinet);
+    await client.createShelf(testCabinet, 'users', 'String', 'String');
+    socket = await client.openSocket();
+  });
+
+  afterAll(async () => {
+    socket.close();
+    await client.deleteCabinet(testCabinet);
+  });
+
+  it('runs data operations', async () => {
+    const set = await socket.request('set', { ...target, key: 'alice', value: 'admin' });
+    expect(set.status).toBe(204);
+
+    const get = await socket.request('get', { ...target, key: 'alice' });
+    expect(get.status).toBe(200);
+    expect(get.body).toEqual({ value: 'admin' });
+
+    const count = await socket.request('count', target);
+    expect(count.body).toEqual({ count: 1 });
+  });
+
+  it('matches pipelined replies to their requests', async () => {
+    const writes = ['a', 'b', 'c'].map((key) => socket.request('set', { ...target, key, value: key.toUpperCase() }));
+    await Promise.all(writes);
+
+    const reads = await Promise.all(['a', 'b', 'c'].map((key) => socket.request('get', { ...target, key })));
+    expect(reads.map((r) => r.body)).toEqual([{ value: 'A' }, { value: 'B' }, { value: 'C' }]);
+  });
+
+  it('returns HTTP errors in the reply', async () => {
+    const result = await socket.request('get', { cabinet: testCabinet, shelf: 'missing', key: 'a' });
+    expect(result.status).toBe(404);
+    expect(result.body).toMatchObject({ code: 'shelf_not_found' });
+
+    const unknown = await socket.request('frobnicate', target);
+    expect(unknown.status).toBe(400);
+  });
+
+  it('pushes changes to subscribers', async () => {
+    const subscribed = await socket.request<{ subscription: number }>('subscribe', { cabinet: testCabinet });
+    expect(subscribed.status).toBe(200);
+    const subscription = subscribed.body!.subscription;
+
+    await client.set(testCabinet, 'users', 'bob', 'viewer');
+    await waitFor(() => socket.changes.some((m) => m.subscription === subscription));
+    const pushed = socket.changes.filter((m) => m.subscription === subscription);
+    expect(pushed.map((m) => [m.change.op, m.change.key, m.change.value])).toEqual([['set', 'bob', 'viewer']]);
+
+    const unsubscribed = await socket.request('unsubscribe', { subscription });
+    expect(unsubscribed.status).toBe(204);
+
+    const again = await socket.request('unsubscribe', { subscription });
+    expect(again.status).toBe(400);
+  });
+
+  it('caps subscriptions per connection', async () => {
+    const capped = await client.openSocket();
+    try {
+      const ids: number[] = [];
+      for (let i = 0; i < 32; i++) {
+        const subscribed = await capped.request<{ subscription: number }>('subscribe', { cabinet: testCabinet });
+        expect(subscribed.status).toBe(200);
+        ids.push(subscribed.body!.subscription);
+      }
+
+      const over = await capped.request('subscribe', { cabinet: testCabinet });
+      expect(over.status).toBe(429);
+      expect(over.body).toMatchObject({ code: 'too_many_subscriptions' });
+
+      await capped.request('unsubscribe', { subscription: ids[0] });
+      const freed = await capped.request('subscribe', { cabinet: testCabinet });
+      expect(freed.status).toBe(200);
+    } finally {
+      capped.close();
+    }
+  });
+
+  it('answers a long-poll watch', async () => {
+    const watching = socket.request<{ changes: unknown[]; value: unknown }>('watch', { ...target, key: 'carol', timeout: 5 });
+    await client.set(testCabinet, 'users', 'carol', 'editor');
+    const result = await watching;
+    expect(result.status).toBe(200);
+    expect(result.body!.value).toBe('editor');
+  });
+
+  it('reserves the cabinet name used by the endpoint', async () => {
+    const created = await client.createCabinet('ws');
+    expect(created.status).toBe(400);
+
+    const renamed = await client.renameCabinet(testCabinet, 'ws');
+    expect(renamed.status).toBe(400);
+  });
+});